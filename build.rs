@@ -0,0 +1,58 @@
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    #[cfg(feature = "grpc")]
+    grpc::compile();
+}
+
+/// Generates the server of the gRPC service, described in
+/// `proto/feeder_cache.proto` for the clients
+#[cfg(feature = "grpc")]
+mod grpc {
+    use tonic_build::manual::{Builder, Method, MethodBuilder, Service};
+
+    fn method(name: &str, route_name: &str, input: &str, output: &str) -> MethodBuilder {
+        Method::builder()
+            .name(name)
+            .route_name(route_name)
+            .input_type(format!("crate::grpc::{}", input))
+            .output_type(format!("crate::grpc::{}", output))
+            .codec_path("tonic_prost::ProstCodec")
+    }
+
+    pub fn compile() {
+        let service = Service::builder()
+            .name("FeederCache")
+            .package("feeder_cache.v1")
+            .method(method("get_block", "GetBlock", "BlockRequest", "Payload").build())
+            .method(
+                method(
+                    "get_state_update",
+                    "GetStateUpdate",
+                    "BlockRequest",
+                    "Payload",
+                )
+                .build(),
+            )
+            .method(method("get_class", "GetClass", "ClassRequest", "ClassPayload").build())
+            .method(
+                method("stream_blocks", "StreamBlocks", "RangeRequest", "Payload")
+                    .server_streaming()
+                    .build(),
+            )
+            .method(
+                method(
+                    "stream_state_updates",
+                    "StreamStateUpdates",
+                    "RangeRequest",
+                    "Payload",
+                )
+                .server_streaming()
+                .build(),
+            )
+            .build();
+        Builder::new()
+            .build_client(false)
+            .build_transport(false)
+            .compile(&[service]);
+    }
+}
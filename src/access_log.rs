@@ -0,0 +1,121 @@
+#[cfg(feature = "server")]
+use actix_web::{
+    body::{BodySize, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderName, HeaderValue},
+    middleware::Next,
+    Error, HttpRequest,
+};
+#[cfg(feature = "server")]
+use serde_json::json;
+#[cfg(feature = "server")]
+use std::collections::hash_map::RandomState;
+#[cfg(feature = "server")]
+use std::hash::{BuildHasher, Hasher};
+#[cfg(feature = "server")]
+use std::time::Instant;
+
+#[cfg(feature = "server")]
+/// Response header set by the data handlers to `HIT`, `MISS`, or `PEER` when
+/// a peer cache answered the miss
+pub const CACHE_HEADER: &str = "x-cache";
+#[cfg(feature = "server")]
+/// Response header carrying the block number a request resolved to
+pub const BLOCK_HEADER: &str = "x-block-number";
+#[cfg(feature = "server")]
+/// Request and response header correlating a request across the node, the
+/// cache and the gateway
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+#[cfg(feature = "server")]
+/// Longer ids sent by clients are replaced
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+#[cfg(feature = "server")]
+pub const JSON_FORMAT: &str = "json";
+pub const DEFAULT_FORMAT: &str =
+    "%a \"%r\" %s %b %Dms cache=%{x-cache}o block=%{x-block-number}o id=%{x-request-id}i";
+
+/// Keeps the `X-Request-Id` of the request, or sets a new one, so the access
+/// logs, the spans and the upstream requests all see it, and answers it
+#[cfg(feature = "server")]
+pub async fn request_id(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let name = HeaderName::from_static(REQUEST_ID_HEADER);
+    let id = req
+        .headers()
+        .get(&name)
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.to_str().is_ok())
+        .cloned()
+        .unwrap_or_else(|| {
+            let random = || RandomState::new().build_hasher().finish();
+            HeaderValue::from_str(&format!("{:016x}{:016x}", random(), random()))
+                .expect("hex digits are a valid header value")
+        });
+    req.headers_mut().insert(name.clone(), id.clone());
+
+    let mut res = next.call(req).await?;
+    res.headers_mut().insert(name, id);
+    Ok(res)
+}
+
+/// The id `request_id` set, to forward it upstream
+#[cfg(feature = "server")]
+pub fn forwarded_request_id(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+}
+
+#[cfg(feature = "server")]
+pub async fn json_logger(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let start = Instant::now();
+    let remote = req
+        .connection_info()
+        .realip_remote_addr()
+        .map(str::to_string);
+    let method = req.method().to_string();
+    let path = req.path().to_string();
+    let query = req.query_string().to_string();
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .map(str::to_string);
+
+    let res = next.call(req).await?;
+
+    let size = match res.response().body().size() {
+        BodySize::Sized(size) => Some(size),
+        _ => None,
+    };
+    let header = |name: &str| {
+        res.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    };
+
+    tracing::info!(
+        target: "access_log",
+        "{}",
+        json!({
+            "remote": remote,
+            "method": method,
+            "path": path,
+            "query": query,
+            "status": res.status().as_u16(),
+            "size": size,
+            "latency_ms": start.elapsed().as_secs_f64() * 1000.0,
+            "cache": header(CACHE_HEADER),
+            "block_number": header(BLOCK_HEADER),
+            "request_id": request_id,
+        })
+    );
+
+    Ok(res)
+}
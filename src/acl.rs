@@ -0,0 +1,170 @@
+//! IP lists and API keys restricting the data routes, for a cache offered to
+//! a closed set of partners
+
+#[cfg(feature = "server")]
+use actix_web::body::{BoxBody, MessageBody};
+#[cfg(feature = "server")]
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+#[cfg(feature = "server")]
+use actix_web::middleware::Next;
+#[cfg(feature = "server")]
+use actix_web::{web, Error, HttpResponse};
+use std::net::IpAddr;
+
+#[cfg(feature = "server")]
+use crate::config::ServeArgs;
+#[cfg(feature = "server")]
+use crate::server::scope_path;
+
+/// First path segment of the routes restricted, also the scopes of the keys
+pub const DATA_ROUTES: [&str; 4] = ["feeder_gateway", "rpc", "index", "snapshots"];
+
+/// Header carrying the API key
+#[cfg(feature = "server")]
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+/// An address range, `10.0.0.0/8` or a single address
+#[derive(Clone, Copy)]
+#[cfg_attr(not(feature = "server"), allow(dead_code))]
+pub struct Cidr {
+    addr: IpAddr,
+    len: u8,
+}
+
+impl Cidr {
+    pub fn parse(value: &str) -> Result<Cidr, String> {
+        let (addr, len) = match value.trim().split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (value.trim(), None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|e| format!("{}: {}", value, e))?;
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let len = match len {
+            Some(len) => len
+                .parse::<u8>()
+                .ok()
+                .filter(|len| *len <= max)
+                .ok_or(format!("{}: invalid prefix length", value))?,
+            None => max,
+        };
+        Ok(Cidr { addr, len })
+    }
+
+    #[cfg(feature = "server")]
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.len as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.len as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Parses `<name>=<token>`, a named admin token
+pub fn parse_admin_operator(value: &str) -> Result<(&str, &str), String> {
+    match value.split_once('=') {
+        Some((name, token)) if !name.is_empty() && !token.is_empty() => Ok((name, token)),
+        _ => Err("not `<name>=<token>`".to_string()),
+    }
+}
+
+/// Parses `<key>=<scope>[+<scope>]`, a scope being one of `DATA_ROUTES` or
+/// `*` for all of them
+pub fn parse_api_key(value: &str) -> Result<(&str, Vec<&str>), String> {
+    let (key, scopes) = value
+        .split_once('=')
+        .ok_or("not `<key>=<scope>[+<scope>]`".to_string())?;
+    if key.is_empty() {
+        return Err("empty key".to_string());
+    }
+    let scopes: Vec<&str> = scopes.split('+').map(str::trim).collect();
+    if let Some(scope) = scopes
+        .iter()
+        .find(|scope| **scope != "*" && !DATA_ROUTES.contains(scope))
+    {
+        return Err(format!("unknown scope `{}`", scope));
+    }
+    Ok((key, scopes))
+}
+
+/// First path segment of a data route, `None` for the other routes
+#[cfg(feature = "server")]
+pub fn data_route(req: &ServiceRequest) -> Option<&'static str> {
+    let route = scope_path(req)
+        .trim_start_matches('/')
+        .split('/')
+        .next()
+        .unwrap_or_default();
+    DATA_ROUTES.into_iter().find(|data| *data == route)
+}
+
+/// Refuses the data requests from an address not allowed or denied, and,
+/// once keys are configured, those without a key allowed on the route
+#[cfg(feature = "server")]
+pub async fn check(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let Some(args) = req.app_data::<web::Data<ServeArgs>>() else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+    let Some(route) = data_route(&req) else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+
+    if !args.allow_ip.is_empty() || !args.deny_ip.is_empty() {
+        let addr = match args.trust_forwarded_for {
+            true => req
+                .connection_info()
+                .realip_remote_addr()
+                .and_then(|addr| addr.parse::<IpAddr>().ok()),
+            false => req.peer_addr().map(|addr| addr.ip()),
+        };
+        let listed = |ranges: &[String]| {
+            addr.is_some_and(|addr| {
+                ranges
+                    .iter()
+                    .filter_map(|range| Cidr::parse(range).ok())
+                    .any(|range| range.contains(addr))
+            })
+        };
+        let allowed = args.allow_ip.is_empty() || listed(&args.allow_ip);
+        if !allowed || listed(&args.deny_ip) {
+            let response = HttpResponse::Forbidden().body("Address not allowed");
+            return Ok(req.into_response(response));
+        }
+    }
+
+    if !args.api_key.is_empty() {
+        let key = req
+            .headers()
+            .get(API_KEY_HEADER)
+            .and_then(|key| key.to_str().ok());
+        let scopes = key.and_then(|key| {
+            args.api_key
+                .iter()
+                .filter_map(|value| parse_api_key(value).ok())
+                .find(|(configured, _)| *configured == key)
+                .map(|(_, scopes)| scopes)
+        });
+        let response = match scopes {
+            None => HttpResponse::Unauthorized().body("Invalid API key"),
+            Some(scopes) if !scopes.iter().any(|scope| *scope == "*" || *scope == route) => {
+                HttpResponse::Forbidden().body("API key not allowed on this route")
+            }
+            Some(_) => return Ok(next.call(req).await?.map_into_boxed_body()),
+        };
+        return Ok(req.into_response(response));
+    }
+
+    Ok(next.call(req).await?.map_into_boxed_body())
+}
@@ -0,0 +1,220 @@
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use std::sync::Arc;
+use subtle::ConstantTimeEq;
+
+use crate::acl;
+use crate::audit::{self, Operator};
+use crate::config::{DeleteRangeArgs, ServeArgs};
+use crate::logging;
+use crate::maintenance;
+use crate::reload::{self, Reloadable};
+use crate::replicate::{self, IngestError, Item, INGEST_MAX_BODY, INGEST_PATH};
+use crate::storage::Storage;
+use crate::upstream::Upstream;
+
+/// Registers the `/admin` routes, only when a token is configured
+pub fn configure(cfg: &mut web::ServiceConfig, args: &ServeArgs) {
+    if args.admin_token.is_some() || !args.admin_operator.is_empty() {
+        cfg.route("/admin/reload", web::post().to(reload));
+        cfg.route("/admin/log_filter", web::put().to(log_filter));
+        cfg.route("/admin/delete_range", web::post().to(delete_range));
+        cfg.route("/admin/audit", web::get().to(audit_log));
+        cfg.service(
+            web::resource(INGEST_PATH)
+                .app_data(web::PayloadConfig::new(INGEST_MAX_BODY))
+                .route(web::post().to(ingest)),
+        );
+    }
+}
+
+/// Whether the bearer token is the admin token or an operator's, whose name
+/// is kept in the request for the audit log
+fn authorized(req: &HttpRequest, args: &ServeArgs) -> bool {
+    let Some(token) = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+    if args
+        .admin_token
+        .as_deref()
+        .is_some_and(|admin_token| same_token(token, admin_token))
+    {
+        return true;
+    }
+    // Every token compared, so the time taken does not tell which matched
+    let operator = args
+        .admin_operator
+        .iter()
+        .filter_map(|value| acl::parse_admin_operator(value).ok())
+        .fold(None, |found, (name, operator_token)| {
+            match same_token(token, operator_token) {
+                true => Some(name),
+                false => found,
+            }
+        });
+    match operator {
+        Some(name) => {
+            req.extensions_mut().insert(Operator(name.to_string()));
+            true
+        }
+        None => false,
+    }
+}
+
+/// Whether `token` is `expected`, in a time not telling how much of it matches
+fn same_token(token: &str, expected: &str) -> bool {
+    token.as_bytes().ct_eq(expected.as_bytes()).into()
+}
+
+async fn reload(
+    req: HttpRequest,
+    args: web::Data<ServeArgs>,
+    storage: web::Data<Arc<Storage>>,
+    reloadables: web::Data<Vec<Arc<Reloadable>>>,
+    upstream: web::Data<Arc<Upstream>>,
+) -> HttpResponse {
+    let response = async {
+        if !authorized(&req, &args) {
+            return HttpResponse::Unauthorized().body("Invalid admin token");
+        }
+        match reload::reload(&reloadables, &upstream).await {
+            Ok(()) => HttpResponse::Ok().body("Configuration reloaded"),
+            Err(e) => {
+                tracing::error!("❌ Error reloading configuration: {}", e);
+                HttpResponse::BadRequest().body(e)
+            }
+        }
+    }
+    .await;
+    audit::record(&storage, &req, "", response)
+}
+
+/// Replaces the log filter until the next reload, e.g. with
+/// `info,[sync_class]=debug` to debug the class sync only
+async fn log_filter(
+    req: HttpRequest,
+    args: web::Data<ServeArgs>,
+    storage: web::Data<Arc<Storage>>,
+    body: String,
+) -> HttpResponse {
+    let response = async {
+        if !authorized(&req, &args) {
+            return HttpResponse::Unauthorized().body("Invalid admin token");
+        }
+        match logging::set_filter(Some(body.trim())) {
+            Ok(()) => {
+                tracing::info!("🔄 Log filter set to {}", body.trim());
+                HttpResponse::Ok().body("Log filter set")
+            }
+            Err(e) => HttpResponse::BadRequest().body(e),
+        }
+    }
+    .await;
+    audit::record(&storage, &req, body.trim(), response)
+}
+
+/// Runs `delete-range` with the same options as query parameters, e.g.
+/// `?from=100&to=199&states=true` or `?prefix=meta_`
+async fn delete_range(
+    req: HttpRequest,
+    args: web::Data<ServeArgs>,
+    storage: web::Data<Arc<Storage>>,
+    query: web::Query<DeleteRangeArgs>,
+) -> HttpResponse {
+    let response = async {
+        if !authorized(&req, &args) {
+            return HttpResponse::Unauthorized().body("Invalid admin token");
+        }
+        let query = query.into_inner();
+        if let Err(e) = query.check() {
+            return HttpResponse::BadRequest().body(e);
+        }
+        match storage
+            .blocking(move |storage| maintenance::delete_range(storage, &query))
+            .await
+        {
+            Ok(summary) => HttpResponse::Ok().body(summary),
+            Err(e) => {
+                tracing::error!("❌ Error deleting a range: {}", e);
+                HttpResponse::InternalServerError().body(e.to_string())
+            }
+        }
+    }
+    .await;
+    audit::record(&storage, &req, req.query_string(), response)
+}
+
+/// Stores the items pushed by a `--replicate-to` primary or another writer,
+/// a JSON array in the format described in the README
+async fn ingest(
+    req: HttpRequest,
+    args: web::Data<ServeArgs>,
+    storage: web::Data<Arc<Storage>>,
+    body: web::Bytes,
+) -> HttpResponse {
+    let response = async {
+        if !authorized(&req, &args) {
+            return HttpResponse::Unauthorized().body("Invalid admin token");
+        }
+        let items: Vec<Item> = match serde_json::from_slice(&body) {
+            Ok(items) => items,
+            Err(e) => return HttpResponse::BadRequest().body(format!("Invalid items: {}", e)),
+        };
+        match storage
+            .blocking(move |storage| replicate::ingest(storage, &items))
+            .await
+        {
+            Ok(stored) => {
+                tracing::debug!("📥 Ingested {} items", stored);
+                HttpResponse::Ok().json(serde_json::json!({ "stored": stored }))
+            }
+            Err(e @ IngestError::Invalid { .. }) => HttpResponse::BadRequest().body(e.to_string()),
+            Err(e) => {
+                tracing::error!("❌ Error ingesting: {}", e);
+                HttpResponse::InternalServerError().body(e.to_string())
+            }
+        }
+    }
+    .await;
+    audit::record(&storage, &req, format!("{} bytes", body.len()), response)
+}
+
+const AUDIT_DEFAULT_LIMIT: usize = 100;
+
+// url ...audit?before=...&limit=...
+#[derive(Deserialize)]
+struct AuditQuery {
+    before: Option<u64>,
+    limit: Option<usize>,
+}
+
+/// Lists the recorded admin calls, newest first. Reading the log is not
+/// recorded
+async fn audit_log(
+    req: HttpRequest,
+    args: web::Data<ServeArgs>,
+    storage: web::Data<Arc<Storage>>,
+    web::Query(query): web::Query<AuditQuery>,
+) -> HttpResponse {
+    if !authorized(&req, &args) {
+        return HttpResponse::Unauthorized().body("Invalid admin token");
+    }
+    let limit = query
+        .limit
+        .unwrap_or(AUDIT_DEFAULT_LIMIT)
+        .min(audit::CAPACITY as usize);
+    let entries = web::block(move || audit::entries(storage.db(), query.before, limit)).await;
+    match entries {
+        Ok(entries) => HttpResponse::Ok().json(entries),
+        Err(e) => {
+            tracing::error!("❌ Error reading the audit log: {}", e);
+            HttpResponse::InternalServerError().body("Error reading the audit log")
+        }
+    }
+}
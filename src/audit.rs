@@ -0,0 +1,134 @@
+//! Persistent log of the `/admin` calls, for operations teams sharing an
+//! instance to review who deleted or rewrote what
+
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::http::header::{FORWARDED, X_FORWARDED_FOR};
+use actix_web::{HttpMessage, HttpRequest, HttpResponse};
+use rocksdb::{Direction, IteratorMode, WriteBatch, DB};
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::access_log::forwarded_request_id;
+use crate::primitives::Audit;
+use crate::storage::Storage;
+
+/// Number of calls kept, the oldest ones are dropped first
+pub const CAPACITY: u64 = 100_000;
+
+/// Name of the `--admin-operator` token of a call, in the request extensions
+#[derive(Clone)]
+pub struct Operator(pub String);
+
+/// An admin call and its outcome
+#[derive(Serialize, Deserialize)]
+pub struct Entry {
+    pub seq: u64,
+    /// Unix time in seconds
+    pub timestamp: u64,
+    /// Address of the connection
+    pub client: Option<String>,
+    /// Client reported by `Forwarded` or `X-Forwarded-For`, unverified
+    pub forwarded_for: Option<String>,
+    /// Name of the `--admin-operator` token used, none with `--admin-token`
+    /// and in the entries recorded before operators were
+    #[serde(default)]
+    pub operator: Option<String>,
+    pub request_id: Option<String>,
+    pub method: String,
+    pub path: String,
+    /// The query, the body or a summary of it
+    pub params: String,
+    pub status: u16,
+    /// Body of the response, cut to `MAX_RESULT` bytes
+    pub result: String,
+}
+
+/// Longest response body kept in an entry
+const MAX_RESULT: usize = 1024;
+
+/// Records the call answered by `response`, and returns it
+pub fn record(
+    storage: &Storage,
+    req: &HttpRequest,
+    params: impl Into<String>,
+    response: HttpResponse,
+) -> HttpResponse {
+    let seq = storage.next_audit_seq();
+    let (response, body) = response.into_parts();
+    let (result, body) = match body.try_into_bytes() {
+        Ok(bytes) => (
+            String::from_utf8_lossy(&bytes[..bytes.len().min(MAX_RESULT)]).into_owned(),
+            BoxBody::new(bytes),
+        ),
+        Err(body) => (String::new(), body),
+    };
+    let response = response.set_body(body);
+    let client = req.peer_addr().map(|addr| addr.ip().to_string());
+    let forwarded = [FORWARDED, X_FORWARDED_FOR]
+        .iter()
+        .any(|name| req.headers().contains_key(name));
+    let forwarded_for = match forwarded {
+        true => req
+            .connection_info()
+            .realip_remote_addr()
+            .map(str::to_string),
+        false => None,
+    };
+    let entry = Entry {
+        seq,
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs()),
+        client,
+        forwarded_for,
+        operator: req
+            .extensions()
+            .get::<Operator>()
+            .map(|operator| operator.0.clone()),
+        request_id: forwarded_request_id(req).map(str::to_string),
+        method: req.method().to_string(),
+        path: req.path().to_string(),
+        params: params.into(),
+        status: response.status().as_u16(),
+        result,
+    };
+    tracing::debug!(
+        "📝 {} {} {} from {} ({})",
+        entry.method,
+        entry.path,
+        entry.status,
+        entry.client.as_deref().unwrap_or("unknown"),
+        entry.operator.as_deref().unwrap_or("admin token")
+    );
+
+    let mut batch = WriteBatch::default();
+    match serde_json::to_string(&entry) {
+        Ok(value) => batch.put(Audit(seq).key(), value),
+        Err(e) => {
+            tracing::error!("❌ Error serializing audit entry {}: {}", seq, e);
+            return response;
+        }
+    }
+    if seq >= CAPACITY {
+        batch.delete(Audit(seq - CAPACITY).key());
+    }
+    if let Err(e) = storage.db().write(batch) {
+        tracing::error!("❌ Error recording audit entry {}: {}", seq, e);
+    }
+    response
+}
+
+/// Newest calls first, starting right before `before` when given
+pub fn entries(db: &DB, before: Option<u64>, limit: usize) -> Vec<Entry> {
+    let start = match before {
+        Some(before) => Audit(before).key(),
+        None => format!("{}:", Audit::KEY_PREFIX),
+    };
+    db.iterator(IteratorMode::From(start.as_bytes(), Direction::Reverse))
+        .map_while(Result::ok)
+        .take_while(|(key, _)| key.starts_with(Audit::KEY_PREFIX.as_bytes()))
+        .filter_map(|(_, value)| serde_json::from_slice::<Entry>(&value).ok())
+        .filter(|entry| before.is_none_or(|before| entry.seq < before))
+        .take(limit)
+        .collect()
+}
@@ -0,0 +1,78 @@
+//! MessagePack encoding of the cached blocks and state updates, for internal
+//! consumers asking for `application/octet-stream` to spare JSON parsing and
+//! bandwidth
+//!
+//! The JSON document is transcoded value by value: objects become maps,
+//! arrays arrays, numbers integers or floats. Strings of `0x` and 1 to 64
+//! hexadecimal digits, field elements such as hashes, addresses and felts,
+//! keys included, become binaries of their big-endian bytes without leading
+//! zeros, so zero is an empty binary. Every other string stays a string.
+
+use actix_web::http::header::ACCEPT;
+use actix_web::HttpRequest;
+use rmpv::{Integer, Utf8String, Value};
+
+pub const CONTENT_TYPE: &str = "application/octet-stream";
+
+/// Whether the request accepts the binary encoding
+pub fn wanted(req: &HttpRequest) -> bool {
+    req.headers()
+        .get_all(ACCEPT)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|media| media.split(';').next().unwrap_or_default().trim() == CONTENT_TYPE)
+}
+
+/// Transcodes a JSON document
+pub fn encode(content: &[u8]) -> Result<Vec<u8>, String> {
+    let json: serde_json::Value = serde_json::from_slice(content).map_err(|e| e.to_string())?;
+    let mut encoded = Vec::with_capacity(content.len() / 2);
+    rmpv::encode::write_value(&mut encoded, &transcode(json)).map_err(|e| e.to_string())?;
+    Ok(encoded)
+}
+
+fn transcode(json: serde_json::Value) -> Value {
+    match json {
+        serde_json::Value::Null => Value::Nil,
+        serde_json::Value::Bool(value) => Value::Boolean(value),
+        serde_json::Value::Number(number) => match (number.as_u64(), number.as_i64()) {
+            (Some(value), _) => Value::Integer(Integer::from(value)),
+            (_, Some(value)) => Value::Integer(Integer::from(value)),
+            _ => Value::F64(number.as_f64().unwrap_or_default()),
+        },
+        serde_json::Value::String(value) => string(value),
+        serde_json::Value::Array(values) => {
+            Value::Array(values.into_iter().map(transcode).collect())
+        }
+        serde_json::Value::Object(entries) => Value::Map(
+            entries
+                .into_iter()
+                .map(|(key, value)| (string(key), transcode(value)))
+                .collect(),
+        ),
+    }
+}
+
+fn string(value: String) -> Value {
+    match felt_bytes(&value) {
+        Some(bytes) => Value::Binary(bytes),
+        None => Value::String(Utf8String::from(value)),
+    }
+}
+
+/// Big-endian bytes of a `0x` prefixed field element, without leading zeros
+fn felt_bytes(value: &str) -> Option<Vec<u8>> {
+    let digits = value.strip_prefix("0x")?;
+    if digits.is_empty() || digits.len() > 64 || !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let digits = digits.trim_start_matches('0');
+    let padded = match digits.len() % 2 {
+        0 => digits.to_string(),
+        _ => format!("0{}", digits),
+    };
+    (0..padded.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&padded[i..i + 2], 16).ok())
+        .collect()
+}
@@ -0,0 +1,684 @@
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+#[cfg(feature = "sync")]
+use crate::class_extract::read_class_seed;
+#[cfg(feature = "sync")]
+use crate::class_hash::VerifiedGateway;
+use crate::config::{Command, Config, Network, ServeArgs};
+#[cfg(feature = "sync")]
+use crate::fixture;
+#[cfg(feature = "sync")]
+use crate::gateway::{BlockedGateway, Gateway, HttpGateway};
+#[cfg(feature = "grpc")]
+use crate::grpc;
+use crate::handoff;
+#[cfg(feature = "server")]
+use crate::integrity;
+use crate::journal;
+#[cfg(feature = "server")]
+use crate::metrics::Metrics;
+use crate::mirror;
+use crate::reload::{self, Reloadable};
+use crate::replicate;
+#[cfg(feature = "server")]
+use crate::schedule;
+#[cfg(feature = "server")]
+use crate::server;
+use crate::shutdown;
+#[cfg(feature = "sync")]
+use crate::snapshot;
+use crate::storage::{Storage, StorageError};
+use crate::supervisor::Supervisor;
+#[cfg(feature = "sync")]
+use crate::sync::{self, NetworkCheck, NetworkCheckError};
+use crate::systemd;
+use crate::upstream::{ClientError, Upstream};
+#[cfg(feature = "server")]
+use crate::warmup::{self, ClassHits};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("invalid configuration: {}", .0.join(", "))]
+    InvalidConfig(Vec<String>),
+    #[error("not a serve nor sync configuration")]
+    NothingToRun,
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+    #[error("upstream client: {0}")]
+    Upstream(#[from] ClientError),
+    #[cfg(feature = "sync")]
+    #[error("network check: {0}")]
+    NetworkCheck(Arc<NetworkCheckError>),
+    #[error("replay: {0}")]
+    Replay(String),
+    #[error("snapshot bootstrap: {0}")]
+    Bootstrap(String),
+    #[error("the DB is still held by {0} handles, not restarting in place")]
+    StillOpen(usize),
+    #[error("binding {addr}: {source}")]
+    Bind {
+        addr: String,
+        source: std::io::Error,
+    },
+}
+
+/// The sync tasks and, unless built with `serve(false)`, the HTTP server
+pub struct FeederCache {
+    config: Config,
+    storage: Arc<Storage>,
+    #[cfg(feature = "sync")]
+    gateway: Option<Arc<dyn Gateway>>,
+}
+
+/// Starts from the defaults of `serve`, the environment and the config file
+/// are not read
+pub struct FeederCacheBuilder {
+    config: Config,
+    serve: bool,
+    #[cfg(feature = "sync")]
+    gateway: Option<Arc<dyn Gateway>>,
+}
+
+// Derivable in builds without the server only
+#[allow(clippy::derivable_impls)]
+impl Default for FeederCacheBuilder {
+    fn default() -> FeederCacheBuilder {
+        FeederCacheBuilder {
+            config: Config::default(),
+            serve: cfg!(feature = "server"),
+            #[cfg(feature = "sync")]
+            gateway: None,
+        }
+    }
+}
+
+impl FeederCacheBuilder {
+    fn args(&mut self) -> &mut ServeArgs {
+        match &mut self.config.command {
+            Some(Command::Serve(args)) => args,
+            _ => unreachable!("Config::default is a serve configuration"),
+        }
+    }
+
+    pub fn network(mut self, network: Network) -> FeederCacheBuilder {
+        self.config.network = network;
+        self
+    }
+
+    pub fn db_path(mut self, db_path: impl Into<PathBuf>) -> FeederCacheBuilder {
+        self.config.db_path = Some(db_path.into());
+        self
+    }
+
+    pub fn feeder_gateway_url(mut self, url: impl Into<String>) -> FeederCacheBuilder {
+        self.config.feeder_gateway_url = Some(url.into());
+        self
+    }
+
+    pub fn upstream_proxy(mut self, proxy: impl Into<String>) -> FeederCacheBuilder {
+        self.config.upstream_proxy = Some(proxy.into());
+        self
+    }
+
+    pub fn max_block_to_sync(mut self, max_block_to_sync: u64) -> FeederCacheBuilder {
+        self.args().sync.max_block_to_sync = max_block_to_sync;
+        self
+    }
+
+    pub fn shutdown_timeout(mut self, seconds: u64) -> FeederCacheBuilder {
+        self.args().sync.shutdown_timeout = seconds;
+        self
+    }
+
+    pub fn server_addr(mut self, server_addr: impl Into<String>) -> FeederCacheBuilder {
+        self.args().server_addr = server_addr.into();
+        self
+    }
+
+    pub fn rpc(mut self, rpc: bool) -> FeederCacheBuilder {
+        self.args().rpc = rpc;
+        self
+    }
+
+    pub fn proxy_cache(mut self, proxy_cache: bool) -> FeederCacheBuilder {
+        self.args().proxy_cache = proxy_cache;
+        self
+    }
+
+    /// Syncs from `gateway`, e.g. a `MockGateway`, instead of the feeder
+    /// gateway URL. The routes forwarded to the gateway still use the URL
+    #[cfg(feature = "sync")]
+    pub fn gateway(mut self, gateway: Arc<dyn Gateway>) -> FeederCacheBuilder {
+        self.gateway = Some(gateway);
+        self
+    }
+
+    /// Only syncs when false
+    pub fn serve(mut self, serve: bool) -> FeederCacheBuilder {
+        self.serve = serve;
+        self
+    }
+
+    /// Validates the configuration and opens the DB
+    pub fn build(mut self) -> Result<FeederCache, Error> {
+        if !self.serve {
+            let sync = self.args().sync.clone();
+            self.config.command = Some(Command::Sync(sync));
+        }
+        let problems = self.config.validate();
+        if !problems.is_empty() {
+            return Err(Error::InvalidConfig(problems));
+        }
+        let storage = Arc::new(Storage::open(
+            &self.config.db_path(),
+            &self.config.db,
+            self.config.wal_retention(),
+        )?);
+        Ok(FeederCache {
+            config: self.config,
+            storage,
+            #[cfg(feature = "sync")]
+            gateway: self.gateway,
+        })
+    }
+}
+
+impl FeederCache {
+    pub fn builder() -> FeederCacheBuilder {
+        FeederCacheBuilder::default()
+    }
+
+    /// `config` must be a validated `serve` or `sync` configuration
+    pub(crate) fn new(config: Config, storage: Arc<Storage>) -> FeederCache {
+        FeederCache {
+            config,
+            storage,
+            #[cfg(feature = "sync")]
+            gateway: None,
+        }
+    }
+
+    pub fn storage(&self) -> &Arc<Storage> {
+        &self.storage
+    }
+
+    /// Runs until `shutdown` resolves with the reason to stop, then until
+    /// the tasks finish or the shutdown timeout is reached
+    pub async fn run_until<F>(self, shutdown: F) -> Result<(), Error>
+    where
+        F: Future<Output = String> + Send + 'static,
+    {
+        self.run(shutdown, false).await
+    }
+
+    /// Also reloads on SIGHUP, stops for a restart on SIGUSR2 and notifies
+    /// systemd, which only the binary should do
+    pub(crate) async fn run_until_signal(self) -> Result<(), Error> {
+        let signal = async {
+            tokio::select! {
+                signal = shutdown::signal() => format!("{} received", signal),
+                signal = handoff::signal() => format!("{} received for a restart", signal),
+            }
+        };
+        self.run(signal, true).await
+    }
+
+    async fn run<F>(self, shutdown: F, standalone: bool) -> Result<(), Error>
+    where
+        F: Future<Output = String> + Send + 'static,
+    {
+        #[cfg(feature = "sync")]
+        let mut gateway = self.gateway;
+        let FeederCache {
+            config, storage, ..
+        } = self;
+        let (sync_args, serve) = match &config.command {
+            Some(Command::Serve(args)) => (&args.sync, Some(args)),
+            Some(Command::Sync(args)) => (args, None),
+            _ => return Err(Error::NothingToRun),
+        };
+        let upstream = Arc::new(Upstream::new(&config)?);
+        // The extra networks sync from their gateway into their own DB
+        let mut extras = vec![];
+        for network in &sync_args.extra_networks {
+            let storage = Arc::new(Storage::open(
+                &config.extra_db_path(*network),
+                &config.db,
+                None,
+            )?);
+            extras.push((
+                *network,
+                storage,
+                Arc::new(Reloadable::extra(&config, *network)),
+            ));
+        }
+
+        let reloadable = Arc::new(Reloadable::new(&config));
+
+        let run = Arc::new(AtomicBool::new(true));
+        let run_clone = run.clone();
+
+        // Change run to false once a shutdown is requested
+        let storage_clone = storage.clone();
+        tokio::spawn(async move {
+            let reason = shutdown.await;
+            tracing::info!("🛑 {}, shutting down", reason);
+            journal::record(&storage_clone, "shutdown", reason);
+            if standalone {
+                systemd::stopping();
+            }
+            run_clone.store(false, Ordering::SeqCst);
+        });
+
+        #[cfg(feature = "sync")]
+        if let Some(url) = &sync_args.bootstrap_from_snapshot {
+            snapshot::bootstrap(&config, &storage, url, &run)
+                .await
+                .map_err(Error::Bootstrap)?;
+        }
+
+        if standalone {
+            let reloadables = std::iter::once(&reloadable)
+                .chain(extras.iter().map(|(_, _, reloadable)| reloadable))
+                .cloned()
+                .collect();
+            tokio::spawn(reload::on_sighup(reloadables, upstream.clone()));
+        }
+
+        let tuning = sync_args.tuning.resolve(config.network);
+        let mut networks = vec![(config.network, storage.clone(), reloadable)];
+        networks.extend(extras);
+
+        // Outside the supervised tasks, so they outlive them for a last copy.
+        // Started before the sync, so a first replication misses no write
+        let replicate_client = match sync_args.replicate_to.is_empty() {
+            true => None,
+            false => Some(replicate::client(&config)?),
+        };
+        let wal_readers_stop = Arc::new(AtomicBool::new(false));
+        let mut wal_readers = vec![];
+        if let Some(path) = &sync_args.mirror_path {
+            wal_readers.push(tokio::spawn(mirror::run(
+                storage.clone(),
+                path.clone(),
+                config.db.clone(),
+                wal_readers_stop.clone(),
+            )));
+        }
+        if let Some(client) = replicate_client {
+            for url in &sync_args.replicate_to {
+                wal_readers.push(tokio::spawn(replicate::run(
+                    storage.clone(),
+                    client.clone(),
+                    url.clone(),
+                    sync_args.replicate_token.clone(),
+                    tuning.retry_delay,
+                    replicate::position(&storage, url),
+                    wal_readers_stop.clone(),
+                )));
+            }
+        }
+
+        let supervisor = Arc::new(Supervisor::new(run.clone(), tuning, storage.clone()));
+        let mut set = tokio::task::JoinSet::new();
+        #[cfg(feature = "server")]
+        let mut chains = vec![];
+        #[cfg(feature = "server")]
+        let mut class_hits_saved = vec![];
+        let mut storages = vec![];
+        #[cfg(feature = "sync")]
+        let mut checks = vec![];
+        for (network, storage, reloadable) in networks {
+            storages.push(storage.clone());
+            journal::record(
+                &storage,
+                "started",
+                format!(
+                    "{} up to block {}",
+                    match serve {
+                        Some(_) => "serve",
+                        None => "sync",
+                    },
+                    sync_args.max_block_to_sync
+                ),
+            );
+
+            // The tasks of the extra networks are named after them
+            let prefix = match network == config.network {
+                true => String::new(),
+                false => format!("{}/", network.name()),
+            };
+            #[cfg(feature = "sync")]
+            let tuning = sync_args.tuning.resolve(network);
+            // An embedded or replayed gateway is not checked against the network
+            #[cfg(feature = "sync")]
+            let (gateway, checked): (Arc<dyn Gateway>, bool) =
+                match (prefix.is_empty(), gateway.take(), &sync_args.replay) {
+                    (true, Some(gateway), _) => (gateway, false),
+                    (true, None, Some(replay)) => {
+                        tracing::info!("📼 Replaying {}", replay.display());
+                        (
+                            Arc::new(fixture::load(replay).map_err(Error::Replay)?),
+                            false,
+                        )
+                    }
+                    _ => (
+                        Arc::new(HttpGateway::new(
+                            upstream.clone(),
+                            reloadable.clone(),
+                            tuning.retry_delay,
+                        )),
+                        true,
+                    ),
+                };
+            #[cfg(feature = "sync")]
+            let gateway: Arc<dyn Gateway> = match sync_args.verify_class_hash {
+                true => Arc::new(VerifiedGateway(gateway)),
+                false => gateway,
+            };
+            #[cfg(feature = "sync")]
+            let gateway: Arc<dyn Gateway> = match sync_args.class_blocklist.is_empty() {
+                true => gateway,
+                false => Arc::new(BlockedGateway::new(gateway, &sync_args.class_blocklist)),
+            };
+            #[cfg(feature = "sync")]
+            let check = Arc::new(NetworkCheck::new(
+                network,
+                sync_args,
+                !checked,
+                storage.clone(),
+                gateway.clone(),
+                reloadable.clone(),
+            ));
+            #[cfg(feature = "sync")]
+            checks.push(check.clone());
+            #[cfg(feature = "sync")]
+            sync::spawn(
+                &supervisor,
+                &mut set,
+                &prefix,
+                sync_args.max_block_to_sync,
+                tuning,
+                &check,
+            );
+            #[cfg(feature = "sync")]
+            if let (true, Some(path)) = (prefix.is_empty(), &sync_args.class_seed_file) {
+                let class_hashes = read_class_seed(path)
+                    .map_err(|e| Error::InvalidConfig(vec![format!("class_seed_file: {}", e)]))?;
+                supervisor.spawn(&mut set, "class_seed", true, {
+                    let (run, storage, gateway) = (run.clone(), storage.clone(), gateway.clone());
+                    let check = check.clone();
+                    move || {
+                        sync::after_check(
+                            check.clone(),
+                            run.clone(),
+                            sync::seed_classes(
+                                class_hashes.clone(),
+                                tuning,
+                                run.clone(),
+                                storage.clone(),
+                                gateway.clone(),
+                            ),
+                        )
+                    }
+                });
+            }
+            #[cfg(feature = "sync")]
+            if let (true, Some(url)) = (prefix.is_empty(), &sync_args.verify_against_url) {
+                let reference: Arc<dyn Gateway> = Arc::new(HttpGateway::new(
+                    upstream.clone(),
+                    Arc::new(Reloadable::fixed(url)),
+                    tuning.retry_delay,
+                ));
+                let (end, rate) = (sync_args.max_block_to_sync, sync_args.verify_sample_rate);
+                supervisor.spawn(&mut set, "cross_check", true, {
+                    let (run, storage) = (run.clone(), storage.clone());
+                    move || {
+                        sync::cross_check(
+                            end,
+                            tuning,
+                            run.clone(),
+                            storage.clone(),
+                            reference.clone(),
+                            rate,
+                        )
+                    }
+                });
+            }
+
+            #[cfg(feature = "server")]
+            if serve.is_some() {
+                let metrics = Arc::new(Metrics::default());
+                // The lag is only reported by the server, and would keep `sync` running
+                #[cfg(feature = "sync")]
+                supervisor.spawn(&mut set, format!("{}head", prefix), true, {
+                    let (run, gateway, metrics) = (run.clone(), gateway.clone(), metrics.clone());
+                    move || sync::watch_head(tuning, run.clone(), gateway.clone(), metrics.clone())
+                });
+                if let (true, Some(args)) = (network == config.network, serve) {
+                    let context = schedule::Context {
+                        storage: storage.clone(),
+                        backup_dir: args.schedule.schedule_backup_dir.clone(),
+                        #[cfg(feature = "sync")]
+                        reverify_tail: args.schedule.reverify_tail,
+                        #[cfg(feature = "sync")]
+                        gateway: gateway.clone(),
+                    };
+                    for value in &args.schedule.schedule {
+                        let (job, schedule) = schedule::parse(value)
+                            .map_err(|e| Error::InvalidConfig(vec![format!("schedule: {}", e)]))?;
+                        supervisor.spawn(&mut set, format!("scheduled_{}", job.name()), true, {
+                            let (run, context) = (run.clone(), context.clone());
+                            move || {
+                                schedule::run(job, schedule.clone(), run.clone(), context.clone())
+                            }
+                        });
+                    }
+                }
+                if let Some(args) = serve {
+                    if args.warmup_blocks > 0 || args.warmup_classes > 0 {
+                        let (blocks, classes) = (args.warmup_blocks, args.warmup_classes);
+                        supervisor.spawn(&mut set, format!("{}warmup", prefix), false, {
+                            let storage = storage.clone();
+                            move || warmup::run(storage.clone(), blocks, classes)
+                        });
+                    }
+                    if args.integrity_samples > 0 {
+                        let samples = args.integrity_samples;
+                        supervisor.spawn(&mut set, format!("{}integrity", prefix), true, {
+                            let (run, storage, metrics) =
+                                (run.clone(), storage.clone(), metrics.clone());
+                            move || {
+                                integrity::run(
+                                    samples,
+                                    run.clone(),
+                                    storage.clone(),
+                                    metrics.clone(),
+                                )
+                            }
+                        });
+                    }
+                }
+                let class_hits = Arc::new(ClassHits::default());
+                class_hits_saved.push((storage.clone(), class_hits.clone()));
+                chains.push(server::Chain {
+                    network,
+                    storage,
+                    reloadable,
+                    metrics,
+                    class_hits,
+                    tuning,
+                });
+            }
+        }
+
+        // Awaited after the stop, its workers hold the DB until then
+        #[cfg(feature = "server")]
+        let mut server_task = None;
+        #[cfg(feature = "server")]
+        if let Some(args) = serve {
+            let server = match server::start(args, &chains, upstream, supervisor.clone()) {
+                Ok(server) => server,
+                Err(source) => {
+                    // Lets the sync tasks stop before the DB is closed
+                    run.store(false, Ordering::SeqCst);
+                    while set.join_next().await.is_some() {}
+                    return Err(Error::Bind {
+                        addr: args.server_addr.clone(),
+                        source,
+                    });
+                }
+            };
+
+            let server_handle = server.handle();
+            server_task = Some(tokio::spawn(server));
+
+            let run_clone = run.clone();
+            // The server cannot be started again, so it is not restarted
+            supervisor.spawn(&mut set, "server", false, move || {
+                let (run, server_handle) = (run_clone.clone(), server_handle.clone());
+                async move {
+                    while run.load(Ordering::SeqCst) {
+                        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+                    }
+                    server_handle.stop(true).await;
+                    Ok("server stop".to_string())
+                }
+            });
+
+            #[cfg(feature = "grpc")]
+            if let Some(addr) = &args.grpc_addr {
+                let handle = match grpc::start(addr, storage.clone(), args, run.clone()) {
+                    Ok(handle) => handle,
+                    Err(source) => {
+                        run.store(false, Ordering::SeqCst);
+                        while set.join_next().await.is_some() {}
+                        return Err(Error::Bind {
+                            addr: addr.clone(),
+                            source,
+                        });
+                    }
+                };
+                // Stops with `run`, taken once as it is not restarted
+                let handle = Arc::new(std::sync::Mutex::new(Some(handle)));
+                supervisor.spawn(&mut set, "grpc", false, move || {
+                    let handle = handle.lock().unwrap().take();
+                    async move {
+                        if let Some(handle) = handle {
+                            let _ = handle.await;
+                        }
+                        Ok("grpc stop".to_string())
+                    }
+                });
+            }
+        }
+
+        let watchdog = standalone.then(|| {
+            systemd::ready(match serve {
+                Some(_) => "Syncing and serving",
+                None => "Syncing",
+            });
+            tokio::spawn(systemd::watchdog(storage.clone()))
+        });
+
+        let deadline = shutdown_deadline(run.clone(), sync_args.shutdown_timeout);
+        tokio::pin!(deadline);
+        let mut aborted = false;
+        loop {
+            tokio::select! {
+                result = set.join_next() => match result {
+                    Some(Ok((name, Ok(summary)))) => {
+                        tracing::info!("🔴 Task {} stopped: {}", name, summary);
+                        journal::record(&storage, "task_stopped", format!("{}: {}", name, summary));
+                    }
+                    Some(Ok((name, Err(e)))) => {
+                        tracing::error!("❌ Task {} failed: {}", name, e);
+                        journal::record(&storage, "task_failed", format!("{}: {}", name, e));
+                    }
+                    Some(Err(e)) if e.is_cancelled() => {}
+                    Some(Err(e)) => {
+                        tracing::error!("❌ Error: {}", e);
+                        journal::record(&storage, "task_failed", e.to_string());
+                    }
+                    None => break,
+                },
+                _ = &mut deadline, if !aborted => {
+                    tracing::warn!("⏱️ Shutdown timeout reached, aborting {} tasks", set.len());
+                    journal::record(&storage, "tasks_aborted", format!("{} tasks", set.len()));
+                    // Tasks only yield between DB writes, so none is cut short
+                    set.abort_all();
+                    aborted = true;
+                }
+            }
+        }
+
+        #[cfg(feature = "server")]
+        if let Some(args) = serve.filter(|args| args.warmup_classes > 0) {
+            for (storage, class_hits) in &class_hits_saved {
+                if let Err(e) = class_hits.save(storage, args.warmup_classes) {
+                    tracing::error!("❌ Error saving the requested classes: {}", e);
+                }
+            }
+        }
+        wal_readers_stop.store(true, Ordering::SeqCst);
+        for handle in wal_readers {
+            if let Err(e) = handle.await {
+                tracing::error!("❌ Error stopping a WAL reader: {}", e);
+            }
+        }
+        // Pinged until the tasks stopped, it holds the DB open otherwise
+        if let Some(watchdog) = watchdog {
+            watchdog.abort();
+            let _ = watchdog.await;
+        }
+        #[cfg(feature = "server")]
+        if let Some(server) = server_task {
+            if let Err(e) = server.await {
+                tracing::error!("❌ Error stopping the server: {}", e);
+            }
+        }
+        for storage in &storages {
+            match storage.flush() {
+                Ok(()) => tracing::info!("💾 Storage flushed"),
+                Err(e) => tracing::error!("❌ Error flushing storage: {}", e),
+            }
+        }
+
+        // The next process opens the DB and takes its lock once this one
+        // is replaced, so no task may still hold it
+        drop((storage, supervisor));
+        #[cfg(feature = "server")]
+        drop((chains, class_hits_saved));
+        // A `sync` refused by the check fails, `serve` kept serving the DB
+        #[cfg(feature = "sync")]
+        let refused = checks.iter().find_map(|check| check.refused());
+        #[cfg(feature = "sync")]
+        drop(checks);
+        if handoff::requested() {
+            let holders = storages
+                .iter()
+                .map(|storage| Arc::strong_count(storage) - 1)
+                .sum::<usize>();
+            if holders > 0 {
+                handoff::cancel();
+                return Err(Error::StillOpen(holders));
+            }
+        }
+        #[cfg(feature = "sync")]
+        if let (None, Some(e)) = (serve, refused) {
+            return Err(Error::NetworkCheck(e));
+        }
+        Ok(())
+    }
+}
+
+/// Resolves `timeout` seconds after a shutdown was requested
+async fn shutdown_deadline(run: Arc<AtomicBool>, timeout: u64) {
+    while run.load(Ordering::SeqCst) {
+        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+    }
+    tokio::time::sleep(tokio::time::Duration::from_secs(timeout)).await;
+}
@@ -0,0 +1,163 @@
+//! `Cache-Control` and `Expires` headers per route, so a CDN in front of the
+//! cache keeps the historical data and never the chain head
+
+#[cfg(feature = "server")]
+use actix_web::body::MessageBody;
+#[cfg(feature = "server")]
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+#[cfg(feature = "server")]
+use actix_web::http::header::{HeaderValue, HttpDate, CACHE_CONTROL, EXPIRES};
+#[cfg(feature = "server")]
+use actix_web::http::StatusCode;
+#[cfg(feature = "server")]
+use actix_web::middleware::Next;
+#[cfg(feature = "server")]
+use actix_web::{web, Error};
+#[cfg(feature = "server")]
+use std::time::{Duration, SystemTime};
+
+#[cfg(feature = "server")]
+use crate::proxy::is_immutable;
+
+/// Responses a rule applies to
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    /// Successful responses pinned to a block number, block hash or class
+    /// hash, which never change
+    Pinned,
+    /// Every other response, following the chain head
+    Head,
+}
+
+#[cfg_attr(not(feature = "server"), allow(dead_code))]
+struct Rule {
+    /// Last path segment, `None` for every route
+    route: Option<String>,
+    kind: Option<Kind>,
+    /// Empty to send no header
+    value: String,
+}
+
+/// The rules of `--cache-control`, after the defaults
+#[cfg_attr(not(feature = "server"), allow(dead_code))]
+pub struct CacheControl(Vec<Rule>);
+
+/// Applied unless overridden
+const DEFAULT_RULES: [&str; 2] = [
+    "*:pinned=public, max-age=31536000, immutable",
+    "*:head=no-store",
+];
+
+/// Parses `<route>[:pinned|:head]=<value>` rules, a later rule replacing the
+/// earlier ones it covers
+pub fn parse(rules: &[String]) -> Result<CacheControl, String> {
+    let mut parsed: Vec<Rule> = vec![];
+    for value in DEFAULT_RULES
+        .iter()
+        .copied()
+        .chain(rules.iter().map(String::as_str))
+    {
+        let rule = parse_rule(value)?;
+        parsed.retain(|other| {
+            other.route != rule.route || rule.kind.is_some_and(|kind| other.kind != Some(kind))
+        });
+        parsed.push(rule);
+    }
+    Ok(CacheControl(parsed))
+}
+
+fn parse_rule(value: &str) -> Result<Rule, String> {
+    let (selector, header) = value.split_once('=').ok_or(format!(
+        "`{}` is not `<route>[:pinned|:head]=<value>`",
+        value
+    ))?;
+    let (route, kind) = match selector.trim().split_once(':') {
+        Some((route, "pinned")) => (route, Some(Kind::Pinned)),
+        Some((route, "head")) => (route, Some(Kind::Head)),
+        Some((_, kind)) => return Err(format!("{}: unknown kind `{}`", value, kind)),
+        None => (selector.trim(), None),
+    };
+    let header = header.trim();
+    if !header.bytes().all(|byte| (0x20..0x7f).contains(&byte)) {
+        return Err(format!("{}: not a valid header value", value));
+    }
+    Ok(Rule {
+        route: (route != "*").then(|| route.to_string()),
+        kind,
+        value: header.to_string(),
+    })
+}
+
+impl CacheControl {
+    /// The most specific rule, the route counting more than the kind
+    #[cfg(feature = "server")]
+    fn value(&self, route: &str, kind: Kind) -> Option<&str> {
+        let specificity = |rule: &Rule| {
+            let route_matches = rule.route.as_deref().is_none_or(|other| other == route);
+            let kind_matches = rule.kind.is_none_or(|other| other == kind);
+            (route_matches && kind_matches)
+                .then_some(2 * u8::from(rule.route.is_some()) + u8::from(rule.kind.is_some()))
+        };
+        self.0
+            .iter()
+            .filter_map(|rule| Some((specificity(rule)?, rule)))
+            .max_by_key(|(specificity, _)| *specificity)
+            .map(|(_, rule)| rule.value.as_str())
+            .filter(|value| !value.is_empty())
+    }
+}
+
+/// `max-age` of a `Cache-Control` value
+#[cfg(feature = "server")]
+fn max_age(value: &str) -> Option<u64> {
+    value.split(',').find_map(|directive| {
+        let (name, seconds) = directive.trim().split_once('=')?;
+        match name.eq_ignore_ascii_case("max-age") {
+            true => seconds.trim().parse().ok(),
+            false => None,
+        }
+    })
+}
+
+/// Sets the headers of the matching rule, along with `Expires` when it has a
+/// `max-age`, unless the handler set them
+#[cfg(feature = "server")]
+pub async fn apply(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let rules = req.app_data::<web::Data<CacheControl>>().cloned();
+    let route = req
+        .path()
+        .rsplit('/')
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    let pinned = is_immutable(req.query_string());
+
+    let mut res = next.call(req).await?;
+
+    let Some(rules) = rules else {
+        return Ok(res);
+    };
+    if res.headers().contains_key(CACHE_CONTROL) {
+        return Ok(res);
+    }
+    let status = res.status();
+    let kind = match pinned && (status.is_success() || status == StatusCode::NOT_MODIFIED) {
+        true => Kind::Pinned,
+        false => Kind::Head,
+    };
+    if let Some(value) = rules.value(&route, kind) {
+        if let Ok(header) = HeaderValue::from_str(value) {
+            res.headers_mut().insert(CACHE_CONTROL, header);
+        }
+        if let Some(seconds) = max_age(value) {
+            let expires = HttpDate::from(SystemTime::now() + Duration::from_secs(seconds));
+            if let Ok(header) = HeaderValue::from_str(&expires.to_string()) {
+                res.headers_mut().insert(EXPIRES, header);
+            }
+        }
+    }
+    Ok(res)
+}
@@ -0,0 +1,154 @@
+//! Aggregates over the last blocks, read from the headers and state diff
+//! sizes, for indexers that would otherwise download every block
+
+use serde::Serialize;
+
+use crate::header::{self, Header, StateDiffSize};
+use crate::storage::Storage;
+
+/// Windows answered when none is asked, in blocks
+pub const DEFAULT_WINDOWS: [u64; 3] = [10, 100, 1000];
+
+/// Largest window, in blocks
+pub const MAX_WINDOW: u64 = 10_000;
+
+#[derive(Serialize)]
+pub struct ChainStats {
+    /// Last block synced, the windows end with it
+    pub head: Option<u64>,
+    pub windows: Vec<Window>,
+}
+
+/// Aggregates over the last `blocks` blocks. Blocks without a header or a
+/// state diff size, stored before they were recorded, are left out
+#[derive(Serialize)]
+pub struct Window {
+    pub blocks: u64,
+    pub from_block: u64,
+    pub headers: usize,
+    pub transactions: usize,
+    pub transactions_per_block: Option<f64>,
+    /// Seconds between the first and the last block, per block
+    pub average_block_time: Option<f64>,
+    pub state_diffs: usize,
+    pub average_state_diff_bytes: Option<f64>,
+    pub average_storage_entries: Option<f64>,
+    pub average_nonces: Option<f64>,
+    pub average_deployed_contracts: Option<f64>,
+}
+
+/// Parses `10,100,1000`
+pub fn parse_windows(value: &str) -> Result<Vec<u64>, String> {
+    value
+        .split(',')
+        .map(|window| match window.trim().parse::<u64>() {
+            Ok(window) if (1..=MAX_WINDOW).contains(&window) => Ok(window),
+            _ => Err(format!(
+                "`{}` is not a number of blocks from 1 to {}",
+                window, MAX_WINDOW
+            )),
+        })
+        .collect()
+}
+
+pub fn compute(storage: &Storage, windows: &[u64]) -> Result<ChainStats, String> {
+    let Some(head) = storage.max_block_sync().map(|block| block.0) else {
+        return Ok(ChainStats {
+            head: None,
+            windows: vec![],
+        });
+    };
+    let largest = windows.iter().copied().max().unwrap_or(0);
+    let headers = header::headers_down_from(storage.db(), head, largest as usize)
+        .map_err(|e| e.to_string())?;
+    let sizes = header::state_diff_sizes_down_from(storage.db(), head, largest as usize)
+        .map_err(|e| e.to_string())?;
+
+    let windows = windows
+        .iter()
+        .map(|&blocks| {
+            let from_block = (head + 1).saturating_sub(blocks);
+            let headers: Vec<&Header> = headers
+                .iter()
+                .filter(|header| header.block_number >= from_block)
+                .collect();
+            let sizes: Vec<&StateDiffSize> = sizes
+                .iter()
+                .filter(|size| size.block_number >= from_block)
+                .collect();
+            let transactions = headers.iter().map(|header| header.transaction_count).sum();
+            // Sorted from the last block down
+            let average_block_time = match (headers.first(), headers.last()) {
+                (Some(last), Some(first)) if last.block_number > first.block_number => Some(
+                    last.timestamp.saturating_sub(first.timestamp) as f64
+                        / (last.block_number - first.block_number) as f64,
+                ),
+                _ => None,
+            };
+            Window {
+                blocks,
+                from_block,
+                headers: headers.len(),
+                transactions,
+                transactions_per_block: average(transactions, headers.len()),
+                average_block_time,
+                state_diffs: sizes.len(),
+                average_state_diff_bytes: average(
+                    sizes.iter().map(|size| size.bytes).sum(),
+                    sizes.len(),
+                ),
+                average_storage_entries: average(
+                    sizes.iter().map(|size| size.storage_entries).sum(),
+                    sizes.len(),
+                ),
+                average_nonces: average(sizes.iter().map(|size| size.nonces).sum(), sizes.len()),
+                average_deployed_contracts: average(
+                    sizes.iter().map(|size| size.deployed_contracts).sum(),
+                    sizes.len(),
+                ),
+            }
+        })
+        .collect();
+
+    Ok(ChainStats {
+        head: Some(head),
+        windows,
+    })
+}
+
+/// Consecutive cached blocks of one protocol version
+#[derive(Serialize)]
+pub struct VersionRange {
+    /// `None` for the blocks without a version
+    pub starknet_version: Option<String>,
+    pub from_block: u64,
+    pub to_block: u64,
+    /// Blocks with a header in the range
+    pub blocks: usize,
+}
+
+/// The protocol versions of the blocks with a header, in block order, each
+/// with the block it starts at
+pub fn versions(storage: &Storage) -> Result<Vec<VersionRange>, String> {
+    let mut ranges: Vec<VersionRange> = vec![];
+    for header in header::all(storage.db()).map_err(|e| e.to_string())? {
+        let header = header.map_err(|e| e.to_string())?;
+        match ranges.last_mut() {
+            Some(range) if range.starknet_version == header.starknet_version => {
+                range.to_block = header.block_number;
+                range.blocks += 1;
+            }
+            _ => ranges.push(VersionRange {
+                starknet_version: header.starknet_version,
+                from_block: header.block_number,
+                to_block: header.block_number,
+                blocks: 1,
+            }),
+        }
+    }
+    Ok(ranges)
+}
+
+fn average(total: usize, count: usize) -> Option<f64> {
+    (count > 0).then(|| total as f64 / count as f64)
+}
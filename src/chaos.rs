@@ -0,0 +1,64 @@
+use actix_web::body::{self, BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::RETRY_AFTER;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpResponse};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+use crate::config::ServeArgs;
+
+/// A number in [0, 1), the keys of `RandomState` are random and change on
+/// each call
+pub fn random() -> f64 {
+    let bits = RandomState::new().build_hasher().finish() >> 11;
+    bits as f64 / (1u64 << 53) as f64
+}
+
+/// Delays, fails or truncates the feeder gateway responses at the rates of
+/// `--chaos-*`, the other routes are left alone so the cache stays observable
+pub async fn inject(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let chaos = match req.app_data::<web::Data<ServeArgs>>() {
+        Some(args) if req.path().contains("/feeder_gateway/") => args.chaos.clone(),
+        _ => return Ok(next.call(req).await?.map_into_boxed_body()),
+    };
+
+    if chaos.chaos_latency_ms > 0 {
+        let latency = (random() * (chaos.chaos_latency_ms + 1) as f64) as u64;
+        tokio::time::sleep(Duration::from_millis(latency)).await;
+    }
+
+    if random() < chaos.chaos_error_rate {
+        let response = match random() < 0.5 {
+            true => HttpResponse::TooManyRequests()
+                .insert_header((RETRY_AFTER, 1))
+                .body("Too many requests (chaos)"),
+            false => HttpResponse::ServiceUnavailable().body("Service unavailable (chaos)"),
+        };
+        tracing::debug!("🐒 Chaos: {} for {}", response.status(), req.path());
+        return Ok(req.into_response(response));
+    }
+
+    let res = next.call(req).await?;
+    if random() >= chaos.chaos_truncate_rate {
+        return Ok(res.map_into_boxed_body());
+    }
+    let (req, res) = res.into_parts();
+    let (res, body) = res.into_parts();
+    let content = match body::to_bytes(body).await {
+        Ok(content) => content,
+        Err(_) => {
+            return Ok(ServiceResponse::new(
+                req,
+                HttpResponse::InternalServerError().finish(),
+            ))
+        }
+    };
+    tracing::debug!("🐒 Chaos: truncated {}", req.path());
+    let res = res.set_body(content.slice(..content.len() / 2));
+    Ok(ServiceResponse::new(req, res).map_into_boxed_body())
+}
@@ -1,4 +1,30 @@
 use serde::Deserialize;
+#[cfg(feature = "server")]
+use serde::Serialize;
+#[cfg(feature = "server")]
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use crate::primitives::normalize_hash;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ExtractError {
+    #[error("invalid state update: {0}")]
+    InvalidStateUpdate(#[from] serde_json::Error),
+}
+
+/// A value given where a class hash is expected
+#[derive(Debug, thiserror::Error)]
+#[error("`{0}` is not a class hash")]
+pub struct ClassHashError(String);
+
+#[derive(Debug, thiserror::Error)]
+pub enum ClassSeedError {
+    #[error(transparent)]
+    Read(#[from] std::io::Error),
+    #[error("line {line}: {source}")]
+    Line { line: usize, source: ClassHashError },
+}
 
 #[derive(Deserialize)]
 struct StateUpdate {
@@ -9,6 +35,11 @@ struct StateUpdate {
 struct StateDiff {
     deployed_contracts: Vec<Contract>,
     declared_classes: Vec<Class>,
+    #[serde(default)]
+    old_declared_contracts: Vec<String>,
+    #[cfg(feature = "server")]
+    #[serde(default)]
+    replaced_classes: Vec<Contract>,
 }
 
 #[derive(Deserialize)]
@@ -21,9 +52,38 @@ struct Class {
     class_hash: String,
 }
 
-pub fn extract_class_hash(srd_state_update: &str) -> Result<Vec<String>, String> {
-    let state_update: StateUpdate =
-        serde_json::from_str(srd_state_update).map_err(|e| e.to_string())?;
+/// Accepts `0x` prefixed hex only, normalized
+pub fn parse_class_hash(value: &str) -> Result<String, ClassHashError> {
+    let hex = value.strip_prefix("0x").unwrap_or_default();
+    if hex.is_empty() || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Err(ClassHashError(value.to_string()));
+    }
+    Ok(normalize_hash(value))
+}
+
+/// Reads a file of class hashes, one per line. Blank lines and lines
+/// starting with `#` are skipped, duplicates are read once
+pub fn read_class_seed(path: &Path) -> Result<Vec<String>, ClassSeedError> {
+    let content = std::fs::read_to_string(path)?;
+    let mut class_hashes = vec![];
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let class_hash = parse_class_hash(line).map_err(|source| ClassSeedError::Line {
+            line: i + 1,
+            source,
+        })?;
+        if !class_hashes.contains(&class_hash) {
+            class_hashes.push(class_hash);
+        }
+    }
+    Ok(class_hashes)
+}
+
+pub fn extract_class_hash(srd_state_update: &[u8]) -> Result<Vec<String>, ExtractError> {
+    let state_update: StateUpdate = serde_json::from_slice(srd_state_update)?;
 
     let state_diff = state_update.state_diff;
 
@@ -35,6 +95,44 @@ pub fn extract_class_hash(srd_state_update: &str) -> Result<Vec<String>, String>
     state_diff.declared_classes.iter().for_each(|class| {
         class_hashes.push(class.class_hash.clone());
     });
+    class_hashes.extend(state_diff.old_declared_contracts);
 
     Ok(class_hashes)
 }
+
+/// The classes a block needs, from its state update, normalized and sorted
+#[cfg(feature = "server")]
+#[derive(Serialize)]
+pub struct BlockClasses {
+    /// Declared in the block, Cairo 0 ones included
+    pub declared: BTreeSet<String>,
+    /// Of the contracts deployed in the block
+    pub deployed: BTreeSet<String>,
+    /// Set on existing contracts by the block
+    pub replaced: BTreeSet<String>,
+}
+
+#[cfg(feature = "server")]
+pub fn block_classes(srd_state_update: &[u8]) -> Result<BlockClasses, ExtractError> {
+    let state_update: StateUpdate = serde_json::from_slice(srd_state_update)?;
+    let state_diff = state_update.state_diff;
+
+    let declared = state_diff
+        .declared_classes
+        .iter()
+        .map(|class| &class.class_hash)
+        .chain(&state_diff.old_declared_contracts)
+        .map(|hash| normalize_hash(hash))
+        .collect();
+    let contract_classes = |contracts: &[Contract]| {
+        contracts
+            .iter()
+            .map(|contract| normalize_hash(&contract.class_hash))
+            .collect()
+    };
+    Ok(BlockClasses {
+        declared,
+        deployed: contract_classes(&state_diff.deployed_contracts),
+        replaced: contract_classes(&state_diff.replaced_classes),
+    })
+}
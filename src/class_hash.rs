@@ -0,0 +1,67 @@
+use starknet_core::types::contract::legacy::LegacyContractClass;
+use starknet_core::types::FlattenedSierraClass;
+use std::sync::Arc;
+
+use crate::gateway::{Fetched, Gateway, GatewayError, GatewayFuture};
+use crate::primitives::{normalize_hash, Block, State};
+
+/// Computes the hash of a class as returned by the gateway, with the Sierra
+/// algorithm for classes with a `sierra_program` and the Cairo 0 one otherwise
+pub fn compute(content: &str) -> Result<String, String> {
+    let value: serde_json::Value = serde_json::from_str(content).map_err(|e| e.to_string())?;
+    let hash = match value.get("sierra_program") {
+        Some(_) => serde_json::from_value::<FlattenedSierraClass>(value)
+            .map_err(|e| e.to_string())?
+            .class_hash(),
+        None => serde_json::from_value::<LegacyContractClass>(value)
+            .map_err(|e| e.to_string())?
+            .class_hash()
+            .map_err(|e| e.to_string())?,
+    };
+    Ok(format!("{:#x}", hash))
+}
+
+/// Rejects the classes whose hash does not match the one requested, as
+/// invalid responses. Classes whose hash cannot be computed are let through
+pub struct VerifiedGateway(pub Arc<dyn Gateway>);
+
+impl Gateway for VerifiedGateway {
+    fn get_block(&self, block: Block) -> GatewayFuture<'_, Fetched> {
+        self.0.get_block(block)
+    }
+
+    fn get_state_update(&self, state: State) -> GatewayFuture<'_, Fetched> {
+        self.0.get_state_update(state)
+    }
+
+    fn get_class(&self, class_hash: &str) -> GatewayFuture<'_, Fetched> {
+        let class_hash = normalize_hash(class_hash);
+        Box::pin(async move {
+            let fetched = self.0.get_class(&class_hash).await?;
+            // Hashing a large program takes a while
+            let (computed, fetched) =
+                tokio::task::spawn_blocking(move || (compute(&fetched.content), fetched))
+                    .await
+                    .map_err(|e| GatewayError::InvalidResponse(e.to_string()))?;
+            match computed {
+                Ok(computed) if computed == class_hash => Ok(fetched),
+                Ok(computed) => Err(GatewayError::InvalidResponse(format!(
+                    "class hash mismatch, computed {}",
+                    computed
+                ))),
+                Err(e) => {
+                    tracing::warn!("❌ Error computing the hash of class {}: {}", class_hash, e);
+                    Ok(fetched)
+                }
+            }
+        })
+    }
+
+    fn latest_block_number(&self) -> GatewayFuture<'_, u64> {
+        self.0.latest_block_number()
+    }
+
+    fn name(&self) -> String {
+        self.0.name()
+    }
+}
@@ -0,0 +1,150 @@
+//! The commands of the `cache_feeder` binary
+
+use std::process::ExitCode;
+use std::sync::Arc;
+
+use crate::cache::FeederCache;
+use crate::config::{Command, Config, ConfigCommand};
+#[cfg(feature = "sync")]
+use crate::fixture;
+use crate::index;
+use crate::maintenance;
+#[cfg(feature = "server")]
+use crate::mock;
+use crate::storage::Storage;
+#[cfg(feature = "sync")]
+use crate::sync;
+
+/// Runs the command of `config`, once logging is initialized
+pub async fn execute(config: Config) -> ExitCode {
+    let command = config
+        .command
+        .clone()
+        .expect("Config::new defaults to serve");
+
+    // Printed before validation so an invalid configuration can be inspected
+    if let Command::Config(ConfigCommand::Show(args)) = &command {
+        return match config.show(args) {
+            Ok(shown) => {
+                print!("{}", shown);
+                ExitCode::SUCCESS
+            }
+            Err(e) => exit_code("showing the configuration", Err(e)),
+        };
+    }
+
+    let problems = config.validate();
+    for problem in &problems {
+        tracing::error!("❌ Invalid configuration: {}", problem);
+    }
+    if config.check_config && problems.is_empty() {
+        println!("Configuration is valid");
+    }
+    if config.check_config || !problems.is_empty() {
+        return match problems.is_empty() {
+            true => ExitCode::SUCCESS,
+            false => ExitCode::FAILURE,
+        };
+    }
+
+    // The DB must not be open while it is replaced
+    if let Command::Restore(args) = &command {
+        return exit_code("restoring", maintenance::restore(&config.db_path(), args));
+    }
+
+    #[cfg(feature = "sync")]
+    if let Command::Record(args) = &command {
+        return exit_code("recording", fixture::record(&config, args).await);
+    }
+
+    // The mock gateway has no DB
+    #[cfg(feature = "server")]
+    if let Command::MockServe(args) = &command {
+        return exit_code("serving the mock gateway", mock::serve(args).await);
+    }
+
+    let storage = match Storage::open(&config.db_path(), &config.db, config.wal_retention()) {
+        Ok(storage) => Arc::new(storage),
+        Err(e) => {
+            tracing::error!("❌ Error initializing storage: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    tracing::info!("💾 Storage initialized");
+    if let Some(max_block_sync) = storage.max_block_sync() {
+        tracing::info!("📦 Max block to sync: {}", max_block_sync);
+    }
+    if let Some(max_state_sync) = storage.max_state_sync() {
+        tracing::info!("📦 Max state to sync: {}", max_state_sync);
+    }
+    tracing::info!("🌐 Network: {}", config.network.name());
+    tracing::info!("🔗 Feeder gateway URL: {}", config.feeder_gateway_url());
+
+    match command {
+        Command::Serve(_) => exit_code(
+            "serving",
+            FeederCache::new(config, storage)
+                .run_until_signal()
+                .await
+                .map_err(|e| e.to_string()),
+        ),
+        Command::Sync(_) => exit_code(
+            "syncing",
+            FeederCache::new(config, storage)
+                .run_until_signal()
+                .await
+                .map_err(|e| e.to_string()),
+        ),
+        Command::Export(args) => exit_code("exporting", maintenance::export(&storage, &args)),
+        Command::Import(args) => exit_code("importing", maintenance::import(&storage, &args)),
+        Command::ExportClasses(args) => exit_code(
+            "exporting classes",
+            maintenance::export_classes(&storage, &args),
+        ),
+        Command::ExportHeaders(args) => exit_code(
+            "exporting headers",
+            maintenance::export_headers(&storage, &args),
+        ),
+        Command::ImportClasses(args) => exit_code(
+            "importing classes",
+            maintenance::import_classes(&storage, &args),
+        ),
+        Command::Dump(args) => exit_code("dumping", maintenance::dump(&storage, &args)),
+        Command::Verify => exit_code("verifying", maintenance::verify(&storage)),
+        Command::Stats => exit_code("reading stats", maintenance::stats(&storage)),
+        Command::Compact => exit_code("compacting", maintenance::compact(&storage)),
+        Command::Backup(args) => exit_code("backing up", maintenance::backup(&storage, &args)),
+        Command::Restore(_) | Command::Record(_) | Command::MockServe(_) | Command::Config(_) => {
+            unreachable!("handled before the DB is opened")
+        }
+        Command::DeleteRange(args) => exit_code(
+            "deleting",
+            maintenance::delete_range(&storage, &args).map(|_| ()),
+        ),
+        Command::Reindex => exit_code("reindexing", index::reindex(&storage)),
+        #[cfg(feature = "sync")]
+        Command::Resync(args) => {
+            exit_code("resyncing", sync::resync(&config, &storage, &args).await)
+        }
+        #[cfg(feature = "sync")]
+        Command::VerifyUpstream(args) => exit_code(
+            "verifying against the gateway",
+            sync::verify_upstream(&config, &storage, &args).await,
+        ),
+        #[cfg(not(feature = "sync"))]
+        Command::Resync(_) | Command::VerifyUpstream(_) => {
+            unreachable!("refused by the validation")
+        }
+    }
+}
+
+fn exit_code(action: &str, result: Result<(), impl std::fmt::Display>) -> ExitCode {
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            tracing::error!("❌ Error {}: {}", action, e);
+            ExitCode::FAILURE
+        }
+    }
+}
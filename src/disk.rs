@@ -0,0 +1,109 @@
+//! Disk usage of the DB, to project when the volume fills up
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::storage::Storage;
+
+#[derive(Serialize)]
+pub struct DiskUsage {
+    /// Every file of the DB directory, logs and WAL included
+    pub db_dir_bytes: u64,
+    pub column_families: BTreeMap<String, ColumnFamilyUsage>,
+    /// `None` when unknown on the platform
+    pub volume_free_bytes: Option<u64>,
+    pub volume_total_bytes: Option<u64>,
+}
+
+#[derive(Default, Serialize)]
+pub struct ColumnFamilyUsage {
+    pub sst_bytes: u64,
+    pub memtable_bytes: u64,
+    /// SST bytes per key prefix, a file spanning several prefixes counted
+    /// under `mixed`
+    pub prefixes: BTreeMap<String, u64>,
+}
+
+/// Reads the sizes from the file list of the DB, without scanning the keys
+pub fn usage(storage: &Storage) -> Result<DiskUsage, String> {
+    let db = storage.db();
+    let mut column_families: BTreeMap<String, ColumnFamilyUsage> = BTreeMap::new();
+    for file in db.live_files()? {
+        let usage = column_families
+            .entry(file.column_family_name.clone())
+            .or_default();
+        usage.sst_bytes += file.size as u64;
+        let start = file.start_key.as_deref().map(key_prefix);
+        let end = file.end_key.as_deref().map(key_prefix);
+        let prefix = match (start, end) {
+            (Some(start), Some(end)) if start == end => start,
+            _ => "mixed".to_string(),
+        };
+        *usage.prefixes.entry(prefix).or_default() += file.size as u64;
+    }
+    let memtable_bytes = db
+        .property_int_value("rocksdb.size-all-mem-tables")?
+        .unwrap_or(0);
+    column_families
+        .entry(rocksdb::DEFAULT_COLUMN_FAMILY_NAME.to_string())
+        .or_default()
+        .memtable_bytes = memtable_bytes;
+
+    let (volume_free_bytes, volume_total_bytes) = match volume_space(db.path()) {
+        Some((free, total)) => (Some(free), Some(total)),
+        None => (None, None),
+    };
+    Ok(DiskUsage {
+        db_dir_bytes: dir_size(db.path()).map_err(|e| e.to_string())?,
+        column_families,
+        volume_free_bytes,
+        volume_total_bytes,
+    })
+}
+
+/// Up to the first `_`, as in `stats`
+fn key_prefix(key: &[u8]) -> String {
+    let key = String::from_utf8_lossy(key);
+    match key.find('_') {
+        Some(end) => key[..=end].to_string(),
+        None => key.to_string(),
+    }
+}
+
+fn dir_size(dir: &Path) -> std::io::Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += match metadata.is_dir() {
+            true => dir_size(&entry.path())?,
+            false => metadata.len(),
+        };
+    }
+    Ok(size)
+}
+
+/// Bytes available to the process and total bytes of the volume of `path`
+#[cfg(unix)]
+fn volume_space(path: &Path) -> Option<(u64, u64)> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `path` is NUL terminated and `stat` is a valid out pointer
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    let fragment = stat.f_frsize as u64;
+    Some((
+        stat.f_bavail as u64 * fragment,
+        stat.f_blocks as u64 * fragment,
+    ))
+}
+
+#[cfg(not(unix))]
+fn volume_space(_path: &Path) -> Option<(u64, u64)> {
+    None
+}
@@ -0,0 +1,181 @@
+//! Weighted fair queuing of the data requests once `--max-concurrent-requests`
+//! are in progress, so a mirror pulling ranges can't hold every slot while
+//! node clients wait
+//!
+//! The consumers are the API keys when keys are configured, the first path
+//! segment of the route otherwise. Each waiting request is tagged with the
+//! virtual time its consumer would finish at, one unit divided by its weight
+//! after the later of its previous tag and the current virtual time, and a
+//! freed slot goes to the smallest tag.
+
+#[cfg(feature = "server")]
+use actix_web::body::MessageBody;
+#[cfg(feature = "server")]
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+#[cfg(feature = "server")]
+use actix_web::middleware::Next;
+#[cfg(feature = "server")]
+use actix_web::{web, Error};
+use std::collections::HashMap;
+#[cfg(feature = "server")]
+use std::collections::{BTreeMap, VecDeque};
+#[cfg(feature = "server")]
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "server")]
+use tokio::sync::oneshot;
+
+#[cfg(feature = "server")]
+use crate::acl::{data_route, API_KEY_HEADER};
+#[cfg(feature = "server")]
+use crate::config::ServeArgs;
+
+/// Largest weight, so a tag step is never below one
+pub const MAX_WEIGHT: u64 = 1000;
+
+/// Virtual time of a request at weight 1
+#[cfg(feature = "server")]
+const UNIT: u64 = 1_000_000;
+
+/// Parses `<consumer>=<weight>`, the consumer being an API key or a route
+/// such as `feeder_gateway`. Unlisted consumers weigh 1. The errors leave the
+/// consumer out, it may be a key
+pub fn parse(weights: &[String]) -> Result<HashMap<String, u64>, String> {
+    let mut parsed = HashMap::new();
+    for value in weights {
+        let (consumer, weight) = value
+            .split_once('=')
+            .ok_or("not `<consumer>=<weight>`".to_string())?;
+        let consumer = consumer.trim();
+        if consumer.is_empty() {
+            return Err("empty consumer".to_string());
+        }
+        let weight = weight
+            .trim()
+            .parse::<u64>()
+            .ok()
+            .filter(|weight| (1..=MAX_WEIGHT).contains(weight))
+            .ok_or(format!("weight must be from 1 to {}", MAX_WEIGHT))?;
+        parsed.insert(consumer.to_string(), weight);
+    }
+    Ok(parsed)
+}
+
+#[cfg(feature = "server")]
+pub struct FairQueue {
+    slots: usize,
+    weights: HashMap<String, u64>,
+    state: Mutex<State>,
+}
+
+#[cfg(feature = "server")]
+#[derive(Default)]
+struct State {
+    busy: usize,
+    virtual_time: u64,
+    /// Tag of the last request queued per consumer
+    finish: HashMap<String, u64>,
+    /// Waiting requests by tag, in arrival order for equal tags
+    waiting: BTreeMap<u64, VecDeque<oneshot::Sender<Permit>>>,
+}
+
+/// A slot, freed on drop
+#[cfg(feature = "server")]
+pub struct Permit(Option<Arc<FairQueue>>);
+
+#[cfg(feature = "server")]
+impl FairQueue {
+    pub fn new(slots: usize, weights: HashMap<String, u64>) -> FairQueue {
+        FairQueue {
+            slots,
+            weights,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Waits for a slot, immediately while some are free and nobody waits
+    pub async fn acquire(self: &Arc<Self>, consumer: &str) -> Permit {
+        let receiver = {
+            let mut state = self.state.lock().unwrap();
+            if state.busy < self.slots && state.waiting.is_empty() {
+                state.busy += 1;
+                return Permit(Some(self.clone()));
+            }
+            let weight = self.weights.get(consumer).copied().unwrap_or(1);
+            let start = state
+                .finish
+                .get(consumer)
+                .copied()
+                .unwrap_or_default()
+                .max(state.virtual_time);
+            let tag = start + UNIT / weight;
+            state.finish.insert(consumer.to_string(), tag);
+            let (sender, receiver) = oneshot::channel();
+            state.waiting.entry(tag).or_default().push_back(sender);
+            receiver
+        };
+        tracing::debug!("⏳ Request queued, no slot free");
+        // The sender is only dropped with a permit sent
+        receiver.await.unwrap_or(Permit(None))
+    }
+
+    /// Hands the slot to the smallest tag still waiting, or frees it
+    fn release(self: &Arc<Self>) {
+        loop {
+            let sender = {
+                let mut state = self.state.lock().unwrap();
+                let Some(mut entry) = state.waiting.first_entry() else {
+                    state.busy -= 1;
+                    return;
+                };
+                let tag = *entry.key();
+                let sender = entry.get_mut().pop_front();
+                if entry.get().is_empty() {
+                    entry.remove();
+                }
+                state.virtual_time = tag;
+                sender
+            };
+            let Some(sender) = sender else {
+                continue;
+            };
+            match sender.send(Permit(Some(self.clone()))) {
+                Ok(()) => return,
+                // The request went away while waiting
+                Err(mut permit) => {
+                    permit.0.take();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(queue) = self.0.take() {
+            queue.release();
+        }
+    }
+}
+
+/// Holds a slot of the data requests until their response is ready
+#[cfg(feature = "server")]
+pub async fn admit(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let queue = req.app_data::<web::Data<Arc<FairQueue>>>().cloned();
+    let args = req.app_data::<web::Data<ServeArgs>>().cloned();
+    let (Some(queue), Some(args), Some(route)) = (queue, args, data_route(&req)) else {
+        return next.call(req).await;
+    };
+    // The keys were checked by the ACL
+    let key = req
+        .headers()
+        .get(API_KEY_HEADER)
+        .and_then(|key| key.to_str().ok())
+        .filter(|_| !args.api_key.is_empty());
+    let consumer = key.unwrap_or(route).to_string();
+    let _permit = queue.acquire(&consumer).await;
+    next.call(req).await
+}
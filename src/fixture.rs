@@ -0,0 +1,100 @@
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::class_extract::extract_class_hash;
+use crate::config::{Config, RecordArgs};
+use crate::gateway::{Gateway, HttpGateway, MockGateway};
+use crate::maintenance::{key_number, Entry};
+use crate::primitives::{Block, Class, State};
+use crate::reload::Reloadable;
+use crate::upstream::Upstream;
+
+fn write_entry(output: &mut impl Write, key: String, value: String) -> Result<(), String> {
+    serde_json::to_writer(&mut *output, &Entry { key, value }).map_err(|e| e.to_string())?;
+    output.write_all(b"\n").map_err(|e| e.to_string())
+}
+
+/// Writes the blocks, state updates and classes of a range of blocks in the
+/// format of `export`, straight from the gateway
+#[tracing::instrument(skip_all)]
+pub async fn record(config: &Config, args: &RecordArgs) -> Result<(), String> {
+    let gateway = HttpGateway::new(
+        Arc::new(Upstream::new(config).map_err(|e| e.to_string())?),
+        Arc::new(Reloadable::new(config)),
+        config.network.sync_tuning().retry_delay,
+    );
+    let mut output = BufWriter::new(File::create(&args.output).map_err(|e| e.to_string())?);
+    let classes = write_fixture(&gateway, args.from_block, args.to_block, &mut output).await?;
+    output.flush().map_err(|e| e.to_string())?;
+
+    tracing::info!(
+        "📼 Recorded blocks {} to {} and {} classes to {}",
+        args.from_block,
+        args.to_block,
+        classes,
+        args.output.display()
+    );
+    Ok(())
+}
+
+/// Writes blocks `from` to `to` of `gateway`, then the classes their state
+/// updates reference. Returns the number of classes
+pub async fn write_fixture(
+    gateway: &dyn Gateway,
+    from: u64,
+    to: u64,
+    output: &mut impl Write,
+) -> Result<usize, String> {
+    let mut class_hashes = BTreeSet::new();
+    for number in from..=to {
+        let block = gateway
+            .get_block(Block(number))
+            .await
+            .map_err(|e| format!("block {}: {}", number, e))?
+            .content;
+        write_entry(output, Block(number).key(), block)?;
+
+        let state_update = gateway
+            .get_state_update(State(number))
+            .await
+            .map_err(|e| format!("state update {}: {}", number, e))?
+            .content;
+        class_hashes
+            .extend(extract_class_hash(state_update.as_bytes()).map_err(|e| e.to_string())?);
+        write_entry(output, State(number).key(), state_update)?;
+    }
+    for hash in &class_hashes {
+        let class = gateway
+            .get_class(hash)
+            .await
+            .map_err(|e| format!("class {}: {}", hash, e))?
+            .content;
+        write_entry(output, Class(hash.clone()).key(), class)?;
+    }
+    Ok(class_hashes.len())
+}
+
+/// Reads a file written by `record` or `export` into a gateway answering
+/// from memory
+pub fn load(path: &Path) -> Result<MockGateway, String> {
+    let input = File::open(path).map_err(|e| e.to_string())?;
+    let gateway = MockGateway::default();
+    for (line, content) in BufReader::new(input).lines().enumerate() {
+        let content = content.map_err(|e| e.to_string())?;
+        let entry: Entry =
+            serde_json::from_str(&content).map_err(|e| format!("line {}: {}", line + 1, e))?;
+        if let Some(number) = key_number(&entry.key, Block::KEY_PREFIX) {
+            gateway.insert_block(number, entry.value);
+        } else if let Some(number) = key_number(&entry.key, State::KEY_PREFIX) {
+            gateway.insert_state_update(number, entry.value);
+        } else if let Some(hash) = entry.key.strip_prefix(Class::KEY_PREFIX) {
+            gateway.insert_class(hash, entry.value);
+        } else {
+            return Err(format!("line {}: unexpected key {}", line + 1, entry.key));
+        }
+    }
+    Ok(gateway)
+}
@@ -0,0 +1,297 @@
+use reqwest::StatusCode;
+use std::collections::{BTreeMap, HashSet};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::primitives::{normalize_hash, Block, State};
+use crate::reload::Reloadable;
+use crate::upstream::{upstream_name, Upstream, UpstreamError};
+
+/// Attempts at a request failing with a timeout or an unreadable body before
+/// the error is returned, the sync tasks then back off
+const TRANSIENT_ATTEMPTS: u32 = 3;
+
+#[derive(Debug, thiserror::Error)]
+pub enum GatewayError {
+    /// Not produced yet, answered by the gateway with `BLOCK_NOT_FOUND`
+    #[error("not found")]
+    NotFound,
+    #[error("{0}")]
+    Status(StatusCode),
+    /// Any other error of the gateway, from its `{ code, message }` envelope
+    #[error("{code}: {message}")]
+    Starknet { code: String, message: String },
+    #[error(transparent)]
+    Upstream(#[from] UpstreamError),
+    #[error("invalid response: {0}")]
+    InvalidResponse(String),
+    #[error("blocked by the class blocklist")]
+    Blocked,
+}
+
+pub type GatewayFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, GatewayError>> + Send + 'a>>;
+
+/// An entry as sent by the gateway
+pub struct Fetched {
+    pub content: String,
+    /// Response headers of `--capture-upstream-header`
+    pub headers: BTreeMap<String, String>,
+}
+
+impl From<String> for Fetched {
+    fn from(content: String) -> Self {
+        Fetched {
+            content,
+            headers: BTreeMap::new(),
+        }
+    }
+}
+
+/// Source of the data synced, the responses are returned as sent by the
+/// feeder gateway
+pub trait Gateway: Send + Sync {
+    fn get_block(&self, block: Block) -> GatewayFuture<'_, Fetched>;
+    fn get_state_update(&self, state: State) -> GatewayFuture<'_, Fetched>;
+    fn get_class(&self, class_hash: &str) -> GatewayFuture<'_, Fetched>;
+    fn latest_block_number(&self) -> GatewayFuture<'_, u64>;
+
+    /// Recorded as the upstream of the entries fetched
+    fn name(&self) -> String {
+        "gateway".to_string()
+    }
+}
+
+/// The feeder gateway over HTTP, at the URL currently configured
+pub struct HttpGateway {
+    upstream: Arc<Upstream>,
+    reloadable: Arc<Reloadable>,
+    retry_delay: u64,
+}
+
+impl HttpGateway {
+    /// Requests answered with 429 are retried after `retry_delay` seconds
+    pub fn new(upstream: Arc<Upstream>, reloadable: Arc<Reloadable>, retry_delay: u64) -> Self {
+        HttpGateway {
+            upstream,
+            reloadable,
+            retry_delay,
+        }
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn fetch(&self, url: String) -> Result<Fetched, GatewayError> {
+        let mut attempts = 0;
+        loop {
+            let response = match self.upstream.get(&url).await {
+                Ok(response) => response,
+                Err(UpstreamError::Transport(e))
+                    if e.is_timeout() && attempts + 1 < TRANSIENT_ATTEMPTS =>
+                {
+                    attempts += 1;
+                    tracing::warn!("⏱️ Timeout fetching {}, retrying", url);
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            match response.status() {
+                StatusCode::OK => {
+                    let headers = self.upstream.capture(&response);
+                    match self.upstream.text(response).await {
+                        Ok(content) => return Ok(Fetched { content, headers }),
+                        // Retried as a whole
+                        Err(e) if attempts + 1 < TRANSIENT_ATTEMPTS => {
+                            attempts += 1;
+                            tracing::warn!("❌ Error reading {}, retrying: {}", url, e);
+                        }
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+                StatusCode::TOO_MANY_REQUESTS => {
+                    tracing::info!(
+                        "📈 Too many requests, waiting {} seconds 💤",
+                        self.retry_delay
+                    );
+                    tokio::time::sleep(Duration::from_secs(self.retry_delay)).await;
+                }
+                status => {
+                    let error = match self.upstream.text(response).await {
+                        Ok(content) => starknet_error(&content),
+                        Err(_) => None,
+                    };
+                    return Err(error.unwrap_or(GatewayError::Status(status)));
+                }
+            }
+        }
+    }
+
+    fn url(&self, path_and_query: String) -> String {
+        format!(
+            "{}/feeder_gateway/{}",
+            self.reloadable.feeder_gateway_url(),
+            path_and_query
+        )
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct ErrorEnvelope {
+    code: String,
+    #[serde(default)]
+    message: String,
+}
+
+/// The error of a gateway response, when the body is the gateway's error
+/// envelope
+fn starknet_error(content: &str) -> Option<GatewayError> {
+    let envelope: ErrorEnvelope = serde_json::from_str(content).ok()?;
+    let code = envelope.code.strip_prefix("StarknetErrorCode.")?;
+    Some(match code {
+        "BLOCK_NOT_FOUND" => GatewayError::NotFound,
+        code => GatewayError::Starknet {
+            code: code.to_string(),
+            message: envelope.message,
+        },
+    })
+}
+
+impl Gateway for HttpGateway {
+    fn get_block(&self, block: Block) -> GatewayFuture<'_, Fetched> {
+        Box::pin(self.fetch(self.url(format!("get_block?blockNumber={}", block.0))))
+    }
+
+    fn get_state_update(&self, state: State) -> GatewayFuture<'_, Fetched> {
+        Box::pin(self.fetch(self.url(format!("get_state_update?blockNumber={}", state.0))))
+    }
+
+    fn get_class(&self, class_hash: &str) -> GatewayFuture<'_, Fetched> {
+        Box::pin(self.fetch(self.url(format!("get_class_by_hash?classHash={}", class_hash))))
+    }
+
+    fn latest_block_number(&self) -> GatewayFuture<'_, u64> {
+        Box::pin(async move {
+            let content = self
+                .fetch(self.url("get_block?blockNumber=latest".to_string()))
+                .await?
+                .content;
+            serde_json::from_str::<serde_json::Value>(&content)
+                .map_err(|e| GatewayError::InvalidResponse(e.to_string()))?["block_number"]
+                .as_u64()
+                .ok_or_else(|| GatewayError::InvalidResponse("missing block_number".to_string()))
+        })
+    }
+
+    fn name(&self) -> String {
+        upstream_name(&self.reloadable.feeder_gateway_url())
+    }
+}
+
+/// Refuses the classes of `--class-blocklist` without fetching them
+pub struct BlockedGateway {
+    gateway: Arc<dyn Gateway>,
+    blocklist: HashSet<String>,
+}
+
+impl BlockedGateway {
+    pub fn new(gateway: Arc<dyn Gateway>, blocklist: &[String]) -> Self {
+        BlockedGateway {
+            gateway,
+            blocklist: blocklist.iter().map(|hash| normalize_hash(hash)).collect(),
+        }
+    }
+}
+
+impl Gateway for BlockedGateway {
+    fn get_block(&self, block: Block) -> GatewayFuture<'_, Fetched> {
+        self.gateway.get_block(block)
+    }
+
+    fn get_state_update(&self, state: State) -> GatewayFuture<'_, Fetched> {
+        self.gateway.get_state_update(state)
+    }
+
+    fn get_class(&self, class_hash: &str) -> GatewayFuture<'_, Fetched> {
+        match self.blocklist.contains(&normalize_hash(class_hash)) {
+            true => Box::pin(async { Err(GatewayError::Blocked) }),
+            false => self.gateway.get_class(class_hash),
+        }
+    }
+
+    fn latest_block_number(&self) -> GatewayFuture<'_, u64> {
+        self.gateway.latest_block_number()
+    }
+
+    fn name(&self) -> String {
+        self.gateway.name()
+    }
+}
+
+/// In-memory gateway for tests, answering `NotFound` for anything not
+/// inserted. Entries can be inserted while syncing to simulate a growing
+/// chain
+#[derive(Default)]
+pub struct MockGateway {
+    blocks: RwLock<BTreeMap<u64, String>>,
+    state_updates: RwLock<BTreeMap<u64, String>>,
+    classes: RwLock<BTreeMap<String, String>>,
+}
+
+impl MockGateway {
+    pub fn insert_block(&self, block_number: u64, content: impl Into<String>) {
+        self.blocks
+            .write()
+            .unwrap()
+            .insert(block_number, content.into());
+    }
+
+    pub fn insert_state_update(&self, block_number: u64, content: impl Into<String>) {
+        self.state_updates
+            .write()
+            .unwrap()
+            .insert(block_number, content.into());
+    }
+
+    pub fn insert_class(&self, class_hash: impl Into<String>, content: impl Into<String>) {
+        self.classes
+            .write()
+            .unwrap()
+            .insert(normalize_hash(&class_hash.into()), content.into());
+    }
+}
+
+fn found<T: Clone>(value: Option<&T>) -> Result<T, GatewayError> {
+    value.cloned().ok_or(GatewayError::NotFound)
+}
+
+impl Gateway for MockGateway {
+    fn get_block(&self, block: Block) -> GatewayFuture<'_, Fetched> {
+        let result = found(self.blocks.read().unwrap().get(&block.0)).map(Fetched::from);
+        Box::pin(async move { result })
+    }
+
+    fn get_state_update(&self, state: State) -> GatewayFuture<'_, Fetched> {
+        let result = found(self.state_updates.read().unwrap().get(&state.0)).map(Fetched::from);
+        Box::pin(async move { result })
+    }
+
+    fn get_class(&self, class_hash: &str) -> GatewayFuture<'_, Fetched> {
+        let result = found(
+            self.classes
+                .read()
+                .unwrap()
+                .get(&normalize_hash(class_hash)),
+        )
+        .map(Fetched::from);
+        Box::pin(async move { result })
+    }
+
+    fn latest_block_number(&self) -> GatewayFuture<'_, u64> {
+        let result = found(self.blocks.read().unwrap().keys().next_back());
+        Box::pin(async move { result })
+    }
+
+    fn name(&self) -> String {
+        "mock".to_string()
+    }
+}
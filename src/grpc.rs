@@ -0,0 +1,200 @@
+//! gRPC service mirroring the data routes of the main network, with streams
+//! of block ranges for ingestion pipelines. The messages are described in
+//! `proto/feeder_cache.proto` and only answer from the DB
+
+use futures_util::stream::{self, Stream};
+use std::net::ToSocketAddrs;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+use tonic::transport::server::TcpIncoming;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+
+use crate::config::ServeArgs;
+use crate::primitives::{Block, Class, State};
+use crate::server::is_blocked;
+use crate::storage::Storage;
+
+mod service {
+    include!(concat!(env!("OUT_DIR"), "/feeder_cache.v1.FeederCache.rs"));
+}
+
+use service::feeder_cache_server::{FeederCache, FeederCacheServer};
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct BlockRequest {
+    #[prost(uint64, tag = "1")]
+    pub block_number: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ClassRequest {
+    #[prost(string, tag = "1")]
+    pub class_hash: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RangeRequest {
+    #[prost(uint64, tag = "1")]
+    pub from_block: u64,
+    #[prost(uint64, tag = "2")]
+    pub to_block: u64,
+}
+
+/// A block or a state update, as the JSON of the gateway
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Payload {
+    #[prost(uint64, tag = "1")]
+    pub block_number: u64,
+    #[prost(bytes = "vec", tag = "2")]
+    pub json: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ClassPayload {
+    #[prost(string, tag = "1")]
+    pub class_hash: String,
+    #[prost(bytes = "vec", tag = "2")]
+    pub json: Vec<u8>,
+}
+
+type PayloadStream = Pin<Box<dyn Stream<Item = Result<Payload, Status>> + Send>>;
+
+struct Service {
+    storage: Arc<Storage>,
+    args: ServeArgs,
+}
+
+/// Binds `--grpc-addr` and serves until `run` is cleared
+pub fn start(
+    addr: &str,
+    storage: Arc<Storage>,
+    args: &ServeArgs,
+    run: Arc<AtomicBool>,
+) -> std::io::Result<JoinHandle<()>> {
+    let socket_addr = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| std::io::Error::other("no address"))?;
+    let incoming = TcpIncoming::bind(socket_addr)?;
+    let service = FeederCacheServer::new(Service {
+        storage,
+        args: args.clone(),
+    });
+    let stopped = async move {
+        while run.load(Ordering::SeqCst) {
+            tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+        }
+    };
+    let handle = tokio::spawn(async move {
+        if let Err(e) = Server::builder()
+            .serve_with_incoming_shutdown(service, incoming, stopped)
+            .await
+        {
+            tracing::error!("❌ gRPC server error: {}", e);
+        }
+    });
+    tracing::info!("🟢 gRPC server running on {}", addr);
+    Ok(handle)
+}
+
+impl Service {
+    async fn payload(&self, key: String, block_number: u64) -> Result<Payload, Status> {
+        read(&self.storage, key)
+            .await
+            .map(|json| Payload { block_number, json })
+    }
+
+    /// Reads the payloads of a range one at a time, as the client consumes
+    /// them
+    fn range(&self, range: RangeRequest, key: fn(u64) -> String) -> Result<PayloadStream, Status> {
+        if range.from_block > range.to_block {
+            return Err(Status::invalid_argument("from_block is after to_block"));
+        }
+        let storage = self.storage.clone();
+        let payloads = stream::unfold(Some(range.from_block), move |next| {
+            let storage = storage.clone();
+            async move {
+                let number = next.filter(|number| *number <= range.to_block)?;
+                match read(&storage, key(number)).await {
+                    Ok(json) => Some((
+                        Ok(Payload {
+                            block_number: number,
+                            json,
+                        }),
+                        number.checked_add(1),
+                    )),
+                    Err(status) => Some((Err(status), None)),
+                }
+            }
+        });
+        Ok(Box::pin(payloads))
+    }
+}
+
+async fn read(storage: &Arc<Storage>, key: String) -> Result<Vec<u8>, Status> {
+    match storage.read(key.clone()).await {
+        Ok(Some(content)) => Ok(content),
+        Ok(None) => Err(Status::not_found(format!("{} is not cached", key))),
+        Err(e) => {
+            tracing::error!("❌ Error reading {}: {}", key, e);
+            Err(Status::internal(format!("error reading {}", key)))
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl FeederCache for Service {
+    type StreamBlocksStream = PayloadStream;
+    type StreamStateUpdatesStream = PayloadStream;
+
+    async fn get_block(&self, request: Request<BlockRequest>) -> Result<Response<Payload>, Status> {
+        let number = request.into_inner().block_number;
+        self.payload(Block(number).key(), number)
+            .await
+            .map(Response::new)
+    }
+
+    async fn get_state_update(
+        &self,
+        request: Request<BlockRequest>,
+    ) -> Result<Response<Payload>, Status> {
+        let number = request.into_inner().block_number;
+        self.payload(State(number).key(), number)
+            .await
+            .map(Response::new)
+    }
+
+    async fn get_class(
+        &self,
+        request: Request<ClassRequest>,
+    ) -> Result<Response<ClassPayload>, Status> {
+        let class_hash = request.into_inner().class_hash;
+        if is_blocked(&self.args, &class_hash) {
+            return Err(Status::not_found(format!(
+                "class {} is blocked",
+                class_hash
+            )));
+        }
+        let json = read(&self.storage, Class(class_hash.clone()).key()).await?;
+        Ok(Response::new(ClassPayload { class_hash, json }))
+    }
+
+    async fn stream_blocks(
+        &self,
+        request: Request<RangeRequest>,
+    ) -> Result<Response<PayloadStream>, Status> {
+        self.range(request.into_inner(), |number| Block(number).key())
+            .map(Response::new)
+    }
+
+    async fn stream_state_updates(
+        &self,
+        request: Request<RangeRequest>,
+    ) -> Result<Response<PayloadStream>, Status> {
+        self.range(request.into_inner(), |number| State(number).key())
+            .map(Response::new)
+    }
+}
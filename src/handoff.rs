@@ -0,0 +1,138 @@
+//! In-place restart on SIGUSR2: once the tasks stopped and the DB is closed,
+//! the binary, possibly upgraded, is executed again with the same arguments
+//! and inherits the listening socket, so clients connecting meanwhile wait
+//! in its backlog instead of being refused. Nothing waits for the gateway
+//! before the server starts, the network check runs in the sync tasks
+
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// Descriptor of the listening socket inherited from the previous process
+const LISTEN_FD_ENV: &str = "FEEDER_CACHE_LISTEN_FD";
+
+/// Pending connections queued by the kernel, as actix does
+const BACKLOG: u32 = 2048;
+
+/// Set once SIGUSR2 was received
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Set once the inherited socket was used, so a server started again binds
+static INHERITED: AtomicBool = AtomicBool::new(false);
+
+/// A copy of the listening socket, handed to the next process
+static LISTENER: Mutex<Option<TcpListener>> = Mutex::new(None);
+
+/// Resolves once SIGUSR2 is received, never on other platforms
+pub async fn signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut restart =
+            signal(SignalKind::user_defined2()).expect("Failed to listen for SIGUSR2");
+        restart.recv().await;
+        REQUESTED.store(true, Ordering::SeqCst);
+        "SIGUSR2"
+    }
+    #[cfg(not(unix))]
+    std::future::pending().await
+}
+
+/// Whether the process should be executed again once stopped
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Exits instead of restarting, when the process could not stop cleanly
+pub fn cancel() {
+    REQUESTED.store(false, Ordering::SeqCst);
+}
+
+/// The socket inherited from the previous process when it listens on `addr`,
+/// or a new one bound to it. A copy is kept for the next restart
+pub fn listener(addr: &str) -> std::io::Result<TcpListener> {
+    let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+    let listener = match inherited() {
+        Some(listener) if addrs.contains(&listener.local_addr()?) => {
+            tracing::info!("🔁 Listening on the socket of the previous process");
+            listener
+        }
+        _ => bind(&addrs)?,
+    };
+    listener.set_nonblocking(true)?;
+    *LISTENER.lock().unwrap() = Some(listener.try_clone()?);
+    Ok(listener)
+}
+
+fn bind(addrs: &[SocketAddr]) -> std::io::Result<TcpListener> {
+    let mut last_error = None;
+    for addr in addrs {
+        let socket = match addr {
+            SocketAddr::V4(_) => tokio::net::TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => tokio::net::TcpSocket::new_v6()?,
+        };
+        socket.set_reuseaddr(true)?;
+        match socket
+            .bind(*addr)
+            .and_then(|()| socket.listen(BACKLOG))
+            .and_then(|listener| listener.into_std())
+        {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, "no address to bind")
+    }))
+}
+
+#[cfg(unix)]
+fn inherited() -> Option<TcpListener> {
+    use std::os::fd::FromRawFd;
+
+    if INHERITED.swap(true, Ordering::SeqCst) {
+        return None;
+    }
+    let fd = std::env::var(LISTEN_FD_ENV).ok()?.parse::<i32>().ok()?;
+    // Only the previous process sets the variable, to a socket it left open
+    Some(unsafe { TcpListener::from_raw_fd(fd) })
+}
+
+#[cfg(not(unix))]
+fn inherited() -> Option<TcpListener> {
+    INHERITED.store(true, Ordering::SeqCst);
+    None
+}
+
+/// Replaces the process with the binary it was started from, passing it the
+/// listening socket. Only returns on failure
+#[cfg(unix)]
+pub fn exec() -> String {
+    use std::os::fd::AsRawFd;
+    use std::os::unix::process::CommandExt;
+
+    let mut args = std::env::args_os();
+    // The path started, rather than this executable, which may be replaced
+    let Some(program) = args.next() else {
+        return "no program path".to_string();
+    };
+    let mut command = std::process::Command::new(&program);
+    command.args(args).env_remove(LISTEN_FD_ENV);
+    if let Some(listener) = LISTENER.lock().unwrap().take() {
+        let fd = listener.as_raw_fd();
+        // The copy is closed on exec otherwise
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, 0) } != 0 {
+            return std::io::Error::last_os_error().to_string();
+        }
+        command.env(LISTEN_FD_ENV, fd.to_string());
+        std::mem::forget(listener);
+    }
+    tracing::info!("🔁 Restarting {}", program.to_string_lossy());
+    command.exec().to_string()
+}
+
+#[cfg(not(unix))]
+pub fn exec() -> String {
+    "restarting in place is only supported on Unix".to_string()
+}
@@ -0,0 +1,167 @@
+//! Compact headers of the synced blocks and sizes of their state diffs, in
+//! their own column family, for the features needing a hash, a timestamp or
+//! a size without parsing a whole block
+
+use rocksdb::{ColumnFamily, Direction, IteratorMode, WriteBatch, DB};
+use serde::{Deserialize, Serialize};
+
+use crate::primitives::{BlockHeader, BlockStateDiff};
+use crate::storage::HEADERS_CF;
+
+#[derive(Debug, thiserror::Error)]
+pub enum HeaderError {
+    #[error("no {HEADERS_CF} column family")]
+    NoColumnFamily,
+    #[error(transparent)]
+    RocksDb(#[from] rocksdb::Error),
+    #[error("invalid header: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Header {
+    pub block_number: u64,
+    pub block_hash: String,
+    pub parent_block_hash: String,
+    pub timestamp: u64,
+    pub state_root: String,
+    pub transaction_count: usize,
+    /// Missing in the oldest blocks, and in the headers recorded before it
+    /// was
+    #[serde(default)]
+    pub starknet_version: Option<String>,
+}
+
+/// Size of the state diff of a block
+#[derive(Serialize, Deserialize)]
+pub struct StateDiffSize {
+    pub block_number: u64,
+    /// Of the whole state update
+    pub bytes: usize,
+    pub storage_entries: usize,
+    pub nonces: usize,
+    pub deployed_contracts: usize,
+}
+
+fn headers(db: &DB) -> Result<&ColumnFamily, HeaderError> {
+    db.cf_handle(HEADERS_CF).ok_or(HeaderError::NoColumnFamily)
+}
+
+/// Adds the write of `header` to `batch`
+pub fn put(db: &DB, batch: &mut WriteBatch, header: &Header) -> Result<(), HeaderError> {
+    let value = serde_json::to_vec(header)?;
+    batch.put_cf(headers(db)?, BlockHeader(header.block_number).key(), value);
+    Ok(())
+}
+
+#[cfg(feature = "server")]
+pub fn read(db: &DB, number: u64) -> Result<Option<Header>, HeaderError> {
+    match db.get_cf(headers(db)?, BlockHeader(number).key())? {
+        Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+        None => Ok(None),
+    }
+}
+
+pub fn put_state_diff_size(
+    db: &DB,
+    batch: &mut WriteBatch,
+    size: &StateDiffSize,
+) -> Result<(), HeaderError> {
+    let value = serde_json::to_vec(size)?;
+    batch.put_cf(headers(db)?, BlockStateDiff(size.block_number).key(), value);
+    Ok(())
+}
+
+/// Adds the deletes of the header and the state diff size of block `number`
+/// to `batch`
+pub fn delete(
+    db: &DB,
+    batch: &mut WriteBatch,
+    number: u64,
+    header: bool,
+    state_diff: bool,
+) -> Result<(), HeaderError> {
+    if header {
+        batch.delete_cf(headers(db)?, BlockHeader(number).key());
+    }
+    if state_diff {
+        batch.delete_cf(headers(db)?, BlockStateDiff(number).key());
+    }
+    Ok(())
+}
+
+/// The highest block number with a header or a state diff size
+pub fn highest(db: &DB) -> Result<Option<u64>, HeaderError> {
+    let mut highest = None;
+    for prefix in [BlockHeader::KEY_PREFIX, BlockStateDiff::KEY_PREFIX] {
+        // Just past the prefix, the keys being zero padded
+        let mut end = prefix.as_bytes().to_vec();
+        *end.last_mut().expect("prefixes are not empty") += 1;
+        let mode = IteratorMode::From(&end, Direction::Reverse);
+        if let Some(entry) = db.iterator_cf(headers(db)?, mode).next() {
+            let (key, _) = entry?;
+            let number = key
+                .strip_prefix(prefix.as_bytes())
+                .and_then(|number| std::str::from_utf8(number).ok()?.parse().ok());
+            highest = highest.max(number);
+        }
+    }
+    Ok(highest)
+}
+
+/// Every header, in block order
+pub fn all(db: &DB) -> Result<impl Iterator<Item = Result<Header, HeaderError>> + '_, HeaderError> {
+    let prefix = BlockHeader::KEY_PREFIX.as_bytes();
+    let mode = IteratorMode::From(prefix, Direction::Forward);
+    Ok(db
+        .iterator_cf(headers(db)?, mode)
+        .take_while(move |entry| {
+            entry
+                .as_ref()
+                .map_or(true, |(key, _)| key.starts_with(prefix))
+        })
+        .map(|entry| {
+            let (_, value) = entry?;
+            Ok(serde_json::from_slice(&value)?)
+        }))
+}
+
+/// The headers of up to `limit` blocks, from `last` down
+#[cfg(feature = "server")]
+pub fn headers_down_from(db: &DB, last: u64, limit: usize) -> Result<Vec<Header>, HeaderError> {
+    down_from(db, BlockHeader::KEY_PREFIX, &BlockHeader(last).key(), limit)
+}
+
+/// The state diff sizes of up to `limit` blocks, from `last` down
+#[cfg(feature = "server")]
+pub fn state_diff_sizes_down_from(
+    db: &DB,
+    last: u64,
+    limit: usize,
+) -> Result<Vec<StateDiffSize>, HeaderError> {
+    down_from(
+        db,
+        BlockStateDiff::KEY_PREFIX,
+        &BlockStateDiff(last).key(),
+        limit,
+    )
+}
+
+#[cfg(feature = "server")]
+fn down_from<T: serde::de::DeserializeOwned>(
+    db: &DB,
+    prefix: &str,
+    start: &str,
+    limit: usize,
+) -> Result<Vec<T>, HeaderError> {
+    let mode = IteratorMode::From(start.as_bytes(), Direction::Reverse);
+    let mut items = vec![];
+    for entry in db.iterator_cf(headers(db)?, mode).take(limit) {
+        let (key, value) = entry?;
+        if !key.starts_with(prefix.as_bytes()) {
+            break;
+        }
+        items.push(serde_json::from_slice(&value)?);
+    }
+    Ok(items)
+}
@@ -0,0 +1,575 @@
+#[cfg(feature = "server")]
+use rocksdb::{Direction, IteratorMode};
+use rocksdb::{WriteBatch, DB};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::class_extract::{extract_class_hash, ExtractError};
+use crate::header::{self, Header, HeaderError, StateDiffSize};
+use crate::primitives::{
+    Block, BlockHash, BlockTimestamp, ClassDeclaration, Contract, NonceChange, State, StorageWrite,
+    Transaction,
+};
+#[cfg(feature = "server")]
+use crate::storage::iter_prefix;
+use crate::storage::{is_key_present, read_data, Storage, StorageError};
+
+#[derive(Debug, thiserror::Error)]
+pub enum IndexError {
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+    #[error(transparent)]
+    RocksDb(#[from] rocksdb::Error),
+    #[error(transparent)]
+    Header(#[from] HeaderError),
+    #[error(transparent)]
+    Extract(#[from] ExtractError),
+    #[error("invalid JSON: {0}")]
+    Json(#[from] serde_json::Error),
+    #[cfg(feature = "server")]
+    #[error("`{0}` is not a number")]
+    NotANumber(String),
+    #[cfg(feature = "server")]
+    #[error("timestamp of block {0} is not indexed")]
+    NotIndexed(Block),
+    #[error("{kind} {number} is missing")]
+    Missing { kind: &'static str, number: u64 },
+    #[error("{kind} {number}: {source}")]
+    Item {
+        kind: &'static str,
+        number: u64,
+        source: Box<IndexError>,
+    },
+}
+
+#[derive(Deserialize)]
+struct BlockTransactions {
+    #[serde(default)]
+    block_hash: String,
+    #[serde(default)]
+    parent_block_hash: String,
+    timestamp: u64,
+    #[serde(default)]
+    state_root: String,
+    #[serde(default)]
+    starknet_version: Option<String>,
+    transactions: Vec<TransactionHash>,
+}
+
+#[derive(Deserialize)]
+struct TransactionHash {
+    transaction_hash: String,
+}
+
+#[derive(Deserialize)]
+struct StateUpdateDeployments {
+    state_diff: StateDiffDeployments,
+}
+
+#[derive(Deserialize)]
+struct StateDiffDeployments {
+    deployed_contracts: Vec<DeployedContract>,
+    #[serde(default)]
+    storage_diffs: HashMap<String, Vec<StorageDiff>>,
+    #[serde(default)]
+    nonces: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct StorageDiff {
+    key: String,
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct DeployedContract {
+    address: String,
+    class_hash: String,
+}
+
+/// Position of a transaction in the chain
+#[derive(Serialize, Deserialize)]
+pub struct TransactionLocation {
+    pub block_number: u64,
+    pub transaction_index: usize,
+}
+
+/// Records the timestamp, the hash and the header of a block and the location
+/// of every one of its transactions, returns the number of transactions indexed
+#[tracing::instrument(skip_all, fields(block = block.0))]
+pub fn index_block(db: &DB, block: Block, content: &[u8]) -> Result<usize, IndexError> {
+    let block_transactions: BlockTransactions = serde_json::from_slice(content)?;
+
+    let mut batch = WriteBatch::default();
+    batch.put(
+        BlockTimestamp(block.0).key(),
+        block_transactions.timestamp.to_string(),
+    );
+    if !block_transactions.block_hash.is_empty() {
+        batch.put(
+            BlockHash(block_transactions.block_hash.clone()).key(),
+            block.0.to_string(),
+        );
+    }
+    for (index, tx) in block_transactions.transactions.iter().enumerate() {
+        let location = TransactionLocation {
+            block_number: block.0,
+            transaction_index: index,
+        };
+        let location = serde_json::to_string(&location)?;
+        batch.put(Transaction(tx.transaction_hash.clone()).key(), location);
+    }
+    db.write(batch)?;
+
+    // In a batch of its own, as the readers of the WAL skip the rest of a
+    // batch from the first write of another column family
+    let header = Header {
+        block_number: block.0,
+        block_hash: block_transactions.block_hash,
+        parent_block_hash: block_transactions.parent_block_hash,
+        timestamp: block_transactions.timestamp,
+        state_root: block_transactions.state_root,
+        transaction_count: block_transactions.transactions.len(),
+        starknet_version: block_transactions.starknet_version,
+    };
+    let mut batch = WriteBatch::default();
+    header::put(db, &mut batch, &header)?;
+    db.write(batch)?;
+
+    Ok(header.transaction_count)
+}
+
+/// Adds to `batch` the deletes of what `index_block` recorded for `block`
+pub fn unindex_block(
+    db: &DB,
+    batch: &mut WriteBatch,
+    block: Block,
+    content: &[u8],
+) -> Result<(), IndexError> {
+    let block_transactions: BlockTransactions = serde_json::from_slice(content)?;
+
+    batch.delete(BlockTimestamp(block.0).key());
+    if !block_transactions.block_hash.is_empty() {
+        batch.delete(BlockHash(block_transactions.block_hash).key());
+    }
+    for tx in block_transactions.transactions {
+        batch.delete(Transaction(tx.transaction_hash).key());
+    }
+    header::delete(db, batch, block.0, true, false)?;
+    Ok(())
+}
+
+#[cfg(feature = "server")]
+pub fn transaction_location(
+    db: &DB,
+    hash: &str,
+) -> Result<Option<TransactionLocation>, IndexError> {
+    match read_data(db, &Transaction(hash.to_string()).key())? {
+        Some(location) => Ok(Some(serde_json::from_slice(&location)?)),
+        None => Ok(None),
+    }
+}
+
+/// A block whose hash matched a search
+#[cfg(feature = "server")]
+#[derive(Serialize)]
+pub struct BlockMatch {
+    pub block_hash: String,
+    pub block_number: u64,
+}
+
+/// A transaction whose hash matched a search
+#[cfg(feature = "server")]
+#[derive(Serialize)]
+pub struct TransactionMatch {
+    pub transaction_hash: String,
+    #[serde(flatten)]
+    pub location: TransactionLocation,
+}
+
+/// Up to `limit` blocks whose hash starts with `prefix`, a `0x` prefixed
+/// hexadecimal hash or start of one
+#[cfg(feature = "server")]
+pub fn search_block_hashes(
+    db: &DB,
+    prefix: &str,
+    limit: usize,
+) -> Result<Vec<BlockMatch>, IndexError> {
+    let mut matches = vec![];
+    for (key, value) in iter_prefix(db, &BlockHash(prefix.to_string()).key()).take(limit) {
+        let block_number = parse_number(&value)?;
+        matches.push(BlockMatch {
+            block_hash: String::from_utf8_lossy(&key[BlockHash::KEY_PREFIX.len()..]).into_owned(),
+            block_number,
+        });
+    }
+    Ok(matches)
+}
+
+/// Up to `limit` transactions whose hash starts with `prefix`
+#[cfg(feature = "server")]
+pub fn search_transactions(
+    db: &DB,
+    prefix: &str,
+    limit: usize,
+) -> Result<Vec<TransactionMatch>, IndexError> {
+    let mut matches = vec![];
+    for (key, value) in iter_prefix(db, &Transaction(prefix.to_string()).key()).take(limit) {
+        matches.push(TransactionMatch {
+            transaction_hash: String::from_utf8_lossy(&key[Transaction::KEY_PREFIX.len()..])
+                .into_owned(),
+            location: serde_json::from_slice(&value)?,
+        });
+    }
+    Ok(matches)
+}
+
+/// Block and class a contract was deployed with
+#[derive(Serialize, Deserialize)]
+pub struct ContractDeployment {
+    pub block_number: u64,
+    pub class_hash: String,
+}
+
+/// Records the deployment of every contract of a state update, the first
+/// block each of its classes was seen at, the size of its state diff and,
+/// with `--index-storage-history`, its storage writes and nonce changes
+#[tracing::instrument(skip_all, fields(block = state.0))]
+pub fn index_state_update(
+    storage: &Storage,
+    state: State,
+    content: &[u8],
+) -> Result<(), IndexError> {
+    let db = storage.db();
+    let state_update: StateUpdateDeployments = serde_json::from_slice(content)?;
+    let deployed_contracts = &state_update.state_diff.deployed_contracts;
+
+    let mut batch = WriteBatch::default();
+    for hash in extract_class_hash(content)? {
+        let declaration = ClassDeclaration(hash);
+        if !is_key_present(db, &declaration.key()) {
+            batch.put(declaration.key(), state.0.to_string());
+        }
+    }
+    for contract in deployed_contracts {
+        let deployment = ContractDeployment {
+            block_number: state.0,
+            class_hash: contract.class_hash.clone(),
+        };
+        let deployment = serde_json::to_string(&deployment)?;
+        batch.put(Contract(contract.address.clone()).key(), deployment);
+    }
+    if storage.index_storage_history() {
+        for (address, entries) in &state_update.state_diff.storage_diffs {
+            for entry in entries {
+                let write = StorageWrite {
+                    address: address.clone(),
+                    key: entry.key.clone(),
+                    block: state.0,
+                };
+                batch.put(write.key(), &entry.value);
+            }
+        }
+        for (address, nonce) in &state_update.state_diff.nonces {
+            let change = NonceChange {
+                address: address.clone(),
+                block: state.0,
+            };
+            batch.put(change.key(), nonce);
+        }
+    }
+    db.write(batch)?;
+
+    // In a batch of its own, as the readers of the WAL skip the rest of a
+    // batch from the first write of another column family
+    let state_diff = &state_update.state_diff;
+    let size = StateDiffSize {
+        block_number: state.0,
+        bytes: content.len(),
+        storage_entries: state_diff.storage_diffs.values().map(Vec::len).sum(),
+        nonces: state_diff.nonces.len(),
+        deployed_contracts: deployed_contracts.len(),
+    };
+    let mut batch = WriteBatch::default();
+    header::put_state_diff_size(db, &mut batch, &size)?;
+    db.write(batch)?;
+
+    Ok(())
+}
+
+/// Adds to `batch` the deletes of what `index_state_update` recorded for
+/// `state`. A class is only forgotten when first declared at `state`
+pub fn unindex_state_update(
+    storage: &Storage,
+    batch: &mut WriteBatch,
+    state: State,
+    content: &[u8],
+) -> Result<(), IndexError> {
+    let db = storage.db();
+    let state_update: StateUpdateDeployments = serde_json::from_slice(content)?;
+
+    for hash in extract_class_hash(content)? {
+        let declaration = ClassDeclaration(hash).key();
+        let declared = read_data(db, &declaration)?;
+        if declared.as_deref() == Some(state.0.to_string().as_bytes()) {
+            batch.delete(declaration);
+        }
+    }
+    let state_diff = state_update.state_diff;
+    for contract in state_diff.deployed_contracts {
+        batch.delete(Contract(contract.address).key());
+    }
+    if storage.index_storage_history() {
+        for (address, entries) in &state_diff.storage_diffs {
+            for entry in entries {
+                let write = StorageWrite {
+                    address: address.clone(),
+                    key: entry.key.clone(),
+                    block: state.0,
+                };
+                batch.delete(write.key());
+            }
+        }
+        for address in state_diff.nonces.into_keys() {
+            batch.delete(
+                NonceChange {
+                    address,
+                    block: state.0,
+                }
+                .key(),
+            );
+        }
+    }
+    header::delete(db, batch, state.0, false, true)?;
+    Ok(())
+}
+
+#[cfg(feature = "server")]
+pub fn contract_deployment(
+    db: &DB,
+    address: &str,
+) -> Result<Option<ContractDeployment>, IndexError> {
+    match read_data(db, &Contract(address.to_string()).key())? {
+        Some(deployment) => Ok(Some(serde_json::from_slice(&deployment)?)),
+        None => Ok(None),
+    }
+}
+
+/// A value of a storage slot and the block it was written at
+#[cfg(feature = "server")]
+#[derive(Serialize)]
+pub struct StorageValue {
+    pub block_number: u64,
+    pub value: String,
+}
+
+/// Up to `limit` writes of a storage slot from block `from` to `to`, in
+/// block order
+#[cfg(feature = "server")]
+pub fn storage_history(
+    db: &DB,
+    address: &str,
+    key: &str,
+    from: u64,
+    to: u64,
+    limit: usize,
+) -> Result<Vec<StorageValue>, IndexError> {
+    let prefix = StorageWrite::slot_prefix(address, key);
+    let values = history(db, &prefix, from, to, limit)?
+        .into_iter()
+        .map(|(block_number, value)| StorageValue {
+            block_number,
+            value,
+        })
+        .collect();
+    Ok(values)
+}
+
+/// The nonce of an account from the block it was set at
+#[cfg(feature = "server")]
+#[derive(Serialize)]
+pub struct NonceValue {
+    pub block_number: u64,
+    pub nonce: String,
+}
+
+/// Up to `limit` changes of the nonce of an account from block `from` to
+/// `to`, in block order
+#[cfg(feature = "server")]
+pub fn nonce_history(
+    db: &DB,
+    address: &str,
+    from: u64,
+    to: u64,
+    limit: usize,
+) -> Result<Vec<NonceValue>, IndexError> {
+    let prefix = NonceChange::account_prefix(address);
+    let nonces = history(db, &prefix, from, to, limit)?
+        .into_iter()
+        .map(|(block_number, nonce)| NonceValue {
+            block_number,
+            nonce,
+        })
+        .collect();
+    Ok(nonces)
+}
+
+/// The values under `prefix` followed by a zero padded block number, from
+/// block `from` to `to`
+#[cfg(feature = "server")]
+fn history(
+    db: &DB,
+    prefix: &str,
+    from: u64,
+    to: u64,
+    limit: usize,
+) -> Result<Vec<(u64, String)>, IndexError> {
+    let start = format!("{}{:020}", prefix, from);
+    let mode = IteratorMode::From(start.as_bytes(), Direction::Forward);
+    let mut values = vec![];
+    for entry in db.iterator(mode) {
+        let (key, value) = entry?;
+        let Some(block) = key
+            .strip_prefix(prefix.as_bytes())
+            .and_then(|block| std::str::from_utf8(block).ok())
+            .and_then(|block| block.parse::<u64>().ok())
+        else {
+            break;
+        };
+        if block > to || values.len() == limit {
+            break;
+        }
+        values.push((block, String::from_utf8_lossy(&value).into_owned()));
+    }
+    Ok(values)
+}
+
+/// Block at which a class was first declared or deployed
+#[cfg(feature = "server")]
+pub fn class_declaration(db: &DB, hash: &str) -> Result<Option<u64>, IndexError> {
+    match read_data(db, &ClassDeclaration(hash.to_string()).key())? {
+        Some(block_number) => parse_number(&block_number).map(Some),
+        None => Ok(None),
+    }
+}
+
+#[cfg(feature = "server")]
+pub fn block_timestamp(db: &DB, block: Block) -> Result<Option<u64>, IndexError> {
+    match read_data(db, &BlockTimestamp(block.0).key())? {
+        Some(timestamp) => parse_number(&timestamp).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Finds the last block produced at or before `timestamp`, relying on block
+/// timestamps never decreasing
+#[cfg(feature = "server")]
+pub fn block_at_timestamp(storage: &Storage, timestamp: u64) -> Result<Option<Block>, IndexError> {
+    let max_block_sync = match storage.max_block_sync() {
+        Some(block) => block,
+        None => return Ok(None),
+    };
+    let timestamp_of =
+        |block: Block| block_timestamp(storage.db(), block)?.ok_or(IndexError::NotIndexed(block));
+
+    if timestamp_of(Block(0))? > timestamp {
+        return Ok(None);
+    }
+
+    // Invariant: the block at `low` is at or before `timestamp`
+    let (mut low, mut high) = (0, max_block_sync.0);
+    while low < high {
+        let middle = low + (high - low).div_ceil(2);
+        match timestamp_of(Block(middle))? <= timestamp {
+            true => low = middle,
+            false => high = middle - 1,
+        }
+    }
+
+    Ok(Some(Block(low)))
+}
+
+/// A number stored as decimal text
+#[cfg(feature = "server")]
+fn parse_number(value: &[u8]) -> Result<u64, IndexError> {
+    std::str::from_utf8(value)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| IndexError::NotANumber(String::from_utf8_lossy(value).into_owned()))
+}
+
+#[cfg(feature = "server")]
+pub fn count_class_declarations(db: &DB) -> usize {
+    let prefix = ClassDeclaration::KEY_PREFIX.as_bytes();
+    db.prefix_iterator(prefix)
+        .map_while(Result::ok)
+        .take_while(|(key, _)| key.starts_with(prefix))
+        .count()
+}
+
+/// Rebuilds the indexes from the blocks and state updates already cached
+#[tracing::instrument(skip_all)]
+pub fn reindex(storage: &Storage) -> Result<(), IndexError> {
+    reindex_blocks(storage)?;
+    reindex_state_updates(storage)
+}
+
+fn reindex_blocks(storage: &Storage) -> Result<(), IndexError> {
+    let max_block_sync = match storage.max_block_sync() {
+        Some(block) => block,
+        None => {
+            tracing::info!("📭 No block to reindex");
+            return Ok(());
+        }
+    };
+
+    let mut block = Block(0);
+    while block.0 <= max_block_sync.0 {
+        let content = read_data(storage.db(), &block.key())?.ok_or(IndexError::Missing {
+            kind: "block",
+            number: block.0,
+        })?;
+        index_block(storage.db(), block, &content).map_err(|e| IndexError::Item {
+            kind: "block",
+            number: block.0,
+            source: Box::new(e),
+        })?;
+        if block.0.is_multiple_of(10_000) {
+            tracing::info!("🗂️ Reindexed up to block {}", block);
+        }
+        block = block.next();
+    }
+
+    tracing::info!("🗂️ Reindexed blocks 0 to {}", max_block_sync);
+    Ok(())
+}
+
+fn reindex_state_updates(storage: &Storage) -> Result<(), IndexError> {
+    let max_state_sync = match storage.max_state_sync() {
+        Some(state) => state,
+        None => {
+            tracing::info!("📭 No state update to reindex");
+            return Ok(());
+        }
+    };
+
+    let mut state = State(0);
+    while state.0 <= max_state_sync.0 {
+        let content = read_data(storage.db(), &state.key())?.ok_or(IndexError::Missing {
+            kind: "state update",
+            number: state.0,
+        })?;
+        index_state_update(storage, state, &content).map_err(|e| IndexError::Item {
+            kind: "state update",
+            number: state.0,
+            source: Box::new(e),
+        })?;
+        if state.0.is_multiple_of(10_000) {
+            tracing::info!("🗂️ Reindexed up to state update {}", state);
+        }
+        state = state.next();
+    }
+
+    tracing::info!("🗂️ Reindexed state updates 0 to {}", max_state_sync);
+    Ok(())
+}
@@ -0,0 +1,212 @@
+//! Verifies a few stored items picked at random in the background, to catch
+//! silent corruption of the volume before the items are requested
+
+use rocksdb::{Direction, ErrorKind, IteratorMode};
+use serde_json::Value;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::chaos::random;
+use crate::header;
+use crate::journal;
+use crate::meta::read_meta;
+use crate::metrics::Metrics;
+use crate::primitives::{normalize_hash, Block, Class, State};
+use crate::storage::{classes, read_data, Storage, StorageError};
+use crate::supervisor::TaskResult;
+
+/// Types of the items sampled, in turn
+const KINDS: [&str; 3] = ["block", "state_update", "class"];
+
+/// A sampled item, with the problems found
+struct Sample {
+    key: String,
+    problems: Vec<String>,
+}
+
+/// Checks `per_minute` items a minute, spread over the minute, until
+/// `running` is cleared
+pub async fn run(
+    per_minute: u64,
+    running: Arc<AtomicBool>,
+    storage: Arc<Storage>,
+    metrics: Arc<Metrics>,
+) -> TaskResult {
+    let interval = Duration::from_secs(60).div_f64(per_minute as f64);
+    let (mut checked, mut corrupted) = (0, 0);
+    let mut kinds = KINDS.iter().cycle();
+    while wait(interval, &running).await {
+        let kind = *kinds.next().unwrap_or(&KINDS[0]);
+        let sample = storage.blocking(move |storage| sample(storage, kind)).await;
+        let sample = match sample {
+            Ok(Some(sample)) => sample,
+            // Nothing stored yet
+            Ok(None) => continue,
+            Err(e) => {
+                tracing::error!("❌ Error sampling a {}: {}", kind, e);
+                continue;
+            }
+        };
+        checked += 1;
+        metrics.record_integrity(kind, !sample.problems.is_empty());
+        match sample.problems.is_empty() {
+            true => tracing::debug!("🔬 {} is intact", sample.key),
+            false => {
+                corrupted += 1;
+                let problems = sample.problems.join(", ");
+                tracing::error!("🚨 {} is corrupted: {}", sample.key, problems);
+                journal::record(
+                    &storage,
+                    "corruption",
+                    format!("{}: {}", sample.key, problems),
+                );
+            }
+        }
+    }
+
+    Ok(format!(
+        "Sampled {} items, {} corrupted",
+        checked, corrupted
+    ))
+}
+
+/// Sleeps for `duration`, false when `running` was cleared meanwhile
+async fn wait(duration: Duration, running: &AtomicBool) -> bool {
+    let started = Instant::now();
+    while started.elapsed() < duration {
+        if !running.load(Ordering::SeqCst) {
+            return false;
+        }
+        let left = duration.saturating_sub(started.elapsed());
+        tokio::time::sleep(left.min(Duration::from_secs(1))).await;
+    }
+    running.load(Ordering::SeqCst)
+}
+
+/// Reads an item of type `kind` picked at random and checks it, `None` when
+/// none is stored
+fn sample(storage: &Storage, kind: &str) -> Result<Option<Sample>, String> {
+    let key = match kind {
+        "block" => storage
+            .max_block_sync()
+            .map(|max| Block(random_up_to(max.0)).key()),
+        "state_update" => storage
+            .max_state_sync()
+            .map(|max| State(random_up_to(max.0)).key()),
+        _ => random_class_key(storage)?,
+    };
+    let Some(key) = key else {
+        return Ok(None);
+    };
+
+    let mut problems = vec![];
+    // RocksDB verifies the checksums of the blocks read
+    let Some(content) = read(storage, &key, &mut problems)? else {
+        return Ok((!problems.is_empty()).then_some(Sample { key, problems }));
+    };
+    let item = match serde_json::from_slice::<Value>(&content) {
+        Ok(item) if item.is_object() => item,
+        _ => {
+            problems.push("not a JSON object".to_string());
+            Value::Null
+        }
+    };
+    match read_meta(storage.db(), &key) {
+        Ok(Some(meta)) if meta.size != content.len() => problems.push(format!(
+            "{} bytes stored, {} fetched",
+            content.len(),
+            meta.size
+        )),
+        Ok(_) => {}
+        Err(e) => problems.push(format!("metadata: {}", e)),
+    }
+    if !item.is_null() {
+        check_linkage(storage, kind, &key, &item, &mut problems)?;
+    }
+    Ok(Some(Sample { key, problems }))
+}
+
+/// Compares the hashes of the item with the ones of the block it follows or
+/// belongs to, when stored
+fn check_linkage(
+    storage: &Storage,
+    kind: &str,
+    key: &str,
+    item: &Value,
+    problems: &mut Vec<String>,
+) -> Result<(), String> {
+    let (linked, field, linked_field) = match kind {
+        "block" => match key[Block::KEY_PREFIX.len()..].parse::<u64>() {
+            Ok(number) if number > 0 => (Block(number - 1), "parent_block_hash", "block_hash"),
+            _ => return Ok(()),
+        },
+        "state_update" => match key[State::KEY_PREFIX.len()..].parse::<u64>() {
+            Ok(number) => (Block(number), "block_hash", "block_hash"),
+            Err(_) => return Ok(()),
+        },
+        _ => return Ok(()),
+    };
+    // The header saves parsing the linked block
+    let linked_hash = match header::read(storage.db(), linked.0).map_err(|e| e.to_string())? {
+        Some(header) => Some(header.block_hash),
+        None => {
+            let Some(content) = read(storage, &linked.key(), problems)? else {
+                return Ok(());
+            };
+            serde_json::from_slice::<Value>(&content)
+                .ok()
+                .and_then(|linked| linked[linked_field].as_str().map(str::to_string))
+        }
+    };
+    if let (Some(hash), Some(linked_hash)) = (item[field].as_str(), linked_hash) {
+        if normalize_hash(hash) != normalize_hash(&linked_hash) {
+            problems.push(format!(
+                "{} {} is not the {} {} of block {}",
+                field, hash, linked_field, linked_hash, linked.0
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Reads `key`, recording a corruption reported by RocksDB as a problem
+fn read(
+    storage: &Storage,
+    key: &str,
+    problems: &mut Vec<String>,
+) -> Result<Option<Vec<u8>>, String> {
+    match read_data(storage.db(), key) {
+        Ok(content) => Ok(content),
+        Err(StorageError::RocksDb(e)) if e.kind() == ErrorKind::Corruption => {
+            problems.push(format!("{}: {}", key, e));
+            Ok(None)
+        }
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn random_up_to(max: u64) -> u64 {
+    ((random() * (max + 1) as f64) as u64).min(max)
+}
+
+/// The first class stored from a random hash on, the first one when past the
+/// last
+fn random_class_key(storage: &Storage) -> Result<Option<String>, String> {
+    let from = format!(
+        "{}0x{:x}{:016x}",
+        Class::KEY_PREFIX,
+        (random() * u64::MAX as f64) as u64,
+        (random() * u64::MAX as f64) as u64
+    );
+    for start in [from.as_str(), Class::KEY_PREFIX] {
+        let mode = IteratorMode::From(start.as_bytes(), Direction::Forward);
+        if let Some(entry) = storage.db().iterator_cf(classes(storage.db()), mode).next() {
+            let (key, _) = entry?;
+            if key.starts_with(Class::KEY_PREFIX.as_bytes()) {
+                return Ok(Some(String::from_utf8_lossy(&key).into_owned()));
+            }
+        }
+    }
+    Ok(None)
+}
@@ -0,0 +1,80 @@
+use rocksdb::{Direction, IteratorMode, WriteBatch, DB};
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::primitives::Event;
+use crate::storage::Storage;
+
+/// Number of events kept, the oldest ones are dropped first
+pub const CAPACITY: u64 = 10_000;
+
+/// Significant sync and lifecycle events, kept on disk for post-incident
+/// analysis
+#[derive(Serialize, Deserialize)]
+pub struct Entry {
+    pub seq: u64,
+    /// Unix time in seconds
+    pub timestamp: u64,
+    pub kind: String,
+    pub message: String,
+}
+
+/// Sequence number following the last entry recorded under `prefix`, the
+/// events or the audit log
+pub fn next_seq(db: &DB, prefix: &str) -> u64 {
+    // ':' sorts right after the digits of the zero padded sequence numbers
+    let end = format!("{}:", prefix);
+    db.iterator(IteratorMode::From(end.as_bytes(), Direction::Reverse))
+        .map_while(Result::ok)
+        .next()
+        .and_then(|(key, _)| {
+            std::str::from_utf8(&key)
+                .ok()?
+                .strip_prefix(prefix)?
+                .parse::<u64>()
+                .ok()
+        })
+        .map_or(0, |seq| seq + 1)
+}
+
+/// Appends an event, dropping the oldest one once the journal is full
+pub fn record(storage: &Storage, kind: &str, message: impl Into<String>) {
+    let seq = storage.next_event_seq();
+    let entry = Entry {
+        seq,
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs()),
+        kind: kind.to_string(),
+        message: message.into(),
+    };
+
+    let mut batch = WriteBatch::default();
+    match serde_json::to_string(&entry) {
+        Ok(value) => batch.put(Event(seq).key(), value),
+        Err(e) => return tracing::error!("❌ Error serializing event {}: {}", seq, e),
+    }
+    if seq >= CAPACITY {
+        batch.delete(Event(seq - CAPACITY).key());
+    }
+    if let Err(e) = storage.db().write(batch) {
+        tracing::error!("❌ Error recording event {}: {}", seq, e);
+    }
+}
+
+/// Newest events first, starting right before `before` when given
+#[cfg(feature = "server")]
+pub fn events(db: &DB, before: Option<u64>, kind: Option<&str>, limit: usize) -> Vec<Entry> {
+    let start = match before {
+        Some(before) => Event(before).key(),
+        None => format!("{}:", Event::KEY_PREFIX),
+    };
+    db.iterator(IteratorMode::From(start.as_bytes(), Direction::Reverse))
+        .map_while(Result::ok)
+        .take_while(|(key, _)| key.starts_with(Event::KEY_PREFIX.as_bytes()))
+        .filter_map(|(_, value)| serde_json::from_slice::<Entry>(&value).ok())
+        .filter(|entry| before.is_none_or(|before| entry.seq < before))
+        .filter(|entry| kind.is_none_or(|kind| entry.kind == kind))
+        .take(limit)
+        .collect()
+}
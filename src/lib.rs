@@ -0,0 +1,100 @@
+//! Caches the Starknet feeder gateway in RocksDB and serves it over HTTP.
+//!
+//! The `cache_feeder` binary is a thin wrapper around [`cli::execute`], the
+//! cache can also run in-process:
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), cache_feeder::Error> {
+//! let cache = cache_feeder::FeederCache::builder()
+//!     .db_path("/tmp/feeder_db")
+//!     .max_block_to_sync(100)
+//!     .server_addr("127.0.0.1:3001")
+//!     .build()?;
+//! cache.run_until(async { "test done".to_string() }).await
+//! # }
+//! ```
+
+#[cfg(not(any(feature = "server", feature = "sync")))]
+compile_error!("at least one of the `server` and `sync` features is required");
+
+mod access_log;
+mod acl;
+#[cfg(feature = "server")]
+mod admin;
+#[cfg(feature = "server")]
+mod audit;
+#[cfg(feature = "server")]
+mod binary;
+mod cache;
+mod cache_control;
+#[cfg(feature = "server")]
+mod chain_stats;
+#[cfg(feature = "server")]
+mod chaos;
+mod class_extract;
+#[cfg(feature = "sync")]
+mod class_hash;
+pub mod cli;
+pub mod config;
+#[cfg(feature = "server")]
+mod disk;
+mod fair_queue;
+#[cfg(feature = "sync")]
+mod fixture;
+#[cfg(feature = "sync")]
+mod gateway;
+#[cfg(feature = "grpc")]
+mod grpc;
+pub mod handoff;
+mod header;
+mod index;
+#[cfg(feature = "server")]
+mod integrity;
+mod journal;
+pub mod logging;
+mod maintenance;
+mod meta;
+#[cfg(feature = "server")]
+mod metrics;
+mod mirror;
+#[cfg(feature = "server")]
+mod mock;
+#[cfg(feature = "server")]
+mod peer;
+mod primitives;
+#[cfg(feature = "server")]
+mod proxy;
+#[cfg(feature = "server")]
+mod range;
+mod read_pool;
+mod reload;
+mod replicate;
+#[cfg(feature = "server")]
+mod rpc;
+mod schedule;
+#[cfg(feature = "server")]
+mod server;
+mod shutdown;
+#[cfg(feature = "server")]
+mod single_flight;
+mod snapshot;
+#[cfg(feature = "server")]
+mod state_diff;
+mod storage;
+#[cfg(feature = "server")]
+mod streaming;
+mod supervisor;
+#[cfg(feature = "sync")]
+mod sync;
+mod systemd;
+pub mod telemetry;
+mod upstream;
+#[cfg(feature = "server")]
+mod warmup;
+
+pub use cache::{Error, FeederCache, FeederCacheBuilder};
+pub use config::Network;
+#[cfg(feature = "sync")]
+pub use gateway::{Gateway, GatewayError, GatewayFuture, MockGateway};
+pub use primitives::{Block, State};
+pub use storage::{Storage, StorageError};
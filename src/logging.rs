@@ -0,0 +1,63 @@
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use std::io::IsTerminal;
+use std::sync::OnceLock;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, EnvFilter, Layer, Registry};
+
+use crate::config::LogFormat;
+
+/// Swaps the filter of the log output without restarting, the exported
+/// traces are not filtered
+static FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// `filter` uses the `RUST_LOG` syntax, extended with span names such as
+/// `info,[sync_class]=debug`, `RUST_LOG` itself is read when none is given
+pub fn parse_filter(filter: Option<&str>) -> Result<EnvFilter, String> {
+    match filter {
+        Some(filter) => EnvFilter::try_new(filter).map_err(|e| e.to_string()),
+        None => match std::env::var(EnvFilter::DEFAULT_ENV) {
+            Ok(filter) => EnvFilter::try_new(filter).map_err(|e| e.to_string()),
+            Err(_) => Ok(EnvFilter::new("error")),
+        },
+    }
+}
+
+/// Logs to stderr, and exports the spans through `tracer_provider` when given
+pub fn init(filter: Option<&str>, format: LogFormat, tracer_provider: Option<&SdkTracerProvider>) {
+    let filter = parse_filter(filter).unwrap_or_else(|_| EnvFilter::new("error"));
+    let (filter, handle) = reload::Layer::new(filter);
+    FILTER.get_or_init(|| handle);
+
+    let output = match format {
+        LogFormat::Text => fmt::layer()
+            .with_ansi(std::io::stderr().is_terminal())
+            .with_writer(std::io::stderr)
+            .boxed(),
+        LogFormat::Json => fmt::layer()
+            .json()
+            .flatten_event(true)
+            .with_current_span(false)
+            .with_span_list(false)
+            .with_writer(std::io::stderr)
+            .boxed(),
+    };
+    let traces = tracer_provider.map(|provider| {
+        tracing_opentelemetry::layer().with_tracer(provider.tracer(env!("CARGO_PKG_NAME")))
+    });
+
+    tracing_subscriber::registry()
+        .with(output.with_filter(filter))
+        .with(traces)
+        .try_init()
+        .expect("Logger already initialized");
+}
+
+pub fn set_filter(filter: Option<&str>) -> Result<(), String> {
+    let filter = parse_filter(filter)?;
+    if let Some(handle) = FILTER.get() {
+        handle.reload(filter).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
@@ -0,0 +1,648 @@
+use rocksdb::backup::{BackupEngine, BackupEngineOptions, RestoreOptions};
+use rocksdb::Env;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use crate::class_extract::{extract_class_hash, ExtractError};
+use crate::config::{
+    BackupArgs, DeleteRangeArgs, DumpArgs, DumpType, ExportArgs, ExportClassesArgs,
+    ExportHeadersArgs, HeadersFormat, ImportArgs, RestoreArgs,
+};
+use crate::header::{self, Header, HeaderError};
+use crate::index::{self, IndexError};
+use crate::journal;
+use crate::meta::{write_fetched, FetchMeta};
+use crate::primitives::{Block, Class, State};
+use crate::snapshot;
+use crate::storage::{
+    classes, find_gaps, is_key_present, iter_prefix, read_data, write_data, Storage, StorageError,
+    HEADERS_CF,
+};
+
+/// Key prefixes of the data fetched from the gateway, everything else can be
+/// rebuilt from it
+const DATA_PREFIXES: [&str; 3] = [Block::KEY_PREFIX, State::KEY_PREFIX, Class::KEY_PREFIX];
+
+#[derive(Debug, thiserror::Error)]
+pub enum MaintenanceError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+    #[error(transparent)]
+    RocksDb(#[from] rocksdb::Error),
+    #[error(transparent)]
+    Header(#[from] HeaderError),
+    #[error(transparent)]
+    Extract(#[from] ExtractError),
+    #[error(transparent)]
+    Index(#[from] IndexError),
+    #[error("invalid JSON: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("not UTF-8: {0}")]
+    Utf8(#[from] std::string::FromUtf8Error),
+    #[cfg(feature = "parquet")]
+    #[error(transparent)]
+    Parquet(#[from] parquet::errors::ParquetError),
+    #[error("publishing the snapshot: {0}")]
+    Snapshot(String),
+    #[error("line {line}: {reason}")]
+    Line { line: usize, reason: LineError },
+    #[error("{key}: {source}")]
+    Payload {
+        key: String,
+        source: serde_json::Error,
+    },
+    #[error("{0}")]
+    InvalidArgs(String),
+    #[error("the parquet format needs the `parquet` feature and --output")]
+    ParquetUnavailable,
+    #[error("{0} problems found")]
+    Problems(u64),
+}
+
+/// Why a line of an export file is refused
+#[derive(Debug, thiserror::Error)]
+pub enum LineError {
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error("unexpected key {0}")]
+    UnexpectedKey(String),
+    #[error("{0} does not hold a class")]
+    NotAClass(String),
+}
+
+/// One line of an export file
+#[derive(Serialize, Deserialize)]
+pub struct Entry {
+    pub key: String,
+    pub value: String,
+}
+
+#[tracing::instrument(skip_all)]
+pub fn export(storage: &Storage, args: &ExportArgs) -> Result<(), MaintenanceError> {
+    // Blocks synced meanwhile are exported, and left out of the range
+    let to_block = storage.max_block_sync().map_or(0, |block| block.0);
+    let snapshot_name = format!("snapshot-{}.jsonl", to_block);
+    let output: Box<dyn Write> = match (&args.output, &args.snapshot_dir) {
+        (Some(path), _) => Box::new(File::create(path)?),
+        (None, Some(dir)) => {
+            std::fs::create_dir_all(dir)?;
+            // Renamed once complete, the published file may be downloaded
+            let partial = dir.join(format!("{}.part", snapshot_name));
+            Box::new(File::create(partial)?)
+        }
+        (None, None) => Box::new(std::io::stdout().lock()),
+    };
+    let count = write_entries(storage, &DATA_PREFIXES, output)?;
+
+    tracing::info!("📤 Exported {} entries", count);
+    if let Some(dir) = &args.snapshot_dir {
+        std::fs::rename(
+            dir.join(format!("{}.part", snapshot_name)),
+            dir.join(&snapshot_name),
+        )?;
+        snapshot::publish(dir, &snapshot_name, 0, to_block).map_err(MaintenanceError::Snapshot)?;
+    }
+    Ok(())
+}
+
+/// Writes every entry under `prefixes` as a JSON line, returns their number
+fn write_entries(
+    storage: &Storage,
+    prefixes: &[&str],
+    output: impl Write,
+) -> Result<u64, MaintenanceError> {
+    let mut output = BufWriter::new(output);
+    let mut count = 0;
+    for prefix in prefixes {
+        for (key, value) in iter_prefix(storage.db(), prefix) {
+            let entry = Entry {
+                key: String::from_utf8(key.to_vec())?,
+                value: String::from_utf8(value.to_vec())?,
+            };
+            serde_json::to_writer(&mut output, &entry)?;
+            output.write_all(b"\n")?;
+            count += 1;
+        }
+    }
+    output.flush()?;
+    Ok(count)
+}
+
+/// Writes the classes only, in the `export` format
+#[tracing::instrument(skip_all)]
+pub fn export_classes(storage: &Storage, args: &ExportClassesArgs) -> Result<(), MaintenanceError> {
+    let output: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(std::io::stdout().lock()),
+    };
+    let count = write_entries(storage, &[Class::KEY_PREFIX], output)?;
+    tracing::info!("📤 Exported {} classes", count);
+    Ok(())
+}
+
+/// Writes the payloads stored for a range of blocks as they were fetched,
+/// one per line
+#[tracing::instrument(skip_all)]
+pub fn dump(storage: &Storage, args: &DumpArgs) -> Result<(), MaintenanceError> {
+    let from = args.from.unwrap_or(0);
+    let Some(to) = args.to.or(storage.max_block_sync().map(|block| block.0)) else {
+        tracing::info!("📭 No block to dump");
+        return Ok(());
+    };
+    let output: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(std::io::stdout().lock()),
+    };
+    let mut output = BufWriter::new(output);
+
+    let keys: Vec<String> = match args.kind {
+        DumpType::Blocks => (from..=to).map(|number| Block(number).key()).collect(),
+        DumpType::StateUpdates => (from..=to).map(|number| State(number).key()).collect(),
+        DumpType::Classes => {
+            let mut class_hashes = BTreeSet::new();
+            for number in from..=to {
+                if let Some(state_update) = storage.db().get(State(number).key())? {
+                    class_hashes.extend(extract_class_hash(&state_update)?);
+                }
+            }
+            class_hashes
+                .into_iter()
+                .map(|hash| Class(hash).key())
+                .collect()
+        }
+    };
+    let (mut count, mut missing) = (0, 0);
+    for key in keys {
+        let Some(payload) = read_data(storage.db(), &key)? else {
+            missing += 1;
+            continue;
+        };
+        // Payloads are stored as fetched, which may span lines
+        match payload.contains(&b'\n') {
+            true => {
+                let value: serde_json::Value =
+                    serde_json::from_slice(&payload).map_err(|source| {
+                        MaintenanceError::Payload {
+                            key: key.clone(),
+                            source,
+                        }
+                    })?;
+                serde_json::to_writer(&mut output, &value)?;
+            }
+            false => output.write_all(&payload)?,
+        }
+        output.write_all(b"\n")?;
+        count += 1;
+    }
+    output.flush()?;
+    tracing::info!("📤 Dumped {} payloads, {} not stored", count, missing);
+    Ok(())
+}
+
+/// Columns of `export-headers`, in order
+const HEADER_COLUMNS: [&str; 6] = [
+    "block_number",
+    "block_hash",
+    "parent_block_hash",
+    "timestamp",
+    "state_root",
+    "transaction_count",
+];
+
+/// Writes the block headers as CSV or Parquet
+#[tracing::instrument(skip_all)]
+pub fn export_headers(storage: &Storage, args: &ExportHeadersArgs) -> Result<(), MaintenanceError> {
+    let headers = header::all(storage.db())?;
+    let count = match (args.format, &args.output) {
+        (HeadersFormat::Csv, Some(path)) => write_headers_csv(headers, File::create(path)?)?,
+        (HeadersFormat::Csv, None) => write_headers_csv(headers, std::io::stdout().lock())?,
+        #[cfg(feature = "parquet")]
+        (HeadersFormat::Parquet, Some(path)) => {
+            write_headers_parquet(headers, File::create(path)?)?
+        }
+        // Rejected by the validation
+        _ => return Err(MaintenanceError::ParquetUnavailable),
+    };
+    tracing::info!("📤 Exported {} headers", count);
+    Ok(())
+}
+
+fn write_headers_csv(
+    headers: impl Iterator<Item = Result<Header, HeaderError>>,
+    output: impl Write,
+) -> Result<u64, MaintenanceError> {
+    let mut output = BufWriter::new(output);
+    writeln!(output, "{}", HEADER_COLUMNS.join(","))?;
+    let mut count = 0;
+    for header in headers {
+        let header = header?;
+        // Hashes are hexadecimal, so nothing needs quoting
+        writeln!(
+            output,
+            "{},{},{},{},{},{}",
+            header.block_number,
+            header.block_hash,
+            header.parent_block_hash,
+            header.timestamp,
+            header.state_root,
+            header.transaction_count
+        )?;
+        count += 1;
+    }
+    output.flush()?;
+    Ok(count)
+}
+
+/// Headers per row group, bounding the memory used
+#[cfg(feature = "parquet")]
+const PARQUET_ROW_GROUP: usize = 100_000;
+
+#[cfg(feature = "parquet")]
+fn write_headers_parquet(
+    headers: impl Iterator<Item = Result<Header, HeaderError>>,
+    output: File,
+) -> Result<u64, MaintenanceError> {
+    use parquet::basic::Compression;
+    use parquet::data_type::{ByteArray, ByteArrayType, Int64Type};
+    use parquet::file::properties::WriterProperties;
+    use parquet::file::writer::SerializedFileWriter;
+    use parquet::schema::parser::parse_message_type;
+
+    let schema = parse_message_type(
+        "message header {
+            required int64 block_number;
+            required binary block_hash (STRING);
+            required binary parent_block_hash (STRING);
+            required int64 timestamp;
+            required binary state_root (STRING);
+            required int64 transaction_count;
+        }",
+    )?;
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .build();
+    let mut writer = SerializedFileWriter::new(output, schema.into(), properties.into())?;
+
+    let mut count = 0;
+    let mut headers = headers.peekable();
+    while headers.peek().is_some() {
+        let group = headers
+            .by_ref()
+            .take(PARQUET_ROW_GROUP)
+            .collect::<Result<Vec<Header>, HeaderError>>()?;
+        let numbers = |field: fn(&Header) -> u64| {
+            group
+                .iter()
+                .map(field)
+                .map(|n| n as i64)
+                .collect::<Vec<_>>()
+        };
+        let strings = |field: fn(&Header) -> &str| {
+            group
+                .iter()
+                .map(|header| ByteArray::from(field(header)))
+                .collect::<Vec<_>>()
+        };
+        let mut row_group = writer.next_row_group()?;
+        let mut index = 0;
+        while let Some(mut column) = row_group.next_column()? {
+            let written = match index {
+                0 => column.typed::<Int64Type>().write_batch(
+                    &numbers(|h| h.block_number),
+                    None,
+                    None,
+                ),
+                1 => column.typed::<ByteArrayType>().write_batch(
+                    &strings(|h| &h.block_hash),
+                    None,
+                    None,
+                ),
+                2 => column.typed::<ByteArrayType>().write_batch(
+                    &strings(|h| &h.parent_block_hash),
+                    None,
+                    None,
+                ),
+                3 => column
+                    .typed::<Int64Type>()
+                    .write_batch(&numbers(|h| h.timestamp), None, None),
+                4 => column.typed::<ByteArrayType>().write_batch(
+                    &strings(|h| &h.state_root),
+                    None,
+                    None,
+                ),
+                _ => column.typed::<Int64Type>().write_batch(
+                    &numbers(|h| h.transaction_count as u64),
+                    None,
+                    None,
+                ),
+            };
+            written?;
+            column.close()?;
+            index += 1;
+        }
+        row_group.close()?;
+        count += group.len() as u64;
+    }
+    writer.close()?;
+    Ok(count)
+}
+
+/// Writes the classes of a file in the `export` format which are not stored
+/// yet, the other entries are skipped so a full export can be used as well
+#[tracing::instrument(skip_all)]
+pub fn import_classes(storage: &Storage, args: &ImportArgs) -> Result<(), MaintenanceError> {
+    let input = File::open(&args.input)?;
+
+    let (mut imported, mut present, mut skipped): (u64, u64, u64) = (0, 0, 0);
+    for (line, content) in BufReader::new(input).lines().enumerate() {
+        let content = content?;
+        let entry: Entry = serde_json::from_str(&content).map_err(|e| MaintenanceError::Line {
+            line: line + 1,
+            reason: e.into(),
+        })?;
+        let Some(hash) = entry.key.strip_prefix(Class::KEY_PREFIX) else {
+            skipped += 1;
+            continue;
+        };
+        if !serde_json::from_str::<serde_json::Value>(&entry.value).is_ok_and(|c| c.is_object()) {
+            return Err(MaintenanceError::Line {
+                line: line + 1,
+                reason: LineError::NotAClass(entry.key),
+            });
+        }
+
+        // A class never changes once declared
+        let key = Class(hash.to_string()).key();
+        if is_key_present(storage.db(), &key) {
+            present += 1;
+            continue;
+        }
+        write_imported_class(storage, &key, &entry.value)?;
+        imported += 1;
+        if imported.is_multiple_of(1_000) {
+            tracing::info!("📥 Imported {} classes", imported);
+        }
+    }
+
+    tracing::info!(
+        "📥 Imported {} classes, {} already stored, {} other entries skipped",
+        imported,
+        present,
+        skipped
+    );
+    Ok(())
+}
+
+/// Writes an imported class along with metadata, which the replication reads
+/// the classes written from
+fn write_imported_class(storage: &Storage, key: &str, class: &str) -> Result<(), StorageError> {
+    write_fetched(
+        storage.db(),
+        key,
+        class,
+        &FetchMeta::new("import", 200, class.len()),
+    )
+}
+
+#[tracing::instrument(skip_all)]
+pub fn import(storage: &Storage, args: &ImportArgs) -> Result<(), MaintenanceError> {
+    import_file(storage, &args.input)
+}
+
+/// Writes and indexes the entries of a file in the `export` format
+pub fn import_file(storage: &Storage, path: &Path) -> Result<(), MaintenanceError> {
+    let input = File::open(path)?;
+
+    let mut count: u64 = 0;
+    for (line, content) in BufReader::new(input).lines().enumerate() {
+        let content = content?;
+        let mut entry: Entry =
+            serde_json::from_str(&content).map_err(|e| MaintenanceError::Line {
+                line: line + 1,
+                reason: e.into(),
+            })?;
+        if !DATA_PREFIXES
+            .iter()
+            .any(|prefix| entry.key.starts_with(prefix))
+        {
+            return Err(MaintenanceError::Line {
+                line: line + 1,
+                reason: LineError::UnexpectedKey(entry.key),
+            });
+        }
+
+        if let Some(hash) = entry.key.strip_prefix(Class::KEY_PREFIX) {
+            entry.key = Class(hash.to_string()).key();
+            write_imported_class(storage, &entry.key, &entry.value)?;
+        } else {
+            write_data(storage.db(), &entry.key, &entry.value)?;
+        }
+        if let Some(number) = key_number(&entry.key, Block::KEY_PREFIX) {
+            index::index_block(storage.db(), Block(number), entry.value.as_bytes()).map_err(
+                |e| IndexError::Item {
+                    kind: "block",
+                    number,
+                    source: Box::new(e),
+                },
+            )?;
+        }
+        if let Some(number) = key_number(&entry.key, State::KEY_PREFIX) {
+            index::index_state_update(storage, State(number), entry.value.as_bytes()).map_err(
+                |e| IndexError::Item {
+                    kind: "state update",
+                    number,
+                    source: Box::new(e),
+                },
+            )?;
+        }
+
+        count += 1;
+        if count.is_multiple_of(10_000) {
+            tracing::info!("📥 Imported {} entries", count);
+        }
+    }
+
+    tracing::info!("📥 Imported {} entries", count);
+    Ok(())
+}
+
+pub fn key_number(key: &str, prefix: &str) -> Option<u64> {
+    key.strip_prefix(prefix)?.parse().ok()
+}
+
+/// Logs every problem found and fails when there is at least one
+#[tracing::instrument(skip_all)]
+pub fn verify(storage: &Storage) -> Result<(), MaintenanceError> {
+    let db = storage.db();
+    let mut problems: u64 = 0;
+
+    for (name, prefix) in [
+        ("blocks", Block::KEY_PREFIX),
+        ("state updates", State::KEY_PREFIX),
+    ] {
+        let gaps = find_gaps(db, prefix);
+        for range in &gaps.ranges {
+            tracing::error!("❌ Missing {} {} to {}", name, range.from, range.to);
+        }
+        problems += gaps.ranges.len() as u64;
+    }
+
+    for (key, value) in iter_prefix(db, Block::KEY_PREFIX) {
+        let key = String::from_utf8_lossy(&key);
+        let block_number = serde_json::from_slice::<serde_json::Value>(&value)
+            .ok()
+            .and_then(|block| block["block_number"].as_u64());
+        if block_number.is_none() || block_number != key_number(&key, Block::KEY_PREFIX) {
+            tracing::error!("❌ {} does not hold a matching block", key);
+            problems += 1;
+        }
+    }
+
+    for (key, value) in iter_prefix(db, State::KEY_PREFIX) {
+        let key = String::from_utf8_lossy(&key);
+        let class_hashes = match extract_class_hash(&value) {
+            Ok(class_hashes) => class_hashes,
+            Err(e) => {
+                tracing::error!("❌ {} does not hold a state update: {}", key, e);
+                problems += 1;
+                continue;
+            }
+        };
+        for hash in class_hashes {
+            if !is_key_present(db, &Class(hash.to_string()).key()) {
+                tracing::error!("❌ Class {} declared in {} is missing", hash, key);
+                problems += 1;
+            }
+        }
+    }
+
+    for (key, value) in iter_prefix(db, Class::KEY_PREFIX) {
+        if serde_json::from_slice::<serde_json::Value>(&value).is_err() {
+            tracing::error!("❌ {} does not hold a class", String::from_utf8_lossy(&key));
+            problems += 1;
+        }
+    }
+
+    match problems {
+        0 => {
+            tracing::info!("✅ No problem found");
+            Ok(())
+        }
+        problems => Err(MaintenanceError::Problems(problems)),
+    }
+}
+
+/// Prints the number of entries and their size per key prefix
+pub fn stats(storage: &Storage) -> Result<(), MaintenanceError> {
+    let db = storage.db();
+    let headers = db
+        .cf_handle(HEADERS_CF)
+        .ok_or(HeaderError::NoColumnFamily)?;
+    let mut prefixes: BTreeMap<String, (u64, u64)> = BTreeMap::new();
+    for (key, value) in db
+        .iterator(rocksdb::IteratorMode::Start)
+        .chain(db.iterator_cf(headers, rocksdb::IteratorMode::Start))
+        .chain(db.iterator_cf(classes(db), rocksdb::IteratorMode::Start))
+        .map_while(Result::ok)
+    {
+        let key = String::from_utf8_lossy(&key);
+        let prefix = match key.find('_') {
+            Some(end) => &key[..=end],
+            None => &key,
+        };
+        let (entries, size) = prefixes.entry(prefix.to_string()).or_default();
+        *entries += 1;
+        *size += (key.len() + value.len()) as u64;
+    }
+
+    for (prefix, (entries, size)) in prefixes {
+        println!("{:<16} {:>12} entries {:>16} bytes", prefix, entries, size);
+    }
+    for property in [
+        "rocksdb.estimate-live-data-size",
+        "rocksdb.total-sst-files-size",
+    ] {
+        if let Some(value) = storage.db().property_int_value(property)? {
+            println!("{:<32} {:>16}", property, value);
+        }
+    }
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+pub fn compact(storage: &Storage) -> Result<(), MaintenanceError> {
+    tracing::info!("🗜️ Compacting");
+    storage.db().compact_range(None::<&[u8]>, None::<&[u8]>);
+    storage
+        .db()
+        .compact_range_cf(classes(storage.db()), None::<&[u8]>, None::<&[u8]>);
+    tracing::info!("🗜️ Compacted");
+    Ok(())
+}
+
+/// Deletes a range of blocks and state updates with their index entries, or
+/// every key with a prefix. The classes are kept
+#[tracing::instrument(skip_all)]
+pub fn delete_range(storage: &Storage, args: &DeleteRangeArgs) -> Result<String, MaintenanceError> {
+    args.check().map_err(MaintenanceError::InvalidArgs)?;
+    let summary = match (&args.prefix, args.from, args.to) {
+        (Some(prefix), _, _) => {
+            storage.delete_prefix(prefix)?;
+            storage.rescan();
+            format!("Deleted the keys starting with {}", prefix)
+        }
+        (None, Some(from), Some(to)) => {
+            let (blocks, states) = args.selected();
+            let deleted = storage.delete_numbered(from, to, blocks, states)?;
+            format!(
+                "Deleted {} blocks and state updates of blocks {} to {}",
+                deleted, from, to
+            )
+        }
+        _ => unreachable!("checked above"),
+    };
+    tracing::info!("🗑️ {}", summary);
+    journal::record(storage, "deleted", summary.clone());
+    Ok(summary)
+}
+
+fn backup_engine(backup_dir: &Path) -> Result<BackupEngine, MaintenanceError> {
+    let options = BackupEngineOptions::new(backup_dir)?;
+    Ok(BackupEngine::open(&options, &Env::new()?)?)
+}
+
+#[tracing::instrument(skip_all)]
+pub fn backup(storage: &Storage, args: &BackupArgs) -> Result<(), MaintenanceError> {
+    let mut engine = backup_engine(&args.backup_dir)?;
+    engine.create_new_backup_flush(storage.db(), true)?;
+    if let Some(info) = engine.get_backup_info().last() {
+        tracing::info!(
+            "🗄️ Created backup {} in {} ({} bytes)",
+            info.backup_id,
+            args.backup_dir.display(),
+            info.size
+        );
+    }
+    Ok(())
+}
+
+/// Runs without the DB open since the restore replaces it
+pub fn restore(db_path: &Path, args: &RestoreArgs) -> Result<(), MaintenanceError> {
+    let mut engine = backup_engine(&args.backup_dir)?;
+    let options = RestoreOptions::default();
+    match args.backup_id {
+        Some(backup_id) => engine.restore_from_backup(db_path, db_path, &options, backup_id)?,
+        None => engine.restore_from_latest_backup(db_path, db_path, &options)?,
+    }
+    tracing::info!(
+        "🗄️ Restored {} from {}",
+        db_path.display(),
+        args.backup_dir.display()
+    );
+    Ok(())
+}
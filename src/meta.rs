@@ -0,0 +1,119 @@
+use rocksdb::{WriteBatch, DB};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::primitives::{normalize_hash, Meta};
+use crate::storage::{self, StorageError};
+
+/// How a stored entry was fetched, kept next to it for auditing
+#[derive(Serialize, Deserialize)]
+pub struct FetchMeta {
+    /// Unix time in seconds
+    pub fetched_at: u64,
+    /// Origin of the gateway or peer the entry came from
+    pub upstream: String,
+    pub status: u16,
+    /// In bytes
+    pub size: usize,
+    /// Response headers of `--capture-upstream-header`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+}
+
+impl FetchMeta {
+    pub fn new(upstream: impl Into<String>, status: u16, size: usize) -> FetchMeta {
+        FetchMeta {
+            fetched_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs()),
+            upstream: upstream.into(),
+            status,
+            size,
+            headers: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_headers(mut self, headers: BTreeMap<String, String>) -> FetchMeta {
+        self.headers = headers;
+        self
+    }
+}
+
+/// Writes `content` under `key` along with its metadata, atomically
+pub fn write_fetched(
+    db: &DB,
+    key: &str,
+    content: &str,
+    meta: &FetchMeta,
+) -> Result<(), StorageError> {
+    let mut batch = WriteBatch::default();
+    // The WAL readers only see the writes to the default column family, they
+    // read the class a metadata names from its own
+    batch.put(
+        Meta(key.to_string()).key(),
+        serde_json::to_vec(meta).unwrap_or_default(),
+    );
+    storage::put(db, &mut batch, key, content);
+    db.write(batch)?;
+    Ok(())
+}
+
+/// The metadata of the entry under `key`, `None` for blocks and state updates
+/// imported and entries stored before metadata was recorded
+#[cfg(feature = "server")]
+pub fn read_meta(db: &DB, key: &str) -> Result<Option<FetchMeta>, StorageError> {
+    Ok(db
+        .get(Meta(key.to_string()).key())?
+        .and_then(|content| serde_json::from_slice(&content).ok()))
+}
+
+/// Key of the genesis the DB was first synced with
+pub const GENESIS_KEY: &str = "genesis";
+
+/// The chain a DB holds, recorded on the first sync so a gateway of another
+/// chain is refused on the next starts
+#[cfg(feature = "sync")]
+#[derive(Serialize, Deserialize)]
+pub struct Genesis {
+    pub block_hash: String,
+    /// Origin of the gateway it was recorded from
+    pub upstream: String,
+    /// Unix time in seconds
+    pub recorded_at: u64,
+}
+
+#[cfg(feature = "sync")]
+impl Genesis {
+    pub fn new(block_hash: impl Into<String>, upstream: impl Into<String>) -> Genesis {
+        Genesis {
+            block_hash: block_hash.into(),
+            upstream: upstream.into(),
+            recorded_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs()),
+        }
+    }
+}
+
+#[cfg(feature = "sync")]
+pub fn read_genesis(db: &DB) -> Result<Option<Genesis>, StorageError> {
+    Ok(db
+        .get(GENESIS_KEY)?
+        .and_then(|content| serde_json::from_slice(&content).ok()))
+}
+
+#[cfg(feature = "sync")]
+pub fn write_genesis(db: &DB, genesis: &Genesis) -> Result<(), StorageError> {
+    db.put(GENESIS_KEY, serde_json::to_vec(genesis).unwrap_or_default())?;
+    Ok(())
+}
+
+/// The normalized `block_hash` of a block
+pub fn block_hash(block: &[u8]) -> Option<String> {
+    serde_json::from_slice::<serde_json::Value>(block)
+        .ok()?
+        .get("block_hash")?
+        .as_str()
+        .map(normalize_hash)
+}
@@ -0,0 +1,469 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpResponse, Responder};
+use rocksdb::statistics::Ticker;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::access_log::CACHE_HEADER;
+use crate::storage::Storage;
+use crate::upstream::{Circuit, Upstream, LATENCY_BUCKETS};
+
+/// Response extension set when the response was fetched from the gateway
+/// because the cache could not answer
+pub struct Proxied;
+
+#[derive(Clone, Copy)]
+pub struct SyncLag {
+    pub block: u64,
+    pub state_update: u64,
+}
+
+impl SyncLag {
+    /// The cache lags as much as its slowest task
+    pub fn max(&self) -> u64 {
+        self.block.max(self.state_update)
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+pub struct CacheCounters {
+    pub hits: u64,
+    pub misses: u64,
+    /// Misses answered by fetching the gateway or a peer
+    pub proxied: u64,
+}
+
+/// Cache outcomes per route, counted from the `x-cache` header of the
+/// responses, and the chain head last seen upstream
+#[derive(Default)]
+pub struct Metrics {
+    routes: Mutex<BTreeMap<String, CacheCounters>>,
+    upstream_head: RwLock<Option<u64>>,
+    /// Items sampled and found corrupted per item type
+    integrity: Mutex<BTreeMap<&'static str, (u64, u64)>>,
+}
+
+impl Metrics {
+    pub fn upstream_head(&self) -> Option<u64> {
+        *self.upstream_head.read().unwrap()
+    }
+
+    #[cfg(feature = "sync")]
+    pub fn set_upstream_head(&self, head: u64) {
+        *self.upstream_head.write().unwrap() = Some(head);
+    }
+
+    /// Blocks each sync task is behind the upstream head, unknown until the
+    /// head was fetched once
+    pub fn sync_lag(&self, storage: &Storage) -> Option<SyncLag> {
+        let head = self.upstream_head()?;
+        let lag = |synced: Option<u64>| match synced {
+            Some(synced) => head.saturating_sub(synced),
+            None => head + 1,
+        };
+        Some(SyncLag {
+            block: lag(storage.max_block_sync().map(|block| block.0)),
+            state_update: lag(storage.max_state_sync().map(|state| state.0)),
+        })
+    }
+
+    fn record(&self, route: &str, hit: bool, proxied: bool) {
+        let mut routes = self.routes.lock().unwrap();
+        let counters = routes.entry(route.to_string()).or_default();
+        match hit {
+            true => counters.hits += 1,
+            false => counters.misses += 1,
+        }
+        if proxied {
+            counters.proxied += 1;
+        }
+    }
+
+    pub fn record_integrity(&self, item: &'static str, corrupted: bool) {
+        let mut integrity = self.integrity.lock().unwrap();
+        let (checked, corruptions) = integrity.entry(item).or_default();
+        *checked += 1;
+        if corrupted {
+            *corruptions += 1;
+        }
+    }
+
+    pub fn cache(&self) -> BTreeMap<String, CacheCounters> {
+        self.routes.lock().unwrap().clone()
+    }
+
+    /// Counters per route with their item type, as reported by `/status`
+    pub fn cache_status(&self) -> serde_json::Value {
+        self.cache()
+            .into_iter()
+            .map(|(route, counters)| {
+                let status = serde_json::json!({
+                    "item": item_type(&route),
+                    "hits": counters.hits,
+                    "misses": counters.misses,
+                    "proxied": counters.proxied,
+                });
+                (route, status)
+            })
+            .collect()
+    }
+}
+
+/// Type of the items served by a route, `other` for the routes proxied as is
+pub fn item_type(route: &str) -> &'static str {
+    match route.rsplit('/').next() {
+        Some("get_block") => "block",
+        Some("get_state_update") => "state_update",
+        Some("get_class_by_hash") => "class",
+        Some("get_transaction") => "transaction",
+        Some("get_transaction_receipt") => "transaction_receipt",
+        _ => "other",
+    }
+}
+
+pub async fn count(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let metrics = req.app_data::<web::Data<Arc<Metrics>>>().cloned();
+    let route = req.match_pattern();
+
+    let res = next.call(req).await?;
+
+    let cache = res
+        .headers()
+        .get(CACHE_HEADER)
+        .and_then(|value| value.to_str().ok());
+    if let (Some(metrics), Some(route), Some(cache)) = (metrics, route, cache) {
+        let proxied = res.response().extensions().get::<Proxied>().is_some();
+        metrics.record(&route, cache == "HIT", proxied);
+    }
+
+    Ok(res)
+}
+
+/// Writes the `# HELP` and `# TYPE` lines of a metric
+fn describe(body: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(body, "# HELP {} {}", name, help);
+    let _ = writeln!(body, "# TYPE {} {}", name, kind);
+}
+
+/// Integer properties of the DB exported as gauges
+const ROCKSDB_PROPERTIES: [&str; 10] = [
+    "rocksdb.estimate-pending-compaction-bytes",
+    "rocksdb.compaction-pending",
+    "rocksdb.num-running-compactions",
+    "rocksdb.num-running-flushes",
+    "rocksdb.cur-size-all-mem-tables",
+    "rocksdb.estimate-live-data-size",
+    "rocksdb.total-sst-files-size",
+    "rocksdb.estimate-num-keys",
+    "rocksdb.is-write-stopped",
+    "rocksdb.actual-delayed-write-rate",
+];
+
+/// Statistics of the DB exported as counters
+const ROCKSDB_TICKERS: [Ticker; 8] = [
+    Ticker::BlockCacheHit,
+    Ticker::BlockCacheMiss,
+    Ticker::StallMicros,
+    Ticker::BytesWritten,
+    Ticker::BytesRead,
+    Ticker::CompactReadBytes,
+    Ticker::CompactWriteBytes,
+    Ticker::FlushWriteBytes,
+];
+
+/// `rocksdb.estimate-live-data-size` becomes
+/// `feeder_cache_rocksdb_estimate_live_data_size`
+fn rocksdb_metric_name(name: &str) -> String {
+    format!("feeder_cache_{}", name.replace(['.', '-'], "_"))
+}
+
+fn write_rocksdb(body: &mut String, storage: &Storage) {
+    for property in ROCKSDB_PROPERTIES {
+        if let Ok(Some(value)) = storage.db().property_int_value(property) {
+            let name = rocksdb_metric_name(property);
+            describe(
+                body,
+                &name,
+                "gauge",
+                &format!("RocksDB property {}", property),
+            );
+            let _ = writeln!(body, "{} {}", name, value);
+        }
+    }
+
+    for ticker in ROCKSDB_TICKERS {
+        let name = format!("{}_total", rocksdb_metric_name(ticker.name()));
+        describe(
+            body,
+            &name,
+            "counter",
+            &format!("RocksDB statistic {}", ticker.name()),
+        );
+        let _ = writeln!(body, "{} {}", name, storage.ticker(ticker));
+    }
+
+    for (name, help, value) in [
+        (
+            "feeder_cache_storage_read_retries_total",
+            "Reads retried after a transient RocksDB error",
+            storage.read_retries(),
+        ),
+        (
+            "feeder_cache_storage_read_retries_exhausted_total",
+            "Reads still failing with a transient RocksDB error once retried",
+            storage.read_retries_exhausted(),
+        ),
+    ] {
+        describe(body, name, "counter", help);
+        let _ = writeln!(body, "{} {}", name, value);
+    }
+
+    match storage.db().live_files() {
+        Ok(files) => {
+            let mut levels: BTreeMap<i32, (u64, u64)> = BTreeMap::new();
+            for file in files {
+                let (count, size) = levels.entry(file.level).or_default();
+                *count += 1;
+                *size += file.size as u64;
+            }
+            describe(
+                body,
+                "feeder_cache_rocksdb_level_files",
+                "gauge",
+                "SST files per level",
+            );
+            for (level, (count, _)) in &levels {
+                let _ = writeln!(
+                    body,
+                    "feeder_cache_rocksdb_level_files{{level=\"{}\"}} {}",
+                    level, count
+                );
+            }
+            describe(
+                body,
+                "feeder_cache_rocksdb_level_bytes",
+                "gauge",
+                "Size of the SST files per level",
+            );
+            for (level, (_, size)) in &levels {
+                let _ = writeln!(
+                    body,
+                    "feeder_cache_rocksdb_level_bytes{{level=\"{}\"}} {}",
+                    level, size
+                );
+            }
+        }
+        Err(e) => tracing::error!("❌ Error listing the SST files: {}", e),
+    }
+}
+
+fn write_upstream(body: &mut String, upstream: &Upstream) {
+    let stats = upstream.stats();
+
+    describe(
+        body,
+        "feeder_cache_upstream_requests_total",
+        "counter",
+        "Gateway requests per upstream and response status",
+    );
+    for (name, stats) in &stats {
+        for (status, count) in &stats.statuses {
+            let _ = writeln!(
+                body,
+                "feeder_cache_upstream_requests_total{{upstream=\"{}\",status=\"{}\"}} {}",
+                name, status, count
+            );
+        }
+    }
+
+    describe(
+        body,
+        "feeder_cache_upstream_consecutive_failures",
+        "gauge",
+        "Gateway requests failed in a row per upstream",
+    );
+    for (name, stats) in &stats {
+        let _ = writeln!(
+            body,
+            "feeder_cache_upstream_consecutive_failures{{upstream=\"{}\"}} {}",
+            name, stats.consecutive_failures
+        );
+    }
+
+    describe(
+        body,
+        "feeder_cache_upstream_circuit_open",
+        "gauge",
+        "1 while no request is sent to the upstream but probes",
+    );
+    for (name, stats) in &stats {
+        let _ = writeln!(
+            body,
+            "feeder_cache_upstream_circuit_open{{upstream=\"{}\"}} {}",
+            name,
+            u8::from(stats.circuit != Circuit::Closed)
+        );
+    }
+
+    describe(
+        body,
+        "feeder_cache_upstream_request_duration_seconds",
+        "histogram",
+        "Latency of the gateway requests per upstream",
+    );
+    for (name, stats) in &stats {
+        let mut cumulative = 0;
+        let bounds = LATENCY_BUCKETS
+            .iter()
+            .map(|bound| bound.to_string())
+            .chain(["+Inf".to_string()]);
+        for (bound, count) in bounds.zip(&stats.latency_buckets) {
+            cumulative += count;
+            let _ = writeln!(
+                body,
+                "feeder_cache_upstream_request_duration_seconds_bucket{{upstream=\"{}\",le=\"{}\"}} {}",
+                name, bound, cumulative
+            );
+        }
+        let _ = writeln!(
+            body,
+            "feeder_cache_upstream_request_duration_seconds_sum{{upstream=\"{}\"}} {}",
+            name, stats.latency_sum_seconds
+        );
+        let _ = writeln!(
+            body,
+            "feeder_cache_upstream_request_duration_seconds_count{{upstream=\"{}\"}} {}",
+            name, stats.requests
+        );
+    }
+}
+
+/// Serves the counters in the Prometheus text format
+pub async fn prometheus(
+    metrics: web::Data<Arc<Metrics>>,
+    storage: web::Data<Arc<Storage>>,
+    upstream: web::Data<Arc<Upstream>>,
+) -> impl Responder {
+    let cache = metrics.cache();
+    let mut body = String::new();
+
+    if let (Some(head), Some(lag)) = (metrics.upstream_head(), metrics.sync_lag(&storage)) {
+        describe(
+            &mut body,
+            "feeder_cache_upstream_head",
+            "gauge",
+            "Latest block number of the gateway",
+        );
+        let _ = writeln!(body, "feeder_cache_upstream_head {}", head);
+        describe(
+            &mut body,
+            "feeder_cache_sync_lag_blocks",
+            "gauge",
+            "Blocks the cache is behind the gateway",
+        );
+        let _ = writeln!(body, "feeder_cache_sync_lag_blocks {}", lag.max());
+        describe(
+            &mut body,
+            "feeder_cache_sync_task_lag_blocks",
+            "gauge",
+            "Blocks each sync task is behind the gateway",
+        );
+        for (task, value) in [("block", lag.block), ("state_update", lag.state_update)] {
+            let _ = writeln!(
+                body,
+                "feeder_cache_sync_task_lag_blocks{{task=\"{}\"}} {}",
+                task, value
+            );
+        }
+    }
+
+    describe(
+        &mut body,
+        "feeder_cache_requests_total",
+        "counter",
+        "Requests per route, item type and cache outcome",
+    );
+    for (route, counters) in &cache {
+        for (outcome, value) in [("hit", counters.hits), ("miss", counters.misses)] {
+            let _ = writeln!(
+                body,
+                "feeder_cache_requests_total{{route=\"{}\",item=\"{}\",cache=\"{}\"}} {}",
+                route,
+                item_type(route),
+                outcome,
+                value
+            );
+        }
+    }
+
+    describe(
+        &mut body,
+        "feeder_cache_proxied_total",
+        "counter",
+        "Misses answered by fetching the gateway",
+    );
+    for (route, counters) in &cache {
+        let _ = writeln!(
+            body,
+            "feeder_cache_proxied_total{{route=\"{}\",item=\"{}\"}} {}",
+            route,
+            item_type(route),
+            counters.proxied
+        );
+    }
+
+    write_upstream(&mut body, &upstream);
+
+    let integrity = metrics.integrity.lock().unwrap().clone();
+    if !integrity.is_empty() {
+        describe(
+            &mut body,
+            "feeder_cache_integrity_checked_total",
+            "counter",
+            "Stored items sampled and checked per item type",
+        );
+        for (item, (checked, _)) in &integrity {
+            let _ = writeln!(
+                body,
+                "feeder_cache_integrity_checked_total{{item=\"{}\"}} {}",
+                item, checked
+            );
+        }
+        describe(
+            &mut body,
+            "feeder_cache_corruption_detected_total",
+            "counter",
+            "Sampled items found corrupted per item type",
+        );
+        for (item, (_, corrupted)) in &integrity {
+            let _ = writeln!(
+                body,
+                "feeder_cache_corruption_detected_total{{item=\"{}\"}} {}",
+                item, corrupted
+            );
+        }
+    }
+
+    // Listing the SST files takes a lock on the DB
+    let storage = storage.into_inner();
+    let rocksdb = web::block(move || {
+        let mut body = String::new();
+        write_rocksdb(&mut body, &storage);
+        body
+    })
+    .await;
+    match rocksdb {
+        Ok(rocksdb) => body.push_str(&rocksdb),
+        Err(e) => tracing::error!("❌ Error reading the RocksDB statistics: {}", e),
+    }
+
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body)
+}
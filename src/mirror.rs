@@ -0,0 +1,172 @@
+//! Copies every write of the DB to a second one in the background, by
+//! replaying the WAL, to keep a standby copy
+
+use rocksdb::{IteratorMode, Options, WriteBatch, DB};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config::DbArgs;
+use crate::storage::{classes, column_families, Storage, HEADERS_CF};
+
+/// Sequence number of the last write of the DB applied to the mirror, stored
+/// in the mirror only
+const MIRROR_SEQUENCE: &str = "mirror_sequence";
+
+/// Keys written per batch when copying the whole DB
+const COPY_BATCH: usize = 1000;
+
+#[derive(Debug, thiserror::Error)]
+enum MirrorError {
+    #[error(transparent)]
+    RocksDb(#[from] rocksdb::Error),
+    #[error("no {HEADERS_CF} column family")]
+    NoHeaders,
+    #[error(transparent)]
+    Join(#[from] tokio::task::JoinError),
+}
+
+/// Replays the new writes every second until `stop` is set, then once more
+/// so the mirror holds every write. Errors are logged and retried
+pub async fn run(storage: Arc<Storage>, path: PathBuf, db: DbArgs, stop: Arc<AtomicBool>) {
+    let opened = tokio::task::spawn_blocking(move || open(&path, &db))
+        .await
+        .unwrap_or_else(|e| Err(e.into()));
+    let mirror = match opened {
+        Ok(mirror) => Arc::new(mirror),
+        Err(e) => {
+            tracing::error!("❌ Error opening the mirror: {}", e);
+            return;
+        }
+    };
+    tracing::info!("🪞 Mirroring to {}", mirror.path().display());
+
+    loop {
+        let stopping = stop.load(Ordering::SeqCst);
+        let (mirror_clone, stop_clone) = (mirror.clone(), stop.clone());
+        let applied = storage
+            .blocking(move |storage| catch_up(storage, &mirror_clone, &stop_clone))
+            .await;
+        match applied {
+            Ok(0) => {}
+            Ok(applied) => tracing::debug!("🪞 Mirrored {} writes", applied),
+            Err(e) => tracing::error!("❌ Error mirroring: {}", e),
+        }
+        if stopping {
+            break;
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+
+    match mirror.flush() {
+        Ok(()) => tracing::info!("🪞 Mirror flushed"),
+        Err(e) => tracing::error!("❌ Error flushing the mirror: {}", e),
+    }
+}
+
+/// Compressed and checked like the DB
+fn open(path: &PathBuf, db: &DbArgs) -> Result<DB, MirrorError> {
+    let mut opts = Options::default();
+    opts.create_if_missing(true);
+    db.apply(&mut opts);
+    opts.create_missing_column_families(true);
+    // Created in the same order in both, so the writes replayed name them
+    // with the same ids
+    let mirror = DB::open_cf_descriptors(&opts, path, column_families(&opts, db))?;
+    if db.paranoid_file_checks {
+        mirror.set_options(&[("paranoid_file_checks", "true")])?;
+    }
+    Ok(mirror)
+}
+
+/// Applies the writes made since the last one mirrored, or copies the whole
+/// DB when they are no longer in the WAL. Returns the number of write
+/// batches applied
+fn catch_up(storage: &Storage, mirror: &DB, stop: &AtomicBool) -> Result<u64, MirrorError> {
+    let db = storage.db();
+    let sequence = mirror
+        .get(MIRROR_SEQUENCE)?
+        .and_then(|value| String::from_utf8(value).ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    let Some(mut sequence) = sequence.filter(|sequence| *sequence <= db.latest_sequence_number())
+    else {
+        return copy_all(storage, mirror, stop);
+    };
+    if sequence == db.latest_sequence_number() {
+        return Ok(0);
+    }
+
+    let mut applied = 0;
+    // The bindings skip the batches starting at `sequence` or before,
+    // `sequence` ending the last batch mirrored
+    for update in db.get_updates_since(sequence)? {
+        let (first, mut batch) = update?;
+        if first > sequence + 1 {
+            tracing::warn!("🪞 The WAL no longer holds the writes since the last mirrored");
+            return copy_all(storage, mirror, stop);
+        }
+        sequence = first + batch.len() as u64 - 1;
+        batch.put(MIRROR_SEQUENCE, sequence.to_string());
+        mirror.write(batch)?;
+        applied += 1;
+    }
+    Ok(applied)
+}
+
+/// Replaces the content of the mirror with a copy of the DB. The writes made
+/// during the copy are replayed afterwards, rewriting the same values
+fn copy_all(storage: &Storage, mirror: &DB, stop: &AtomicBool) -> Result<u64, MirrorError> {
+    if stop.load(Ordering::SeqCst) {
+        return Ok(0);
+    }
+    tracing::info!("🪞 Copying the whole DB to the mirror");
+    let db = storage.db();
+    let sequence = db.latest_sequence_number();
+
+    let (Some(headers), Some(mirror_headers)) =
+        (db.cf_handle(HEADERS_CF), mirror.cf_handle(HEADERS_CF))
+    else {
+        return Err(MirrorError::NoHeaders);
+    };
+
+    // Every key is ASCII
+    let mut batch = WriteBatch::default();
+    batch.delete_range(&[][..], &[u8::MAX][..]);
+    batch.delete_range_cf(mirror_headers, &[][..], &[u8::MAX][..]);
+    batch.delete_range_cf(classes(mirror), &[][..], &[u8::MAX][..]);
+    mirror.write(batch)?;
+
+    let mut batch = WriteBatch::default();
+    let mut copied = 0;
+    let entries = db
+        .iterator(IteratorMode::Start)
+        .map(|entry| (entry, None))
+        .chain(
+            db.iterator_cf(headers, IteratorMode::Start)
+                .map(|entry| (entry, Some(mirror_headers))),
+        )
+        .chain(
+            db.iterator_cf(classes(db), IteratorMode::Start)
+                .map(|entry| (entry, Some(classes(mirror)))),
+        );
+    for (entry, column_family) in entries {
+        let (key, value) = entry?;
+        match column_family {
+            Some(column_family) => batch.put_cf(column_family, key, value),
+            None => batch.put(key, value),
+        }
+        copied += 1;
+        if batch.len() == COPY_BATCH {
+            if stop.load(Ordering::SeqCst) {
+                tracing::info!("🪞 Copy interrupted, started again on the next start");
+                return Ok(0);
+            }
+            mirror.write(std::mem::take(&mut batch))?;
+        }
+    }
+    batch.put(MIRROR_SEQUENCE, sequence.to_string());
+    mirror.write(batch)?;
+    tracing::info!("🪞 Copied {} keys to the mirror", copied);
+    Ok(1)
+}
@@ -0,0 +1,252 @@
+use actix_web::middleware::Logger;
+use actix_web::{web, App, HttpResponse, HttpServer};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::config::MockServeArgs;
+use crate::shutdown;
+
+/// A felt made of a one digit tag and a number, below 2^251 so it is a valid
+/// hash or address, e.g. `felt(BLOCK_HASH, 3)` for the hash of block 3
+fn felt(tag: u8, number: u64) -> String {
+    format!("0x{:x}{:062x}", tag, number)
+}
+
+const BLOCK_HASH: u8 = 1;
+const STATE_ROOT: u8 = 2;
+const CONTRACT: u8 = 3;
+const CLASS_HASH: u8 = 4;
+const COMPILED_CLASS_HASH: u8 = 5;
+const TRANSACTION_HASH: u8 = 6;
+
+const TRANSACTIONS_PER_BLOCK: u64 = 2;
+const GENESIS_TIMESTAMP: u64 = 1_700_000_000;
+const BLOCK_TIME: u64 = 30;
+
+#[derive(Clone, Copy)]
+struct Range {
+    from: u64,
+    to: u64,
+}
+
+impl Range {
+    /// Resolves `latest` and rejects the blocks outside of the range
+    fn block(&self, block_number: &str) -> Option<u64> {
+        let number = match block_number {
+            "latest" => self.to,
+            number => number.parse().ok()?,
+        };
+        (self.from..=self.to).contains(&number).then_some(number)
+    }
+
+    /// The parent of the first block is the zero hash, as for genesis
+    fn parent(&self, number: u64, tag: u8) -> String {
+        match number > self.from {
+            true => felt(tag, number - 1),
+            false => "0x0".to_string(),
+        }
+    }
+}
+
+/// Every block sends its transactions from the contract deployed in the
+/// previous block
+fn block(range: Range, number: u64) -> Value {
+    let transactions: Vec<Value> = (0..TRANSACTIONS_PER_BLOCK)
+        .map(|i| {
+            json!({
+                "transaction_hash": felt(TRANSACTION_HASH, number * TRANSACTIONS_PER_BLOCK + i),
+                "version": "0x1",
+                "max_fee": "0x2386f26fc10000",
+                "signature": [],
+                "nonce": format!("0x{:x}", i),
+                "sender_address": felt(CONTRACT, number.saturating_sub(1).max(range.from)),
+                "calldata": [],
+                "type": "INVOKE_FUNCTION",
+            })
+        })
+        .collect();
+    let receipts: Vec<Value> = transactions
+        .iter()
+        .enumerate()
+        .map(|(i, transaction)| {
+            json!({
+                "transaction_hash": transaction["transaction_hash"],
+                "transaction_index": i,
+                "actual_fee": "0x1",
+                "execution_status": "SUCCEEDED",
+                "events": [],
+                "l2_to_l1_messages": [],
+                "execution_resources": {
+                    "n_steps": 100,
+                    "builtin_instance_counter": {},
+                    "n_memory_holes": 0,
+                },
+            })
+        })
+        .collect();
+    let gas_price = json!({ "price_in_wei": "0x3b9aca00", "price_in_fri": "0x3b9aca00" });
+
+    json!({
+        "block_hash": felt(BLOCK_HASH, number),
+        "parent_block_hash": range.parent(number, BLOCK_HASH),
+        "block_number": number,
+        "state_root": felt(STATE_ROOT, number),
+        "status": "ACCEPTED_ON_L1",
+        "timestamp": GENESIS_TIMESTAMP + number * BLOCK_TIME,
+        "sequencer_address": felt(CONTRACT, 0),
+        "l1_gas_price": gas_price,
+        "l1_data_gas_price": gas_price,
+        "l1_da_mode": "BLOB",
+        "starknet_version": "0.13.2",
+        "transactions": transactions,
+        "transaction_receipts": receipts,
+    })
+}
+
+fn state_update(range: Range, number: u64) -> Value {
+    json!({
+        "block_hash": felt(BLOCK_HASH, number),
+        "new_root": felt(STATE_ROOT, number),
+        "old_root": range.parent(number, STATE_ROOT),
+        "state_diff": {
+            "storage_diffs": {
+                felt(CONTRACT, number): [{ "key": "0x1", "value": format!("0x{:x}", number) }],
+            },
+            "nonces": { felt(CONTRACT, number): "0x1" },
+            "deployed_contracts": [{
+                "address": felt(CONTRACT, number),
+                "class_hash": felt(CLASS_HASH, number),
+            }],
+            "old_declared_contracts": [],
+            "declared_classes": [{
+                "class_hash": felt(CLASS_HASH, number),
+                "compiled_class_hash": felt(COMPILED_CLASS_HASH, number),
+            }],
+            "replaced_classes": [],
+        },
+    })
+}
+
+fn class(number: u64) -> Value {
+    json!({
+        "sierra_program": ["0x1", "0x3", "0x0", felt(CLASS_HASH, number)],
+        "contract_class_version": "0.1.0",
+        "entry_points_by_type": {
+            "CONSTRUCTOR": [],
+            "EXTERNAL": [],
+            "L1_HANDLER": [],
+        },
+        "abi": "[]",
+    })
+}
+
+/// The block of the class declared in it, `None` for any other hash
+fn class_block(range: Range, class_hash: &str) -> Option<u64> {
+    let digits = class_hash.strip_prefix("0x")?;
+    let number = digits.strip_prefix(&format!("{:x}", CLASS_HASH))?;
+    if digits.len() != 63 {
+        return None;
+    }
+    let number = u64::from_str_radix(number, 16).ok()?;
+    (range.from..=range.to).contains(&number).then_some(number)
+}
+
+/// Errors are answered as the gateway does, with a 400 and a Starknet code
+fn starknet_error(code: &str, message: String) -> HttpResponse {
+    HttpResponse::BadRequest().json(json!({
+        "code": format!("StarknetErrorCode.{}", code),
+        "message": message,
+    }))
+}
+
+// url ...blockNumber=...
+#[derive(Deserialize)]
+struct BlockNumber {
+    #[serde(rename = "blockNumber")]
+    block_number: String,
+}
+
+async fn get_block(
+    range: web::Data<Range>,
+    web::Query(query): web::Query<BlockNumber>,
+) -> HttpResponse {
+    match range.block(&query.block_number) {
+        Some(number) => HttpResponse::Ok().json(block(**range, number)),
+        None => starknet_error(
+            "BLOCK_NOT_FOUND",
+            format!("Block number {} was not found.", query.block_number),
+        ),
+    }
+}
+
+async fn get_state_update(
+    range: web::Data<Range>,
+    web::Query(query): web::Query<BlockNumber>,
+) -> HttpResponse {
+    match range.block(&query.block_number) {
+        Some(number) => HttpResponse::Ok().json(state_update(**range, number)),
+        None => starknet_error(
+            "BLOCK_NOT_FOUND",
+            format!("Block number {} was not found.", query.block_number),
+        ),
+    }
+}
+
+// url ...classHash=...
+#[derive(Deserialize)]
+struct ClassHash {
+    #[serde(rename = "classHash")]
+    class_hash: String,
+}
+
+async fn get_class_by_hash(
+    range: web::Data<Range>,
+    web::Query(query): web::Query<ClassHash>,
+) -> HttpResponse {
+    match class_block(**range, &query.class_hash) {
+        Some(number) => HttpResponse::Ok().json(class(number)),
+        None => starknet_error(
+            "UNDECLARED_CLASS",
+            format!("Class with hash {} is not declared.", query.class_hash),
+        ),
+    }
+}
+
+/// Serves the same synthetic chain on every run, until a shutdown signal
+pub async fn serve(args: &MockServeArgs) -> Result<(), String> {
+    let range = web::Data::new(Range {
+        from: args.from_block,
+        to: args.to_block,
+    });
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(web::Data::clone(&range))
+            .route("/feeder_gateway/get_block", web::get().to(get_block))
+            .route(
+                "/feeder_gateway/get_state_update",
+                web::get().to(get_state_update),
+            )
+            .route(
+                "/feeder_gateway/get_class_by_hash",
+                web::get().to(get_class_by_hash),
+            )
+            .wrap(Logger::default())
+    })
+    .disable_signals()
+    .bind(&args.server_addr)
+    .map_err(|e| format!("binding {}: {}", args.server_addr, e))?
+    .run();
+    let server_handle = server.handle();
+    tokio::spawn(server);
+
+    tracing::info!(
+        "🎭 Mock gateway serving blocks {} to {} on http://{}",
+        args.from_block,
+        args.to_block,
+        args.server_addr
+    );
+    let signal = shutdown::signal().await;
+    tracing::info!("🛑 {} received, shutting down", signal);
+    server_handle.stop(true).await;
+    Ok(())
+}
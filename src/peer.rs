@@ -0,0 +1,111 @@
+use actix_web::HttpRequest;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::access_log::{forwarded_request_id, REQUEST_ID_HEADER};
+use crate::single_flight::SingleFlight;
+use crate::upstream::{upstream_name, Upstream};
+
+/// Header set on the requests to peers. Such requests are answered from the
+/// peer's DB only, so a miss is never forwarded back and forth
+pub const PEER_HEADER: &str = "x-feeder-cache-peer";
+
+/// A slow peer must not hold the request longer than a miss would
+const PEER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A response of a peer
+#[derive(Clone)]
+pub struct PeerContent {
+    pub peer: String,
+    pub content: String,
+    /// Response headers of `--capture-upstream-header`
+    pub headers: BTreeMap<String, String>,
+}
+
+/// Identical misses in flight ask the peers once
+pub type PeerFlights = SingleFlight<Option<PeerContent>>;
+
+/// Asks each peer in turn for the path and query of `req`, returning the
+/// first JSON response found
+pub async fn fetch(
+    req: &HttpRequest,
+    upstream: &Arc<Upstream>,
+    peers: &[String],
+    flights: &PeerFlights,
+) -> Option<PeerContent> {
+    if peers.is_empty() || req.headers().contains_key(PEER_HEADER) {
+        return None;
+    }
+    let path_and_query = match req.query_string() {
+        "" => req.path().to_string(),
+        query => format!("{}?{}", req.path(), query),
+    };
+    flights
+        .run(
+            path_and_query.clone(),
+            fetch_from_peers(
+                upstream.clone(),
+                peers.to_vec(),
+                path_and_query,
+                forwarded_request_id(req).map(str::to_string),
+            ),
+        )
+        .await
+}
+
+async fn fetch_from_peers(
+    upstream: Arc<Upstream>,
+    peers: Vec<String>,
+    path_and_query: String,
+    request_id: Option<String>,
+) -> Option<PeerContent> {
+    let mut headers = vec![(PEER_HEADER, "1")];
+    if let Some(request_id) = &request_id {
+        headers.push((REQUEST_ID_HEADER, request_id));
+    }
+    for peer in &peers {
+        let url = format!("{}{}", peer.trim_end_matches('/'), path_and_query);
+        let response = tokio::time::timeout(PEER_TIMEOUT, async {
+            let response = upstream.get_with_headers(&url, &headers).await?;
+            match response.status().is_success() {
+                true => {
+                    let headers = upstream.capture(&response);
+                    upstream
+                        .text(response)
+                        .await
+                        .map(|content| Some((content, headers)))
+                }
+                false => Ok(None),
+            }
+        })
+        .await;
+
+        match response {
+            Ok(Ok(Some((content, headers)))) => {
+                if serde_json::from_str::<serde_json::Value>(&content).is_ok() {
+                    tracing::debug!("🤝 Found {} on peer {}", path_and_query, peer);
+                    return Some(PeerContent {
+                        peer: upstream_name(peer),
+                        content,
+                        headers,
+                    });
+                }
+                tracing::warn!(
+                    "❌ Invalid response from peer {} for {}",
+                    peer,
+                    path_and_query
+                );
+            }
+            Ok(Ok(None)) => {}
+            Ok(Err(e)) => tracing::warn!(
+                "❌ Error fetching {} from peer {}: {}",
+                path_and_query,
+                peer,
+                e
+            ),
+            Err(_) => tracing::warn!("⏱️ Timeout fetching {} from peer {}", path_and_query, peer),
+        }
+    }
+    None
+}
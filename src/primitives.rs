@@ -1,6 +1,6 @@
 use serde::Deserialize;
 
-#[derive(PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub struct Block(pub u64);
 
 impl std::fmt::Display for Block {
@@ -10,16 +10,18 @@ impl std::fmt::Display for Block {
 }
 
 impl Block {
+    pub const KEY_PREFIX: &'static str = "block_";
+
     pub fn next(&self) -> Block {
         Block(self.0 + 1)
     }
 
     pub fn key(&self) -> String {
-        format!("block_{}", self.0)
+        format!("{}{}", Self::KEY_PREFIX, self.0)
     }
 }
 
-#[derive(PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub struct State(pub u64);
 
 impl std::fmt::Display for State {
@@ -29,12 +31,28 @@ impl std::fmt::Display for State {
 }
 
 impl State {
+    pub const KEY_PREFIX: &'static str = "state_";
+
     pub fn next(&self) -> State {
         State(self.0 + 1)
     }
 
     pub fn key(&self) -> String {
-        format!("state_{}", self.0)
+        format!("{}{}", Self::KEY_PREFIX, self.0)
+    }
+}
+
+/// Lowercase hex without leading zeros, so `0x0ABC` and `0xabc` name the
+/// same class. Anything else than hex is only lowercased
+pub fn normalize_hash(hash: &str) -> String {
+    let hash = hash.to_lowercase();
+    let digits = hash.strip_prefix("0x").unwrap_or(&hash);
+    if digits.is_empty() || !digits.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return hash;
+    }
+    match digits.trim_start_matches('0') {
+        "" => "0x0".to_string(),
+        digits => format!("0x{}", digits),
     }
 }
 
@@ -48,7 +66,209 @@ impl std::fmt::Display for Class {
 }
 
 impl Class {
+    pub const KEY_PREFIX: &'static str = "class_";
+
+    pub fn key(&self) -> String {
+        format!("{}{}", Self::KEY_PREFIX, normalize_hash(&self.0))
+    }
+}
+
+#[derive(PartialEq, Eq)]
+pub struct Transaction(pub String);
+
+impl std::fmt::Display for Transaction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Transaction {
+    pub const KEY_PREFIX: &'static str = "tx_";
+
+    pub fn key(&self) -> String {
+        format!("{}{}", Self::KEY_PREFIX, normalize_hash(&self.0))
+    }
+}
+
+#[derive(PartialEq, Eq)]
+pub struct Contract(pub String);
+
+impl std::fmt::Display for Contract {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Contract {
+    pub const KEY_PREFIX: &'static str = "contract_";
+
+    pub fn key(&self) -> String {
+        format!("{}{}", Self::KEY_PREFIX, normalize_hash(&self.0))
+    }
+}
+
+#[derive(PartialEq, Eq)]
+pub struct ClassDeclaration(pub String);
+
+impl std::fmt::Display for ClassDeclaration {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl ClassDeclaration {
+    pub const KEY_PREFIX: &'static str = "declared_";
+
+    pub fn key(&self) -> String {
+        format!("{}{}", Self::KEY_PREFIX, normalize_hash(&self.0))
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub struct BlockTimestamp(pub u64);
+
+impl std::fmt::Display for BlockTimestamp {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl BlockTimestamp {
+    pub const KEY_PREFIX: &'static str = "timestamp_";
+
+    pub fn key(&self) -> String {
+        format!("{}{}", Self::KEY_PREFIX, self.0)
+    }
+}
+
+/// The number of a block by hash
+#[derive(PartialEq, Eq, Clone)]
+pub struct BlockHash(pub String);
+
+impl BlockHash {
+    pub const KEY_PREFIX: &'static str = "blockhash_";
+
+    pub fn key(&self) -> String {
+        format!("{}{}", Self::KEY_PREFIX, normalize_hash(&self.0))
+    }
+}
+
+/// A block header, in the headers column family
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub struct BlockHeader(pub u64);
+
+impl BlockHeader {
+    pub const KEY_PREFIX: &'static str = "header_";
+
+    /// Zero padded so the keys sort in block order
+    pub fn key(&self) -> String {
+        format!("{}{:020}", Self::KEY_PREFIX, self.0)
+    }
+}
+
+/// The size of the state diff of a block, in the headers column family
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub struct BlockStateDiff(pub u64);
+
+impl BlockStateDiff {
+    pub const KEY_PREFIX: &'static str = "state_diff_";
+
+    /// Zero padded so the keys sort in block order
+    pub fn key(&self) -> String {
+        format!("{}{:020}", Self::KEY_PREFIX, self.0)
+    }
+}
+
+/// The value a storage slot was set to at a block, written when
+/// `--index-storage-history` is set
+pub struct StorageWrite {
+    pub address: String,
+    pub key: String,
+    pub block: u64,
+}
+
+impl StorageWrite {
+    pub const KEY_PREFIX: &'static str = "storage_";
+
+    /// Prefix of every write of the slot
+    pub fn slot_prefix(address: &str, key: &str) -> String {
+        format!(
+            "{}{}_{}_",
+            Self::KEY_PREFIX,
+            normalize_hash(address),
+            normalize_hash(key)
+        )
+    }
+
+    /// Zero padded so the writes of a slot sort in block order
+    pub fn key(&self) -> String {
+        format!(
+            "{}{:020}",
+            Self::slot_prefix(&self.address, &self.key),
+            self.block
+        )
+    }
+}
+
+/// The nonce of an account set at a block, written when
+/// `--index-storage-history` is set
+pub struct NonceChange {
+    pub address: String,
+    pub block: u64,
+}
+
+impl NonceChange {
+    pub const KEY_PREFIX: &'static str = "nonce_";
+
+    /// Prefix of every change of the nonce of the account
+    pub fn account_prefix(address: &str) -> String {
+        format!("{}{}_", Self::KEY_PREFIX, normalize_hash(address))
+    }
+
+    /// Zero padded so the changes of an account sort in block order
+    pub fn key(&self) -> String {
+        format!("{}{:020}", Self::account_prefix(&self.address), self.block)
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub struct Event(pub u64);
+
+impl std::fmt::Display for Event {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Event {
+    pub const KEY_PREFIX: &'static str = "event_";
+
+    /// Zero padded so the keys sort in sequence order
+    pub fn key(&self) -> String {
+        format!("{}{:020}", Self::KEY_PREFIX, self.0)
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub struct Audit(pub u64);
+
+impl Audit {
+    pub const KEY_PREFIX: &'static str = "audit_";
+
+    /// Zero padded so the keys sort in sequence order
+    #[cfg(feature = "server")]
+    pub fn key(&self) -> String {
+        format!("{}{:020}", Self::KEY_PREFIX, self.0)
+    }
+}
+
+/// The fetch metadata of the entry stored under another key
+pub struct Meta(pub String);
+
+impl Meta {
+    pub const KEY_PREFIX: &'static str = "meta_";
+
     pub fn key(&self) -> String {
-        format!("class_{}", self.0)
+        format!("{}{}", Self::KEY_PREFIX, self.0)
     }
 }
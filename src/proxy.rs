@@ -0,0 +1,193 @@
+use actix_web::http::header::CONTENT_TYPE;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse};
+use std::sync::Arc;
+use tracing::Instrument;
+
+use crate::access_log::{forwarded_request_id, CACHE_HEADER, REQUEST_ID_HEADER};
+use crate::config::ServeArgs;
+use crate::meta::{write_fetched, FetchMeta};
+use crate::metrics::Proxied;
+use crate::range;
+use crate::reload::Reloadable;
+use crate::server::is_blocked;
+use crate::single_flight::SingleFlight;
+use crate::storage::Storage;
+use crate::upstream::{upstream_name, Upstream};
+
+/// Query parameters pinning a response to content that can never change
+const IMMUTABLE_PARAMS: [&str; 3] = ["blockNumber", "blockHash", "classHash"];
+
+/// Forwarded route answering `Range` requests, its classes being as large as
+/// those of `get_class_by_hash`
+const RANGED_ROUTE: &str = "/get_compiled_class_by_class_hash";
+
+fn proxy_key(path: &str, query: &str) -> String {
+    format!("proxy_{}?{}", path, query)
+}
+
+/// Only responses pinned to a block number, a block hash or a class hash are
+/// safe to cache, everything else follows the chain head
+pub fn is_immutable(query: &str) -> bool {
+    url::form_urlencoded::parse(query.as_bytes()).any(|(name, value)| {
+        IMMUTABLE_PARAMS.contains(&name.as_ref()) && value != "latest" && value != "pending"
+    })
+}
+
+/// A gateway response, shared by the identical requests forwarded at once
+#[derive(Clone)]
+pub struct Forwarded {
+    status: StatusCode,
+    content_type: String,
+    content: web::Bytes,
+}
+
+/// Identical requests in flight are forwarded once, the error is the body of
+/// the 502 answered
+pub type ForwardFlights = SingleFlight<Result<Forwarded, &'static str>>;
+
+/// Forwards feeder gateway routes the cache does not implement to the
+/// upstream, optionally caching the immutable responses
+pub async fn passthrough(
+    req: HttpRequest,
+    storage: web::Data<Arc<Storage>>,
+    reloadable: web::Data<Arc<Reloadable>>,
+    args: web::Data<ServeArgs>,
+    upstream: web::Data<Arc<Upstream>>,
+    flights: web::Data<ForwardFlights>,
+) -> HttpResponse {
+    // Without the network prefix of the extra networks
+    let path = req.path();
+    let path = path
+        .find("/feeder_gateway/")
+        .map_or(path, |start| &path[start..]);
+    let query = req.query_string();
+    // Forwarded routes taking a class, such as the compiled classes, are
+    // refused for the blocked ones like `get_class_by_hash`
+    let blocked = url::form_urlencoded::parse(query.as_bytes())
+        .any(|(name, value)| name == "classHash" && is_blocked(&args, &value));
+    if blocked {
+        return HttpResponse::Forbidden().body("Class blocked");
+    }
+    let cacheable = args.proxy_cache && is_immutable(query);
+    let key = proxy_key(path, query);
+
+    if cacheable {
+        match storage.read(key.clone()).await {
+            Ok(Some(content)) => {
+                let mut response = HttpResponse::Ok();
+                response
+                    .insert_header((CACHE_HEADER, "HIT"))
+                    .content_type("application/json");
+                return match path.ends_with(RANGED_ROUTE) {
+                    true => range::respond(&req, response, content, None),
+                    false => response.body(content),
+                };
+            }
+            Ok(None) => {}
+            Err(e) => tracing::error!("❌ Error reading {}: {}", key, e),
+        }
+    }
+
+    let feeder_gateway_url = reloadable.feeder_gateway_url();
+    let url = match query.is_empty() {
+        true => format!("{}{}", feeder_gateway_url, path),
+        false => format!("{}{}?{}", feeder_gateway_url, path, query),
+    };
+    let forwarded = flights
+        .run(
+            url.clone(),
+            forward(
+                upstream.get_ref().clone(),
+                storage.get_ref().clone(),
+                url,
+                cacheable.then_some(key),
+                forwarded_request_id(&req).map(str::to_string),
+            ),
+        )
+        .await;
+    let Forwarded {
+        status,
+        content_type,
+        content,
+    } = match forwarded {
+        Ok(forwarded) => forwarded,
+        Err(message) => return HttpResponse::BadGateway().body(message),
+    };
+
+    let mut response = HttpResponse::build(status);
+    response
+        .insert_header((CACHE_HEADER, "MISS"))
+        .content_type(content_type);
+    let mut response = match status == StatusCode::OK && path.ends_with(RANGED_ROUTE) {
+        true => range::respond(&req, response, content, None),
+        false => response.body(content),
+    };
+    response.extensions_mut().insert(Proxied);
+    response
+}
+
+/// Fetches `url` with the id of the request, storing the response under
+/// `cache_key` when given and OK
+async fn forward(
+    upstream: Arc<Upstream>,
+    storage: Arc<Storage>,
+    url: String,
+    cache_key: Option<String>,
+    request_id: Option<String>,
+) -> Result<Forwarded, &'static str> {
+    let headers: Vec<(&str, &str)> = request_id
+        .iter()
+        .map(|request_id| (REQUEST_ID_HEADER, request_id.as_str()))
+        .collect();
+    let response = match upstream
+        .get_with_headers(&url, &headers)
+        .instrument(tracing::info_span!("upstream_fetch", url = url.as_str()))
+        .await
+    {
+        Ok(response) => response,
+        Err(e) => {
+            tracing::error!("❌ Error forwarding {}: {}", url, e);
+            return Err("Error forwarding request to the gateway");
+        }
+    };
+
+    let status =
+        StatusCode::from_u16(response.status().as_u16()).unwrap_or(StatusCode::BAD_GATEWAY);
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("application/json")
+        .to_string();
+    let headers = upstream.capture(&response);
+    let content = match upstream.text(response).await {
+        Ok(content) => content,
+        Err(e) => {
+            tracing::error!("❌ Error reading response of {}: {}", url, e);
+            return Err("Error reading the gateway response");
+        }
+    };
+
+    let content = match cache_key {
+        Some(key) if status == StatusCode::OK => {
+            let meta = FetchMeta::new(upstream_name(&url), status.as_u16(), content.len())
+                .with_headers(headers);
+            storage
+                .blocking(move |storage| {
+                    if let Err(e) = write_fetched(storage.db(), &key, &content, &meta) {
+                        tracing::error!("❌ Error writing to DB {}: {}", key, e);
+                    }
+                    content
+                })
+                .await
+        }
+        _ => content,
+    };
+
+    Ok(Forwarded {
+        status,
+        content_type,
+        content: content.into(),
+    })
+}
@@ -0,0 +1,49 @@
+//! `Range` requests on the class routes, so a large class download cut on a
+//! flaky link can resume where it stopped
+
+use actix_web::http::header::{
+    EntityTag, Header, IfRange, Range, ACCEPT_RANGES, CONTENT_RANGE, ETAG,
+};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, HttpResponseBuilder};
+
+/// Answers the part of `content` asked by a single byte range, or all of it
+/// without one. Several ranges are answered in full, as allowed, and so is a
+/// range conditioned by an `If-Range` other than `etag`
+pub fn respond(
+    req: &HttpRequest,
+    mut response: HttpResponseBuilder,
+    content: impl Into<web::Bytes>,
+    etag: Option<EntityTag>,
+) -> HttpResponse {
+    let content: web::Bytes = content.into();
+    response.insert_header((ACCEPT_RANGES, "bytes"));
+    if let Some(etag) = &etag {
+        response.insert_header((ETAG, etag.clone()));
+    }
+
+    let spec = match Range::parse(req) {
+        Ok(Range::Bytes(specs)) if specs.len() == 1 => specs.into_iter().next(),
+        _ => None,
+    };
+    let current = match IfRange::parse(req) {
+        Ok(IfRange::EntityTag(tag)) => etag.is_some_and(|etag| etag.strong_eq(&tag)),
+        Ok(IfRange::Date(_)) => false,
+        Err(_) => true,
+    };
+    let Some(spec) = spec.filter(|_| current) else {
+        return response.body(content);
+    };
+
+    let length = content.len() as u64;
+    match spec.to_satisfiable_range(length) {
+        Some((start, end)) => response
+            .status(StatusCode::PARTIAL_CONTENT)
+            .insert_header((CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, length)))
+            .body(content.slice(start as usize..=end as usize)),
+        None => response
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .insert_header((CONTENT_RANGE, format!("bytes */{}", length)))
+            .finish(),
+    }
+}
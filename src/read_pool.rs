@@ -0,0 +1,55 @@
+//! Threads dedicated to the payload reads, so the requests do not queue
+//! behind the sync writes in the blocking pool they share
+
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+pub struct ReadPool {
+    jobs: Sender<Job>,
+}
+
+impl ReadPool {
+    /// The threads stop once the pool is dropped
+    pub fn new(threads: usize) -> std::io::Result<ReadPool> {
+        let (jobs, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for i in 0..threads {
+            let receiver = receiver.clone();
+            std::thread::Builder::new()
+                .name(format!("db-read-{}", i))
+                .spawn(move || work(&receiver))?;
+        }
+        Ok(ReadPool { jobs })
+    }
+
+    /// Runs `f` on a pool thread. A panic in `f` is resumed in the caller
+    pub async fn run<T, F>(&self, f: F) -> T
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        let job: Job = Box::new(move || {
+            let _ = sender.send(catch_unwind(AssertUnwindSafe(f)));
+        });
+        self.jobs.send(job).expect("the read threads stopped");
+        match receiver.await.expect("the read threads stopped") {
+            Ok(value) => value,
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+    }
+}
+
+fn work(receiver: &Mutex<Receiver<Job>>) {
+    loop {
+        let job = receiver.lock().unwrap().recv();
+        match job {
+            Ok(job) => job(),
+            Err(_) => return,
+        }
+    }
+}
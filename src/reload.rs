@@ -0,0 +1,138 @@
+use std::sync::{Arc, RwLock};
+
+use crate::config::{Config, Network};
+use crate::logging;
+use crate::meta::block_hash;
+use crate::upstream::Upstream;
+
+/// Options applied without restarting nor re-opening the DB, re-read from
+/// the command line, the environment and the config file on SIGHUP or
+/// `POST /admin/reload`
+pub struct Reloadable {
+    /// `None` for the main network
+    network: Option<Network>,
+    feeder_gateway_url: RwLock<String>,
+    /// Genesis hash found by the network check, a new URL must serve it too
+    genesis: RwLock<Option<String>>,
+}
+
+fn feeder_gateway_url(config: &Config, network: Option<Network>) -> String {
+    match (network, config.sync_args()) {
+        (Some(network), Some(sync)) => sync.network_url(network),
+        (Some(network), None) => network.feeder_gateway_url().to_string(),
+        (None, _) => config.feeder_gateway_url().to_string(),
+    }
+}
+
+impl Reloadable {
+    pub fn new(config: &Config) -> Reloadable {
+        Reloadable {
+            network: None,
+            feeder_gateway_url: RwLock::new(config.feeder_gateway_url().to_string()),
+            genesis: RwLock::new(None),
+        }
+    }
+
+    /// For one of `--extra-networks`
+    pub fn extra(config: &Config, network: Network) -> Reloadable {
+        Reloadable {
+            network: Some(network),
+            feeder_gateway_url: RwLock::new(feeder_gateway_url(config, Some(network))),
+            genesis: RwLock::new(None),
+        }
+    }
+
+    /// For a URL left as is on reload
+    #[cfg(feature = "sync")]
+    pub fn fixed(url: &str) -> Reloadable {
+        Reloadable {
+            network: None,
+            feeder_gateway_url: RwLock::new(url.to_string()),
+            genesis: RwLock::new(None),
+        }
+    }
+
+    pub fn feeder_gateway_url(&self) -> String {
+        self.feeder_gateway_url.read().unwrap().clone()
+    }
+
+    #[cfg(feature = "sync")]
+    pub fn set_genesis(&self, hash: String) {
+        *self.genesis.write().unwrap() = Some(hash);
+    }
+
+    fn genesis(&self) -> Option<String> {
+        self.genesis.read().unwrap().clone()
+    }
+}
+
+/// The genesis hash served at `url`
+async fn genesis_at(upstream: &Upstream, url: &str) -> Result<String, String> {
+    let response = upstream
+        .get(&format!("{}/feeder_gateway/get_block?blockNumber=0", url))
+        .await
+        .map_err(|e| format!("could not read the genesis from {}: {}", url, e))?;
+    if !response.status().is_success() {
+        return Err(format!(
+            "could not read the genesis from {}: {}",
+            url,
+            response.status()
+        ));
+    }
+    let content = upstream
+        .text(response)
+        .await
+        .map_err(|e| format!("could not read the genesis from {}: {}", url, e))?;
+    block_hash(content.as_bytes()).ok_or(format!("{} serves a genesis block without a hash", url))
+}
+
+/// Re-reads the configuration and applies it to every network. A new URL
+/// of a checked network must serve the same genesis, else nothing is applied
+pub async fn reload(reloadables: &[Arc<Reloadable>], upstream: &Upstream) -> Result<(), String> {
+    let config = Config::try_new().map_err(|e| e.to_string())?;
+
+    let mut urls = Vec::with_capacity(reloadables.len());
+    for reloadable in reloadables {
+        let url = feeder_gateway_url(&config, reloadable.network);
+        let name = reloadable.network.unwrap_or(config.network).name();
+        if let Some(genesis) = reloadable.genesis() {
+            if url != reloadable.feeder_gateway_url() {
+                let hash = genesis_at(upstream, &url).await?;
+                if hash != genesis {
+                    return Err(format!(
+                        "{} serves the genesis block {}, {} is synced from {}",
+                        url, hash, name, genesis
+                    ));
+                }
+            }
+        }
+        urls.push((url, name));
+    }
+
+    logging::set_filter(config.log_level.as_deref())?;
+    for (reloadable, (url, name)) in reloadables.iter().zip(urls) {
+        *reloadable.feeder_gateway_url.write().unwrap() = url.clone();
+        tracing::info!(
+            "🔄 Configuration reloaded, {} feeder gateway URL: {}",
+            name,
+            url
+        );
+    }
+    Ok(())
+}
+
+/// Reloads on every SIGHUP until the process exits
+#[cfg(unix)]
+pub async fn on_sighup(reloadables: Vec<Arc<Reloadable>>, upstream: Arc<Upstream>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup()).expect("Failed to listen for SIGHUP");
+    while hangup.recv().await.is_some() {
+        if let Err(e) = reload(&reloadables, &upstream).await {
+            tracing::error!("❌ Error reloading configuration: {}", e);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn on_sighup(_reloadables: Vec<Arc<Reloadable>>, _upstream: Arc<Upstream>) {}
@@ -0,0 +1,589 @@
+//! Pushes the blocks, state updates and classes written to the DB to other
+//! instances, read back from the WAL like the mirror, so replicas need no
+//! access to the gateway
+
+use reqwest::Client;
+use rocksdb::{Direction, IteratorMode, WriteBatchIterator, DB};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+#[cfg(feature = "server")]
+use crate::class_extract::{parse_class_hash, ClassHashError};
+use crate::config::Config;
+#[cfg(feature = "server")]
+use crate::index::{self, IndexError};
+#[cfg(feature = "server")]
+use crate::maintenance::key_number;
+#[cfg(feature = "server")]
+use crate::meta::{write_fetched, FetchMeta};
+use crate::primitives::{Block, Class, Meta, State};
+use crate::snapshot::hex;
+use crate::storage::{classes, read_data, write_data, Storage};
+use crate::upstream::{upstream_name, with_proxy, ClientError};
+
+/// Path of the ingest endpoint of the replicas
+pub const INGEST_PATH: &str = "/admin/ingest";
+
+/// Items pushed per request
+const PUSH_BATCH: usize = 100;
+
+/// Payload bytes past which a push is sent without waiting for more items
+const PUSH_BYTES: usize = 32 << 20;
+
+/// Largest body accepted by the ingest endpoint, a single class may exceed
+/// `PUSH_BYTES`
+#[cfg(feature = "server")]
+pub const INGEST_MAX_BODY: usize = 256 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ItemKind {
+    Block,
+    StateUpdate,
+    Class,
+}
+
+/// One entry of the JSON array sent to the ingest endpoint
+#[derive(Serialize, Deserialize)]
+pub struct Item {
+    #[serde(rename = "type")]
+    pub kind: ItemKind,
+    /// Block number or class hash
+    pub key: String,
+    pub payload: String,
+    /// SHA-256 of the payload, hex encoded
+    pub checksum: String,
+}
+
+impl Item {
+    /// `None` for the keys other than blocks, state updates and classes
+    fn from_entry(key: &[u8], value: &[u8]) -> Option<Item> {
+        let key = std::str::from_utf8(key).ok()?;
+        let (kind, key) = if let Some(number) = key.strip_prefix(Block::KEY_PREFIX) {
+            (ItemKind::Block, number)
+        } else if let Some(number) = key.strip_prefix(State::KEY_PREFIX) {
+            (ItemKind::StateUpdate, number)
+        } else if let Some(hash) = key.strip_prefix(Class::KEY_PREFIX) {
+            (ItemKind::Class, hash)
+        } else {
+            return None;
+        };
+        let payload = String::from_utf8(value.to_vec()).ok()?;
+        Some(Item {
+            kind,
+            key: key.to_string(),
+            checksum: checksum(&payload),
+            payload,
+        })
+    }
+}
+
+pub fn checksum(payload: &str) -> String {
+    hex(&Sha256::digest(payload.as_bytes()))
+}
+
+/// Why the items pushed to the ingest endpoint were not stored
+#[cfg(feature = "server")]
+#[derive(Debug, thiserror::Error)]
+pub enum IngestError {
+    #[error("item {index}: {reason}")]
+    Invalid { index: usize, reason: InvalidItem },
+    #[error("storing item {index}: {source}")]
+    Store { index: usize, source: IndexError },
+}
+
+/// Why an item pushed to the ingest endpoint is refused
+#[cfg(feature = "server")]
+#[derive(Debug, thiserror::Error)]
+pub enum InvalidItem {
+    #[error("checksum mismatch")]
+    Checksum,
+    #[error("payload: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("payload: not a JSON object")]
+    NotObject,
+    #[error("key: not a block number")]
+    BlockNumber,
+    #[error("payload: block_number differs from the key")]
+    BlockNumberMismatch,
+    #[error("key: {0}")]
+    ClassHash(#[from] ClassHashError),
+}
+
+/// Checks an item and returns the key it is stored under
+#[cfg(feature = "server")]
+fn validate(item: &Item) -> Result<String, InvalidItem> {
+    if !item.checksum.eq_ignore_ascii_case(&checksum(&item.payload)) {
+        return Err(InvalidItem::Checksum);
+    }
+    let payload: serde_json::Value = serde_json::from_str(&item.payload)?;
+    if !payload.is_object() {
+        return Err(InvalidItem::NotObject);
+    }
+    match item.kind {
+        ItemKind::Block => {
+            let number: u64 = item.key.parse().map_err(|_| InvalidItem::BlockNumber)?;
+            if payload["block_number"]
+                .as_u64()
+                .is_some_and(|block_number| block_number != number)
+            {
+                return Err(InvalidItem::BlockNumberMismatch);
+            }
+            Ok(Block(number).key())
+        }
+        ItemKind::StateUpdate => {
+            let number: u64 = item.key.parse().map_err(|_| InvalidItem::BlockNumber)?;
+            Ok(State(number).key())
+        }
+        ItemKind::Class => Ok(Class(parse_class_hash(&item.key)?).key()),
+    }
+}
+
+/// Stores and indexes the items like an import, once they are all valid,
+/// then moves the sync cursors past the blocks now contiguous. Returns the
+/// number of items stored
+#[cfg(feature = "server")]
+pub fn ingest(storage: &Storage, items: &[Item]) -> Result<usize, IngestError> {
+    let keys = items
+        .iter()
+        .enumerate()
+        .map(|(index, item)| {
+            validate(item).map_err(|reason| IngestError::Invalid { index, reason })
+        })
+        .collect::<Result<Vec<String>, IngestError>>()?;
+
+    let db = storage.db();
+    for (index, (item, key)) in items.iter().zip(&keys).enumerate() {
+        let store = || -> Result<(), IndexError> {
+            let meta = FetchMeta::new("ingest", 200, item.payload.len());
+            write_fetched(db, key, &item.payload, &meta)?;
+            if let Some(number) = key_number(key, Block::KEY_PREFIX) {
+                index::index_block(db, Block(number), item.payload.as_bytes())?;
+            }
+            if let Some(number) = key_number(key, State::KEY_PREFIX) {
+                index::index_state_update(storage, State(number), item.payload.as_bytes())?;
+            }
+            Ok(())
+        };
+        store().map_err(|source| IngestError::Store { index, source })?;
+    }
+    storage.rescan();
+    Ok(items.len())
+}
+
+/// Collects the items put in a write batch. The writes to the classes column
+/// family are not iterated, the classes are named by their metadata
+struct Collector<'a> {
+    items: &'a mut Vec<Item>,
+    classes: Vec<String>,
+}
+
+impl Collector<'_> {
+    /// Adds the classes named in the batch, as stored now
+    fn read_classes(self, db: &DB) -> Result<(), ReplicateError> {
+        for key in self.classes {
+            if let Some(value) = db.get_cf(classes(db), &key)? {
+                self.items.extend(Item::from_entry(key.as_bytes(), &value));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl WriteBatchIterator for Collector<'_> {
+    fn put(&mut self, key: Box<[u8]>, value: Box<[u8]>) {
+        if let Some(key) = std::str::from_utf8(&key)
+            .ok()
+            .and_then(|key| key.strip_prefix(Meta::KEY_PREFIX))
+            .filter(|key| key.starts_with(Class::KEY_PREFIX))
+        {
+            self.classes.push(key.to_string());
+        }
+        self.items.extend(Item::from_entry(&key, &value));
+    }
+
+    fn delete(&mut self, _key: Box<[u8]>) {}
+}
+
+/// Why the writes could not be sent to a replica
+#[derive(Debug, thiserror::Error)]
+pub enum ReplicateError {
+    #[error(transparent)]
+    Db(#[from] rocksdb::Error),
+    #[error(transparent)]
+    Push(#[from] reqwest::Error),
+}
+
+/// Shared by the replicas, through the configured proxy like the gateway
+pub fn client(config: &Config) -> Result<Client, ClientError> {
+    let builder = Client::builder().timeout(Duration::from_secs(config.upstream_timeout));
+    with_proxy(builder, config)?
+        .build()
+        .map_err(ClientError::Build)
+}
+
+/// Where the WAL was replayed up to for the replica at `url`
+fn position_key(url: &str) -> String {
+    format!("replicated_{}", upstream_name(url))
+}
+
+/// Where the full resend to the replica at `url` is, while one is running
+fn resend_key(url: &str) -> String {
+    format!("replicate_resend_{}", upstream_name(url))
+}
+
+/// Every block, state update and class sent again to a replica, when the WAL
+/// no longer holds the writes since its position
+#[derive(Serialize, Deserialize)]
+struct Resend {
+    /// The WAL is replayed after it once every key was sent
+    sequence: u64,
+    /// Last key sent, the resend continues after it
+    after: Option<String>,
+}
+
+fn read_resend(storage: &Storage, url: &str) -> Option<Resend> {
+    read_data(storage.db(), &resend_key(url))
+        .ok()
+        .flatten()
+        .and_then(|value| serde_json::from_slice(&value).ok())
+}
+
+fn write_resend(storage: &Storage, url: &str, resend: &Resend) {
+    let value = serde_json::to_string(resend).unwrap_or_default();
+    if let Err(e) = write_data(storage.db(), &resend_key(url), &value) {
+        tracing::error!("❌ Error storing the resend position: {}", e);
+    }
+}
+
+/// The sequence number replication to `url` resumes after, the last write
+/// when replicating to it for the first time
+pub fn position(storage: &Storage, url: &str) -> u64 {
+    let stored = read_data(storage.db(), &position_key(url))
+        .ok()
+        .flatten()
+        .and_then(|value| String::from_utf8(value).ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    match stored {
+        Some(sequence) => sequence,
+        None => {
+            tracing::info!("📤 Replicating the writes from now on to {}", url);
+            storage.db().latest_sequence_number()
+        }
+    }
+}
+
+/// Pushes the items written after `since` to the replica at `url` every
+/// second until `stop` is set, then once more. A failed push is retried
+/// after `retry_delay` seconds, and resumes on the next start when stopping.
+/// When the WAL no longer holds the writes since the position, every item of
+/// the DB is sent again, then the WAL is replayed from where the resend began
+pub async fn run(
+    storage: Arc<Storage>,
+    client: Client,
+    url: String,
+    token: Option<String>,
+    retry_delay: u64,
+    since: u64,
+    stop: Arc<AtomicBool>,
+) {
+    let key = position_key(&url);
+    let mut sequence = since;
+    let mut resend = read_resend(&storage, &url);
+
+    loop {
+        let stopping = stop.load(Ordering::SeqCst);
+        let collected = match &resend {
+            Some(Resend { after, .. }) => {
+                let after = after.clone();
+                storage
+                    .blocking(move |storage| collect_all(storage, after.as_deref()))
+                    .await
+                    .map(|(items, last)| (items, Progress::Resent(last)))
+            }
+            None => storage
+                .blocking(move |storage| collect(storage, sequence))
+                .await
+                .map(|collected| match collected {
+                    Some((items, last)) => (items, Progress::Replayed(last)),
+                    None => (vec![], Progress::Gap),
+                }),
+        };
+        let (items, progress) = match collected {
+            Ok(collected) => collected,
+            Err(e) => {
+                tracing::error!("❌ Error reading the DB to replicate: {}", e);
+                if !stopping {
+                    tokio::time::sleep(Duration::from_secs(retry_delay)).await;
+                    continue;
+                }
+                break;
+            }
+        };
+
+        let pushed = match items.is_empty() {
+            true => Ok(()),
+            false => push(&client, &url, token.as_deref(), &items).await,
+        };
+        if let Err(e) = pushed {
+            tracing::error!("❌ Error pushing to {}: {}", url, e);
+            if !stopping {
+                tokio::time::sleep(Duration::from_secs(retry_delay)).await;
+                continue;
+            }
+            break;
+        }
+        if !items.is_empty() {
+            tracing::debug!("📤 Pushed {} items to {}", items.len(), url);
+        }
+        match progress {
+            Progress::Replayed(last) => {
+                // The position is only stored with items, its own write
+                // would be read back otherwise
+                if !items.is_empty() {
+                    if let Err(e) = write_data(storage.db(), &key, &last.to_string()) {
+                        tracing::error!("❌ Error storing the replication position: {}", e);
+                    }
+                }
+                sequence = last;
+                // More may be waiting
+                if full(&items) {
+                    continue;
+                }
+            }
+            Progress::Gap => {
+                tracing::warn!(
+                    "📤 The WAL no longer holds the writes since the position of {}, sending everything again",
+                    url
+                );
+                let started = Resend {
+                    sequence: storage.db().latest_sequence_number(),
+                    after: None,
+                };
+                write_resend(&storage, &url, &started);
+                resend = Some(started);
+                continue;
+            }
+            Progress::Resent(Some(last)) => {
+                if let Some(resend) = &mut resend {
+                    resend.after = Some(last);
+                    write_resend(&storage, &url, resend);
+                }
+                if !stopping {
+                    continue;
+                }
+            }
+            Progress::Resent(None) => {
+                if let Some(Resend {
+                    sequence: resent, ..
+                }) = resend.take()
+                {
+                    tracing::info!("📤 Sent everything again to {}", url);
+                    if let Err(e) = write_data(storage.db(), &key, &resent.to_string()) {
+                        tracing::error!("❌ Error storing the replication position: {}", e);
+                    }
+                    if let Err(e) = storage.db().delete(resend_key(&url)) {
+                        tracing::error!("❌ Error clearing the resend position: {}", e);
+                    }
+                    sequence = resent;
+                }
+                continue;
+            }
+        }
+        if stopping {
+            break;
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+/// How far a collect got
+enum Progress {
+    /// The WAL up to this sequence number
+    Replayed(u64),
+    /// The WAL misses writes after the position
+    Gap,
+    /// The keys up to this one, `None` once all were read
+    Resent(Option<String>),
+}
+
+/// The items written after `since`, up to about a push worth, with the
+/// sequence number they were read up to. `None` when the WAL no longer
+/// holds the writes right after `since`, they are never skipped
+fn collect(storage: &Storage, since: u64) -> Result<Option<(Vec<Item>, u64)>, ReplicateError> {
+    let db = storage.db();
+    let latest = db.latest_sequence_number();
+    if since > latest {
+        tracing::warn!("📤 The DB is behind the replication position, replicating from now on");
+        return Ok(Some((vec![], latest)));
+    }
+    let mut items = vec![];
+    let mut sequence = since;
+    if since == latest {
+        return Ok(Some((items, sequence)));
+    }
+    // The bindings skip the batches starting at `since` or before, `since`
+    // ending the last batch read
+    for update in db.get_updates_since(since)? {
+        let (first, batch) = update?;
+        if first > sequence + 1 {
+            return Ok(None);
+        }
+        let mut collector = Collector {
+            items: &mut items,
+            classes: vec![],
+        };
+        batch.iterate(&mut collector);
+        collector.read_classes(db)?;
+        sequence = first + batch.len() as u64 - 1;
+        if full(&items) {
+            break;
+        }
+    }
+    Ok(Some((items, sequence)))
+}
+
+/// The items stored after the key `after`, up to about a push worth, with
+/// the last key read, `None` once the end of the DB was reached
+fn collect_all(
+    storage: &Storage,
+    after: Option<&str>,
+) -> Result<(Vec<Item>, Option<String>), ReplicateError> {
+    let db = storage.db();
+    let mode = match after {
+        Some(after) => IteratorMode::From(after.as_bytes(), Direction::Forward),
+        None => IteratorMode::Start,
+    };
+    let mut items = vec![];
+    let mut default = db.iterator(mode).peekable();
+    let mut classes = db.iterator_cf(classes(db), mode).peekable();
+    // Both column families merged in key order, `after` is a position in both
+    while let Some(entry) = match (default.peek(), classes.peek()) {
+        (Some(Ok((key, _))), Some(Ok((class, _)))) if class < key => classes.next(),
+        (None, _) | (_, Some(Err(_))) => classes.next(),
+        _ => default.next(),
+    } {
+        let (key, value) = entry?;
+        if after.is_some_and(|after| after.as_bytes() == &key[..]) {
+            continue;
+        }
+        items.extend(Item::from_entry(&key, &value));
+        if full(&items) {
+            return Ok((items, Some(String::from_utf8_lossy(&key).into_owned())));
+        }
+    }
+    Ok((items, None))
+}
+
+/// Whether the items are a push worth
+fn full(items: &[Item]) -> bool {
+    items.len() >= PUSH_BATCH
+        || items.iter().map(|item| item.payload.len()).sum::<usize>() >= PUSH_BYTES
+}
+
+async fn push(
+    client: &Client,
+    url: &str,
+    token: Option<&str>,
+    items: &[Item],
+) -> Result<(), ReplicateError> {
+    let mut request = client
+        .post(format!("{}{}", url.trim_end_matches('/'), INGEST_PATH))
+        .json(items);
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+    request
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index;
+    use crate::meta::{write_fetched, FetchMeta};
+    use crate::storage::tests::temp_storage;
+
+    #[test]
+    fn collect_all_resends_every_item_once() {
+        let (_dir, storage) = temp_storage();
+        for number in 0..250 {
+            write_data(storage.db(), &Block(number).key(), "{}").unwrap();
+            write_data(storage.db(), &State(number).key(), "{}").unwrap();
+        }
+        write_data(storage.db(), &Class("0xa".to_string()).key(), "{}").unwrap();
+        write_data(storage.db(), &position_key("http://replica"), "1").unwrap();
+
+        let mut sent = 0;
+        let mut keys = std::collections::BTreeSet::new();
+        let mut after = None;
+        loop {
+            let (items, last) = collect_all(&storage, after.as_deref()).unwrap();
+            assert!(items.len() <= PUSH_BATCH);
+            sent += items.len();
+            keys.extend(
+                items
+                    .into_iter()
+                    .map(|item| format!("{:?} {}", item.kind, item.key)),
+            );
+            match last {
+                Some(last) => after = Some(last),
+                None => break,
+            }
+        }
+        assert_eq!(sent, 501);
+        assert_eq!(keys.len(), 501);
+    }
+
+    #[test]
+    fn collect_reads_each_write_once() {
+        let (_dir, storage) = temp_storage();
+        let db = storage.db();
+        let block = r#"{"block_hash":"0xb","timestamp":1,"transactions":[]}"#;
+        let meta = FetchMeta::new("test", 200, block.len());
+        write_fetched(db, &Block(0).key(), block, &meta).unwrap();
+        let since = db.latest_sequence_number();
+        index::index_block(db, Block(0), block.as_bytes()).unwrap();
+        write_fetched(db, &Class("0xa".to_string()).key(), "{}", &meta).unwrap();
+
+        let (items, sequence) = collect(&storage, since).unwrap().unwrap();
+        let items: Vec<_> = items
+            .into_iter()
+            .map(|item| format!("{:?} {} {}", item.kind, item.key, item.payload))
+            .collect();
+        assert_eq!(items, ["Class 0xa {}"]);
+        assert_eq!(sequence, db.latest_sequence_number());
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn validate_tells_why_an_item_is_refused() {
+        let item = |kind, key: &str, payload: &str| Item {
+            kind,
+            key: key.to_string(),
+            payload: payload.to_string(),
+            checksum: checksum(payload),
+        };
+        let block = item(ItemKind::Block, "3", r#"{"block_number": 3}"#);
+        assert_eq!(validate(&block).unwrap(), Block(3).key());
+
+        let moved = item(ItemKind::Block, "3", r#"{"block_number": 2}"#);
+        assert!(matches!(
+            validate(&moved),
+            Err(InvalidItem::BlockNumberMismatch)
+        ));
+        let tampered = Item {
+            checksum: checksum("{}"),
+            ..item(ItemKind::StateUpdate, "3", "[]")
+        };
+        assert!(matches!(validate(&tampered), Err(InvalidItem::Checksum)));
+        let class = item(ItemKind::Class, "0xz", "{}");
+        assert!(matches!(validate(&class), Err(InvalidItem::ClassHash(_))));
+    }
+}
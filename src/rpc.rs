@@ -0,0 +1,324 @@
+use actix_web::{web, HttpResponse, Responder};
+use base64::Engine;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde_json::{json, Map, Value};
+use std::io::Write;
+use std::sync::Arc;
+
+use crate::config::ServeArgs;
+use crate::primitives::{Block, Class, State};
+use crate::server;
+use crate::storage::{read_data, Storage};
+
+// Error codes from the Starknet JSON-RPC specification
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+const BLOCK_NOT_FOUND: i64 = 24;
+const CLASS_HASH_NOT_FOUND: i64 = 28;
+const NO_BLOCKS: i64 = 32;
+
+struct RpcError {
+    code: i64,
+    message: &'static str,
+}
+
+impl RpcError {
+    fn new(code: i64, message: &'static str) -> RpcError {
+        RpcError { code, message }
+    }
+}
+
+type RpcResult = Result<Value, RpcError>;
+
+/// Serves the read-only subset of the Starknet JSON-RPC API that can be
+/// derived from the cached gateway payloads, single or batched requests
+pub async fn handle(
+    storage: web::Data<Arc<Storage>>,
+    args: web::Data<ServeArgs>,
+    body: web::Bytes,
+) -> impl Responder {
+    let request: Value = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(_) => {
+            return HttpResponse::Ok().json(error_response(
+                Value::Null,
+                RpcError::new(PARSE_ERROR, "Parse error"),
+            ))
+        }
+    };
+
+    let response = storage
+        .blocking(move |storage| match request {
+            Value::Array(batch) if !batch.is_empty() => Value::Array(
+                batch
+                    .into_iter()
+                    .map(|request| handle_request(storage, &args, request))
+                    .collect(),
+            ),
+            request => handle_request(storage, &args, request),
+        })
+        .await;
+    HttpResponse::Ok().json(response)
+}
+
+fn handle_request(storage: &Storage, args: &ServeArgs, request: Value) -> Value {
+    let id = request.get("id").cloned().unwrap_or(Value::Null);
+    let method = match request.get("method").and_then(Value::as_str) {
+        Some(method) if request.get("jsonrpc") == Some(&json!("2.0")) => method,
+        _ => return error_response(id, RpcError::new(INVALID_REQUEST, "Invalid request")),
+    };
+    let params = request.get("params").cloned().unwrap_or(Value::Null);
+
+    let result =
+        match method {
+            "starknet_blockNumber" => block_number(storage),
+            "starknet_getBlockWithTxHashes" => param(&params, 0, "block_id")
+                .and_then(|block_id| get_block(storage, block_id, false)),
+            "starknet_getBlockWithTxs" => param(&params, 0, "block_id")
+                .and_then(|block_id| get_block(storage, block_id, true)),
+            "starknet_getStateUpdate" => param(&params, 0, "block_id")
+                .and_then(|block_id| get_state_update(storage, block_id)),
+            "starknet_getClass" => param(&params, 0, "block_id").and_then(|block_id| {
+                resolve_block_id(storage, block_id)?;
+                param(&params, 1, "class_hash")
+                    .and_then(|class_hash| get_class(storage, args, class_hash))
+            }),
+            _ => Err(RpcError::new(METHOD_NOT_FOUND, "Method not found")),
+        };
+
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(e) => error_response(id, e),
+    }
+}
+
+fn error_response(id: Value, error: RpcError) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": error.code, "message": error.message },
+    })
+}
+
+/// Params may be given either by position or by name
+fn param<'a>(params: &'a Value, position: usize, name: &str) -> Result<&'a Value, RpcError> {
+    match params {
+        Value::Array(params) => params.get(position),
+        Value::Object(params) => params.get(name),
+        _ => None,
+    }
+    .ok_or(RpcError::new(INVALID_PARAMS, "Invalid params"))
+}
+
+fn resolve_block_id(storage: &Storage, block_id: &Value) -> Result<Block, RpcError> {
+    let latest = || {
+        storage
+            .max_block_sync()
+            .ok_or(RpcError::new(BLOCK_NOT_FOUND, "Block not found"))
+    };
+    match block_id {
+        Value::String(tag) if tag == "latest" => latest(),
+        Value::Object(id) => match id.get("block_number").and_then(Value::as_u64) {
+            Some(number) => match latest()?.0 >= number {
+                true => Ok(Block(number)),
+                false => Err(RpcError::new(BLOCK_NOT_FOUND, "Block not found")),
+            },
+            // Hashes and the pending block cannot be resolved from the cache
+            None => Err(RpcError::new(BLOCK_NOT_FOUND, "Block not found")),
+        },
+        _ => Err(RpcError::new(INVALID_PARAMS, "Invalid params")),
+    }
+}
+
+fn read_json(storage: &Storage, key: &str, not_found: RpcError) -> RpcResult {
+    match read_data(storage.db(), key) {
+        Ok(Some(content)) => serde_json::from_slice(&content)
+            .map_err(|_| RpcError::new(INTERNAL_ERROR, "Internal error")),
+        Ok(None) => Err(not_found),
+        Err(e) => {
+            tracing::error!("❌ Error reading {}: {}", key, e);
+            Err(RpcError::new(INTERNAL_ERROR, "Internal error"))
+        }
+    }
+}
+
+fn block_number(storage: &Storage) -> RpcResult {
+    match storage.max_block_sync() {
+        Some(block) => Ok(json!(block.0)),
+        None => Err(RpcError::new(NO_BLOCKS, "There are no blocks")),
+    }
+}
+
+fn get_block(storage: &Storage, block_id: &Value, with_txs: bool) -> RpcResult {
+    let block = resolve_block_id(storage, block_id)?;
+    let block = read_json(
+        storage,
+        &block.key(),
+        RpcError::new(BLOCK_NOT_FOUND, "Block not found"),
+    )?;
+
+    let transactions: Vec<Value> = block["transactions"]
+        .as_array()
+        .map(|txs| {
+            txs.iter()
+                .map(|tx| match with_txs {
+                    true => transaction(tx),
+                    false => tx["transaction_hash"].clone(),
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Ok(json!({
+        "status": block["status"],
+        "block_hash": block["block_hash"],
+        "parent_hash": block["parent_block_hash"],
+        "block_number": block["block_number"],
+        "new_root": block["state_root"],
+        "timestamp": block["timestamp"],
+        "sequencer_address": block["sequencer_address"],
+        "l1_gas_price": block["l1_gas_price"],
+        "l1_data_gas_price": block["l1_data_gas_price"],
+        "l1_da_mode": block["l1_da_mode"],
+        "starknet_version": block["starknet_version"],
+        "transactions": transactions,
+    }))
+}
+
+/// Converts a gateway transaction into its JSON-RPC representation
+fn transaction(tx: &Value) -> Value {
+    let mut tx = tx.as_object().cloned().unwrap_or_default();
+    tx.remove("transaction_index");
+
+    if tx.get("type") == Some(&json!("INVOKE_FUNCTION")) {
+        tx.insert("type".to_string(), json!("INVOKE"));
+    }
+    if tx.get("type") == Some(&json!("DEPLOY_ACCOUNT")) {
+        tx.remove("contract_address");
+    }
+    if let Some(Value::Object(bounds)) = tx.remove("resource_bounds") {
+        let bounds: Map<String, Value> = bounds
+            .into_iter()
+            .map(|(resource, bound)| (resource.to_lowercase(), bound))
+            .collect();
+        tx.insert("resource_bounds".to_string(), Value::Object(bounds));
+    }
+    for field in ["nonce_data_availability_mode", "fee_data_availability_mode"] {
+        if let Some(mode) = tx.get(field).and_then(Value::as_u64) {
+            let mode = match mode {
+                0 => "L1",
+                _ => "L2",
+            };
+            tx.insert(field.to_string(), json!(mode));
+        }
+    }
+
+    Value::Object(tx)
+}
+
+fn get_state_update(storage: &Storage, block_id: &Value) -> RpcResult {
+    let block = resolve_block_id(storage, block_id)?;
+    let state_update = read_json(
+        storage,
+        &State(block.0).key(),
+        RpcError::new(BLOCK_NOT_FOUND, "Block not found"),
+    )?;
+    let diff = &state_update["state_diff"];
+
+    let storage_diffs: Vec<Value> = entries(&diff["storage_diffs"])
+        .map(|(address, entries)| json!({ "address": address, "storage_entries": entries }))
+        .collect();
+    let nonces: Vec<Value> = entries(&diff["nonces"])
+        .map(|(address, nonce)| json!({ "contract_address": address, "nonce": nonce }))
+        .collect();
+    let replaced_classes: Vec<Value> = diff["replaced_classes"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|replaced| {
+            json!({
+                "contract_address": replaced["address"],
+                "class_hash": replaced["class_hash"],
+            })
+        })
+        .collect();
+
+    Ok(json!({
+        "block_hash": state_update["block_hash"],
+        "new_root": state_update["new_root"],
+        "old_root": state_update["old_root"],
+        "state_diff": {
+            "storage_diffs": storage_diffs,
+            "nonces": nonces,
+            "deployed_contracts": array_or_empty(&diff["deployed_contracts"]),
+            "deprecated_declared_classes": array_or_empty(&diff["old_declared_contracts"]),
+            "declared_classes": array_or_empty(&diff["declared_classes"]),
+            "replaced_classes": replaced_classes,
+        },
+    }))
+}
+
+fn entries(value: &Value) -> impl Iterator<Item = (&String, &Value)> {
+    value.as_object().into_iter().flatten()
+}
+
+fn array_or_empty(value: &Value) -> Value {
+    match value {
+        Value::Array(_) => value.clone(),
+        _ => json!([]),
+    }
+}
+
+fn get_class(storage: &Storage, args: &ServeArgs, class_hash: &Value) -> RpcResult {
+    let class_hash = class_hash
+        .as_str()
+        .ok_or(RpcError::new(INVALID_PARAMS, "Invalid params"))?;
+    // The API has no error for a refused class
+    if server::is_blocked(args, class_hash) {
+        return Err(RpcError::new(CLASS_HASH_NOT_FOUND, "Class hash not found"));
+    }
+    let class = read_json(
+        storage,
+        &Class(class_hash.to_string()).key(),
+        RpcError::new(CLASS_HASH_NOT_FOUND, "Class hash not found"),
+    )?;
+
+    match class.get("sierra_program") {
+        Some(_) => Ok(class),
+        None => deprecated_class(class),
+    }
+}
+
+/// Cairo 0 classes carry their program gzipped and base64 encoded in the
+/// JSON-RPC API, and entry point offsets as integers
+fn deprecated_class(mut class: Value) -> RpcResult {
+    let program = serde_json::to_vec(&class["program"])
+        .map_err(|_| RpcError::new(INTERNAL_ERROR, "Internal error"))?;
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    let compressed = encoder
+        .write_all(&program)
+        .and_then(|_| encoder.finish())
+        .map_err(|_| RpcError::new(INTERNAL_ERROR, "Internal error"))?;
+    class["program"] = json!(base64::engine::general_purpose::STANDARD.encode(compressed));
+
+    if let Some(entry_points) = class["entry_points_by_type"].as_object_mut() {
+        for entry_point in entry_points
+            .values_mut()
+            .filter_map(Value::as_array_mut)
+            .flatten()
+        {
+            let offset = entry_point["offset"]
+                .as_str()
+                .and_then(|offset| u64::from_str_radix(offset.trim_start_matches("0x"), 16).ok());
+            if let Some(offset) = offset {
+                entry_point["offset"] = json!(offset);
+            }
+        }
+    }
+
+    Ok(class)
+}
@@ -0,0 +1,147 @@
+#[cfg(feature = "server")]
+use chrono::Utc;
+use clap::ValueEnum;
+use cron::Schedule;
+#[cfg(feature = "server")]
+use std::path::PathBuf;
+use std::str::FromStr;
+#[cfg(feature = "server")]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "server")]
+use std::sync::Arc;
+#[cfg(feature = "server")]
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "server")]
+use crate::config::BackupArgs;
+#[cfg(all(feature = "server", feature = "sync"))]
+use crate::gateway::Gateway;
+#[cfg(feature = "server")]
+use crate::journal;
+#[cfg(feature = "server")]
+use crate::maintenance;
+#[cfg(feature = "server")]
+use crate::storage::Storage;
+#[cfg(feature = "server")]
+use crate::supervisor::TaskResult;
+
+/// Heavy operations run at scheduled times rather than on demand
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Job {
+    /// The integrity scan of `verify`
+    Verify,
+    Compact,
+    /// A new backup in `--schedule-backup-dir`
+    Backup,
+    /// Fetches the last synced blocks again and compares them
+    Reverify,
+}
+
+impl Job {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Job::Verify => "verify",
+            Job::Compact => "compact",
+            Job::Backup => "backup",
+            Job::Reverify => "reverify",
+        }
+    }
+}
+
+/// Parses `<job>=<cron expression>`, the expression in the standard five
+/// fields or with seconds first
+pub fn parse(value: &str) -> Result<(Job, Schedule), String> {
+    let (job, expression) = value
+        .split_once('=')
+        .ok_or(format!("`{}` is not `<job>=<cron expression>`", value))?;
+    let job = Job::from_str(job.trim(), true)?;
+    let expression = expression.trim();
+    let expression = match expression.split_whitespace().count() {
+        5 => format!("0 {}", expression),
+        _ => expression.to_string(),
+    };
+    let schedule = Schedule::from_str(&expression).map_err(|e| format!("{}: {}", value, e))?;
+    Ok((job, schedule))
+}
+
+/// What the jobs run against
+#[cfg(feature = "server")]
+#[derive(Clone)]
+pub struct Context {
+    pub storage: Arc<Storage>,
+    pub backup_dir: Option<PathBuf>,
+    #[cfg(feature = "sync")]
+    pub reverify_tail: u64,
+    #[cfg(feature = "sync")]
+    pub gateway: Arc<dyn Gateway>,
+}
+
+/// Runs `job` at every time of `schedule`, in UTC, until a shutdown is
+/// requested. A failed run is logged and journaled, the next one still
+/// happens
+#[cfg(feature = "server")]
+#[tracing::instrument(skip_all, fields(job = job.name()))]
+pub async fn run(
+    job: Job,
+    schedule: Schedule,
+    running: Arc<AtomicBool>,
+    context: Context,
+) -> TaskResult {
+    for next in schedule.upcoming(Utc) {
+        tracing::debug!("🗓️ Next {} at {}", job.name(), next);
+        while Utc::now() < next {
+            if !running.load(Ordering::SeqCst) {
+                return Ok(format!("Stopped the {} schedule", job.name()));
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+
+        tracing::info!("🗓️ Running the scheduled {}", job.name());
+        let started = Instant::now();
+        let message = match execute(job, &context).await {
+            Ok(()) => format!("{} done in {} sec", job.name(), started.elapsed().as_secs()),
+            Err(e) => {
+                tracing::error!("❌ Scheduled {} failed: {}", job.name(), e);
+                format!("{} failed: {}", job.name(), e)
+            }
+        };
+        journal::record(&context.storage, "scheduled_job", message);
+    }
+
+    Ok(format!("No upcoming {}", job.name()))
+}
+
+#[cfg(feature = "server")]
+async fn execute(job: Job, context: &Context) -> Result<(), String> {
+    let storage = context.storage.clone();
+    let blocking = match job {
+        Job::Verify => tokio::task::spawn_blocking(move || maintenance::verify(&storage)),
+        Job::Compact => tokio::task::spawn_blocking(move || maintenance::compact(&storage)),
+        Job::Backup => {
+            let backup_dir = context
+                .backup_dir
+                .clone()
+                .ok_or("no --schedule-backup-dir".to_string())?;
+            tokio::task::spawn_blocking(move || {
+                maintenance::backup(&storage, &BackupArgs { backup_dir })
+            })
+        }
+        #[cfg(feature = "sync")]
+        Job::Reverify => {
+            let summary = crate::sync::reverify_tail(
+                &context.storage,
+                context.gateway.as_ref(),
+                context.reverify_tail,
+            )
+            .await?;
+            tracing::info!("🔍 {}", summary);
+            return Ok(());
+        }
+        #[cfg(not(feature = "sync"))]
+        Job::Reverify => return Err("built without the `sync` feature".to_string()),
+    };
+    blocking
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())
+}
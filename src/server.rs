@@ -0,0 +1,1340 @@
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{Server, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{EntityTag, ETAG, RETRY_AFTER, VARY};
+use actix_web::middleware::{from_fn, Condition, Logger, Next};
+use actix_web::{web, App, Error, HttpRequest, HttpResponse, HttpServer, Responder};
+use openssl::error::ErrorStack;
+use openssl::ssl::{SslAcceptor, SslAcceptorBuilder, SslFiletype, SslMethod, SslVerifyMode};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+use crate::access_log::{self, BLOCK_HEADER, CACHE_HEADER};
+use crate::acl;
+use crate::admin;
+use crate::binary;
+use crate::cache_control;
+use crate::chain_stats;
+use crate::chaos;
+use crate::class_extract;
+use crate::config::{Network, ServeArgs, SyncTuning};
+use crate::disk;
+use crate::fair_queue::{self, FairQueue};
+use crate::handoff;
+use crate::index::{self, IndexError};
+use crate::journal;
+use crate::meta::{read_meta, write_fetched, FetchMeta};
+use crate::metrics::{self, Metrics, Proxied};
+use crate::peer::{self, PeerContent, PeerFlights};
+use crate::primitives::{normalize_hash, Block, Class, State};
+use crate::proxy::{self, ForwardFlights};
+use crate::range;
+use crate::reload::Reloadable;
+use crate::rpc;
+use crate::single_flight::SingleFlight;
+use crate::snapshot;
+use crate::state_diff::{self, MergeError};
+use crate::storage::{find_gaps, iter_class_hashes, read_data, Storage};
+use crate::streaming::{self, Streams};
+use crate::supervisor::Supervisor;
+use crate::telemetry;
+use crate::upstream::{Circuit, Upstream, LATENCY_BUCKETS};
+use crate::warmup::ClassHits;
+
+/// What a network is served from, the main network at the root and the
+/// extra ones under `/<network>`
+pub struct Chain {
+    pub network: Network,
+    pub storage: Arc<Storage>,
+    pub reloadable: Arc<Reloadable>,
+    pub metrics: Arc<Metrics>,
+    pub class_hits: Arc<ClassHits>,
+    pub tuning: SyncTuning,
+}
+
+/// Path a network is served under, empty for the main network
+struct ScopePath(String);
+
+/// The path of a request without the network it is served under
+pub fn scope_path(req: &ServiceRequest) -> &str {
+    match req.app_data::<web::Data<ScopePath>>() {
+        Some(scope) => req.path().strip_prefix(&scope.0).unwrap_or(req.path()),
+        None => req.path(),
+    }
+}
+
+/// Answers 404 for the paths of `--disable-route`, and those under them
+async fn route_enabled(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let disabled = match req.app_data::<web::Data<ServeArgs>>() {
+        Some(args) => {
+            let path = scope_path(&req);
+            args.disable_route.iter().any(|disabled| {
+                let disabled = disabled.trim_end_matches('/');
+                path.strip_prefix(disabled)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            })
+        }
+        _ => false,
+    };
+    match disabled {
+        true => Ok(req.into_response(HttpResponse::NotFound().body("Not found"))),
+        false => Ok(next.call(req).await?.map_into_boxed_body()),
+    }
+}
+
+/// The routes served for every network
+fn configure(cfg: &mut web::ServiceConfig, rpc_enabled: bool) {
+    cfg.route("/feeder_gateway/get_block", web::get().to(get_block))
+        .route("/feeder_gateway/get_block", web::head().to(get_block))
+        .route(
+            "/feeder_gateway/get_state_update",
+            web::get().to(get_state_update),
+        )
+        .route(
+            "/feeder_gateway/get_state_update",
+            web::head().to(get_state_update),
+        )
+        .route(
+            "/feeder_gateway/get_class_by_hash",
+            web::get().to(get_class_by_hash),
+        )
+        .route(
+            "/feeder_gateway/get_class_by_hash",
+            web::head().to(get_class_by_hash),
+        )
+        .route("/feeder_gateway/list_classes", web::get().to(list_classes))
+        .route(
+            "/feeder_gateway/get_declared_classes",
+            web::get().to(get_declared_classes),
+        )
+        .route(
+            "/feeder_gateway/get_nonce_history",
+            web::get().to(get_nonce_history),
+        )
+        .route(
+            "/feeder_gateway/get_transaction",
+            web::get().to(get_transaction),
+        )
+        .route(
+            "/feeder_gateway/get_transaction_receipt",
+            web::get().to(get_transaction_receipt),
+        )
+        .route("/status/gaps", web::get().to(status_gaps))
+        .route("/status/disk", web::get().to(status_disk))
+        .route("/status/upstream", web::get().to(status_upstream))
+        .route("/status/events", web::get().to(status_events))
+        .route("/stats/chain", web::get().to(stats_chain))
+        .route("/stats/versions", web::get().to(stats_versions))
+        .route("/index/contract", web::get().to(index_contract))
+        .route("/index/class", web::get().to(index_class))
+        .route("/index/state_diff", web::get().to(index_state_diff))
+        .route(
+            "/index/storage_history",
+            web::get().to(index_storage_history),
+        )
+        .route(
+            "/index/block_at_timestamp",
+            web::get().to(index_block_at_timestamp),
+        )
+        .route("/search", web::get().to(search))
+        .route("/status", web::get().to(status))
+        .route("/metrics", web::get().to(metrics::prometheus))
+        // Must stay after every other feeder gateway route
+        .route(
+            "/feeder_gateway/{tail:.*}",
+            web::get().to(proxy::passthrough),
+        )
+        .route("/", web::get().to(index));
+    if rpc_enabled {
+        cfg.route("/rpc", web::post().to(rpc::handle));
+    }
+}
+
+/// HTTPS settings of `--tls-*`, requiring client certificates signed by
+/// `--tls-client-ca` when set
+fn tls_acceptor(args: &ServeArgs) -> std::io::Result<SslAcceptorBuilder> {
+    let build = || -> Result<SslAcceptorBuilder, ErrorStack> {
+        let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server())?;
+        if let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) {
+            builder.set_certificate_chain_file(cert)?;
+            builder.set_private_key_file(key, SslFiletype::PEM)?;
+            builder.check_private_key()?;
+        }
+        if let Some(ca) = &args.tls_client_ca {
+            builder.set_ca_file(ca)?;
+            builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+            tracing::info!(
+                "🔒 Requiring client certificates signed by {}",
+                ca.display()
+            );
+        }
+        Ok(builder)
+    };
+    build().map_err(std::io::Error::other)
+}
+
+/// Binds the HTTP server, to be awaited once stopped through its handle. The
+/// first chain is served at the root
+pub fn start(
+    args: &ServeArgs,
+    chains: &[Chain],
+    upstream: Arc<Upstream>,
+    supervisor: Arc<Supervisor>,
+) -> std::io::Result<Server> {
+    let scopes: Vec<_> = chains
+        .iter()
+        .enumerate()
+        .map(|(i, chain)| {
+            let path = match i {
+                0 => String::new(),
+                _ => format!("/{}", chain.network.name()),
+            };
+            (
+                path,
+                web::Data::new(chain.storage.clone()),
+                web::Data::new(chain.reloadable.clone()),
+                web::Data::new(chain.metrics.clone()),
+                web::Data::new(ReadFlights::new(None)),
+                web::Data::new(chain.class_hits.clone()),
+                web::Data::new(chain.tuning),
+            )
+        })
+        .collect();
+    let reloadables_data = web::Data::new(
+        chains
+            .iter()
+            .map(|chain| chain.reloadable.clone())
+            .collect::<Vec<_>>(),
+    );
+    let args_data = web::Data::new(args.clone());
+    let upstream_data = web::Data::new(upstream);
+    // Shared by the misses forwarded to the gateway and to the peers
+    let miss_permits = Arc::new(Semaphore::new(args.max_miss_fetches));
+    let forward_flights = web::Data::new(ForwardFlights::new(Some(miss_permits.clone())));
+    let peer_flights = web::Data::new(PeerFlights::new(Some(miss_permits)));
+    let supervisor_data = web::Data::new(supervisor);
+    let streams = web::Data::new(Streams::new(args.max_streamed_responses));
+    let fair_queue = web::Data::new(Arc::new(FairQueue::new(
+        args.max_concurrent_requests,
+        fair_queue::parse(&args.consumer_weight)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?,
+    )));
+    let fair_queue_enabled = args.max_concurrent_requests > 0;
+    let cache_control = web::Data::new(
+        cache_control::parse(&args.cache_control)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?,
+    );
+    let access_log_json = args.access_log_format == access_log::JSON_FORMAT;
+    let access_log_format = args.access_log_format.clone();
+    let rpc_enabled = args.rpc;
+    let chaos_enabled = args.chaos.chaos;
+    let server = HttpServer::new(move || {
+        let mut app = App::new()
+            .app_data(web::Data::clone(&reloadables_data))
+            .app_data(web::Data::clone(&args_data))
+            .app_data(web::Data::clone(&upstream_data))
+            .app_data(web::Data::clone(&forward_flights))
+            .app_data(web::Data::clone(&peer_flights))
+            .app_data(web::Data::clone(&supervisor_data))
+            .app_data(web::Data::clone(&streams))
+            .app_data(web::Data::clone(&cache_control));
+        if fair_queue_enabled {
+            app = app.app_data(web::Data::clone(&fair_queue));
+        }
+        // The main network matches every path, so it is registered last
+        for (path, storage, reloadable, metrics, read_flights, class_hits, tuning) in
+            scopes.iter().rev()
+        {
+            let mut scope = web::scope(path)
+                .app_data(web::Data::new(ScopePath(path.clone())))
+                .app_data(web::Data::clone(storage))
+                .app_data(web::Data::clone(reloadable))
+                .app_data(web::Data::clone(metrics))
+                .app_data(web::Data::clone(read_flights))
+                .app_data(web::Data::clone(class_hits))
+                .app_data(web::Data::clone(tuning))
+                .configure(|cfg| configure(cfg, rpc_enabled));
+            if path.is_empty() {
+                scope = scope
+                    .configure(|cfg| admin::configure(cfg, &args_data))
+                    .configure(|cfg| snapshot::configure(cfg, &args_data));
+            }
+            app = app.service(
+                scope
+                    .wrap(from_fn(fair_queue::admit))
+                    .wrap(from_fn(metrics::count))
+                    .wrap(from_fn(cache_control::apply))
+                    .wrap(from_fn(acl::check))
+                    .wrap(from_fn(route_enabled)),
+            );
+        }
+        app.wrap(Condition::new(chaos_enabled, from_fn(chaos::inject)))
+            .wrap(Condition::new(
+                !access_log_json,
+                Logger::new(&access_log_format),
+            ))
+            .wrap(Condition::new(
+                access_log_json,
+                from_fn(access_log::json_logger),
+            ))
+            .wrap(from_fn(telemetry::http_span))
+            .wrap(from_fn(access_log::request_id))
+    })
+    // Shutdown signals are handled with the sync tasks
+    .disable_signals()
+    .shutdown_timeout(args.sync.shutdown_timeout);
+    let listener = handoff::listener(&args.server_addr)?;
+    let (server, scheme) = match &args.tls_cert {
+        Some(_) => (
+            server.listen_openssl(listener, tls_acceptor(args)?)?,
+            "https",
+        ),
+        None => (server.listen(listener)?, "http"),
+    };
+    let server = server.run();
+
+    tracing::info!("🟢 Server running on {}://{}", scheme, &args.server_addr);
+
+    Ok(server)
+}
+
+async fn index(storage: web::Data<Arc<Storage>>) -> impl Responder {
+    tracing::info!("🔗 Request received");
+    let max_block_sync = storage.max_block_sync().unwrap_or(Block(0));
+    let max_state_sync = storage.max_state_sync().unwrap_or(State(0));
+
+    format!(
+        "Max block to sync: {}, max state to sync: {}\n",
+        max_block_sync, max_state_sync
+    )
+}
+
+async fn status(
+    storage: web::Data<Arc<Storage>>,
+    args: web::Data<ServeArgs>,
+    metrics: web::Data<Arc<Metrics>>,
+    supervisor: web::Data<Arc<Supervisor>>,
+    upstream: web::Data<Arc<Upstream>>,
+) -> impl Responder {
+    // Only the upstreams not answering are listed
+    let open_circuits: BTreeMap<String, &str> = upstream
+        .stats()
+        .into_iter()
+        .filter(|(_, stats)| stats.circuit != Circuit::Closed)
+        .map(|(name, stats)| (name, stats.circuit.as_str()))
+        .collect();
+    let max_block_sync = storage.max_block_sync().map(|block| block.0);
+    let max_state_sync = storage.max_state_sync().map(|state| state.0);
+    let sync_lag = metrics.sync_lag(&storage).map(|lag| {
+        serde_json::json!({
+            "block": lag.block,
+            "state_update": lag.state_update,
+        })
+    });
+    let indexed_classes = web::block(move || index::count_class_declarations(storage.db())).await;
+
+    match indexed_classes {
+        Ok(indexed_classes) => HttpResponse::Ok().json(serde_json::json!({
+            "max_block_sync": max_block_sync,
+            "max_state_sync": max_state_sync,
+            "max_block_to_sync": args.sync.max_block_to_sync,
+            "indexed_classes": indexed_classes,
+            "upstream_head": metrics.upstream_head(),
+            "sync_lag": sync_lag,
+            "cache": metrics.cache_status(),
+            "tasks": supervisor.tasks(),
+            "open_circuits": open_circuits,
+        })),
+        Err(e) => {
+            tracing::error!("❌ Error counting indexed classes: {}", e);
+            HttpResponse::InternalServerError().body("Error reading status")
+        }
+    }
+}
+
+async fn status_gaps(storage: web::Data<Arc<Storage>>) -> impl Responder {
+    // Scanning every key is too slow to run on a worker thread
+    let gaps = web::block(move || {
+        let blocks = find_gaps(storage.db(), Block::KEY_PREFIX);
+        let state_updates = find_gaps(storage.db(), State::KEY_PREFIX);
+        serde_json::json!({
+            "blocks": blocks,
+            "state_updates": state_updates,
+        })
+    })
+    .await;
+
+    match gaps {
+        Ok(gaps) => HttpResponse::Ok().json(gaps),
+        Err(e) => {
+            tracing::error!("❌ Error scanning for gaps: {}", e);
+            HttpResponse::InternalServerError().body("Error scanning for gaps")
+        }
+    }
+}
+
+async fn status_disk(storage: web::Data<Arc<Storage>>) -> impl Responder {
+    match storage.blocking(disk::usage).await {
+        Ok(usage) => HttpResponse::Ok().json(usage),
+        Err(e) => {
+            tracing::error!("❌ Error reading the disk usage: {}", e);
+            HttpResponse::InternalServerError().body("Error reading the disk usage")
+        }
+    }
+}
+
+const EVENTS_DEFAULT_LIMIT: usize = 100;
+
+// url ...events?before=...&kind=...&limit=...
+#[derive(Deserialize)]
+struct EventsQuery {
+    before: Option<u64>,
+    kind: Option<String>,
+    limit: Option<usize>,
+}
+
+async fn status_events(
+    storage: web::Data<Arc<Storage>>,
+    web::Query(query): web::Query<EventsQuery>,
+) -> impl Responder {
+    let limit = query
+        .limit
+        .unwrap_or(EVENTS_DEFAULT_LIMIT)
+        .min(journal::CAPACITY as usize);
+    let events = web::block(move || {
+        journal::events(storage.db(), query.before, query.kind.as_deref(), limit)
+    })
+    .await;
+
+    match events {
+        Ok(events) => HttpResponse::Ok().json(events),
+        Err(e) => {
+            tracing::error!("❌ Error reading events: {}", e);
+            HttpResponse::InternalServerError().body("Error reading events")
+        }
+    }
+}
+
+// url ...chain?windows=10,100,1000
+#[derive(Deserialize)]
+struct ChainStatsQuery {
+    windows: Option<String>,
+}
+
+/// Transactions per block, block time and state diff sizes over the last
+/// blocks
+async fn stats_chain(
+    storage: web::Data<Arc<Storage>>,
+    web::Query(query): web::Query<ChainStatsQuery>,
+) -> impl Responder {
+    let windows = match query.windows.as_deref().map(chain_stats::parse_windows) {
+        Some(Ok(windows)) => windows,
+        Some(Err(e)) => return HttpResponse::BadRequest().body(e),
+        None => chain_stats::DEFAULT_WINDOWS.to_vec(),
+    };
+    match storage
+        .blocking(move |storage| chain_stats::compute(storage, &windows))
+        .await
+    {
+        Ok(stats) => HttpResponse::Ok().json(stats),
+        Err(e) => {
+            tracing::error!("❌ Error computing the chain statistics: {}", e);
+            HttpResponse::InternalServerError().body("Error computing the chain statistics")
+        }
+    }
+}
+
+/// The Starknet versions the cached blocks span
+async fn stats_versions(storage: web::Data<Arc<Storage>>) -> impl Responder {
+    match storage.blocking(chain_stats::versions).await {
+        Ok(versions) => HttpResponse::Ok().json(serde_json::json!({ "versions": versions })),
+        Err(e) => {
+            tracing::error!("❌ Error reading the Starknet versions: {}", e);
+            HttpResponse::InternalServerError().body("Error reading the Starknet versions")
+        }
+    }
+}
+
+/// Request outcomes per upstream, to tell local problems from gateway ones
+async fn status_upstream(upstream: web::Data<Arc<Upstream>>) -> impl Responder {
+    let stats: serde_json::Map<String, serde_json::Value> = upstream
+        .stats()
+        .into_iter()
+        .map(|(name, stats)| {
+            let latency_buckets: Vec<serde_json::Value> = LATENCY_BUCKETS
+                .iter()
+                .map(|bound| serde_json::json!(bound))
+                .chain([serde_json::json!("+Inf")])
+                .zip(&stats.latency_buckets)
+                .map(|(le, count)| serde_json::json!({ "le": le, "count": count }))
+                .collect();
+            let status = serde_json::json!({
+                "requests": stats.requests,
+                "statuses": stats.statuses,
+                "failures": stats.failures,
+                "error_rate": stats.error_rate(),
+                "consecutive_failures": stats.consecutive_failures,
+                "max_consecutive_failures": stats.max_consecutive_failures,
+                "last_error": stats.last_error,
+                "circuit": stats.circuit.as_str(),
+                "latency_mean_ms": match stats.requests {
+                    0 => 0.0,
+                    requests => stats.latency_sum_seconds * 1000.0 / requests as f64,
+                },
+                "latency_buckets_seconds": latency_buckets,
+            });
+            (name, status)
+        })
+        .collect();
+
+    HttpResponse::Ok().json(stats)
+}
+
+/// Stored values never change once written, so a hash of the content is a
+/// strong ETag, the same across restarts, versions and replicas. SHA-256
+/// truncated to 128 bits
+fn etag(content: &[u8]) -> EntityTag {
+    EntityTag::new_strong(snapshot::hex(&Sha256::digest(content)[..16]))
+}
+
+/// A block or state update read from the DB, in MessagePack when the client
+/// accepts `application/octet-stream`, streamed when large
+fn hit_response(
+    req: &HttpRequest,
+    args: &ServeArgs,
+    content: web::Bytes,
+    block_number: u64,
+) -> HttpResponse {
+    let mut response = HttpResponse::Ok();
+    response
+        .insert_header((CACHE_HEADER, "HIT"))
+        .insert_header((BLOCK_HEADER, block_number))
+        .insert_header((VARY, "Accept"));
+    if !binary::wanted(req) {
+        let etag = etag(&content);
+        if streaming::wanted(req, &content, args.max_buffered_response) {
+            return streaming::respond(req, response, content, etag);
+        }
+        return response.insert_header((ETAG, etag)).body(content);
+    }
+    match binary::encode(&content) {
+        Ok(encoded) => response
+            .insert_header((ETAG, etag(&encoded)))
+            .content_type(binary::CONTENT_TYPE)
+            .body(encoded),
+        Err(e) => {
+            tracing::error!("❌ Error encoding block {}: {}", block_number, e);
+            HttpResponse::InternalServerError().body("Error encoding the response")
+        }
+    }
+}
+
+/// A missing block above the sync target or the upstream head last seen
+/// will not be cached before long, while one within both only has not been
+/// fetched yet and is retried after the sync retry delay
+fn not_synced_response(
+    req: &HttpRequest,
+    number: u64,
+    max_block_to_sync: u64,
+    body: &'static str,
+) -> HttpResponse {
+    let head = req
+        .app_data::<web::Data<Arc<Metrics>>>()
+        .and_then(|metrics| metrics.upstream_head());
+    let beyond = number > max_block_to_sync || head.is_some_and(|head| number > head);
+    match req.app_data::<web::Data<SyncTuning>>() {
+        Some(tuning) if !beyond => HttpResponse::ServiceUnavailable()
+            .insert_header((CACHE_HEADER, "MISS"))
+            .insert_header((BLOCK_HEADER, number))
+            .insert_header((RETRY_AFTER, tuning.retry_delay))
+            .body(body),
+        _ => HttpResponse::NotFound()
+            .insert_header((CACHE_HEADER, "MISS"))
+            .insert_header((BLOCK_HEADER, number))
+            .body(body),
+    }
+}
+
+#[derive(Deserialize)]
+struct BlockNumber {
+    #[serde(rename = "blockNumber")]
+    block_number: String,
+    /// Answers the fetch metadata instead
+    #[serde(default)]
+    meta: bool,
+}
+
+impl BlockNumber {
+    /// `latest` is the last one synced, `None` before the first
+    fn resolve(&self, latest: Option<u64>) -> Result<Option<u64>, std::num::ParseIntError> {
+        match self.block_number.as_str() {
+            "latest" => Ok(latest),
+            number => number.parse().map(Some),
+        }
+    }
+}
+
+/// Identical reads in flight of a network's DB share one, keyed by storage
+/// key
+type ReadFlights = SingleFlight<Result<Option<web::Bytes>, String>>;
+
+async fn read_shared(
+    storage: &Arc<Storage>,
+    flights: &ReadFlights,
+    key: String,
+) -> Result<Option<web::Bytes>, String> {
+    let storage = storage.clone();
+    flights
+        .run(key.clone(), async move {
+            match storage.read(key).await {
+                Ok(content) => Ok(content.map(web::Bytes::from)),
+                Err(e) => Err(e.to_string()),
+            }
+        })
+        .await
+}
+
+/// Stores a miss answered by a peer off the async workers, then runs `index`
+/// on it. Failures are only logged, the content is still returned
+async fn store_peer_content<F>(
+    storage: &Arc<Storage>,
+    key: String,
+    found: PeerContent,
+    index: F,
+) -> String
+where
+    F: FnOnce(&Storage, &[u8]) -> Result<(), IndexError> + Send + 'static,
+{
+    let PeerContent {
+        peer,
+        content,
+        headers,
+    } = found;
+    let meta = FetchMeta::new(peer, 200, content.len()).with_headers(headers);
+    storage
+        .blocking(move |storage| {
+            match write_fetched(storage.db(), &key, &content, &meta) {
+                Ok(()) => {
+                    if let Err(e) = index(storage, content.as_bytes()) {
+                        tracing::error!("❌ Error indexing {}: {}", key, e);
+                    }
+                }
+                Err(e) => tracing::error!("❌ Error writing to DB {}: {}", key, e),
+            }
+            content
+        })
+        .await
+}
+
+/// When and from where the entry under `key` was fetched
+async fn meta_response(storage: &Arc<Storage>, key: String) -> HttpResponse {
+    match storage
+        .blocking(move |storage| read_meta(storage.db(), &key))
+        .await
+    {
+        Ok(Some(meta)) => HttpResponse::Ok().json(meta),
+        Ok(None) => HttpResponse::NotFound().body("No fetch metadata"),
+        Err(e) => {
+            tracing::error!("❌ Error reading fetch metadata: {}", e);
+            HttpResponse::InternalServerError().body("Error reading fetch metadata")
+        }
+    }
+}
+
+/// A miss answered by a peer, already stored locally
+fn peer_response(content: String, block_number: Option<u64>) -> HttpResponse {
+    let mut response = HttpResponse::Ok();
+    response.insert_header((CACHE_HEADER, "PEER"));
+    if let Some(block_number) = block_number {
+        response.insert_header((BLOCK_HEADER, block_number));
+    }
+    let mut response = response
+        .insert_header((ETAG, etag(content.as_bytes())))
+        .body(content);
+    response.extensions_mut().insert(Proxied);
+    response
+}
+
+async fn get_block(
+    req: HttpRequest,
+    storage: web::Data<Arc<Storage>>,
+    args: web::Data<ServeArgs>,
+    upstream: web::Data<Arc<Upstream>>,
+    peer_flights: web::Data<PeerFlights>,
+    read_flights: web::Data<ReadFlights>,
+    web::Query(block_number): web::Query<BlockNumber>,
+) -> impl Responder {
+    let latest = storage.max_block_sync().map(|block| block.0);
+    let block = match block_number.resolve(latest) {
+        Ok(Some(number)) => Block(number),
+        Ok(None) => {
+            return HttpResponse::NotFound()
+                .insert_header((CACHE_HEADER, "MISS"))
+                .body("Block not found")
+        }
+        Err(_) => return HttpResponse::BadRequest().body("Invalid blockNumber"),
+    };
+    if block_number.meta {
+        return meta_response(&storage, block.key()).await;
+    }
+    match read_shared(&storage, &read_flights, block.key()).await {
+        Ok(content) => match content {
+            Some(content) => hit_response(&req, &args, content, block.0),
+            None => match peer::fetch(&req, &upstream, &args.peer, &peer_flights).await {
+                Some(found) => {
+                    let content = store_peer_content(
+                        &storage,
+                        block.key(),
+                        found,
+                        move |storage, content| {
+                            index::index_block(storage.db(), block, content).map(|_| ())
+                        },
+                    )
+                    .await;
+                    peer_response(content, Some(block.0))
+                }
+                None => not_synced_response(
+                    &req,
+                    block.0,
+                    args.sync.max_block_to_sync,
+                    "Block not found",
+                ),
+            },
+        },
+        Err(e) => {
+            tracing::error!("❌ Error reading block {}: {}", block, e);
+            HttpResponse::InternalServerError().body("Error reading block")
+        }
+    }
+}
+
+async fn get_state_update(
+    req: HttpRequest,
+    storage: web::Data<Arc<Storage>>,
+    args: web::Data<ServeArgs>,
+    upstream: web::Data<Arc<Upstream>>,
+    peer_flights: web::Data<PeerFlights>,
+    read_flights: web::Data<ReadFlights>,
+    web::Query(block_number): web::Query<BlockNumber>,
+) -> impl Responder {
+    let latest = storage.max_state_sync().map(|state| state.0);
+    let state = match block_number.resolve(latest) {
+        Ok(Some(number)) => State(number),
+        Ok(None) => {
+            return HttpResponse::NotFound()
+                .insert_header((CACHE_HEADER, "MISS"))
+                .body("State update not found")
+        }
+        Err(_) => return HttpResponse::BadRequest().body("Invalid blockNumber"),
+    };
+    if block_number.meta {
+        return meta_response(&storage, state.key()).await;
+    }
+    match read_shared(&storage, &read_flights, state.key()).await {
+        Ok(content) => match content {
+            Some(content) => hit_response(&req, &args, content, state.0),
+            None => match peer::fetch(&req, &upstream, &args.peer, &peer_flights).await {
+                Some(found) => {
+                    let content = store_peer_content(
+                        &storage,
+                        state.key(),
+                        found,
+                        move |storage, content| index::index_state_update(storage, state, content),
+                    )
+                    .await;
+                    peer_response(content, Some(state.0))
+                }
+                None => not_synced_response(
+                    &req,
+                    state.0,
+                    args.sync.max_block_to_sync,
+                    "State update not found",
+                ),
+            },
+        },
+        Err(e) => {
+            tracing::error!("❌ Error reading state update {}: {}", state, e);
+            HttpResponse::InternalServerError().body("Error reading state update")
+        }
+    }
+}
+
+/// The classes declared, deployed and replaced at a block, read from its
+/// cached state update, for clients to prefetch them
+async fn get_declared_classes(
+    storage: web::Data<Arc<Storage>>,
+    web::Query(block_number): web::Query<BlockNumber>,
+) -> impl Responder {
+    let latest = storage.max_state_sync().map(|state| state.0);
+    let state = match block_number.resolve(latest) {
+        Ok(Some(number)) => State(number),
+        Ok(None) => return HttpResponse::NotFound().body("State update not found"),
+        Err(_) => return HttpResponse::BadRequest().body("Invalid blockNumber"),
+    };
+    let classes = storage
+        .blocking(move |storage| {
+            match read_data(storage.db(), &state.key()).map_err(|e| e.to_string())? {
+                Some(content) => class_extract::block_classes(&content)
+                    .map(Some)
+                    .map_err(|e| e.to_string()),
+                None => Ok(None),
+            }
+        })
+        .await;
+    match classes {
+        Ok(Some(classes)) => {
+            let class_hashes: BTreeSet<&String> = classes
+                .declared
+                .iter()
+                .chain(&classes.deployed)
+                .chain(&classes.replaced)
+                .collect();
+            HttpResponse::Ok().json(serde_json::json!({
+                "block_number": state.0,
+                "declared_classes": classes.declared,
+                "deployed_classes": classes.deployed,
+                "replaced_classes": classes.replaced,
+                "class_hashes": class_hashes,
+            }))
+        }
+        Ok(None) => HttpResponse::NotFound().body("State update not found"),
+        Err(e) => {
+            tracing::error!("❌ Error reading the classes of block {}: {}", state, e);
+            HttpResponse::InternalServerError().body("Error reading state update")
+        }
+    }
+}
+
+// url ...classHash=...&blockNumber=...
+#[derive(Deserialize)]
+struct ClassHash {
+    #[serde(rename = "classHash")]
+    class_hash: String,
+    #[serde(rename = "blockNumber")]
+    block_number: Option<u64>,
+    #[serde(default)]
+    meta: bool,
+}
+
+async fn get_class_by_hash(
+    req: HttpRequest,
+    storage: web::Data<Arc<Storage>>,
+    args: web::Data<ServeArgs>,
+    upstream: web::Data<Arc<Upstream>>,
+    peer_flights: web::Data<PeerFlights>,
+    read_flights: web::Data<ReadFlights>,
+    web::Query(class_hash): web::Query<ClassHash>,
+) -> impl Responder {
+    if is_blocked(&args, &class_hash.class_hash) {
+        return HttpResponse::Forbidden().body("Class blocked");
+    }
+
+    // A class pinned to a block before its declaration does not exist yet
+    if let Some(block_number) = class_hash.block_number {
+        let hash = class_hash.class_hash.clone();
+        match storage
+            .blocking(move |storage| index::class_declaration(storage.db(), &hash))
+            .await
+        {
+            Ok(Some(declared_at)) if declared_at > block_number => {
+                return HttpResponse::NotFound()
+                    .insert_header((CACHE_HEADER, "HIT"))
+                    .insert_header((BLOCK_HEADER, block_number))
+                    .body("Class not found")
+            }
+            Ok(_) => {}
+            Err(e) => tracing::error!(
+                "❌ Error reading declaration of class {}: {}",
+                class_hash.class_hash,
+                e
+            ),
+        }
+    }
+
+    let class = Class(class_hash.class_hash);
+    if class_hash.meta {
+        return meta_response(&storage, class.key()).await;
+    }
+    match read_shared(&storage, &read_flights, class.key()).await {
+        Ok(content) => match content {
+            Some(content) => {
+                record_class_hit(&req, &args, &class.0);
+                let mut response = HttpResponse::Ok();
+                response.insert_header((CACHE_HEADER, "HIT"));
+                let etag = etag(&content);
+                match streaming::wanted(&req, &content, args.max_buffered_response) {
+                    true => streaming::respond(&req, response, content, etag),
+                    false => range::respond(&req, response, content, Some(etag)),
+                }
+            }
+            None => match peer::fetch(&req, &upstream, &args.peer, &peer_flights).await {
+                Some(found) => {
+                    record_class_hit(&req, &args, &class.0);
+                    let content =
+                        store_peer_content(&storage, class.key(), found, |_, _| Ok(())).await;
+                    let mut response = HttpResponse::Ok();
+                    response.insert_header((CACHE_HEADER, "PEER"));
+                    let etag = etag(content.as_bytes());
+                    let content = web::Bytes::from(content);
+                    let mut response =
+                        match streaming::wanted(&req, &content, args.max_buffered_response) {
+                            true => streaming::respond(&req, response, content, etag),
+                            false => range::respond(&req, response, content, Some(etag)),
+                        };
+                    response.extensions_mut().insert(Proxied);
+                    response
+                }
+                None => HttpResponse::NotFound()
+                    .insert_header((CACHE_HEADER, "MISS"))
+                    .body("Class not found"),
+            },
+        },
+        Err(e) => {
+            tracing::error!("❌ Error reading class {}: {}", class, e);
+            HttpResponse::InternalServerError().body("Error reading class")
+        }
+    }
+}
+
+/// Whether the class is in `--class-blocklist`
+pub fn is_blocked(args: &ServeArgs, class_hash: &str) -> bool {
+    args.sync
+        .class_blocklist
+        .contains(&normalize_hash(class_hash))
+}
+
+/// Counts the classes served, to preload the most requested ones on the next
+/// start
+fn record_class_hit(req: &HttpRequest, args: &ServeArgs, class_hash: &str) {
+    if args.warmup_classes == 0 {
+        return;
+    }
+    if let Some(class_hits) = req.app_data::<web::Data<Arc<ClassHits>>>() {
+        class_hits.record(class_hash);
+    }
+}
+
+const LIST_CLASSES_DEFAULT_LIMIT: usize = 100;
+const LIST_CLASSES_MAX_LIMIT: usize = 1000;
+
+// url ...list_classes?offset=...&limit=... or ...list_classes?after=...&limit=...
+#[derive(Deserialize)]
+struct ListClasses {
+    after: Option<String>,
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
+
+async fn list_classes(
+    storage: web::Data<Arc<Storage>>,
+    web::Query(query): web::Query<ListClasses>,
+) -> impl Responder {
+    let limit = query
+        .limit
+        .unwrap_or(LIST_CLASSES_DEFAULT_LIMIT)
+        .min(LIST_CLASSES_MAX_LIMIT);
+
+    let class_hashes: Vec<String> = storage
+        .blocking(move |storage| {
+            iter_class_hashes(storage.db(), query.after.as_deref())
+                .skip(query.offset.unwrap_or(0))
+                .take(limit)
+                .collect()
+        })
+        .await;
+
+    // A full page means there may be more classes after the last one
+    let next = match class_hashes.len() == limit {
+        true => class_hashes.last().cloned(),
+        false => None,
+    };
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "class_hashes": class_hashes,
+        "next": next,
+    }))
+}
+
+// url ...transactionHash=...
+#[derive(Deserialize)]
+struct TransactionHash {
+    #[serde(rename = "transactionHash")]
+    transaction_hash: String,
+}
+
+/// Reads the indexed transaction's block, `None` when the transaction is not
+/// indexed so the request can be forwarded to the gateway
+fn read_indexed_transaction(
+    storage: &Storage,
+    hash: &str,
+) -> Result<Option<(index::TransactionLocation, serde_json::Value)>, IndexError> {
+    let location = match index::transaction_location(storage.db(), hash)? {
+        Some(location) => location,
+        None => return Ok(None),
+    };
+    let block = Block(location.block_number);
+    let content = match read_data(storage.db(), &block.key())? {
+        Some(content) => content,
+        None => return Ok(None),
+    };
+    let block = serde_json::from_slice(&content)?;
+    Ok(Some((location, block)))
+}
+
+async fn get_transaction(
+    req: HttpRequest,
+    storage: web::Data<Arc<Storage>>,
+    reloadable: web::Data<Arc<Reloadable>>,
+    args: web::Data<ServeArgs>,
+    upstream: web::Data<Arc<Upstream>>,
+    forward_flights: web::Data<ForwardFlights>,
+    web::Query(transaction_hash): web::Query<TransactionHash>,
+) -> HttpResponse {
+    let hash = transaction_hash.transaction_hash.clone();
+    let indexed = storage
+        .blocking(move |storage| read_indexed_transaction(storage, &hash))
+        .await;
+    let (location, block) = match indexed {
+        Ok(Some(indexed)) => indexed,
+        Ok(None) => {
+            return proxy::passthrough(req, storage, reloadable, args, upstream, forward_flights)
+                .await
+        }
+        Err(e) => {
+            tracing::error!(
+                "❌ Error reading transaction {}: {}",
+                transaction_hash.transaction_hash,
+                e
+            );
+            return HttpResponse::InternalServerError().body("Error reading transaction");
+        }
+    };
+
+    HttpResponse::Ok()
+        .insert_header((CACHE_HEADER, "HIT"))
+        .insert_header((BLOCK_HEADER, location.block_number))
+        .json(serde_json::json!({
+            "status": block["status"],
+            "block_hash": block["block_hash"],
+            "block_number": block["block_number"],
+            "transaction_index": location.transaction_index,
+            "transaction": block["transactions"][location.transaction_index],
+        }))
+}
+
+async fn get_transaction_receipt(
+    req: HttpRequest,
+    storage: web::Data<Arc<Storage>>,
+    reloadable: web::Data<Arc<Reloadable>>,
+    args: web::Data<ServeArgs>,
+    upstream: web::Data<Arc<Upstream>>,
+    forward_flights: web::Data<ForwardFlights>,
+    web::Query(transaction_hash): web::Query<TransactionHash>,
+) -> HttpResponse {
+    let hash = transaction_hash.transaction_hash.clone();
+    let indexed = storage
+        .blocking(move |storage| read_indexed_transaction(storage, &hash))
+        .await;
+    let (location, block) = match indexed {
+        Ok(Some(indexed)) => indexed,
+        Ok(None) => {
+            return proxy::passthrough(req, storage, reloadable, args, upstream, forward_flights)
+                .await
+        }
+        Err(e) => {
+            tracing::error!(
+                "❌ Error reading transaction receipt {}: {}",
+                transaction_hash.transaction_hash,
+                e
+            );
+            return HttpResponse::InternalServerError().body("Error reading transaction receipt");
+        }
+    };
+
+    let mut receipt = block["transaction_receipts"][location.transaction_index].clone();
+    if let Some(receipt) = receipt.as_object_mut() {
+        receipt.insert("status".to_string(), block["status"].clone());
+        receipt.insert("block_hash".to_string(), block["block_hash"].clone());
+        receipt.insert("block_number".to_string(), block["block_number"].clone());
+    }
+
+    HttpResponse::Ok()
+        .insert_header((CACHE_HEADER, "HIT"))
+        .insert_header((BLOCK_HEADER, location.block_number))
+        .json(receipt)
+}
+
+// url ...contractAddress=...
+#[derive(Deserialize)]
+struct ContractAddress {
+    #[serde(rename = "contractAddress")]
+    contract_address: String,
+}
+
+async fn index_contract(
+    storage: web::Data<Arc<Storage>>,
+    web::Query(contract_address): web::Query<ContractAddress>,
+) -> impl Responder {
+    let address = contract_address.contract_address;
+    let lookup = address.clone();
+    match storage
+        .blocking(move |storage| index::contract_deployment(storage.db(), &lookup))
+        .await
+    {
+        Ok(Some(deployment)) => HttpResponse::Ok().json(serde_json::json!({
+            "contract_address": address,
+            "block_number": deployment.block_number,
+            "class_hash": deployment.class_hash,
+        })),
+        Ok(None) => HttpResponse::NotFound().body("Contract not found"),
+        Err(e) => {
+            tracing::error!("❌ Error reading contract {}: {}", address, e);
+            HttpResponse::InternalServerError().body("Error reading contract")
+        }
+    }
+}
+
+// url ...state_diff?fromBlock=...&toBlock=...
+#[derive(Deserialize)]
+struct BlockRange {
+    #[serde(rename = "fromBlock")]
+    from_block: u64,
+    #[serde(rename = "toBlock")]
+    to_block: u64,
+}
+
+/// The state diffs of a range of blocks merged into one
+async fn index_state_diff(
+    storage: web::Data<Arc<Storage>>,
+    web::Query(range): web::Query<BlockRange>,
+) -> impl Responder {
+    let BlockRange {
+        from_block,
+        to_block,
+    } = range;
+    if from_block > to_block || to_block - from_block >= state_diff::MAX_RANGE {
+        return HttpResponse::BadRequest().body(format!(
+            "The range must be ordered and span at most {} blocks",
+            state_diff::MAX_RANGE
+        ));
+    }
+    match storage
+        .blocking(move |storage| state_diff::merge(storage, from_block, to_block))
+        .await
+    {
+        Ok(merged) => HttpResponse::Ok().json(serde_json::json!({
+            "from_block": from_block,
+            "to_block": to_block,
+            "state_diff": merged.to_json(),
+        })),
+        Err(e @ MergeError::Missing(_)) => HttpResponse::NotFound().body(e.to_string()),
+        Err(e) => {
+            tracing::error!("❌ Error merging state diffs: {}", e);
+            HttpResponse::InternalServerError().body("Error merging state diffs")
+        }
+    }
+}
+
+/// Most values returned by a storage or nonce history request
+const MAX_STORAGE_HISTORY: usize = 1000;
+
+// url ...storage_history?contractAddress=...&key=...[&fromBlock=...][&toBlock=...][&limit=...]
+#[derive(Deserialize)]
+struct StorageSlot {
+    #[serde(rename = "contractAddress")]
+    contract_address: String,
+    key: String,
+    #[serde(rename = "fromBlock", default)]
+    from_block: u64,
+    #[serde(rename = "toBlock")]
+    to_block: Option<u64>,
+    limit: Option<usize>,
+}
+
+/// The values a storage slot was set to, in block order
+async fn index_storage_history(
+    storage: web::Data<Arc<Storage>>,
+    web::Query(slot): web::Query<StorageSlot>,
+) -> impl Responder {
+    if !storage.index_storage_history() {
+        return HttpResponse::NotFound()
+            .body("Storage history is not indexed, see --index-storage-history");
+    }
+    let limit = slot
+        .limit
+        .unwrap_or(MAX_STORAGE_HISTORY)
+        .min(MAX_STORAGE_HISTORY);
+    let to_block = slot.to_block.unwrap_or(u64::MAX);
+    let (address, key) = (slot.contract_address.clone(), slot.key.clone());
+    match storage
+        .blocking(move |storage| {
+            index::storage_history(
+                storage.db(),
+                &address,
+                &key,
+                slot.from_block,
+                to_block,
+                limit,
+            )
+        })
+        .await
+    {
+        Ok(values) => HttpResponse::Ok().json(serde_json::json!({
+            "contract_address": slot.contract_address,
+            "key": slot.key,
+            "values": values,
+        })),
+        Err(e) => {
+            tracing::error!("❌ Error reading storage history: {}", e);
+            HttpResponse::InternalServerError().body("Error reading storage history")
+        }
+    }
+}
+
+// url ...get_nonce_history?contractAddress=...[&fromBlock=...][&toBlock=...][&limit=...]
+#[derive(Deserialize)]
+struct NonceAccount {
+    #[serde(rename = "contractAddress")]
+    contract_address: String,
+    #[serde(rename = "fromBlock", default)]
+    from_block: u64,
+    #[serde(rename = "toBlock")]
+    to_block: Option<u64>,
+    limit: Option<usize>,
+}
+
+/// The blocks at which the nonce of an account changed
+async fn get_nonce_history(
+    storage: web::Data<Arc<Storage>>,
+    web::Query(account): web::Query<NonceAccount>,
+) -> impl Responder {
+    if !storage.index_storage_history() {
+        return HttpResponse::NotFound()
+            .body("Nonce history is not indexed, see --index-storage-history");
+    }
+    let limit = account
+        .limit
+        .unwrap_or(MAX_STORAGE_HISTORY)
+        .min(MAX_STORAGE_HISTORY);
+    let to_block = account.to_block.unwrap_or(u64::MAX);
+    let address = account.contract_address.clone();
+    match storage
+        .blocking(move |storage| {
+            index::nonce_history(storage.db(), &address, account.from_block, to_block, limit)
+        })
+        .await
+    {
+        Ok(nonces) => HttpResponse::Ok().json(serde_json::json!({
+            "contract_address": account.contract_address,
+            "nonces": nonces,
+        })),
+        Err(e) => {
+            tracing::error!("❌ Error reading nonce history: {}", e);
+            HttpResponse::InternalServerError().body("Error reading nonce history")
+        }
+    }
+}
+
+/// Most blocks and transactions returned by a search
+const MAX_SEARCH_MATCHES: usize = 20;
+
+// url ...search?q=0x...
+#[derive(Deserialize)]
+struct SearchQuery {
+    q: String,
+}
+
+/// Blocks and transactions whose hash is or starts with `q`
+async fn search(
+    storage: web::Data<Arc<Storage>>,
+    web::Query(query): web::Query<SearchQuery>,
+) -> impl Responder {
+    // The hashes are stored in lowercase
+    let prefix = query.q.to_lowercase();
+    let digits = prefix.strip_prefix("0x").unwrap_or_default();
+    if digits.is_empty() || !digits.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return HttpResponse::BadRequest().body("q must be a 0x prefixed hash or start of one");
+    }
+    match storage
+        .blocking(move |storage| {
+            let blocks = index::search_block_hashes(storage.db(), &prefix, MAX_SEARCH_MATCHES)?;
+            let transactions =
+                index::search_transactions(storage.db(), &prefix, MAX_SEARCH_MATCHES)?;
+            Ok::<_, IndexError>((blocks, transactions))
+        })
+        .await
+    {
+        Ok((blocks, transactions)) => HttpResponse::Ok().json(serde_json::json!({
+            "query": query.q,
+            "blocks": blocks,
+            "transactions": transactions,
+        })),
+        Err(e) => {
+            tracing::error!("❌ Error searching {}: {}", query.q, e);
+            HttpResponse::InternalServerError().body("Error searching")
+        }
+    }
+}
+
+async fn index_class(
+    storage: web::Data<Arc<Storage>>,
+    web::Query(class_hash): web::Query<ClassHash>,
+) -> impl Responder {
+    let hash = class_hash.class_hash;
+    let lookup = hash.clone();
+    match storage
+        .blocking(move |storage| index::class_declaration(storage.db(), &lookup))
+        .await
+    {
+        Ok(Some(block_number)) => HttpResponse::Ok().json(serde_json::json!({
+            "class_hash": hash,
+            "block_number": block_number,
+        })),
+        Ok(None) => HttpResponse::NotFound().body("Class not found"),
+        Err(e) => {
+            tracing::error!("❌ Error reading declaration of class {}: {}", hash, e);
+            HttpResponse::InternalServerError().body("Error reading class declaration")
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Timestamp {
+    timestamp: u64,
+}
+
+async fn index_block_at_timestamp(
+    storage: web::Data<Arc<Storage>>,
+    web::Query(timestamp): web::Query<Timestamp>,
+) -> impl Responder {
+    let timestamp = timestamp.timestamp;
+    let found = storage
+        .blocking(move |storage| {
+            index::block_at_timestamp(storage, timestamp).and_then(|block| match block {
+                Some(block) => {
+                    Ok(index::block_timestamp(storage.db(), block)?.map(|ts| (block, ts)))
+                }
+                None => Ok(None),
+            })
+        })
+        .await;
+
+    match found {
+        Ok(Some((block, block_timestamp))) => HttpResponse::Ok()
+            .insert_header((BLOCK_HEADER, block.0))
+            .json(serde_json::json!({
+                "block_number": block.0,
+                "timestamp": block_timestamp,
+            })),
+        Ok(None) => HttpResponse::NotFound().body("No block at or before this timestamp"),
+        Err(e) => {
+            tracing::error!("❌ Error resolving block at timestamp {}: {}", timestamp, e);
+            HttpResponse::InternalServerError().body("Error resolving block at timestamp")
+        }
+    }
+}
@@ -0,0 +1,33 @@
+/// Resolves with the name of the first shutdown signal received: SIGINT,
+/// SIGTERM or SIGQUIT on Unix, the console control events on Windows
+#[cfg(unix)]
+pub async fn signal() -> &'static str {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+    let mut quit = signal(SignalKind::quit()).expect("Failed to listen for SIGQUIT");
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => {
+            result.expect("Failed to listen for SIGINT");
+            "SIGINT"
+        }
+        _ = terminate.recv() => "SIGTERM",
+        _ = quit.recv() => "SIGQUIT",
+    }
+}
+
+#[cfg(windows)]
+pub async fn signal() -> &'static str {
+    use tokio::signal::windows;
+
+    let mut ctrl_c = windows::ctrl_c().expect("Failed to listen for CTRL_C");
+    let mut ctrl_break = windows::ctrl_break().expect("Failed to listen for CTRL_BREAK");
+    let mut ctrl_close = windows::ctrl_close().expect("Failed to listen for CTRL_CLOSE");
+    let mut ctrl_shutdown = windows::ctrl_shutdown().expect("Failed to listen for CTRL_SHUTDOWN");
+    tokio::select! {
+        _ = ctrl_c.recv() => "CTRL_C",
+        _ = ctrl_break.recv() => "CTRL_BREAK",
+        _ = ctrl_close.recv() => "CTRL_CLOSE",
+        _ = ctrl_shutdown.recv() => "CTRL_SHUTDOWN",
+    }
+}
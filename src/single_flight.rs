@@ -0,0 +1,59 @@
+use futures_util::future::{BoxFuture, FutureExt, Shared};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Semaphore;
+
+/// Runs a single fetch per key at a time, the identical requests arriving
+/// meanwhile wait for its result instead of fetching again
+pub struct SingleFlight<T: Clone> {
+    /// Bounds the fetches in flight, shared with other `SingleFlight`
+    permits: Option<Arc<Semaphore>>,
+    inflight: Mutex<HashMap<String, Shared<BoxFuture<'static, T>>>>,
+}
+
+impl<T: Clone + Send + Sync + 'static> SingleFlight<T> {
+    pub fn new(permits: Option<Arc<Semaphore>>) -> Self {
+        SingleFlight {
+            permits,
+            inflight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Runs `fetch` unless a fetch of `key` is in flight, only the fetches
+    /// run take a permit
+    pub async fn run<F>(&self, key: String, fetch: F) -> T
+    where
+        F: Future<Output = T> + Send + 'static,
+    {
+        let flight = {
+            let mut inflight = self.inflight.lock().unwrap();
+            let permits = self.permits.clone();
+            inflight
+                .entry(key.clone())
+                .or_insert_with(|| {
+                    async move {
+                        let _permit = match permits {
+                            Some(permits) => permits.acquire_owned().await.ok(),
+                            None => None,
+                        };
+                        fetch.await
+                    }
+                    .boxed()
+                    .shared()
+                })
+                .clone()
+        };
+        let result = flight.clone().await;
+
+        // A later fetch of the same key may have started meanwhile
+        let mut inflight = self.inflight.lock().unwrap();
+        if inflight
+            .get(&key)
+            .is_some_and(|current| Shared::ptr_eq(current, &flight))
+        {
+            inflight.remove(&key);
+        }
+        result
+    }
+}
@@ -0,0 +1,305 @@
+#[cfg(feature = "sync")]
+use reqwest::{Client, Proxy};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{BufReader, Read};
+#[cfg(feature = "sync")]
+use std::io::{BufWriter, Write};
+use std::path::Path;
+#[cfg(feature = "sync")]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "sync")]
+use std::sync::Arc;
+
+#[cfg(feature = "sync")]
+use crate::config::Config;
+#[cfg(feature = "sync")]
+use crate::journal;
+#[cfg(feature = "sync")]
+use crate::maintenance;
+#[cfg(feature = "sync")]
+use crate::storage::Storage;
+
+/// Describes the files of a published snapshot, each in the `export` format
+#[derive(Serialize, Deserialize)]
+pub struct Manifest {
+    /// Unix time in seconds
+    pub created_at: u64,
+    pub files: Vec<ManifestFile>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ManifestFile {
+    /// Relative to the manifest URL
+    pub name: String,
+    /// Blocks covered, with their state updates and classes
+    pub from_block: u64,
+    pub to_block: u64,
+    /// In bytes
+    pub size: u64,
+    /// Hex encoded SHA-256 of the file
+    pub sha256: String,
+}
+
+pub const MANIFEST: &str = "manifest.json";
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn read_manifest(dir: &Path) -> Result<Manifest, String> {
+    let content = std::fs::read(dir.join(MANIFEST)).map_err(|e| e.to_string())?;
+    serde_json::from_slice(&content).map_err(|e| e.to_string())
+}
+
+/// Makes `name`, an export in `dir` of blocks `from_block` to `to_block`,
+/// the published snapshot. The manifest is replaced atomically, then the
+/// files of the previous snapshot are removed
+pub fn publish(dir: &Path, name: &str, from_block: u64, to_block: u64) -> Result<(), String> {
+    let mut input = BufReader::new(File::open(dir.join(name)).map_err(|e| e.to_string())?);
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 1 << 16];
+    let mut size = 0;
+    loop {
+        let read = input.read(&mut buffer).map_err(|e| e.to_string())?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        size += read as u64;
+    }
+
+    let previous = read_manifest(dir).map_or(vec![], |manifest| manifest.files);
+    let manifest = Manifest {
+        created_at: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs()),
+        files: vec![ManifestFile {
+            name: name.to_string(),
+            from_block,
+            to_block,
+            size,
+            sha256: hex(&hasher.finalize()),
+        }],
+    };
+    let temporary = dir.join(format!("{}.tmp", MANIFEST));
+    let content = serde_json::to_vec_pretty(&manifest).map_err(|e| e.to_string())?;
+    std::fs::write(&temporary, content).map_err(|e| e.to_string())?;
+    std::fs::rename(&temporary, dir.join(MANIFEST)).map_err(|e| e.to_string())?;
+
+    for file in previous.iter().filter(|file| file.name != name) {
+        if let Some(file_name) = Path::new(&file.name).file_name() {
+            let _ = std::fs::remove_file(dir.join(file_name));
+        }
+    }
+    tracing::info!(
+        "📸 Published snapshot {} of blocks {} to {}, {} bytes",
+        name,
+        from_block,
+        to_block,
+        size
+    );
+    Ok(())
+}
+
+/// Downloads the snapshot described by the manifest at `url` next to the DB,
+/// checks every file against the manifest and imports it. Only an empty DB
+/// is bootstrapped, the sync then starts after the last imported block
+#[cfg(feature = "sync")]
+#[tracing::instrument(skip_all)]
+pub async fn bootstrap(
+    config: &Config,
+    storage: &Arc<Storage>,
+    url: &str,
+    running: &AtomicBool,
+) -> Result<(), String> {
+    if storage.max_block_sync().is_some() {
+        tracing::info!("📸 DB not empty, skipping the snapshot bootstrap");
+        return Ok(());
+    }
+
+    // No timeout nor size limit, snapshots are large
+    let mut builder = Client::builder();
+    if let Some(proxy) = &config.upstream_proxy {
+        builder = builder.proxy(Proxy::all(proxy).map_err(|e| e.to_string())?);
+    }
+    let client = builder.build().map_err(|e| e.to_string())?;
+
+    let manifest_url = url::Url::parse(url).map_err(|e| e.to_string())?;
+    let manifest: Manifest = client
+        .get(manifest_url.clone())
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| format!("manifest: {}", e))?
+        .json()
+        .await
+        .map_err(|e| format!("manifest: {}", e))?;
+    tracing::info!(
+        "📸 Bootstrapping from {} files of the snapshot created at {}",
+        manifest.files.len(),
+        manifest.created_at
+    );
+
+    for file in &manifest.files {
+        // The name is only trusted as a file name
+        let name = Path::new(&file.name)
+            .file_name()
+            .ok_or(format!("invalid file name {}", file.name))?;
+        let path = config
+            .db_path()
+            .with_file_name(format!("snapshot-{}", name.to_string_lossy()));
+        let file_url = manifest_url.join(&file.name).map_err(|e| e.to_string())?;
+
+        let downloaded = download(&client, file_url, &path, file, running).await;
+        let imported = match downloaded {
+            Ok(true) => {
+                let (storage, path) = (storage.clone(), path.clone());
+                tokio::task::spawn_blocking(move || maintenance::import_file(&storage, &path))
+                    .await
+                    .map_err(|e| e.to_string())
+                    .and_then(|imported| imported.map_err(|e| e.to_string()))
+            }
+            Ok(false) => Ok(()),
+            Err(e) => Err(e),
+        };
+        let _ = std::fs::remove_file(&path);
+        imported.map_err(|e| format!("{}: {}", file.name, e))?;
+        if !running.load(Ordering::SeqCst) {
+            tracing::info!("📸 Snapshot bootstrap interrupted");
+            break;
+        }
+        tracing::info!(
+            "📸 Imported blocks {} to {} from {}",
+            file.from_block,
+            file.to_block,
+            file.name
+        );
+    }
+
+    storage.rescan();
+    if let Some(block) = storage.max_block_sync() {
+        journal::record(
+            storage,
+            "bootstrapped",
+            format!("snapshot {} up to block {}", url, block),
+        );
+    }
+    Ok(())
+}
+
+/// Returns false when interrupted by a shutdown
+#[cfg(feature = "sync")]
+async fn download(
+    client: &Client,
+    url: url::Url,
+    path: &Path,
+    file: &ManifestFile,
+    running: &AtomicBool,
+) -> Result<bool, String> {
+    let mut response = client
+        .get(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| e.to_string())?;
+    let mut output = BufWriter::new(File::create(path).map_err(|e| e.to_string())?);
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+    while let Some(chunk) = response.chunk().await.map_err(|e| e.to_string())? {
+        if !running.load(Ordering::SeqCst) {
+            return Ok(false);
+        }
+        size += chunk.len() as u64;
+        if size > file.size {
+            return Err(format!("larger than the {} bytes expected", file.size));
+        }
+        hasher.update(&chunk);
+        output.write_all(&chunk).map_err(|e| e.to_string())?;
+    }
+    output.flush().map_err(|e| e.to_string())?;
+
+    if size != file.size {
+        return Err(format!("{} bytes instead of {}", size, file.size));
+    }
+    let sha256 = hex(&hasher.finalize());
+    if !sha256.eq_ignore_ascii_case(&file.sha256) {
+        return Err(format!("SHA-256 {} instead of {}", sha256, file.sha256));
+    }
+    Ok(true)
+}
+
+/// Registers the `/snapshots/` routes, only when a snapshot directory is
+/// configured
+#[cfg(feature = "server")]
+pub fn configure(cfg: &mut actix_web::web::ServiceConfig, args: &crate::config::ServeArgs) {
+    use actix_web::web;
+
+    if args.snapshot_dir.is_some() {
+        cfg.route("/snapshots/", web::get().to(manifest))
+            .route("/snapshots/manifest.json", web::get().to(manifest))
+            .route("/snapshots/{name}", web::get().to(snapshot_file));
+    }
+}
+
+#[cfg(feature = "server")]
+async fn manifest(args: actix_web::web::Data<crate::config::ServeArgs>) -> actix_web::HttpResponse {
+    use actix_web::HttpResponse;
+
+    let Some(dir) = &args.snapshot_dir else {
+        return HttpResponse::NotFound().finish();
+    };
+    match read_manifest(dir) {
+        Ok(manifest) => HttpResponse::Ok().json(manifest),
+        Err(e) => {
+            tracing::debug!("❌ Error reading the snapshot manifest: {}", e);
+            HttpResponse::NotFound().body("No snapshot published")
+        }
+    }
+}
+
+/// Streams a file listed in the manifest, anything else is not found
+#[cfg(feature = "server")]
+async fn snapshot_file(
+    args: actix_web::web::Data<crate::config::ServeArgs>,
+    name: actix_web::web::Path<String>,
+) -> actix_web::HttpResponse {
+    use actix_web::body::SizedStream;
+    use actix_web::HttpResponse;
+    use tokio::io::AsyncReadExt;
+
+    let Some(dir) = &args.snapshot_dir else {
+        return HttpResponse::NotFound().finish();
+    };
+    let listed = read_manifest(dir)
+        .map(|manifest| manifest.files.iter().any(|file| file.name == *name))
+        .unwrap_or(false);
+    if !listed {
+        return HttpResponse::NotFound().body("Snapshot file not found");
+    }
+    let file = match tokio::fs::File::open(dir.join(name.as_str())).await {
+        Ok(file) => file,
+        Err(e) => {
+            tracing::error!("❌ Error opening snapshot file {}: {}", name, e);
+            return HttpResponse::NotFound().body("Snapshot file not found");
+        }
+    };
+    let size = match file.metadata().await {
+        Ok(metadata) => metadata.len(),
+        Err(e) => {
+            tracing::error!("❌ Error reading snapshot file {}: {}", name, e);
+            return HttpResponse::InternalServerError().body("Error reading the snapshot");
+        }
+    };
+
+    let chunks = futures_util::stream::try_unfold(file, |mut file| async move {
+        let mut buffer = vec![0; 1 << 16];
+        let read = file.read(&mut buffer).await?;
+        buffer.truncate(read);
+        Ok::<_, std::io::Error>((read > 0).then(|| (actix_web::web::Bytes::from(buffer), file)))
+    });
+    HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .body(SizedStream::new(size, chunks))
+}
@@ -0,0 +1,135 @@
+//! Merges the state diffs of a range of cached state updates into the diff
+//! from the state before the first block to the state after the last one
+
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use thiserror::Error;
+
+use crate::primitives::{normalize_hash, State};
+use crate::storage::Storage;
+
+/// Most blocks merged by a request
+pub const MAX_RANGE: u64 = 1000;
+
+#[derive(Debug, Error)]
+pub enum MergeError {
+    #[error("state update {0} is not cached")]
+    Missing(u64),
+    #[error("state update {0}: {1}")]
+    Invalid(u64, String),
+    #[error(transparent)]
+    Storage(#[from] rocksdb::Error),
+}
+
+/// A state diff in the format of the gateway, keyed for merging
+#[derive(Default)]
+pub struct MergedDiff {
+    pub storage_diffs: BTreeMap<String, BTreeMap<String, Value>>,
+    pub nonces: BTreeMap<String, Value>,
+    pub deployed_contracts: BTreeMap<String, Value>,
+    pub old_declared_contracts: BTreeSet<String>,
+    pub declared_classes: BTreeMap<String, Value>,
+    pub replaced_classes: BTreeMap<String, Value>,
+}
+
+/// Applies the state diffs of blocks `from` to `to` in order: the last write
+/// of a storage key or nonce wins, and a class replaced in a contract
+/// deployed within the range changes its deployment instead
+pub fn merge(storage: &Storage, from: u64, to: u64) -> Result<MergedDiff, MergeError> {
+    let mut merged = MergedDiff::default();
+    for number in from..=to {
+        let content = storage
+            .db()
+            .get(State(number).key())?
+            .ok_or(MergeError::Missing(number))?;
+        let state_update: Value = serde_json::from_slice(&content)
+            .map_err(|e| MergeError::Invalid(number, e.to_string()))?;
+        let diff = &state_update["state_diff"];
+
+        for (address, entries) in object(&diff["storage_diffs"]) {
+            let writes = merged
+                .storage_diffs
+                .entry(normalize_hash(address))
+                .or_default();
+            for entry in entries.as_array().into_iter().flatten() {
+                if let (Some(key), Some(value)) = (entry["key"].as_str(), entry.get("value")) {
+                    writes.insert(normalize_hash(key), value.clone());
+                }
+            }
+        }
+        for (address, nonce) in object(&diff["nonces"]) {
+            merged.nonces.insert(normalize_hash(address), nonce.clone());
+        }
+        for contract in array(&diff["deployed_contracts"]) {
+            if let Some(address) = contract["address"].as_str() {
+                merged
+                    .deployed_contracts
+                    .insert(normalize_hash(address), contract["class_hash"].clone());
+            }
+        }
+        for hash in array(&diff["old_declared_contracts"]) {
+            if let Some(hash) = hash.as_str() {
+                merged.old_declared_contracts.insert(normalize_hash(hash));
+            }
+        }
+        for class in array(&diff["declared_classes"]) {
+            if let Some(hash) = class["class_hash"].as_str() {
+                merged
+                    .declared_classes
+                    .insert(normalize_hash(hash), class["compiled_class_hash"].clone());
+            }
+        }
+        for contract in array(&diff["replaced_classes"]) {
+            let Some(address) = contract["address"].as_str().map(normalize_hash) else {
+                continue;
+            };
+            let class_hash = contract["class_hash"].clone();
+            match merged.deployed_contracts.get_mut(&address) {
+                Some(deployed) => *deployed = class_hash,
+                None => {
+                    merged.replaced_classes.insert(address, class_hash);
+                }
+            }
+        }
+    }
+    Ok(merged)
+}
+
+impl MergedDiff {
+    /// In the layout of the `state_diff` of a state update
+    pub fn to_json(&self) -> Value {
+        let storage_diffs: Map<String, Value> = self
+            .storage_diffs
+            .iter()
+            .map(|(address, writes)| {
+                let writes = writes
+                    .iter()
+                    .map(|(key, value)| serde_json::json!({ "key": key, "value": value }))
+                    .collect();
+                (address.clone(), Value::Array(writes))
+            })
+            .collect();
+        let pairs = |entries: &BTreeMap<String, Value>, key: &str, value: &str| {
+            entries
+                .iter()
+                .map(|(k, v)| serde_json::json!({ key: k, value: v }))
+                .collect::<Vec<_>>()
+        };
+        serde_json::json!({
+            "storage_diffs": storage_diffs,
+            "nonces": self.nonces,
+            "deployed_contracts": pairs(&self.deployed_contracts, "address", "class_hash"),
+            "old_declared_contracts": self.old_declared_contracts,
+            "declared_classes": pairs(&self.declared_classes, "class_hash", "compiled_class_hash"),
+            "replaced_classes": pairs(&self.replaced_classes, "address", "class_hash"),
+        })
+    }
+}
+
+fn object(value: &Value) -> impl Iterator<Item = (&String, &Value)> {
+    value.as_object().into_iter().flatten()
+}
+
+fn array(value: &Value) -> impl Iterator<Item = &Value> {
+    value.as_array().into_iter().flatten()
+}
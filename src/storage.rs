@@ -1,24 +1,99 @@
-use rocksdb::{DBCompressionType, Options, DB};
-use std::path::PathBuf;
-use std::sync::RwLock;
+use rocksdb::statistics::Ticker;
+use rocksdb::{
+    ColumnFamily, ColumnFamilyDescriptor, ErrorKind, Options, ReadOptions, WriteBatch, DB,
+    DEFAULT_COLUMN_FAMILY_NAME,
+};
+#[cfg(feature = "server")]
+use rocksdb::{Direction, IteratorMode};
+use serde::Serialize;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
 
-use crate::primitives::{Block, State};
+use crate::config::DbArgs;
+use crate::header;
+use crate::index;
+use crate::journal;
+use crate::primitives::{
+    normalize_hash, Audit, Block, Class, ClassDeclaration, Contract, Event, Meta, State,
+    Transaction,
+};
+use crate::read_pool::ReadPool;
+
+#[derive(Debug, thiserror::Error)]
+pub enum StorageError {
+    #[error(transparent)]
+    RocksDb(#[from] rocksdb::Error),
+    #[error("starting the read threads: {0}")]
+    ReadPool(std::io::Error),
+    /// The pid is the one written in the lock file, if any
+    #[error("{} is used by another instance, pid {pid}", .path.display())]
+    Locked { path: PathBuf, pid: String },
+    #[error("locking {}: {source}", .path.display())]
+    Lock {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+}
+
+/// Column family of the block headers
+pub const HEADERS_CF: &str = "headers";
+
+/// Column family of the classes, compressed apart as they are large and
+/// never rewritten. The other keys are in the default one
+pub const CLASSES_CF: &str = "classes";
+
+/// File in the DB directory locked by the instance writing to it, holding
+/// its pid
+const LOCK_FILE: &str = "feeder_cache.lock";
 
 pub struct Storage {
     db: DB,
+    /// Kept to read the statistics the DB collects
+    opts: Options,
     max_block_sync: RwLock<Option<Block>>,
     max_state_sync: RwLock<Option<State>>,
+    next_event_seq: AtomicU64,
+    next_audit_seq: AtomicU64,
+    index_storage_history: bool,
+    /// `--verify-checksums`, for the reads of `read` and `multi_get`
+    verify_checksums: bool,
+    read_retries: AtomicU64,
+    read_retries_exhausted: AtomicU64,
+    /// Runs the payload reads when `--read-threads` is set
+    read_pool: Option<ReadPool>,
+    /// Released once the DB above is closed, fields being dropped in order
+    _lock: File,
 }
 
 impl Storage {
-    pub fn new(db_path: &PathBuf) -> Result<Storage, String> {
-        init_storage(db_path)
+    /// Keeps the WAL for `wal_retention` seconds, for the mirror and the
+    /// replicas to read it
+    pub fn open(
+        db_path: &PathBuf,
+        db: &DbArgs,
+        wal_retention: Option<u64>,
+    ) -> Result<Storage, StorageError> {
+        init_storage(db_path, db, wal_retention)
     }
 
     pub fn db(&self) -> &DB {
         &self.db
     }
 
+    /// Persists the memtables so a restart does not replay the WAL
+    pub fn flush(&self) -> Result<(), StorageError> {
+        self.db.flush()?;
+        self.db.flush_wal(true)?;
+        Ok(())
+    }
+
+    pub fn ticker(&self, ticker: Ticker) -> u64 {
+        self.opts.get_ticker_count(ticker)
+    }
+
     pub fn max_block_sync(&self) -> Option<Block> {
         *self.max_block_sync.read().unwrap()
     }
@@ -27,79 +102,855 @@ impl Storage {
         *self.max_state_sync.read().unwrap()
     }
 
+    /// Reserves the sequence number of the next journal event
+    pub fn next_event_seq(&self) -> u64 {
+        self.next_event_seq.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// Whether the storage writes of the state updates are indexed
+    pub fn index_storage_history(&self) -> bool {
+        self.index_storage_history
+    }
+
+    /// Reserves the sequence number of the next audit log entry
+    pub fn next_audit_seq(&self) -> u64 {
+        self.next_audit_seq.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// Also persists the cursor, so the next start does not scan the DB.
+    /// Moves the cursor only to the block right after it, so a write racing
+    /// `delete_numbered` cannot move it past the deleted range
     pub fn set_max_block_sync(&self, block: Block) {
-        let mut max_block = self.max_block_sync.write().unwrap();
-        *max_block = Some(block);
+        let mut max = self.max_block_sync.write().unwrap();
+        if max.map_or(0, |max| max.0 + 1) == block.0 {
+            *max = Some(block);
+            store_cursor(&self.db, BLOCK_CURSOR, Some(block.0));
+        }
     }
 
+    /// Also persists the cursor, so the next start does not scan the DB.
+    /// Moves the cursor only to the state update right after it, so a write
+    /// racing `delete_numbered` cannot move it past the deleted range
     pub fn set_max_state_sync(&self, state: State) {
-        let mut max_state = self.max_state_sync.write().unwrap();
-        *max_state = Some(state);
+        let mut max = self.max_state_sync.write().unwrap();
+        if max.map_or(0, |max| max.0 + 1) == state.0 {
+            *max = Some(state);
+            store_cursor(&self.db, STATE_CURSOR, Some(state.0));
+        }
     }
-}
 
-// TODO add options to improve performance due to the inmutable nature of the data
-fn init_storage(db_path: &PathBuf) -> Result<Storage, String> {
-    let mut opts = Options::default();
-    opts.create_if_missing(true);
-    opts.set_compression_type(DBCompressionType::Zstd);
-    let db = DB::open(&opts, db_path)?;
-
-    let max_block_sync = {
-        match is_key_present(&db, &Block(0).key()) {
-            true => {
-                let mut block = Block(0);
-                loop {
-                    if !is_key_present(&db, &block.next().key()) {
-                        break;
+    /// Recomputes the synced heights after blocks and state updates were
+    /// written outside the sync tasks
+    pub fn rescan(&self) {
+        let max_block_sync = recover_cursor(&self.db, BLOCK_CURSOR, |number| Block(number).key());
+        let max_state_sync = recover_cursor(&self.db, STATE_CURSOR, |number| State(number).key());
+        *self.max_block_sync.write().unwrap() = max_block_sync.map(Block);
+        *self.max_state_sync.write().unwrap() = max_state_sync.map(State);
+    }
+
+    fn read_options(&self) -> ReadOptions {
+        let mut opts = ReadOptions::default();
+        opts.set_verify_checksums(self.verify_checksums);
+        opts
+    }
+
+    /// Reads the values of `keys` in a single batched lookup, in the same
+    /// order
+    pub fn multi_get(&self, keys: &[String]) -> Result<Vec<Option<Vec<u8>>>, StorageError> {
+        let values = self.db.multi_get_cf_opt(
+            keys.iter()
+                .map(|key| (column_family(&self.db, key.as_bytes()), key)),
+            &self.read_options(),
+        );
+        Ok(values.into_iter().collect::<Result<_, _>>()?)
+    }
+
+    /// Deletes the keys from `from` included to `to` excluded with a single
+    /// range tombstone per column family of the data
+    pub fn delete_range(&self, from: &[u8], to: &[u8]) -> Result<(), StorageError> {
+        let mut batch = WriteBatch::default();
+        batch.delete_range(from, to);
+        batch.delete_range_cf(classes(&self.db), from, to);
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    /// Deletes every key starting with `prefix`, which must not be empty
+    pub fn delete_prefix(&self, prefix: &str) -> Result<(), StorageError> {
+        // The first key past the prefix, its last byte incremented
+        let mut end = prefix.as_bytes().to_vec();
+        while end.last() == Some(&u8::MAX) {
+            end.pop();
+        }
+        match end.last_mut() {
+            Some(last) => *last += 1,
+            None => return Ok(()),
+        }
+        self.delete_range(prefix.as_bytes(), &end)
+    }
+
+    /// Deletes the blocks and or state updates `from` to `to`, with their
+    /// fetch metadata and what was indexed from them, in batches of
+    /// `DELETE_BATCH` numbers. Their numbers are not zero padded, so the
+    /// range is not a key range, and `to` is lowered to the highest number
+    /// synced or indexed. The sync cursors are moved back before the first
+    /// deleted when it was synced, ahead of the deletes so an interrupted run
+    /// leaves no hole below them. Returns the number of blocks and state
+    /// updates deleted
+    pub fn delete_numbered(
+        &self,
+        from: u64,
+        to: u64,
+        blocks: bool,
+        states: bool,
+    ) -> Result<u64, StorageError> {
+        let highest = match header::highest(&self.db) {
+            Ok(highest) => highest,
+            Err(e) => {
+                tracing::warn!("⚠️ Could not read the highest indexed block: {}", e);
+                None
+            }
+        };
+        let synced = [
+            self.max_block_sync().map(|block| block.0),
+            self.max_state_sync().map(|state| state.0),
+        ];
+        let to = match synced.into_iter().chain([highest]).max().flatten() {
+            Some(highest) => highest.min(to),
+            None => return Ok(0),
+        };
+
+        if blocks {
+            let mut max = self.max_block_sync.write().unwrap();
+            if max.is_some_and(|block| block.0 >= from) {
+                *max = from.checked_sub(1).map(Block);
+                store_cursor(&self.db, BLOCK_CURSOR, from.checked_sub(1));
+            }
+        }
+        if states {
+            let mut max = self.max_state_sync.write().unwrap();
+            if max.is_some_and(|state| state.0 >= from) {
+                *max = from.checked_sub(1).map(State);
+                store_cursor(&self.db, STATE_CURSOR, from.checked_sub(1));
+            }
+        }
+
+        let mut deleted = 0;
+        let mut start = from;
+        while start <= to {
+            let end = start.saturating_add(DELETE_BATCH - 1).min(to);
+            let mut batch = WriteBatch::default();
+            for number in start..=end {
+                if blocks {
+                    if let Some(content) = read_data(&self.db, &Block(number).key())? {
+                        let unindexed =
+                            index::unindex_block(&self.db, &mut batch, Block(number), &content);
+                        if let Err(e) = unindexed {
+                            tracing::warn!(
+                                "⚠️ Keeping the index entries of block {}: {}",
+                                number,
+                                e
+                            );
+                        }
+                        deleted += 1;
+                    }
+                    batch.delete(Meta(Block(number).key()).key());
+                    batch.delete(Block(number).key());
+                }
+                if states {
+                    if let Some(content) = read_data(&self.db, &State(number).key())? {
+                        let unindexed =
+                            index::unindex_state_update(self, &mut batch, State(number), &content);
+                        if let Err(e) = unindexed {
+                            tracing::warn!(
+                                "⚠️ Keeping the index entries of state update {}: {}",
+                                number,
+                                e
+                            );
+                        }
+                        deleted += 1;
                     }
-                    block = block.next();
+                    batch.delete(Meta(State(number).key()).key());
+                    batch.delete(State(number).key());
                 }
-                Some(block)
             }
-            false => None,
+            self.db.write(batch)?;
+            match end.checked_add(1) {
+                Some(next) => start = next,
+                None => break,
+            }
         }
-    };
+        Ok(deleted)
+    }
+
+    /// Runs `f` on the blocking thread pool, so RocksDB I/O does not stall
+    /// the async workers. A panic in `f` is resumed in the caller
+    pub async fn blocking<T, F>(self: &Arc<Self>, f: F) -> T
+    where
+        F: FnOnce(&Storage) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let storage = self.clone();
+        match tokio::task::spawn_blocking(move || f(&storage)).await {
+            Ok(value) => value,
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
+        }
+    }
 
-    let max_state_sync = {
-        match is_key_present(&db, &State(0).key()) {
-            true => {
-                let mut state = State(0);
-                loop {
-                    if !is_key_present(&db, &state.next().key()) {
-                        break;
+    /// `read_data` with `--verify-checksums`, off the async workers, on the
+    /// read threads when set. Retried up to `READ_ATTEMPTS` times on the errors RocksDB expects to go
+    /// away, as under heavy compaction
+    pub async fn read(self: &Arc<Self>, key: String) -> Result<Option<Vec<u8>>, StorageError> {
+        let mut delay = READ_RETRY_DELAY;
+        let mut attempt = 1;
+        loop {
+            let (storage, read_key) = (self.clone(), key.clone());
+            let read = move || {
+                let column_family = column_family(storage.db(), read_key.as_bytes());
+                Ok(storage
+                    .db()
+                    .get_cf_opt(column_family, &read_key, &storage.read_options())?)
+            };
+            let result = match &self.read_pool {
+                Some(pool) => pool.run(read).await,
+                None => self.blocking(move |_| read()).await,
+            };
+            match result {
+                Err(StorageError::RocksDb(e)) if is_transient(&e) => {
+                    if attempt == READ_ATTEMPTS {
+                        self.read_retries_exhausted.fetch_add(1, Ordering::Relaxed);
+                        return Err(e.into());
                     }
-                    state = state.next();
+                    tracing::warn!("⚠️ Retrying the read of {}: {}", key, e);
+                    self.read_retries.fetch_add(1, Ordering::Relaxed);
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                    attempt += 1;
                 }
-                Some(state)
+                result => return result,
             }
-            false => None,
         }
+    }
+
+    /// Reads retried after a transient error
+    pub fn read_retries(&self) -> u64 {
+        self.read_retries.load(Ordering::Relaxed)
+    }
+
+    /// Reads still failing with a transient error once retried
+    pub fn read_retries_exhausted(&self) -> u64 {
+        self.read_retries_exhausted.load(Ordering::Relaxed)
+    }
+
+    /// `write_data` off the async workers
+    pub async fn write(self: &Arc<Self>, key: String, data: String) -> Result<(), StorageError> {
+        self.blocking(move |storage| write_data(storage.db(), &key, &data))
+            .await
+    }
+}
+
+/// Attempts of a read failing with a transient error
+const READ_ATTEMPTS: u32 = 3;
+
+/// Wait before the first retry of a read, doubled for the next
+const READ_RETRY_DELAY: std::time::Duration = std::time::Duration::from_millis(20);
+
+/// Errors RocksDB returns while busy, which a retry may not get again
+fn is_transient(e: &rocksdb::Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::Busy | ErrorKind::TryAgain | ErrorKind::TimedOut | ErrorKind::Incomplete
+    )
+}
+
+/// Start of the keys of the persisted sync cursors
+pub const CURSOR_PREFIX: &str = "cursor_";
+
+/// Keys of the persisted sync cursors, the last block and state update
+/// present without a gap from 0
+const BLOCK_CURSOR: &str = "cursor_block";
+const STATE_CURSOR: &str = "cursor_state";
+
+/// A failure is only logged, the cursor is recovered by scanning the keys
+fn store_cursor(db: &DB, cursor: &str, number: Option<u64>) {
+    let stored = match number {
+        Some(number) => db.put(cursor, number.to_string()),
+        None => db.delete(cursor),
+    };
+    if let Err(e) = stored {
+        tracing::error!("❌ Error storing the sync cursor {}: {}", cursor, e);
+    }
+}
+
+/// Resumes from the stored cursor, looking for keys after it since an import
+/// may have written past it. Without a cursor, as in the DBs written before
+/// they were stored, the keys are checked for a gap from 0 once. The cursor
+/// found is stored
+fn recover_cursor(db: &DB, cursor: &str, key: impl Fn(u64) -> String) -> Option<u64> {
+    let stored = match db.get(cursor) {
+        Ok(Some(value)) => std::str::from_utf8(&value)
+            .ok()
+            .and_then(|value| value.parse().ok()),
+        _ => None,
+    };
+    // Not trusted when the item it points at is missing
+    let stored = stored.filter(|number| is_key_present(db, &key(*number)));
+    if stored.is_none() && is_key_present(db, &key(0)) {
+        tracing::info!("🔎 No {} stored, checking every key from 0", cursor);
+    }
+
+    let last = last_contiguous(db, key, stored);
+    if last != stored {
+        store_cursor(db, cursor, last);
+    }
+    last
+}
+
+/// Keys read at once when checking a run of numbered keys for gaps
+const GAP_SCAN_BATCH: u64 = 1024;
+
+/// Numbers deleted per write by `delete_numbered`, bounding the batch memory
+const DELETE_BATCH: u64 = 4096;
+
+/// The last of the numbered keys present without a gap from `known`, a
+/// number present, or from 0. A key present followed by a missing one is
+/// found by galloping then bisecting from the start, then the keys before
+/// it are read in batches for an earlier gap
+fn last_contiguous(db: &DB, key: impl Fn(u64) -> String, known: Option<u64>) -> Option<u64> {
+    let start = match known {
+        Some(number) => number,
+        None if is_key_present(db, &key(0)) => 0,
+        None => return None,
+    };
+    let (mut low, mut high) = (start, u64::MAX);
+    let mut step = 1u64;
+    while low < u64::MAX {
+        let probe = low.saturating_add(step);
+        if !is_key_present(db, &key(probe)) {
+            high = probe;
+            break;
+        }
+        low = probe;
+        step = step.saturating_mul(2);
+    }
+    while high - low > 1 {
+        let middle = low + (high - low) / 2;
+        match is_key_present(db, &key(middle)) {
+            true => low = middle,
+            false => high = middle,
+        }
+    }
+    Some(first_gap(db, &key, start, low).map_or(low, |gap| gap - 1))
+}
+
+/// The first number missing after `from` up to `to`, read in batches
+fn first_gap(db: &DB, key: &impl Fn(u64) -> String, from: u64, to: u64) -> Option<u64> {
+    let mut next = from.checked_add(1)?;
+    while next <= to {
+        let end = to.min(next.saturating_add(GAP_SCAN_BATCH - 1));
+        let keys: Vec<String> = (next..=end).map(key).collect();
+        let values = db.multi_get(&keys);
+        if let Some((number, _)) = (next..=end)
+            .zip(values)
+            .find(|(_, value)| !matches!(value, Ok(Some(_))))
+        {
+            return Some(number);
+        }
+        next = match end.checked_add(1) {
+            Some(next) => next,
+            None => break,
+        };
+    }
+    None
+}
+
+fn init_storage(
+    db_path: &PathBuf,
+    db_args: &DbArgs,
+    wal_retention: Option<u64>,
+) -> Result<Storage, StorageError> {
+    let lock = lock(db_path)?;
+    let mut opts = Options::default();
+    opts.create_if_missing(true);
+    db_args.apply(&mut opts);
+    opts.enable_statistics();
+    if let Some(wal_retention) = wal_retention {
+        opts.set_wal_ttl_seconds(wal_retention);
+    }
+    opts.create_missing_column_families(true);
+    let db = DB::open_cf_descriptors(&opts, db_path, column_families(&opts, db_args))?;
+    // Has no setter in the bindings, but can be changed once open
+    if db_args.paranoid_file_checks {
+        db.set_options(&[("paranoid_file_checks", "true")])?;
+    }
+
+    let max_block_sync = recover_cursor(&db, BLOCK_CURSOR, |number| Block(number).key()).map(Block);
+    let max_state_sync = recover_cursor(&db, STATE_CURSOR, |number| State(number).key()).map(State);
+
+    migrate_classes_cf(&db)?;
+    migrate_class_keys(&db)?;
+    migrate_transaction_keys(&db)?;
+    migrate_contract_keys(&db)?;
+    let next_event_seq = journal::next_seq(&db, Event::KEY_PREFIX);
+    let next_audit_seq = journal::next_seq(&db, Audit::KEY_PREFIX);
+    let read_pool = match db_args.read_threads {
+        0 => None,
+        threads => Some(ReadPool::new(threads).map_err(StorageError::ReadPool)?),
     };
 
     Ok(Storage {
         db,
+        opts,
         max_block_sync: RwLock::new(max_block_sync),
         max_state_sync: RwLock::new(max_state_sync),
+        next_event_seq: AtomicU64::new(next_event_seq),
+        next_audit_seq: AtomicU64::new(next_audit_seq),
+        index_storage_history: db_args.index_storage_history,
+        verify_checksums: db_args.verify_checksums,
+        read_retries: AtomicU64::new(0),
+        read_retries_exhausted: AtomicU64::new(0),
+        read_pool,
+        _lock: lock,
     })
 }
 
-pub fn write_data(db: &DB, key: &str, data: &str) -> Result<(), String> {
-    db.put(key.as_bytes(), data)?;
+/// Takes the lock of the DB directory, so a second instance fails with its
+/// pid before RocksDB refuses it, or worse, a write midway
+fn lock(db_path: &Path) -> Result<File, StorageError> {
+    let path = db_path.join(LOCK_FILE);
+    let error = |source| StorageError::Lock {
+        path: path.clone(),
+        source,
+    };
+    std::fs::create_dir_all(db_path).map_err(error)?;
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .map_err(error)?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            let mut pid = String::new();
+            let _ = file.read_to_string(&mut pid);
+            return Err(StorageError::Locked {
+                path: db_path.to_path_buf(),
+                pid: match pid.trim() {
+                    "" => "unknown".to_string(),
+                    pid => pid.to_string(),
+                },
+            });
+        }
+        Err(TryLockError::Error(e)) => return Err(error(e)),
+    }
+    file.set_len(0).map_err(error)?;
+    write!(file, "{}", std::process::id()).map_err(error)?;
+    Ok(file)
+}
+
+/// The column families other than the default one, created in this order in
+/// every DB so the WAL of one can be replayed in another
+pub fn column_families(opts: &Options, db_args: &DbArgs) -> Vec<ColumnFamilyDescriptor> {
+    vec![
+        ColumnFamilyDescriptor::new(HEADERS_CF, opts.clone()),
+        ColumnFamilyDescriptor::new(CLASSES_CF, db_args.classes_options(opts)),
+    ]
+}
+
+/// The column family holding `key`
+fn column_family<'a>(db: &'a DB, key: &[u8]) -> &'a ColumnFamily {
+    match key.starts_with(Class::KEY_PREFIX.as_bytes()) {
+        true => classes(db),
+        false => db
+            .cf_handle(DEFAULT_COLUMN_FAMILY_NAME)
+            .expect("the default column family always exists"),
+    }
+}
+
+/// The column family of the classes
+pub fn classes(db: &DB) -> &ColumnFamily {
+    db.cf_handle(CLASSES_CF)
+        .expect("the DBs are opened with the classes column family")
+}
+
+/// Adds the write of `value` under `key` to `batch`, in its column family.
+/// The WAL readers stop at the first write of another column family than
+/// the default one, so a batch writing a class puts its other keys first
+pub fn put(db: &DB, batch: &mut WriteBatch, key: &str, value: impl AsRef<[u8]>) {
+    batch.put_cf(column_family(db, key.as_bytes()), key, value);
+}
+
+/// Set once the classes stored in the default column family were moved to
+/// their own
+const CLASSES_CF_MIGRATED: &str = "migrated_classes_cf";
+
+/// Classes moved per write batch by `migrate_classes_cf`
+const MIGRATE_BATCH: usize = 1000;
+
+/// Moves the classes stored before they had a column family to it, once per
+/// DB
+fn migrate_classes_cf(db: &DB) -> Result<(), StorageError> {
+    if is_key_present(db, CLASSES_CF_MIGRATED) {
+        return Ok(());
+    }
+    let prefix = Class::KEY_PREFIX.as_bytes();
+    let mut migrated = 0;
+    loop {
+        // Read again after each batch, the moved keys being deleted
+        let moved: Vec<_> = db
+            .prefix_iterator(prefix)
+            .map_while(Result::ok)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .take(MIGRATE_BATCH)
+            .collect();
+        if moved.is_empty() {
+            break;
+        }
+        let mut batch = WriteBatch::default();
+        for (key, value) in &moved {
+            // In one batch, so a crash loses nothing
+            batch.delete(key);
+            batch.put_cf(classes(db), key, value);
+        }
+        db.write(batch)?;
+        migrated += moved.len();
+        if migrated % (MIGRATE_BATCH * 100) == 0 {
+            tracing::info!("🔧 Moved {} classes to their column family", migrated);
+        }
+    }
+    db.put(CLASSES_CF_MIGRATED, "1")?;
+    if migrated > 0 {
+        tracing::info!("🔧 Moved {} classes to their column family", migrated);
+    }
+    Ok(())
+}
+
+/// Set once the class hash keys written before normalization were rewritten
+const CLASS_KEYS_MIGRATED: &str = "migrated_class_keys";
+
+/// Rewrites the class and declaration keys stored with the hash as sent by
+/// the gateway, once per DB
+fn migrate_class_keys(db: &DB) -> Result<(), StorageError> {
+    let migrated = normalize_keys(
+        db,
+        CLASS_KEYS_MIGRATED,
+        &[Class::KEY_PREFIX, ClassDeclaration::KEY_PREFIX],
+    )?;
+    if migrated > 0 {
+        tracing::info!("🔧 Normalized {} class hash keys", migrated);
+    }
+    Ok(())
+}
+
+/// Set once the transaction hash keys written before normalization were
+/// rewritten
+const TRANSACTION_KEYS_MIGRATED: &str = "migrated_transaction_keys";
+
+/// Rewrites the transaction keys stored with the hash as sent by the gateway,
+/// once per DB
+fn migrate_transaction_keys(db: &DB) -> Result<(), StorageError> {
+    let migrated = normalize_keys(db, TRANSACTION_KEYS_MIGRATED, &[Transaction::KEY_PREFIX])?;
+    if migrated > 0 {
+        tracing::info!("🔧 Normalized {} transaction hash keys", migrated);
+    }
     Ok(())
 }
 
-pub fn read_data(db: &DB, key: &str) -> Result<Option<String>, String> {
-    let data = db.get(key)?;
-    match data {
-        Some(value) => Ok(Some(String::from_utf8(value).unwrap())),
-        None => Ok(None),
+/// Set once the contract address keys written before normalization were
+/// rewritten
+const CONTRACT_KEYS_MIGRATED: &str = "migrated_contract_keys";
+
+/// Rewrites the contract keys stored with the address as sent by the gateway,
+/// once per DB
+fn migrate_contract_keys(db: &DB) -> Result<(), StorageError> {
+    let migrated = normalize_keys(db, CONTRACT_KEYS_MIGRATED, &[Contract::KEY_PREFIX])?;
+    if migrated > 0 {
+        tracing::info!("🔧 Normalized {} contract address keys", migrated);
     }
+    Ok(())
+}
+
+/// Rewrites the keys of `prefixes` not ending with a normalized hash, unless
+/// `marker` is set, then sets it. Returns the number of keys rewritten
+fn normalize_keys(db: &DB, marker: &str, prefixes: &[&str]) -> Result<u64, StorageError> {
+    if is_key_present(db, marker) {
+        return Ok(0);
+    }
+    let mut migrated = 0;
+    for prefix in prefixes {
+        let keys: Vec<String> = iter_prefix(db, prefix)
+            .filter_map(|(key, _)| String::from_utf8(key.to_vec()).ok())
+            .filter(|key| key[prefix.len()..] != normalize_hash(&key[prefix.len()..]))
+            .collect();
+        for key in keys {
+            if let Some(value) = read_data(db, &key)? {
+                // Written before the old key is deleted, so a crash loses nothing
+                let normalized = format!("{}{}", prefix, normalize_hash(&key[prefix.len()..]));
+                let mut batch = WriteBatch::default();
+                put(db, &mut batch, &normalized, value);
+                db.write(batch)?;
+                db.delete_cf(column_family(db, key.as_bytes()), &key)?;
+                migrated += 1;
+            }
+        }
+    }
+    db.put(marker, "1")?;
+    Ok(migrated)
+}
+
+#[tracing::instrument(skip(db, data))]
+pub fn write_data(db: &DB, key: &str, data: &str) -> Result<(), StorageError> {
+    db.put_cf(column_family(db, key.as_bytes()), key.as_bytes(), data)?;
+    Ok(())
+}
+
+/// Returns the value as stored, it is only checked to be valid UTF-8 when
+/// parsed
+#[tracing::instrument(skip(db))]
+pub fn read_data(db: &DB, key: &str) -> Result<Option<Vec<u8>>, StorageError> {
+    Ok(db.get_cf(column_family(db, key.as_bytes()), key)?)
 }
 
 pub fn is_key_present(db: &DB, key: &str) -> bool {
-    match db.key_may_exist(key) {
-        true => matches!(db.get(key), Ok(Some(_))),
+    let cf = column_family(db, key.as_bytes());
+    match db.key_may_exist_cf(cf, key) {
+        true => matches!(db.get_cf(cf, key), Ok(Some(_))),
         false => false,
     }
 }
+
+/// Iterates over the keys starting with `prefix` and their values, in key
+/// order
+pub fn iter_prefix<'a>(
+    db: &'a DB,
+    prefix: &'a str,
+) -> impl Iterator<Item = (Box<[u8]>, Box<[u8]>)> + 'a {
+    db.prefix_iterator_cf(column_family(db, prefix.as_bytes()), prefix.as_bytes())
+        .map_while(Result::ok)
+        .take_while(move |(key, _)| key.starts_with(prefix.as_bytes()))
+}
+
+/// Iterates over the hashes of the stored classes in key order, starting
+/// right after `after` when given
+#[cfg(feature = "server")]
+pub fn iter_class_hashes<'a>(db: &'a DB, after: Option<&str>) -> impl Iterator<Item = String> + 'a {
+    let prefix = Class::KEY_PREFIX.as_bytes();
+    let start = match after {
+        Some(hash) => format!("{}\0", Class(hash.to_string()).key()),
+        None => Class::KEY_PREFIX.to_string(),
+    };
+    let mode = IteratorMode::From(start.as_bytes(), Direction::Forward);
+    db.iterator_cf(classes(db), mode)
+        .map_while(Result::ok)
+        .take_while(move |(key, _)| key.starts_with(prefix))
+        .filter_map(|(key, _)| String::from_utf8(key[prefix.len()..].to_vec()).ok())
+}
+
+#[derive(Serialize)]
+pub struct GapRange {
+    pub from: u64,
+    pub to: u64,
+}
+
+#[derive(Serialize)]
+pub struct Gaps {
+    /// Highest number stored under the prefix
+    pub highest: Option<u64>,
+    /// Total count of missing numbers below `highest`
+    pub missing: u64,
+    pub ranges: Vec<GapRange>,
+}
+
+/// Scans every `<prefix><number>` key and reports the numbers missing
+/// between 0 and the highest one stored
+pub fn find_gaps(db: &DB, prefix: &str) -> Gaps {
+    let mut numbers: Vec<u64> = iter_prefix(db, prefix)
+        .filter_map(|(key, _)| std::str::from_utf8(&key[prefix.len()..]).ok()?.parse().ok())
+        .collect();
+    numbers.sort_unstable();
+
+    let mut ranges = vec![];
+    let mut missing = 0;
+    let mut expected = 0;
+    for &number in &numbers {
+        if number > expected {
+            ranges.push(GapRange {
+                from: expected,
+                to: number - 1,
+            });
+            missing += number - expected;
+        }
+        expected = number + 1;
+    }
+
+    Gaps {
+        highest: numbers.last().copied(),
+        missing,
+        ranges,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use clap::Parser;
+
+    use crate::config::Config;
+    use crate::primitives::{BlockHash, BlockTimestamp};
+
+    /// A storage in a new directory, with the default options
+    pub(crate) fn temp_storage() -> (tempfile::TempDir, Storage) {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::parse_from(["cache_feeder"]);
+        let storage = Storage::open(&dir.path().to_path_buf(), &config.db, None).unwrap();
+        (dir, storage)
+    }
+
+    fn db_with_blocks(numbers: impl IntoIterator<Item = u64>) -> (tempfile::TempDir, DB) {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::parse_from(["cache_feeder"]);
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+        let db =
+            DB::open_cf_descriptors(&opts, dir.path(), column_families(&opts, &config.db)).unwrap();
+        for number in numbers {
+            db.put(Block(number).key(), "{}").unwrap();
+        }
+        (dir, db)
+    }
+
+    #[test]
+    fn last_contiguous_stops_before_the_first_gap() {
+        let key = |number| Block(number).key();
+        let (_dir, db) = db_with_blocks((0..=9).chain(11..=3000));
+        assert_eq!(last_contiguous(&db, key, None), Some(9));
+        assert_eq!(last_contiguous(&db, key, Some(11)), Some(3000));
+
+        let (_dir, db) = db_with_blocks(0..=2500);
+        assert_eq!(last_contiguous(&db, key, None), Some(2500));
+        assert_eq!(last_contiguous(&db, key, Some(2500)), Some(2500));
+
+        let (_dir, db) = db_with_blocks(1..=5);
+        assert_eq!(last_contiguous(&db, key, None), None);
+    }
+
+    #[test]
+    fn delete_numbered_spans_several_batches() {
+        let (_dir, storage) = temp_storage();
+        for number in 0..DELETE_BATCH * 2 + 10 {
+            write_data(storage.db(), &Block(number).key(), "{}").unwrap();
+            write_data(storage.db(), &State(number).key(), "{}").unwrap();
+        }
+        storage.rescan();
+        assert_eq!(storage.max_block_sync(), Some(Block(DELETE_BATCH * 2 + 9)));
+
+        let deleted = storage
+            .delete_numbered(5, DELETE_BATCH * 2, true, false)
+            .unwrap();
+        assert_eq!(deleted, DELETE_BATCH * 2 - 4);
+        assert_eq!(storage.max_block_sync(), Some(Block(4)));
+        assert_eq!(storage.max_state_sync(), Some(State(DELETE_BATCH * 2 + 9)));
+        let present = |key: String| storage.db().get(key).unwrap().is_some();
+        assert!(!present(Block(DELETE_BATCH).key()));
+        assert!(present(Block(DELETE_BATCH * 2 + 1).key()));
+        assert!(present(State(DELETE_BATCH).key()));
+    }
+
+    #[test]
+    fn delete_numbered_removes_the_index_entries() {
+        let (_dir, storage) = temp_storage();
+        let db = storage.db();
+        for number in 0..3u64 {
+            let block = format!(
+                r#"{{"block_hash":"0xb{n}","timestamp":{n},"transactions":[{{"transaction_hash":"0xa{n}"}}]}}"#,
+                n = number
+            );
+            let state = format!(
+                r#"{{"state_diff":{{"deployed_contracts":[{{"address":"0xc{}","class_hash":"0xd"}}],"declared_classes":[]}}}}"#,
+                number
+            );
+            write_data(db, &Block(number).key(), &block).unwrap();
+            write_data(db, &State(number).key(), &state).unwrap();
+            index::index_block(db, Block(number), block.as_bytes()).unwrap();
+            index::index_state_update(&storage, State(number), state.as_bytes()).unwrap();
+        }
+        storage.rescan();
+
+        // Lowered to the highest number synced
+        let deleted = storage.delete_numbered(1, u64::MAX, true, true).unwrap();
+        assert_eq!(deleted, 4);
+        assert_eq!(storage.max_block_sync(), Some(Block(0)));
+        assert_eq!(storage.max_state_sync(), Some(State(0)));
+        let present = |key: String| db.get(key).unwrap().is_some();
+        assert!(present(BlockTimestamp(0).key()));
+        assert!(!present(BlockTimestamp(2).key()));
+        assert!(!present(BlockHash("0xb1".to_string()).key()));
+        assert!(!present(Transaction("0xa2".to_string()).key()));
+        assert!(!present(Contract("0xc1".to_string()).key()));
+        assert!(present(Contract("0xc0".to_string()).key()));
+        // First declared at 0
+        assert!(present(ClassDeclaration("0xd".to_string()).key()));
+        assert_eq!(header::highest(db).unwrap(), Some(0));
+
+        // A block written past the deleted range does not move the cursor
+        storage.set_max_block_sync(Block(2));
+        assert_eq!(storage.max_block_sync(), Some(Block(0)));
+        storage.set_max_block_sync(Block(1));
+        assert_eq!(storage.max_block_sync(), Some(Block(1)));
+    }
+
+    #[test]
+    fn classes_move_to_their_column_family() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let mut opts = Options::default();
+            opts.create_if_missing(true);
+            let db = DB::open(&opts, dir.path()).unwrap();
+            for hash in ["0x1", "0x2"] {
+                db.put(Class(hash.to_string()).key(), "{}").unwrap();
+            }
+            db.put(Block(0).key(), "{}").unwrap();
+        }
+        let config = Config::parse_from(["cache_feeder"]);
+        let storage = Storage::open(&dir.path().to_path_buf(), &config.db, None).unwrap();
+        let db = storage.db();
+
+        let key = Class("0x1".to_string()).key();
+        assert!(db.get(&key).unwrap().is_none());
+        assert!(db.get_cf(classes(db), &key).unwrap().is_some());
+        assert!(is_key_present(db, &key));
+        assert!(read_data(db, &Block(0).key()).unwrap().is_some());
+        let hashes = |db| {
+            iter_prefix(db, Class::KEY_PREFIX)
+                .map(|(key, _)| String::from_utf8(key.to_vec()).unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(hashes(db), ["class_0x1", "class_0x2"]);
+
+        write_data(db, &Class("0x3".to_string()).key(), "{}").unwrap();
+        assert_eq!(hashes(db).len(), 3);
+    }
+
+    #[test]
+    fn transaction_and_contract_keys_are_normalized() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let mut opts = Options::default();
+            opts.create_if_missing(true);
+            let db = DB::open(&opts, dir.path()).unwrap();
+            db.put("tx_0x0ABC", "{}").unwrap();
+            db.put("contract_0x00de", "{}").unwrap();
+        }
+        let config = Config::parse_from(["cache_feeder"]);
+        let storage = Storage::open(&dir.path().to_path_buf(), &config.db, None).unwrap();
+        let db = storage.db();
+
+        assert!(!is_key_present(db, "tx_0x0ABC"));
+        assert!(is_key_present(db, "tx_0xabc"));
+        assert!(is_key_present(db, &Transaction("0x0abc".to_string()).key()));
+        assert!(is_key_present(db, &Contract("0xDE".to_string()).key()));
+    }
+}
@@ -0,0 +1,159 @@
+//! Large payloads sent in chunks rather than in one piece, gzipped on the fly
+//! when the client accepts it. Each payload is read in full before being
+//! sent, so only `--max-streamed-responses` are sent at once and the requests
+//! past them answered 503, bounding the memory a burst of requests for giant
+//! classes holds
+
+use actix_web::http::header::{
+    EntityTag, ACCEPT_ENCODING, CONTENT_ENCODING, ETAG, RANGE, RETRY_AFTER, VARY,
+};
+use actix_web::{web, HttpRequest, HttpResponse, HttpResponseBuilder};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures_util::stream;
+use std::io::Write;
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Bytes of payload compressed and sent at a time
+const CHUNK: usize = 64 * 1024;
+
+/// The responses being streamed, one permit each until sent or dropped
+pub struct Streams(Arc<Semaphore>);
+
+impl Streams {
+    /// `max` responses at once, `0` for no limit
+    pub fn new(max: usize) -> Streams {
+        Streams(Arc::new(Semaphore::new(match max {
+            0 => Semaphore::MAX_PERMITS,
+            max => max,
+        })))
+    }
+}
+
+/// Whether `content` is sent in chunks rather than in one piece. Range
+/// requests are answered in one piece, the part asked being usually small
+pub fn wanted(req: &HttpRequest, content: &[u8], max_buffered: u64) -> bool {
+    content.len() as u64 > max_buffered && !req.headers().contains_key(RANGE)
+}
+
+/// Sends `content` in chunks, tagged with `etag` when not gzipped. Answers 503
+/// and drops `content` right away when `--max-streamed-responses` are being
+/// sent
+pub fn respond(
+    req: &HttpRequest,
+    mut response: HttpResponseBuilder,
+    content: web::Bytes,
+    etag: EntityTag,
+) -> HttpResponse {
+    let permit = match req.app_data::<web::Data<Streams>>() {
+        Some(streams) => match streams.0.clone().try_acquire_owned() {
+            Ok(permit) => Some(permit),
+            Err(_) => {
+                tracing::warn!("⚠️ Too many large responses being sent, answering 503");
+                return HttpResponse::ServiceUnavailable()
+                    .insert_header((RETRY_AFTER, 1))
+                    .body("Too many large responses being sent");
+            }
+        },
+        None => None,
+    };
+    response.append_header((VARY, "Accept-Encoding"));
+    let encoder = match accepts_gzip(req) {
+        true => {
+            // The gzipped bytes differ from those the tag was computed on
+            response
+                .insert_header((CONTENT_ENCODING, "gzip"))
+                .insert_header((ETAG, EntityTag::new_weak(etag.tag().to_string())));
+            Some(GzEncoder::new(Vec::new(), Compression::fast()))
+        }
+        false => {
+            response.insert_header((ETAG, etag));
+            None
+        }
+    };
+    response.streaming(chunks(content, encoder, permit))
+}
+
+fn accepts_gzip(req: &HttpRequest) -> bool {
+    req.headers()
+        .get_all(ACCEPT_ENCODING)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut parts = coding.split(';').map(str::trim);
+            parts.next() == Some("gzip") && !parts.any(|param| param.replace(' ', "") == "q=0")
+        })
+}
+
+/// The chunks of `content`, holding `permit` until the last one is sent or the
+/// client goes away
+fn chunks(
+    content: web::Bytes,
+    encoder: Option<GzEncoder<Vec<u8>>>,
+    permit: Option<OwnedSemaphorePermit>,
+) -> impl futures_util::Stream<Item = Result<web::Bytes, std::io::Error>> {
+    stream::try_unfold(
+        (content, 0, encoder, permit),
+        |(content, mut offset, mut encoder, permit)| async move {
+            while offset < content.len() {
+                let chunk = content.slice(offset..content.len().min(offset + CHUNK));
+                offset += chunk.len();
+                match encoder {
+                    None => return Ok(Some((chunk, (content, offset, None, permit)))),
+                    Some(ref mut gzip) => {
+                        gzip.write_all(&chunk)?;
+                        let compressed = std::mem::take(gzip.get_mut());
+                        if !compressed.is_empty() {
+                            return Ok(Some((
+                                compressed.into(),
+                                (content, offset, encoder, permit),
+                            )));
+                        }
+                    }
+                }
+            }
+            match encoder {
+                Some(encoder) => Ok(Some((
+                    encoder.finish()?.into(),
+                    (content, offset, None, permit),
+                ))),
+                None => Ok(None),
+            }
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body;
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+
+    #[tokio::test]
+    async fn payloads_past_the_threshold_are_streamed_up_to_the_limit() {
+        let req = TestRequest::default()
+            .app_data(web::Data::new(Streams::new(1)))
+            .to_http_request();
+        let content = web::Bytes::from(vec![b'a'; CHUNK * 2 + 1]);
+        let size = content.len() as u64;
+        assert!(!wanted(&req, &content, size));
+        assert!(wanted(&req, &content, size - 1));
+        let range = TestRequest::default()
+            .insert_header((RANGE, "bytes=0-9"))
+            .to_http_request();
+        assert!(!wanted(&range, &content, 0));
+
+        let etag = EntityTag::new_strong("a".to_string());
+        let respond = || respond(&req, HttpResponse::Ok(), content.clone(), etag.clone());
+        let first = respond();
+        assert_eq!(first.status(), StatusCode::OK);
+        assert_eq!(respond().status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        // The permit is released once the last chunk is sent
+        let sent = body::to_bytes(first.into_body()).await.unwrap();
+        assert_eq!(sent, content);
+        assert_eq!(respond().status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,170 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::task::{JoinHandle, JoinSet};
+
+use crate::config::SyncTuning;
+use crate::journal;
+use crate::storage::Storage;
+#[cfg(feature = "sync")]
+use crate::storage::StorageError;
+
+/// Why a task stopped before reaching its end
+#[derive(Debug, thiserror::Error)]
+pub enum TaskError {
+    #[cfg(feature = "sync")]
+    #[error("writing {key}: {source}")]
+    Write { key: String, source: StorageError },
+    #[error("{0}")]
+    Panicked(String),
+}
+
+/// A summary of what the task did, logged once it is joined
+pub type TaskResult = Result<String, TaskError>;
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskState {
+    Running,
+    /// Waiting to be restarted after a failure
+    Restarting,
+    Stopped,
+    Failed,
+}
+
+#[derive(Clone, Serialize)]
+pub struct TaskStatus {
+    pub state: TaskState,
+    /// Unix time in seconds of the last state change
+    pub since: u64,
+    pub restarts: u64,
+    pub last_error: Option<String>,
+}
+
+/// Runs the long-lived tasks under a name, restarting the failed ones with
+/// a doubling delay until a shutdown is requested
+pub struct Supervisor {
+    running: Arc<AtomicBool>,
+    tuning: SyncTuning,
+    storage: Arc<Storage>,
+    tasks: Mutex<BTreeMap<String, TaskStatus>>,
+}
+
+/// Aborts the supervised task when its supervisor is aborted on shutdown
+struct AbortOnDrop(JoinHandle<TaskResult>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+impl Supervisor {
+    pub fn new(running: Arc<AtomicBool>, tuning: SyncTuning, storage: Arc<Storage>) -> Supervisor {
+        Supervisor {
+            running,
+            tuning,
+            storage,
+            tasks: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    #[cfg(feature = "sync")]
+    pub fn running(&self) -> Arc<AtomicBool> {
+        self.running.clone()
+    }
+
+    #[cfg(feature = "server")]
+    pub fn tasks(&self) -> BTreeMap<String, TaskStatus> {
+        self.tasks.lock().unwrap().clone()
+    }
+
+    fn set_state(&self, name: &str, state: TaskState, error: Option<String>) {
+        let mut tasks = self.tasks.lock().unwrap();
+        let status = tasks.entry(name.to_string()).or_insert(TaskStatus {
+            state,
+            since: 0,
+            restarts: 0,
+            last_error: None,
+        });
+        status.state = state;
+        status.since = now();
+        if let TaskState::Restarting = state {
+            status.restarts += 1;
+        }
+        if error.is_some() {
+            status.last_error = error;
+        }
+    }
+
+    /// Spawns `task` into `set`, the result is labelled with `name` once the
+    /// task stopped for good. Only tasks with `restart` are run again after
+    /// an error or a panic
+    pub fn spawn<F, Fut>(
+        self: &Arc<Self>,
+        set: &mut JoinSet<(String, TaskResult)>,
+        name: impl Into<String>,
+        restart: bool,
+        task: F,
+    ) where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = TaskResult> + Send + 'static,
+    {
+        let supervisor = self.clone();
+        let name = name.into();
+        set.spawn(async move {
+            let mut delay = supervisor.tuning.retry_delay;
+            loop {
+                supervisor.set_state(&name, TaskState::Running, None);
+                let mut handle = AbortOnDrop(tokio::spawn(task()));
+                let result = match (&mut handle.0).await {
+                    Ok(result) => result,
+                    Err(e) => Err(TaskError::Panicked(e.to_string())),
+                };
+
+                let e = match result {
+                    Ok(summary) => {
+                        supervisor.set_state(&name, TaskState::Stopped, None);
+                        return (name, Ok(summary));
+                    }
+                    Err(e) => e,
+                };
+                if !restart || !supervisor.running.load(Ordering::SeqCst) {
+                    supervisor.set_state(&name, TaskState::Failed, Some(e.to_string()));
+                    return (name, Err(e));
+                }
+
+                tracing::error!(
+                    "❌ Task {} failed, restarting in {} sec: {}",
+                    name,
+                    delay,
+                    e
+                );
+                journal::record(
+                    &supervisor.storage,
+                    "task_restarted",
+                    format!("{}: {}", name, e),
+                );
+                supervisor.set_state(&name, TaskState::Restarting, Some(e.to_string()));
+                // Returns early on shutdown, the task is then run once more
+                // to stop cleanly
+                for _ in 0..delay {
+                    if !supervisor.running.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+                delay = (delay * 2).min(supervisor.tuning.max_retry_delay);
+            }
+        });
+    }
+}
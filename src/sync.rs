@@ -0,0 +1,1384 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+
+use crate::class_extract::extract_class_hash;
+use crate::config::{Config, Network, ResyncArgs, SyncArgs, SyncTuning, VerifyUpstreamArgs};
+use crate::gateway::{Fetched, Gateway, GatewayError, GatewayFuture, HttpGateway};
+use crate::index;
+use crate::journal;
+use crate::meta::{block_hash, read_genesis, write_fetched, write_genesis, FetchMeta, Genesis};
+#[cfg(feature = "server")]
+use crate::metrics::Metrics;
+use crate::primitives::{Block, Class, State};
+use crate::reload::Reloadable;
+use crate::storage::{read_data, Storage, StorageError};
+use crate::supervisor::{Supervisor, TaskError, TaskResult};
+use crate::upstream::Upstream;
+
+/// Spawns the block, state update and class sync tasks under `supervisor`,
+/// syncing from the gateway of `check` into its DB once it passed. They stop
+/// once `end` is reached or a shutdown is requested. The task names start
+/// with `prefix`, to tell the networks apart
+pub fn spawn(
+    supervisor: &Arc<Supervisor>,
+    set: &mut JoinSet<(String, TaskResult)>,
+    prefix: &str,
+    end: u64,
+    tuning: SyncTuning,
+    check: &Arc<NetworkCheck>,
+) {
+    let running = supervisor.running();
+    supervisor.spawn(set, format!("{}block", prefix), true, {
+        let (running, check) = (running.clone(), check.clone());
+        move || {
+            after_check(
+                check.clone(),
+                running.clone(),
+                sync_block(
+                    end,
+                    tuning,
+                    running.clone(),
+                    check.storage.clone(),
+                    check.gateway.clone(),
+                ),
+            )
+        }
+    });
+    supervisor.spawn(set, format!("{}state_update", prefix), true, {
+        let (running, check) = (running.clone(), check.clone());
+        move || {
+            after_check(
+                check.clone(),
+                running.clone(),
+                sync_state_update(
+                    end,
+                    tuning,
+                    running.clone(),
+                    check.storage.clone(),
+                    check.gateway.clone(),
+                ),
+            )
+        }
+    });
+    supervisor.spawn(set, format!("{}class", prefix), true, {
+        let check = check.clone();
+        move || {
+            after_check(
+                check.clone(),
+                running.clone(),
+                sync_class(
+                    0,
+                    end,
+                    tuning,
+                    running.clone(),
+                    check.storage.clone(),
+                    check.gateway.clone(),
+                ),
+            )
+        }
+    });
+}
+
+/// Runs `task` once `check` passed, it is not run when the gateway was
+/// refused
+pub async fn after_check(
+    check: Arc<NetworkCheck>,
+    running: Arc<AtomicBool>,
+    task: impl Future<Output = TaskResult>,
+) -> TaskResult {
+    match check.passed(&running).await {
+        Ok(()) => task.await,
+        Err(e) => Ok(format!("Not synced: {}", e)),
+    }
+}
+
+/// Doubles the wait after each failure in a row, up to the configured cap
+struct Backoff {
+    tuning: SyncTuning,
+    delay: u64,
+    failures: u64,
+}
+
+impl Backoff {
+    fn new(tuning: SyncTuning) -> Backoff {
+        Backoff {
+            tuning,
+            delay: tuning.retry_delay,
+            failures: 0,
+        }
+    }
+
+    /// A task is stalled once its retry delay reached the cap
+    fn stalled(&self) -> bool {
+        self.failures > 0 && self.delay == self.tuning.max_retry_delay
+    }
+
+    /// Returns true when the task just became stalled
+    async fn wait(&mut self) -> bool {
+        tokio::time::sleep(Duration::from_secs(self.delay)).await;
+        let stalled = self.stalled();
+        self.failures += 1;
+        self.delay = (self.delay * 2).min(self.tuning.max_retry_delay);
+        !stalled && self.stalled()
+    }
+
+    /// Returns true when the task was stalled
+    fn reset(&mut self) -> bool {
+        let stalled = self.stalled();
+        self.failures = 0;
+        self.delay = self.tuning.retry_delay;
+        stalled
+    }
+}
+
+/// Fetches every item concurrently, the results are in the order of `items`
+async fn fetch_many<T: Send + 'static>(
+    gateway: &Arc<dyn Gateway>,
+    items: Vec<T>,
+    fetch: for<'a> fn(&'a dyn Gateway, T) -> GatewayFuture<'a, Fetched>,
+) -> Vec<Result<Fetched, GatewayError>> {
+    let handles: Vec<_> = items
+        .into_iter()
+        .map(|item| {
+            let gateway = gateway.clone();
+            tokio::spawn(async move { fetch(gateway.as_ref(), item).await })
+        })
+        .collect();
+
+    let mut results = Vec::with_capacity(handles.len());
+    for handle in handles {
+        // A panicking fetch fails the task, for the supervisor to restart it
+        results.push(
+            handle
+                .await
+                .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic())),
+        );
+    }
+    results
+}
+
+/// Polls the latest block number of the gateway, so the sync lag can be
+/// reported
+#[cfg(feature = "server")]
+#[tracing::instrument(skip_all)]
+pub async fn watch_head(
+    tuning: SyncTuning,
+    running: Arc<AtomicBool>,
+    gateway: Arc<dyn Gateway>,
+    metrics: Arc<Metrics>,
+) -> TaskResult {
+    let mut last_poll: Option<Instant> = None;
+    while running.load(Ordering::SeqCst) {
+        if last_poll.is_some_and(|last| last.elapsed().as_secs() < tuning.poll_interval) {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            continue;
+        }
+        last_poll = Some(Instant::now());
+
+        match gateway.latest_block_number().await {
+            Ok(head) => metrics.set_upstream_head(head),
+            Err(e) => tracing::error!("❌ Error fetching the upstream head: {}", e),
+        }
+    }
+
+    Ok("Stopped watching the upstream head".to_string())
+}
+
+/// Compares every `1 / sample_rate`-th block synced from now on with the same
+/// block from the `reference` gateway, logging and journaling divergences.
+/// Stops after `end` like the sync tasks
+#[tracing::instrument(skip_all)]
+pub async fn cross_check(
+    end: u64,
+    tuning: SyncTuning,
+    running: Arc<AtomicBool>,
+    storage: Arc<Storage>,
+    reference: Arc<dyn Gateway>,
+    sample_rate: f64,
+) -> TaskResult {
+    let mut block = storage
+        .max_block_sync()
+        .map_or(Block(0), |block| block.next());
+    let (mut checked, mut diverged) = (0, 0);
+    while running.load(Ordering::SeqCst) && block.0 <= end {
+        if storage.max_block_sync().is_none_or(|max| max.0 < block.0) {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            continue;
+        }
+        if !sampled(block, sample_rate) {
+            block = block.next();
+            continue;
+        }
+
+        let stored = match storage.read(block.key()).await {
+            Ok(Some(content)) => content,
+            Ok(None) => {
+                block = block.next();
+                continue;
+            }
+            Err(e) => {
+                tracing::error!("❌ Error reading block {}: {}", block.0, e);
+                block = block.next();
+                continue;
+            }
+        };
+        match reference.get_block(block).await {
+            Ok(fetched) => {
+                checked += 1;
+                let fields = diverging_fields(&stored, fetched.content.as_bytes());
+                match fields.is_empty() {
+                    true => tracing::debug!("🔍 Block {} matches the reference gateway", block.0),
+                    false => {
+                        diverged += 1;
+                        tracing::error!(
+                            "🚨 Block {} differs from the reference gateway in {}",
+                            block.0,
+                            fields.join(", ")
+                        );
+                        journal::record(
+                            &storage,
+                            "verify_mismatch",
+                            format!("block {}: {}", block, fields.join(", ")),
+                        );
+                    }
+                }
+                block = block.next();
+            }
+            // The reference gateway may be behind, the block is tried again
+            Err(e) => {
+                tracing::warn!(
+                    "❌ Error fetching block {} from the reference gateway: {}",
+                    block.0,
+                    e
+                );
+                for _ in 0..tuning.poll_interval {
+                    if !running.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            }
+        }
+    }
+
+    Ok(format!(
+        "Cross-checked {} blocks, {} diverged",
+        checked, diverged
+    ))
+}
+
+/// Fetches the last `count` synced blocks again and compares them with the
+/// stored ones, journaling divergences like `cross_check`
+#[cfg(feature = "server")]
+pub async fn reverify_tail(
+    storage: &Arc<Storage>,
+    gateway: &dyn Gateway,
+    count: u64,
+) -> Result<String, String> {
+    let Some(last) = storage.max_block_sync() else {
+        return Ok("No block to verify".to_string());
+    };
+    let first = (last.0 + 1).saturating_sub(count);
+    let mut diverged = 0;
+    for number in first..=last.0 {
+        let block = Block(number);
+        let Some(stored) = storage.read(block.key()).await.map_err(|e| e.to_string())? else {
+            continue;
+        };
+        let content = gateway
+            .get_block(block)
+            .await
+            .map_err(|e| format!("block {}: {}", number, e))?
+            .content;
+        let fields = diverging_fields(&stored, content.as_bytes());
+        if !fields.is_empty() {
+            diverged += 1;
+            tracing::error!(
+                "🚨 Block {} differs from the gateway in {}",
+                number,
+                fields.join(", ")
+            );
+            journal::record(
+                storage,
+                "verify_mismatch",
+                format!("block {}: {}", block, fields.join(", ")),
+            );
+        }
+    }
+    Ok(format!(
+        "Re-verified blocks {} to {}, {} diverged",
+        first, last.0, diverged
+    ))
+}
+
+/// Compares the cached blocks of `args`, their state updates and the classes
+/// these reference with the gateway, logging every difference. Nothing is
+/// written, entries not cached are skipped
+#[tracing::instrument(skip_all)]
+pub async fn verify_upstream(
+    config: &Config,
+    storage: &Arc<Storage>,
+    args: &VerifyUpstreamArgs,
+) -> Result<(), String> {
+    let gateway = HttpGateway::new(
+        Arc::new(Upstream::new(config).map_err(|e| e.to_string())?),
+        Arc::new(Reloadable::new(config)),
+        config.network.sync_tuning().retry_delay,
+    );
+    let Some(to) = args.to.or(storage.max_block_sync().map(|block| block.0)) else {
+        return Err("no block synced".to_string());
+    };
+
+    let (mut compared, mut mismatches) = (0, 0);
+    let mut class_hashes = std::collections::BTreeSet::new();
+    let mut compare = |name: String, stored: &[u8], content: &str| {
+        compared += 1;
+        let fields = diverging_fields(stored, content.as_bytes());
+        if !fields.is_empty() {
+            mismatches += 1;
+            tracing::error!(
+                "🚨 {} differs from the gateway in {}",
+                name,
+                fields.join(", ")
+            );
+        }
+    };
+    for number in (args.from..=to).filter(|number| sampled(Block(*number), args.sample_rate)) {
+        let (block, state) = (Block(number), State(number));
+        if let Some(stored) = storage.read(block.key()).await.map_err(|e| e.to_string())? {
+            let content = gateway
+                .get_block(block)
+                .await
+                .map_err(|e| format!("block {}: {}", number, e))?
+                .content;
+            compare(format!("Block {}", number), &stored, &content);
+        }
+        if let Some(stored) = storage.read(state.key()).await.map_err(|e| e.to_string())? {
+            let content = gateway
+                .get_state_update(state)
+                .await
+                .map_err(|e| format!("state update {}: {}", number, e))?
+                .content;
+            compare(format!("State update {}", number), &stored, &content);
+            class_hashes.extend(extract_class_hash(&stored).map_err(|e| e.to_string())?);
+        }
+    }
+    for hash in class_hashes {
+        if let Some(stored) = storage
+            .read(Class(hash.clone()).key())
+            .await
+            .map_err(|e| e.to_string())?
+        {
+            let content = gateway
+                .get_class(&hash)
+                .await
+                .map_err(|e| format!("class {}: {}", hash, e))?
+                .content;
+            compare(format!("Class {}", hash), &stored, &content);
+        }
+    }
+
+    match mismatches {
+        0 => {
+            tracing::info!("✅ {} entries match the gateway", compared);
+            Ok(())
+        }
+        mismatches => Err(format!("{} of {} entries differ", mismatches, compared)),
+    }
+}
+
+/// Multiple of the gateway head past which `--max-block-to-sync` looks meant
+/// for another network
+const PLAUSIBLE_HEAD_FACTOR: u64 = 10;
+
+/// Why a gateway is refused by the network check
+#[derive(Debug, thiserror::Error)]
+pub enum NetworkCheckError {
+    #[error("{upstream} serves a genesis block without a hash")]
+    NoHash { upstream: String },
+    #[error("{upstream} serves the genesis block {hash}, {network} starts with {expected}")]
+    OtherNetwork {
+        upstream: String,
+        hash: String,
+        network: &'static str,
+        expected: &'static str,
+    },
+    #[error("{upstream} serves the genesis block {hash}, the DB holds the chain of {recorded} synced from {recorded_from}")]
+    OtherChain {
+        upstream: String,
+        hash: String,
+        recorded: String,
+        recorded_from: String,
+    },
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+    #[error("stopped before the gateway answered")]
+    Stopped,
+}
+
+/// Refuses a gateway whose block 0 is not the genesis of its network, or not
+/// the one the DB was first synced with, so one chain is never written into
+/// the DB of another. Run by the first sync task about to write and awaited
+/// by the others, the server starts whatever the gateway
+pub struct NetworkCheck {
+    network: Network,
+    tuning: SyncTuning,
+    max_block_to_sync: u64,
+    /// Embedded and replayed gateways, and `--skip-network-check`
+    skip: bool,
+    storage: Arc<Storage>,
+    gateway: Arc<dyn Gateway>,
+    /// Given the genesis once checked, for the reloaded URLs to serve it too
+    reloadable: Arc<Reloadable>,
+    /// Genesis recorded by an earlier start, compared on the first answer
+    recorded: Option<String>,
+    outcome: tokio::sync::OnceCell<Result<(), Arc<NetworkCheckError>>>,
+}
+
+impl NetworkCheck {
+    pub fn new(
+        network: Network,
+        args: &SyncArgs,
+        skip: bool,
+        storage: Arc<Storage>,
+        gateway: Arc<dyn Gateway>,
+        reloadable: Arc<Reloadable>,
+    ) -> NetworkCheck {
+        let skip = skip || args.skip_network_check;
+        let recorded = match skip {
+            true => None,
+            false => read_genesis(storage.db())
+                .unwrap_or_else(|e| {
+                    tracing::error!("❌ Error reading the recorded genesis: {}", e);
+                    None
+                })
+                .map(|recorded| recorded.block_hash),
+        };
+        // Checked on reload before the gateway answered
+        if let Some(recorded) = &recorded {
+            reloadable.set_genesis(recorded.clone());
+        }
+        NetworkCheck {
+            network,
+            tuning: args.tuning.resolve(network),
+            max_block_to_sync: args.max_block_to_sync,
+            skip,
+            storage,
+            gateway,
+            reloadable,
+            recorded,
+            outcome: tokio::sync::OnceCell::new(),
+        }
+    }
+
+    /// Waits for the check, running it if no other task did
+    pub async fn passed(&self, running: &AtomicBool) -> Result<(), Arc<NetworkCheckError>> {
+        self.outcome
+            .get_or_init(|| async {
+                if self.skip {
+                    return Ok(());
+                }
+                let outcome = self.run(running).await;
+                match &outcome {
+                    Err(NetworkCheckError::Stopped) | Ok(()) => {}
+                    Err(e) => {
+                        tracing::error!("❌ Not syncing {}: {}", self.network.name(), e);
+                        journal::record(&self.storage, "network_refused", e.to_string());
+                    }
+                }
+                outcome.map_err(Arc::new)
+            })
+            .await
+            .clone()
+    }
+
+    /// Why the gateway was refused, if it was
+    pub fn refused(&self) -> Option<Arc<NetworkCheckError>> {
+        match self.outcome.get() {
+            Some(Err(e)) if !matches!(e.as_ref(), NetworkCheckError::Stopped) => Some(e.clone()),
+            _ => None,
+        }
+    }
+
+    /// Retries a gateway not answering with the sync backoff until a
+    /// shutdown. The genesis is recorded on the first check, from the block 0
+    /// stored by older versions if any. Warns when `max_block_to_sync` is far
+    /// beyond the head
+    async fn run(&self, running: &AtomicBool) -> Result<(), NetworkCheckError> {
+        let (network, gateway) = (self.network, self.gateway.as_ref());
+        let mut delay = self.tuning.retry_delay;
+        let genesis = loop {
+            match gateway.get_block(Block(0)).await {
+                Ok(fetched) => break fetched.content,
+                Err(e) => {
+                    match &self.recorded {
+                        Some(recorded) => tracing::warn!(
+                            "⚠️ Could not read the genesis of {} to compare it with the recorded {}, nothing is synced until then, retrying in {} sec: {}",
+                            network.name(),
+                            recorded,
+                            delay,
+                            e
+                        ),
+                        None => tracing::warn!(
+                            "⚠️ Could not read the genesis of {} to check it, retrying in {} sec: {}",
+                            network.name(),
+                            delay,
+                            e
+                        ),
+                    }
+                    for _ in 0..delay {
+                        if !running.load(Ordering::SeqCst) {
+                            return Err(NetworkCheckError::Stopped);
+                        }
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                    }
+                    delay = (delay * 2).min(self.tuning.max_retry_delay);
+                }
+            }
+        };
+        let hash = block_hash(genesis.as_bytes()).ok_or(NetworkCheckError::NoHash {
+            upstream: gateway.name(),
+        })?;
+        if hash != network.genesis_hash() {
+            return Err(NetworkCheckError::OtherNetwork {
+                upstream: gateway.name(),
+                hash,
+                network: network.name(),
+                expected: network.genesis_hash(),
+            });
+        }
+
+        let db = self.storage.db();
+        let recorded = match read_genesis(db)? {
+            Some(recorded) => recorded,
+            None => {
+                let stored =
+                    read_data(db, &Block(0).key())?.and_then(|content| block_hash(&content));
+                let recorded = Genesis::new(stored.unwrap_or(hash.clone()), gateway.name());
+                write_genesis(db, &recorded)?;
+                tracing::info!("🧬 Recorded the genesis {}", recorded.block_hash);
+                recorded
+            }
+        };
+        if hash != recorded.block_hash {
+            return Err(NetworkCheckError::OtherChain {
+                upstream: gateway.name(),
+                hash,
+                recorded: recorded.block_hash,
+                recorded_from: recorded.upstream,
+            });
+        }
+        tracing::info!(
+            "🧬 {} serves the genesis of {}",
+            gateway.name(),
+            network.name()
+        );
+        self.reloadable.set_genesis(hash);
+
+        match gateway.latest_block_number().await {
+            Ok(head) if self.max_block_to_sync > head.saturating_mul(PLAUSIBLE_HEAD_FACTOR) => {
+                tracing::warn!(
+                    "⚠️ --max-block-to-sync {} is far beyond the head of {}, block {}",
+                    self.max_block_to_sync,
+                    network.name(),
+                    head
+                );
+            }
+            Ok(_) => {}
+            Err(e) => tracing::warn!("⚠️ Could not read the head of {}: {}", network.name(), e),
+        }
+        Ok(())
+    }
+}
+
+/// The content the gateway answered, with its metadata
+fn with_meta(gateway: &dyn Gateway, fetched: Fetched) -> (String, FetchMeta) {
+    let meta =
+        FetchMeta::new(gateway.name(), 200, fetched.content.len()).with_headers(fetched.headers);
+    (fetched.content, meta)
+}
+
+/// Spreads the sampled blocks evenly, the same ones on every run
+fn sampled(block: Block, sample_rate: f64) -> bool {
+    (block.0 as f64 * sample_rate).floor() != ((block.0 + 1) as f64 * sample_rate).floor()
+}
+
+/// The top-level fields differing once both payloads are parsed
+fn diverging_fields(stored: &[u8], reference: &[u8]) -> Vec<String> {
+    let (stored, reference) = match (
+        serde_json::from_slice::<serde_json::Value>(stored),
+        serde_json::from_slice::<serde_json::Value>(reference),
+    ) {
+        (Ok(stored), Ok(reference)) => (stored, reference),
+        _ => return vec!["invalid JSON".to_string()],
+    };
+    match (stored.as_object(), reference.as_object()) {
+        (Some(stored), Some(reference)) => {
+            let mut fields: Vec<String> = stored
+                .keys()
+                .chain(reference.keys().filter(|key| !stored.contains_key(*key)))
+                .filter(|key| stored.get(*key) != reference.get(*key))
+                .cloned()
+                .collect();
+            fields.sort();
+            fields
+        }
+        _ if stored == reference => vec![],
+        _ => vec!["content".to_string()],
+    }
+}
+
+/// Fetches the blocks of `args` again and overwrites the stored ones, the
+/// sync cursors are left as they are. Stops at the first failure
+#[tracing::instrument(skip_all)]
+pub async fn resync(
+    config: &Config,
+    storage: &Arc<Storage>,
+    args: &ResyncArgs,
+) -> Result<(), String> {
+    let tuning = config.network.sync_tuning();
+    let gateway: Arc<dyn Gateway> = Arc::new(HttpGateway::new(
+        Arc::new(Upstream::new(config).map_err(|e| e.to_string())?),
+        Arc::new(Reloadable::new(config)),
+        tuning.retry_delay,
+    ));
+    let (blocks, states, classes) = args.selected();
+    let numbers: Vec<u64> = (args.from..=args.to).collect();
+
+    for batch in numbers.chunks(tuning.block_workers) {
+        if blocks {
+            let items = batch.iter().map(|number| Block(*number)).collect();
+            let fetched = fetch_many(&gateway, items, |gateway, block| gateway.get_block(block));
+            for (number, result) in batch.iter().zip(fetched.await) {
+                let block = Block(*number);
+                let fetched = result.map_err(|e| format!("block {}: {}", number, e))?;
+                let (content, meta) = with_meta(gateway.as_ref(), fetched);
+                storage
+                    .blocking(move |storage| {
+                        write_fetched(storage.db(), &block.key(), &content, &meta)?;
+                        index::index_block(storage.db(), block, content.as_bytes()).map(|_| ())
+                    })
+                    .await
+                    .map_err(|e| format!("block {}: {}", number, e))?;
+                tracing::info!("🔁 Resynced block {}", number);
+            }
+        }
+        if states {
+            let items = batch.iter().map(|number| State(*number)).collect();
+            let fetched = fetch_many(&gateway, items, |gateway, state| {
+                gateway.get_state_update(state)
+            });
+            for (number, result) in batch.iter().zip(fetched.await) {
+                let state = State(*number);
+                let fetched = result.map_err(|e| format!("state update {}: {}", number, e))?;
+                let (content, meta) = with_meta(gateway.as_ref(), fetched);
+                storage
+                    .blocking(move |storage| {
+                        write_fetched(storage.db(), &state.key(), &content, &meta)?;
+                        index::index_state_update(storage, state, content.as_bytes())
+                    })
+                    .await
+                    .map_err(|e| format!("state update {}: {}", number, e))?;
+                tracing::info!("🔁 Resynced state update {}", number);
+            }
+        }
+    }
+
+    let mut class_hashes = std::collections::BTreeSet::new();
+    if classes {
+        for number in &numbers {
+            let state = State(*number);
+            let state_update = read_data(storage.db(), &state.key())
+                .map_err(|e| e.to_string())?
+                .ok_or(format!("state update {} not synced", number))?;
+            class_hashes.extend(extract_class_hash(&state_update).map_err(|e| e.to_string())?);
+        }
+    }
+    let class_hashes: Vec<String> = class_hashes.into_iter().collect();
+    for batch in class_hashes.chunks(tuning.class_workers) {
+        let fetched = fetch_many(&gateway, batch.to_vec(), |gateway, hash| {
+            gateway.get_class(&hash)
+        });
+        for (hash, result) in batch.iter().zip(fetched.await) {
+            let fetched = result.map_err(|e| format!("class {}: {}", hash, e))?;
+            let key = Class(hash.clone()).key();
+            let (content, meta) = with_meta(gateway.as_ref(), fetched);
+            storage
+                .blocking(move |storage| write_fetched(storage.db(), &key, &content, &meta))
+                .await
+                .map_err(|e| format!("class {}: {}", hash, e))?;
+            tracing::info!("🔁 Resynced class {}", hash);
+        }
+    }
+
+    let mut resynced = vec![];
+    if blocks {
+        resynced.push("blocks".to_string());
+    }
+    if states {
+        resynced.push("state updates".to_string());
+    }
+    if classes {
+        resynced.push(format!("{} classes", class_hashes.len()));
+    }
+    journal::record(
+        storage,
+        "resynced",
+        format!(
+            "{} of blocks {} to {}",
+            resynced.join(", "),
+            args.from,
+            args.to
+        ),
+    );
+    tracing::info!("🔁 Resynced blocks {} to {}", args.from, args.to);
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+async fn sync_block(
+    end: u64,
+    tuning: SyncTuning,
+    running: Arc<AtomicBool>,
+    storage: Arc<Storage>,
+    gateway: Arc<dyn Gateway>,
+) -> TaskResult {
+    let start = match storage.max_block_sync() {
+        Some(block) => block.next(),
+        None => Block(0),
+    };
+
+    if start.0 > end {
+        return Ok("No block to sync".to_string());
+    }
+
+    let mut block = start;
+    let mut backoff = Backoff::new(tuning);
+    loop {
+        // Check if a graceful shutdown was requested or sync is finished
+        if !running.load(Ordering::SeqCst) || block.0 > end {
+            break;
+        }
+
+        let batch: Vec<Block> = (block.0..=end)
+            .take(tuning.block_workers)
+            .map(Block)
+            .collect();
+        let started = Instant::now();
+        // Blocks are written in order, stopping at the first failed fetch
+        for (fetched, result) in batch
+            .clone()
+            .into_iter()
+            .zip(fetch_many(&gateway, batch, |gateway, block| gateway.get_block(block)).await)
+        {
+            match result {
+                Ok(entry) => {
+                    let (content, meta) = with_meta(gateway.as_ref(), entry);
+                    match storage
+                        .blocking(move |storage| {
+                            write_fetched(storage.db(), &fetched.key(), &content, &meta)?;
+                            let indexed =
+                                index::index_block(storage.db(), fetched, content.as_bytes());
+                            storage.set_max_block_sync(fetched);
+                            Ok(indexed)
+                        })
+                        .await
+                    {
+                        Ok(indexed) => {
+                            tracing::info!(
+                                task = "block",
+                                block_number = fetched.0,
+                                duration_ms = started.elapsed().as_millis() as u64,
+                                "📦 Fetched block {}",
+                                fetched.0
+                            );
+                            if let Err(e) = indexed {
+                                tracing::error!("❌ Error indexing block {}: {}", fetched.0, e);
+                            }
+                            block = fetched.next();
+                            if backoff.reset() {
+                                journal::record(
+                                    &storage,
+                                    "sync_resumed",
+                                    format!("block {}", fetched),
+                                );
+                            }
+                        }
+                        Err(source) => {
+                            return Err(TaskError::Write {
+                                key: fetched.key(),
+                                source,
+                            });
+                        }
+                    }
+                }
+                // Past the head, polled again rather than backed off
+                Err(GatewayError::NotFound) => {
+                    tracing::info!(
+                        "📭 Block {} not produced yet, 💤 waiting {} sec",
+                        fetched.0,
+                        tuning.poll_interval
+                    );
+                    tokio::time::sleep(Duration::from_secs(tuning.poll_interval)).await;
+                    break;
+                }
+                Err(e) => {
+                    tracing::error!(
+                        task = "block",
+                        block_number = fetched.0,
+                        "❌ Error fetching block {}: {}",
+                        fetched.0,
+                        e
+                    );
+                    if backoff.wait().await {
+                        journal::record(
+                            &storage,
+                            "sync_stalled",
+                            format!("block {}: {}", fetched, e),
+                        );
+                    }
+                    break;
+                }
+            }
+        }
+    }
+
+    Ok(format!("Synched block {} to {}", start.0, block.0))
+}
+
+#[tracing::instrument(skip_all)]
+async fn sync_state_update(
+    end: u64,
+    tuning: SyncTuning,
+    running: Arc<AtomicBool>,
+    storage: Arc<Storage>,
+    gateway: Arc<dyn Gateway>,
+) -> TaskResult {
+    let start = match storage.max_state_sync() {
+        Some(state) => state.next(),
+        None => State(0),
+    };
+
+    if start.0 > end {
+        return Ok("No state update to sync".to_string());
+    }
+
+    let mut state = start;
+    let mut backoff = Backoff::new(tuning);
+    loop {
+        // Check if a graceful shutdown was requested or sync is finished
+        if !running.load(Ordering::SeqCst) || state.0 > end {
+            break;
+        }
+
+        let batch: Vec<State> = (state.0..=end)
+            .take(tuning.block_workers)
+            .map(State)
+            .collect();
+        let started = Instant::now();
+        // State updates are written in order, stopping at the first failed fetch
+        for (fetched, result) in batch.clone().into_iter().zip(
+            fetch_many(&gateway, batch, |gateway, state| {
+                gateway.get_state_update(state)
+            })
+            .await,
+        ) {
+            match result {
+                Ok(entry) => {
+                    let (content, meta) = with_meta(gateway.as_ref(), entry);
+                    match storage
+                        .blocking(move |storage| {
+                            write_fetched(storage.db(), &fetched.key(), &content, &meta)?;
+                            let indexed =
+                                index::index_state_update(storage, fetched, content.as_bytes());
+                            storage.set_max_state_sync(fetched);
+                            Ok(indexed)
+                        })
+                        .await
+                    {
+                        Ok(indexed) => {
+                            tracing::info!(
+                                task = "state_update",
+                                block_number = fetched.0,
+                                duration_ms = started.elapsed().as_millis() as u64,
+                                "📦 Fetched state update {}",
+                                fetched.0
+                            );
+                            if let Err(e) = indexed {
+                                tracing::error!(
+                                    "❌ Error indexing state update {}: {}",
+                                    fetched.0,
+                                    e
+                                );
+                            }
+                            state = fetched.next();
+                            if backoff.reset() {
+                                journal::record(
+                                    &storage,
+                                    "sync_resumed",
+                                    format!("state update {}", fetched),
+                                );
+                            }
+                        }
+                        Err(source) => {
+                            return Err(TaskError::Write {
+                                key: fetched.key(),
+                                source,
+                            });
+                        }
+                    }
+                }
+                // Past the head, polled again rather than backed off
+                Err(GatewayError::NotFound) => {
+                    tracing::info!(
+                        "📭 State update {} not produced yet, 💤 waiting {} sec",
+                        fetched.0,
+                        tuning.poll_interval
+                    );
+                    tokio::time::sleep(Duration::from_secs(tuning.poll_interval)).await;
+                    break;
+                }
+                Err(e) => {
+                    tracing::error!(
+                        task = "state_update",
+                        block_number = fetched.0,
+                        "❌ Error fetching state update {}: {}",
+                        fetched.0,
+                        e
+                    );
+                    if backoff.wait().await {
+                        journal::record(
+                            &storage,
+                            "sync_stalled",
+                            format!("state update {}: {}", fetched, e),
+                        );
+                    }
+                    break;
+                }
+            }
+        }
+    }
+
+    Ok(format!("Synched state update {} to {}", start.0, state.0))
+}
+
+#[tracing::instrument(skip_all)]
+async fn sync_class(
+    start: u64,
+    end: u64,
+    tuning: SyncTuning,
+    running: Arc<AtomicBool>,
+    storage: Arc<Storage>,
+    gateway: Arc<dyn Gateway>,
+) -> TaskResult {
+    let mut state = State(start);
+    loop {
+        // Check if a graceful shutdown was requested
+        if !running.load(Ordering::SeqCst) || state.0 > end {
+            break;
+        }
+
+        let state_update = match storage.read(state.key()).await {
+            Ok(state_update) => match state_update {
+                Some(state_update) => state_update,
+                None => {
+                    tracing::info!(
+                        "💾 State update {} not found, 💤 waiting {} sec",
+                        state,
+                        tuning.poll_interval
+                    );
+                    tokio::time::sleep(Duration::from_secs(tuning.poll_interval)).await;
+                    continue;
+                }
+            },
+            Err(e) => {
+                tracing::error!("❌ Error reading state update {}: {}", state, e);
+                tokio::time::sleep(Duration::from_secs(tuning.retry_delay)).await;
+                continue;
+            }
+        };
+
+        let class_hashes = match extract_class_hash(&state_update) {
+            Ok(class_hashes) => class_hashes,
+            Err(e) => {
+                tracing::error!(
+                    "❌ Error extracting class hashes from state update {}: {}",
+                    state,
+                    e
+                );
+                continue;
+            }
+        };
+
+        state = state.next();
+
+        fetch_classes(class_hashes, tuning, &storage, &gateway).await;
+    }
+
+    Ok(format!("Synched class from block {} to {}", start, end))
+}
+
+/// Seeded classes looked up at once, a shutdown is checked between batches
+const SEED_BATCH: usize = 100;
+
+/// Classes fetched from a `--class-seed-file`, whether or not a synced state
+/// update references them
+#[tracing::instrument(skip_all)]
+pub async fn seed_classes(
+    class_hashes: Vec<String>,
+    tuning: SyncTuning,
+    running: Arc<AtomicBool>,
+    storage: Arc<Storage>,
+    gateway: Arc<dyn Gateway>,
+) -> TaskResult {
+    let mut stored = 0;
+    for batch in class_hashes.chunks(SEED_BATCH) {
+        if !running.load(Ordering::SeqCst) {
+            break;
+        }
+        stored += fetch_classes(batch.to_vec(), tuning, &storage, &gateway).await;
+    }
+    Ok(format!(
+        "Seeded {} classes of {} listed",
+        stored,
+        class_hashes.len()
+    ))
+}
+
+/// Fetches and stores the classes of `class_hashes` missing from the DB,
+/// returns how many were stored
+async fn fetch_classes(
+    class_hashes: Vec<String>,
+    tuning: SyncTuning,
+    storage: &Arc<Storage>,
+    gateway: &Arc<dyn Gateway>,
+) -> usize {
+    let missing: Vec<String> = storage
+        .blocking(move |storage| {
+            let keys: Vec<String> = class_hashes
+                .iter()
+                .map(|hash| Class(hash.to_string()).key())
+                .collect();
+            match storage.multi_get(&keys) {
+                Ok(stored) => class_hashes
+                    .into_iter()
+                    .zip(stored)
+                    .filter_map(|(hash, stored)| stored.is_none().then_some(hash))
+                    .collect(),
+                // Fetched again rather than skipped
+                Err(e) => {
+                    tracing::error!("❌ Error reading classes: {}", e);
+                    class_hashes
+                }
+            }
+        })
+        .await;
+    let mut stored = 0;
+    for batch in missing.chunks(tuning.class_workers) {
+        let started = Instant::now();
+        let fetched = fetch_many(gateway, batch.to_vec(), |gateway, hash| {
+            gateway.get_class(&hash)
+        })
+        .await;
+        for (hash, result) in batch.iter().zip(fetched) {
+            let class = Class(hash.to_string());
+            match result {
+                Ok(fetched) => match storage
+                    .blocking({
+                        let key = class.key();
+                        let (content, meta) = with_meta(gateway.as_ref(), fetched);
+                        move |storage| write_fetched(storage.db(), &key, &content, &meta)
+                    })
+                    .await
+                {
+                    Ok(_) => {
+                        stored += 1;
+                        tracing::info!(
+                            task = "class",
+                            class_hash = hash.as_str(),
+                            duration_ms = started.elapsed().as_millis() as u64,
+                            "📦 Fetched class {}",
+                            hash
+                        );
+                    }
+                    Err(e) => {
+                        tracing::error!("❌ Error writing to DB {}: {}", &class.key(), e);
+                        journal::record(storage, "class_skipped", format!("class {}: {}", hash, e));
+                    }
+                },
+                Err(GatewayError::Blocked) => {
+                    tracing::debug!("🚫 Skipping blocked class {}", hash);
+                }
+                Err(e) => {
+                    tracing::error!(
+                        task = "class",
+                        class_hash = hash.as_str(),
+                        "❌ Error fetching class {}: {}",
+                        hash,
+                        e
+                    );
+                    // The class is not retried until the next start
+                    journal::record(storage, "class_skipped", format!("class {}: {}", hash, e));
+                }
+            }
+        }
+    }
+    stored
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    use crate::fixture;
+    use crate::gateway::{BlockedGateway, MockGateway};
+    use crate::maintenance::Entry;
+    use crate::primitives::Meta;
+    use crate::storage::tests::temp_storage;
+    use crate::storage::write_data;
+
+    /// No wait between polls, so a test never sleeps
+    const TUNING: SyncTuning = SyncTuning {
+        block_workers: 4,
+        class_workers: 4,
+        poll_interval: 0,
+        retry_delay: 0,
+        max_retry_delay: 0,
+    };
+
+    fn block(number: u64) -> String {
+        format!(
+            r#"{{"block_number":{},"block_hash":"0x{:x}","transactions":[]}}"#,
+            number,
+            number + 0x100
+        )
+    }
+
+    fn state_update(declared: &str, deployed: &str) -> String {
+        format!(
+            r#"{{"state_diff":{{"declared_classes":[{{"class_hash":"{}"}}],"deployed_contracts":[{{"class_hash":"{}"}}]}}}}"#,
+            declared, deployed
+        )
+    }
+
+    fn stored(storage: &Storage, key: String) -> Option<String> {
+        read_data(storage.db(), &key)
+            .unwrap()
+            .map(|content| String::from_utf8(content).unwrap())
+    }
+
+    #[tokio::test]
+    async fn sync_block_and_state_update_store_every_entry() {
+        let (_dir, storage) = temp_storage();
+        let storage = Arc::new(storage);
+        let gateway = MockGateway::default();
+        for number in 0..10 {
+            gateway.insert_block(number, block(number));
+            gateway.insert_state_update(number, state_update("0xa", "0xb"));
+        }
+        let gateway: Arc<dyn Gateway> = Arc::new(gateway);
+        let running = Arc::new(AtomicBool::new(true));
+
+        sync_block(9, TUNING, running.clone(), storage.clone(), gateway.clone())
+            .await
+            .unwrap();
+        sync_state_update(9, TUNING, running, storage.clone(), gateway)
+            .await
+            .unwrap();
+
+        assert_eq!(storage.max_block_sync(), Some(Block(9)));
+        assert_eq!(storage.max_state_sync(), Some(State(9)));
+        assert_eq!(stored(&storage, Block(3).key()), Some(block(3)));
+        assert_eq!(
+            stored(&storage, State(9).key()),
+            Some(state_update("0xa", "0xb"))
+        );
+        assert!(stored(&storage, Meta(Block(3).key()).key()).is_some());
+    }
+
+    #[tokio::test]
+    async fn sync_block_polls_the_head_until_produced() {
+        let (_dir, storage) = temp_storage();
+        let storage = Arc::new(storage);
+        let gateway = Arc::new(MockGateway::default());
+        for number in 0..3 {
+            gateway.insert_block(number, block(number));
+        }
+        let running = Arc::new(AtomicBool::new(true));
+        let task = tokio::spawn(sync_block(
+            5,
+            TUNING,
+            running,
+            storage.clone(),
+            gateway.clone(),
+        ));
+
+        while storage.max_block_sync() != Some(Block(2)) {
+            tokio::task::yield_now().await;
+        }
+        // Blocks 3 to 5 answer `NotFound`, the task keeps polling
+        tokio::task::yield_now().await;
+        assert!(!task.is_finished());
+        assert_eq!(stored(&storage, Block(3).key()), None);
+
+        for number in 3..6 {
+            gateway.insert_block(number, block(number));
+        }
+        tokio::time::timeout(Duration::from_secs(10), task)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(storage.max_block_sync(), Some(Block(5)));
+        assert_eq!(stored(&storage, Block(5).key()), Some(block(5)));
+    }
+
+    #[tokio::test]
+    async fn sync_class_skips_the_blocked_classes() {
+        let (_dir, storage) = temp_storage();
+        let storage = Arc::new(storage);
+        write_data(storage.db(), &State(0).key(), &state_update("0xa", "0xB")).unwrap();
+        let gateway = MockGateway::default();
+        gateway.insert_class("0xa", r#"{"abi":"a"}"#);
+        gateway.insert_class("0xb", r#"{"abi":"b"}"#);
+        let gateway: Arc<dyn Gateway> = Arc::new(BlockedGateway::new(
+            Arc::new(gateway),
+            &["0x0b".to_string()],
+        ));
+
+        sync_class(
+            0,
+            0,
+            TUNING,
+            Arc::new(AtomicBool::new(true)),
+            storage.clone(),
+            gateway,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            stored(&storage, Class("0xa".to_string()).key()),
+            Some(r#"{"abi":"a"}"#.to_string())
+        );
+        assert_eq!(stored(&storage, Class("0xb".to_string()).key()), None);
+    }
+
+    #[tokio::test]
+    async fn a_recorded_fixture_syncs_into_the_same_entries() {
+        let source = MockGateway::default();
+        for number in 0..4 {
+            source.insert_block(number, block(number));
+            let declared = format!("0x{:x}", 0xa0 + number);
+            source.insert_state_update(number, state_update(&declared, "0xb"));
+            source.insert_class(declared, format!(r#"{{"abi":"{}"}}"#, number));
+        }
+        source.insert_class("0xb", r#"{"abi":"b"}"#);
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let mut output = std::fs::File::create(&path).unwrap();
+        let classes = fixture::write_fixture(&source, 0, 3, &mut output)
+            .await
+            .unwrap();
+        assert_eq!(classes, 5);
+
+        let (_dir, storage) = temp_storage();
+        let storage = Arc::new(storage);
+        let gateway: Arc<dyn Gateway> = Arc::new(fixture::load(&path).unwrap());
+        let running = Arc::new(AtomicBool::new(true));
+        sync_block(3, TUNING, running.clone(), storage.clone(), gateway.clone())
+            .await
+            .unwrap();
+        sync_state_update(3, TUNING, running.clone(), storage.clone(), gateway.clone())
+            .await
+            .unwrap();
+        sync_class(0, 3, TUNING, running, storage.clone(), gateway)
+            .await
+            .unwrap();
+
+        let recorded: BTreeMap<String, String> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let entry: Entry = serde_json::from_str(line).unwrap();
+                (entry.key, entry.value)
+            })
+            .collect();
+        let synced: BTreeMap<String, String> = storage
+            .db()
+            .iterator(rocksdb::IteratorMode::Start)
+            .chain(storage.db().iterator_cf(
+                crate::storage::classes(storage.db()),
+                rocksdb::IteratorMode::Start,
+            ))
+            .map(|item| {
+                let (key, value) = item.unwrap();
+                let key = String::from_utf8(key.to_vec()).unwrap();
+                (key, String::from_utf8(value.to_vec()).unwrap())
+            })
+            .filter(|(key, _)| {
+                [Block::KEY_PREFIX, State::KEY_PREFIX, Class::KEY_PREFIX]
+                    .iter()
+                    .any(|prefix| key.starts_with(prefix))
+            })
+            .collect();
+        assert_eq!(recorded.len(), 13);
+        assert_eq!(synced, recorded);
+    }
+
+    #[tokio::test]
+    async fn network_check_waits_for_the_genesis_and_refuses_another_chain() {
+        use clap::Parser;
+
+        let (_dir, storage) = temp_storage();
+        let storage = Arc::new(storage);
+        let config = Config::parse_from(["cache_feeder", "sync"]);
+        let args = config.sync_args().unwrap();
+        let check = |gateway: &Arc<MockGateway>, skip| {
+            NetworkCheck::new(
+                Network::Mainnet,
+                args,
+                skip,
+                storage.clone(),
+                gateway.clone(),
+                Arc::new(Reloadable::new(&config)),
+            )
+        };
+        let genesis = |hash: &str| format!(r#"{{"block_number":0,"block_hash":"{}"}}"#, hash);
+
+        // Nothing is decided before the gateway answers, nor fetched when skipped
+        let gateway = Arc::new(MockGateway::default());
+        let unanswered = check(&gateway, false);
+        let stopped = unanswered.passed(&AtomicBool::new(false)).await;
+        assert!(matches!(
+            stopped.unwrap_err().as_ref(),
+            NetworkCheckError::Stopped
+        ));
+        assert!(unanswered.refused().is_none());
+        assert!(check(&gateway, true)
+            .passed(&AtomicBool::new(true))
+            .await
+            .is_ok());
+
+        let running = AtomicBool::new(true);
+        gateway.insert_block(0, genesis("0x1"));
+        let other = check(&gateway, false);
+        assert!(other.passed(&running).await.is_err());
+        assert!(matches!(
+            other.refused().unwrap().as_ref(),
+            NetworkCheckError::OtherNetwork { .. }
+        ));
+        assert!(read_genesis(storage.db()).unwrap().is_none());
+
+        let gateway = Arc::new(MockGateway::default());
+        gateway.insert_block(0, genesis(Network::Mainnet.genesis_hash()));
+        assert!(check(&gateway, false).passed(&running).await.is_ok());
+        assert_eq!(
+            read_genesis(storage.db()).unwrap().unwrap().block_hash,
+            Network::Mainnet.genesis_hash()
+        );
+    }
+}
@@ -0,0 +1,59 @@
+//! Readiness and watchdog notifications for `Type=notify` units, no-ops when
+//! not started by systemd or on other platforms
+
+use std::sync::Arc;
+
+use crate::storage::Storage;
+
+#[cfg(unix)]
+fn notify(state: &[sd_notify::NotifyState]) {
+    if let Err(e) = sd_notify::notify(false, state) {
+        tracing::error!("❌ Error notifying systemd: {}", e);
+    }
+}
+
+/// Sent once the DB is open and, when serving, the server is bound
+#[cfg(unix)]
+pub fn ready(status: &str) {
+    notify(&[
+        sd_notify::NotifyState::Ready,
+        sd_notify::NotifyState::Status(status),
+    ]);
+}
+
+#[cfg(not(unix))]
+pub fn ready(_status: &str) {}
+
+#[cfg(unix)]
+pub fn stopping() {
+    notify(&[sd_notify::NotifyState::Stopping]);
+}
+
+#[cfg(not(unix))]
+pub fn stopping() {}
+
+/// Pings the watchdog at half the interval systemd expects, as long as the
+/// runtime is responsive and the DB can be read, so a hang gets the unit
+/// restarted
+#[cfg(unix)]
+pub async fn watchdog(storage: Arc<Storage>) {
+    use crate::primitives::Block;
+
+    let mut usec = 0;
+    if !sd_notify::watchdog_enabled(false, &mut usec) {
+        return;
+    }
+    tracing::info!("🐶 Pinging the systemd watchdog every {}ms", usec / 2000);
+
+    let mut interval = tokio::time::interval(std::time::Duration::from_micros(usec / 2));
+    loop {
+        interval.tick().await;
+        match storage.read(Block(0).key()).await {
+            Ok(_) => notify(&[sd_notify::NotifyState::Watchdog]),
+            Err(e) => tracing::error!("❌ Health check failed, skipping watchdog ping: {}", e),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn watchdog(_storage: Arc<Storage>) {}
@@ -0,0 +1,72 @@
+#[cfg(feature = "server")]
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    middleware::Next,
+    Error,
+};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+#[cfg(feature = "server")]
+use tracing::Instrument;
+
+#[cfg(feature = "server")]
+use crate::access_log::REQUEST_ID_HEADER;
+
+/// Provider exporting the spans to the OTLP collector at `endpoint`, spans
+/// are dropped when no endpoint is given
+pub fn init(endpoint: Option<&str>) -> Result<Option<SdkTracerProvider>, String> {
+    let Some(endpoint) = endpoint else {
+        return Ok(None);
+    };
+
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()
+        .map_err(|e| e.to_string())?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            Resource::builder()
+                .with_service_name(env!("CARGO_PKG_NAME"))
+                .build(),
+        )
+        .build();
+
+    Ok(Some(provider))
+}
+
+/// Flushes the spans not exported yet
+pub fn shutdown(provider: Option<SdkTracerProvider>) {
+    if let Some(provider) = provider {
+        if let Err(e) = provider.shutdown() {
+            tracing::error!("❌ Error exporting traces: {}", e);
+        }
+    }
+}
+
+/// Wraps every request in a span, so the DB reads and upstream fetches of a
+/// handler are attributed to it
+#[cfg(feature = "server")]
+pub async fn http_span(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let span = tracing::info_span!(
+        "http_request",
+        method = %req.method(),
+        path = req.path(),
+        query = req.query_string(),
+        request_id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|id| id.to_str().ok()),
+        status = tracing::field::Empty,
+    );
+
+    let res = next.call(req).instrument(span.clone()).await?;
+    span.record("status", res.status().as_u16());
+    Ok(res)
+}
@@ -0,0 +1,305 @@
+use reqwest::{Client, ClientBuilder, Proxy, Response};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+
+/// Upper bounds of the request latency histogram buckets, in seconds
+pub const LATENCY_BUCKETS: [f64; 10] = [0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Requests sent to one upstream
+#[derive(Default, Clone)]
+pub struct UpstreamStats {
+    pub requests: u64,
+    /// Requests per response status, `error` when no response was received
+    pub statuses: BTreeMap<String, u64>,
+    /// Transport errors, 429 and 5xx responses
+    pub failures: u64,
+    pub consecutive_failures: u64,
+    pub max_consecutive_failures: u64,
+    pub last_error: Option<String>,
+    /// Requests per latency bucket of `LATENCY_BUCKETS`, the last one counting
+    /// the slower requests
+    pub latency_buckets: Vec<u64>,
+    pub latency_sum_seconds: f64,
+    pub circuit: Circuit,
+    /// When the circuit was opened or last probed
+    circuit_since: Option<Instant>,
+}
+
+impl UpstreamStats {
+    #[cfg(feature = "server")]
+    pub fn error_rate(&self) -> f64 {
+        match self.requests {
+            0 => 0.0,
+            requests => self.failures as f64 / requests as f64,
+        }
+    }
+
+    fn record(&mut self, status: String, failed: bool, error: Option<String>, latency: f64) {
+        self.requests += 1;
+        *self.statuses.entry(status).or_default() += 1;
+
+        match failed {
+            true => {
+                self.failures += 1;
+                self.consecutive_failures += 1;
+                self.max_consecutive_failures =
+                    self.max_consecutive_failures.max(self.consecutive_failures);
+            }
+            false => self.consecutive_failures = 0,
+        }
+        if error.is_some() {
+            self.last_error = error;
+        }
+
+        if self.latency_buckets.is_empty() {
+            self.latency_buckets = vec![0; LATENCY_BUCKETS.len() + 1];
+        }
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| latency <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.latency_buckets[bucket] += 1;
+        self.latency_sum_seconds += latency;
+    }
+
+    /// Once the cool-down is over, the first request is let through as a
+    /// probe and the others are rejected until the next cool-down
+    fn admit(&mut self, cooldown: Duration) -> bool {
+        match self.circuit {
+            Circuit::Closed => true,
+            Circuit::Open | Circuit::HalfOpen => {
+                if self
+                    .circuit_since
+                    .is_some_and(|since| since.elapsed() < cooldown)
+                {
+                    return false;
+                }
+                self.circuit = Circuit::HalfOpen;
+                self.circuit_since = Some(Instant::now());
+                true
+            }
+        }
+    }
+
+    /// Opens the circuit after `threshold` failures in a row or a failed
+    /// probe, and closes it on the first success. Returns the new state on
+    /// a change
+    fn trip(&mut self, failed: bool, threshold: u64) -> Option<Circuit> {
+        let circuit = match (self.circuit, failed) {
+            (Circuit::Closed, true) if threshold > 0 && self.consecutive_failures >= threshold => {
+                Circuit::Open
+            }
+            (Circuit::HalfOpen, true) => Circuit::Open,
+            (Circuit::Open | Circuit::HalfOpen, false) => Circuit::Closed,
+            _ => return None,
+        };
+        self.circuit = circuit;
+        self.circuit_since = Some(Instant::now());
+        Some(circuit)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum UpstreamError {
+    /// Not sent, the upstream failed too many times in a row
+    #[error("circuit open to {0}")]
+    CircuitOpen(String),
+    #[error("body larger than {0} bytes")]
+    TooLarge(u64),
+    #[error(transparent)]
+    Transport(#[from] reqwest::Error),
+}
+
+/// Why an HTTP client to the upstream could not be built
+#[derive(Debug, thiserror::Error)]
+pub enum ClientError {
+    #[error("proxy {url}: {source}")]
+    Proxy { url: String, source: reqwest::Error },
+    #[error(transparent)]
+    Build(reqwest::Error),
+}
+
+/// The client builder with `--upstream-proxy` applied
+pub fn with_proxy(builder: ClientBuilder, config: &Config) -> Result<ClientBuilder, ClientError> {
+    match &config.upstream_proxy {
+        Some(url) => Ok(
+            builder.proxy(Proxy::all(url).map_err(|source| ClientError::Proxy {
+                url: url.clone(),
+                source,
+            })?),
+        ),
+        None => Ok(builder),
+    }
+}
+
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+pub enum Circuit {
+    #[default]
+    Closed,
+    /// No request is sent until the cool-down is over
+    Open,
+    /// A single request is sent to probe the upstream
+    HalfOpen,
+}
+
+impl Circuit {
+    #[cfg(feature = "server")]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Circuit::Closed => "closed",
+            Circuit::Open => "open",
+            Circuit::HalfOpen => "half_open",
+        }
+    }
+}
+
+/// Client for the gateway requests, recording the outcome of every request
+/// per upstream
+pub struct Upstream {
+    client: Client,
+    max_body_size: u64,
+    circuit_threshold: u64,
+    circuit_cooldown: Duration,
+    /// Lowercase names of `--capture-upstream-header`
+    captured_headers: Vec<String>,
+    stats: Mutex<BTreeMap<String, UpstreamStats>>,
+}
+
+impl Upstream {
+    /// Goes through the configured proxy when given and through
+    /// `HTTPS_PROXY`/`HTTP_PROXY`/`ALL_PROXY` otherwise. The client is shared
+    /// by the sync tasks, the peers and the proxy, so their connections are
+    /// pooled and multiplexed over HTTP/2 when the gateway supports it
+    pub fn new(config: &Config) -> Result<Upstream, ClientError> {
+        let mut builder = Client::builder()
+            .timeout(Duration::from_secs(config.upstream_timeout))
+            .pool_max_idle_per_host(config.upstream_max_idle_connections)
+            .pool_idle_timeout(Duration::from_secs(90))
+            .tcp_keepalive(Duration::from_secs(60))
+            .http2_adaptive_window(true)
+            .http2_keep_alive_interval(Duration::from_secs(30))
+            .http2_keep_alive_while_idle(true);
+        if config.upstream_http2_prior_knowledge {
+            builder = builder.http2_prior_knowledge();
+        }
+        Ok(Upstream {
+            client: with_proxy(builder, config)?
+                .build()
+                .map_err(ClientError::Build)?,
+            max_body_size: config.upstream_max_body_size,
+            circuit_threshold: config.upstream_circuit_threshold,
+            circuit_cooldown: Duration::from_secs(config.upstream_circuit_cooldown),
+            captured_headers: config
+                .capture_upstream_header
+                .iter()
+                .map(|name| name.trim().to_lowercase())
+                .collect(),
+            stats: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    /// Reads the body of `response`, giving up past the maximum body size
+    /// instead of buffering it all
+    pub async fn text(&self, mut response: Response) -> Result<String, UpstreamError> {
+        if response.content_length().unwrap_or(0) > self.max_body_size {
+            return Err(UpstreamError::TooLarge(self.max_body_size));
+        }
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            if (body.len() + chunk.len()) as u64 > self.max_body_size {
+                return Err(UpstreamError::TooLarge(self.max_body_size));
+            }
+            body.extend_from_slice(&chunk);
+        }
+        Ok(String::from_utf8_lossy(&body).into_owned())
+    }
+
+    /// The headers of `response` to keep in the fetch metadata, repeated
+    /// ones joined with commas
+    pub fn capture(&self, response: &Response) -> BTreeMap<String, String> {
+        self.captured_headers
+            .iter()
+            .filter_map(|name| {
+                let values: Vec<&str> = response
+                    .headers()
+                    .get_all(name.as_str())
+                    .iter()
+                    .filter_map(|value| value.to_str().ok())
+                    .collect();
+                (!values.is_empty()).then(|| (name.clone(), values.join(", ")))
+            })
+            .collect()
+    }
+
+    pub async fn get(&self, url: &str) -> Result<Response, UpstreamError> {
+        self.get_with_headers(url, &[]).await
+    }
+
+    pub async fn get_with_headers(
+        &self,
+        url: &str,
+        headers: &[(&str, &str)],
+    ) -> Result<Response, UpstreamError> {
+        let name = upstream_name(url);
+        let admitted = self
+            .stats
+            .lock()
+            .unwrap()
+            .entry(name.clone())
+            .or_default()
+            .admit(self.circuit_cooldown);
+        if !admitted {
+            return Err(UpstreamError::CircuitOpen(name));
+        }
+
+        let started = Instant::now();
+        let mut request = self.client.get(url);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let response = request.send().await;
+        let latency = started.elapsed().as_secs_f64();
+
+        let (status, failed, error) = match &response {
+            Ok(response) => {
+                let status = response.status();
+                let failed = status.is_server_error() || status.as_u16() == 429;
+                let error = failed.then(|| status.to_string());
+                (status.as_u16().to_string(), failed, error)
+            }
+            Err(e) => ("error".to_string(), true, Some(e.to_string())),
+        };
+        let mut stats = self.stats.lock().unwrap();
+        let stats = stats.entry(name.clone()).or_default();
+        stats.record(status, failed, error, latency);
+        match stats.trip(failed, self.circuit_threshold) {
+            Some(Circuit::Open) => tracing::warn!(
+                "🔌 Circuit to {} open after {} failures in a row, pausing for {} sec",
+                name,
+                stats.consecutive_failures,
+                self.circuit_cooldown.as_secs()
+            ),
+            Some(_) => tracing::info!("🔌 Circuit to {} closed", name),
+            None => {}
+        }
+
+        Ok(response?)
+    }
+
+    #[cfg(feature = "server")]
+    pub fn stats(&self) -> BTreeMap<String, UpstreamStats> {
+        self.stats.lock().unwrap().clone()
+    }
+}
+
+/// Requests are grouped by scheme, host and port, the gateway URL can change
+/// on reload
+pub fn upstream_name(url: &str) -> String {
+    match url::Url::parse(url) {
+        Ok(url) => url.origin().ascii_serialization(),
+        Err(_) => "invalid".to_string(),
+    }
+}
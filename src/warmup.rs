@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::primitives::{Block, Class, State};
+use crate::storage::{read_data, write_data, Storage};
+use crate::supervisor::TaskResult;
+
+/// Classes most requested during the previous run, as a JSON array
+const HOT_CLASSES: &str = "hot_classes";
+
+/// Keys read per batched lookup
+const BATCH: u64 = 64;
+
+/// Requests per class since the start, to preload the most requested ones on
+/// the next start
+#[derive(Default)]
+pub struct ClassHits(Mutex<HashMap<String, u64>>);
+
+impl ClassHits {
+    pub fn record(&self, class_hash: &str) {
+        *self
+            .0
+            .lock()
+            .unwrap()
+            .entry(class_hash.to_string())
+            .or_default() += 1;
+    }
+
+    /// Stores the `limit` most requested classes, the previous ones are kept
+    /// when no class was requested
+    pub fn save(&self, storage: &Storage, limit: usize) -> Result<(), String> {
+        let hits = self.0.lock().unwrap();
+        if hits.is_empty() {
+            return Ok(());
+        }
+        let mut hottest: Vec<(&String, &u64)> = hits.iter().collect();
+        hottest.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        let hottest: Vec<&String> = hottest
+            .into_iter()
+            .take(limit)
+            .map(|(hash, _)| hash)
+            .collect();
+        let content = serde_json::to_string(&hottest).map_err(|e| e.to_string())?;
+        write_data(storage.db(), HOT_CLASSES, &content).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+}
+
+/// Runs the preload on the blocking pool, a failure is logged and does not
+/// stop the server
+pub async fn run(storage: Arc<Storage>, blocks: u64, classes: usize) -> TaskResult {
+    match storage
+        .blocking(move |storage| preload(storage, blocks, classes))
+        .await
+    {
+        Ok(summary) => Ok(summary),
+        Err(e) => {
+            tracing::error!("❌ Error warming up: {}", e);
+            Ok(format!("warm-up failed: {}", e))
+        }
+    }
+}
+
+/// Reads the `blocks` last blocks and state updates and the `classes` most
+/// requested classes of the previous run, so the DB blocks holding them are
+/// in memory before the first requests
+fn preload(storage: &Storage, blocks: u64, classes: usize) -> Result<String, String> {
+    let started = Instant::now();
+    let mut bytes = 0;
+    let mut read = |keys: Vec<String>| -> Result<(), String> {
+        for value in storage
+            .multi_get(&keys)
+            .map_err(|e| e.to_string())?
+            .into_iter()
+            .flatten()
+        {
+            bytes += value.len();
+        }
+        Ok(())
+    };
+
+    let tail =
+        |last: Option<u64>| last.map_or(0..0, |last| (last + 1).saturating_sub(blocks)..last + 1);
+    let block_range = tail(storage.max_block_sync().map(|block| block.0));
+    let state_range = tail(storage.max_state_sync().map(|state| state.0));
+    for start in block_range.clone().step_by(BATCH as usize) {
+        let end = (start + BATCH).min(block_range.end);
+        read((start..end).map(|number| Block(number).key()).collect())?;
+    }
+    for start in state_range.clone().step_by(BATCH as usize) {
+        let end = (start + BATCH).min(state_range.end);
+        read((start..end).map(|number| State(number).key()).collect())?;
+    }
+
+    let hot_classes: Vec<String> =
+        match read_data(storage.db(), HOT_CLASSES).map_err(|e| e.to_string())? {
+            Some(content) => serde_json::from_slice(&content).map_err(|e| e.to_string())?,
+            None => vec![],
+        };
+    let hot_classes: Vec<String> = hot_classes.into_iter().take(classes).collect();
+    for batch in hot_classes.chunks(BATCH as usize) {
+        read(batch.iter().map(|hash| Class(hash.clone()).key()).collect())?;
+    }
+
+    Ok(format!(
+        "Preloaded {} blocks, {} state updates and {} classes, {} bytes in {} ms",
+        block_range.count(),
+        state_range.count(),
+        hot_classes.len(),
+        bytes,
+        started.elapsed().as_millis()
+    ))
+}